@@ -0,0 +1,186 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use poison::recover_poisoned;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A request answered by the portal, as the access log and the metrics
+/// record it
+pub struct AccessEntry<'a> {
+    pub client: &'a str,
+    pub method: &'a str,
+    /// The path without the query string, which may carry credentials
+    pub path: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    /// Bytes of the body, if its length is known
+    pub size: Option<u64>,
+    pub duration_ms: u128,
+    /// Quoted, or `-` without one
+    pub user_agent: &'a str,
+}
+
+/// The file a line is appended to for every request, in the Common Log
+/// Format with the milliseconds the request took and the `User-Agent`
+/// added, like
+/// `192.168.42.10 - - [14/Nov/2023:22:13:20 +0000] "GET /networks HTTP/1.1" 200 512 35 "Mozilla/5.0"`
+pub struct AccessLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AccessLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = append(&path)?;
+
+        Ok(AccessLog {
+            path,
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Opens the file by its path again, so that lines are appended to a new
+    /// one once logrotate moved the old one away. Keeps writing to the old
+    /// one if that fails.
+    pub fn reopen(&self) {
+        let mut file = self.file.lock().unwrap_or_else(recover_poisoned);
+
+        match append(&self.path) {
+            Ok(reopened) => *file = reopened,
+            Err(e) => warn!(
+                "Reopening the access log {} failed: {}",
+                self.path.display(),
+                e
+            ),
+        }
+    }
+
+    /// Failing to write is logged, the request is answered regardless
+    pub fn write(&self, entry: &AccessEntry) {
+        let line = format!(
+            "{} - - [{}] \"{} {} {}\" {} {} {} {}\n",
+            entry.client,
+            clf_time(now()),
+            entry.method,
+            entry.path,
+            entry.version,
+            entry.status,
+            entry
+                .size
+                .map_or_else(|| "-".to_string(), |size| size.to_string()),
+            entry.duration_ms,
+            entry.user_agent
+        );
+
+        let mut file = self.file.lock().unwrap_or_else(recover_poisoned);
+
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!(
+                "Writing to the access log {} failed: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+fn append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// The seconds since the epoch as `14/Nov/2023:22:13:20 +0000`
+fn clf_time(timestamp: u64) -> String {
+    let (year, month, day) = civil_date(timestamp / 86_400);
+    let seconds = timestamp % 86_400;
+
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// The year, month and day of the days since the epoch, by the algorithm
+/// of Howard Hinnant's `civil_from_days`
+fn civil_date(days: u64) -> (u64, u64, u64) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    #[test]
+    fn formats_times_in_the_common_log_format() {
+        assert_eq!(clf_time(0), "01/Jan/1970:00:00:00 +0000");
+        assert_eq!(clf_time(1_700_000_000), "14/Nov/2023:22:13:20 +0000");
+        // The leap day of a year divisible by 400
+        assert_eq!(clf_time(951_782_400), "29/Feb/2000:00:00:00 +0000");
+    }
+
+    #[test]
+    fn reopens_a_rotated_file() {
+        let directory = env::temp_dir().join(format!("wifi-connect-access-log-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("access.log");
+        let rotated = directory.join("access.log.1");
+
+        let entry = AccessEntry {
+            client: "192.168.42.10:50000",
+            method: "GET",
+            path: "/networks",
+            version: "HTTP/1.1",
+            status: 200,
+            size: Some(512),
+            duration_ms: 35,
+            user_agent: "-",
+        };
+
+        let access_log = AccessLog::open(&path).unwrap();
+        access_log.write(&entry);
+        fs::rename(&path, &rotated).unwrap();
+        access_log.reopen();
+        access_log.write(&entry);
+
+        let lines = |path: &Path| fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&rotated), 1);
+        assert_eq!(lines(&path), 1);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
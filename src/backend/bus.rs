@@ -0,0 +1,136 @@
+use std::thread;
+use std::time::Duration;
+
+use dbus::arg::{Arg, Get};
+use dbus::{BusType, Connection, Message};
+
+use errors::*;
+
+pub const DBUS_TIMEOUT_MS: i32 = 5000;
+
+/// Errors NetworkManager answers with while a device is still settling, as
+/// right after the portal access point has been torn down
+const BUSY_ERRORS: [&str; 2] = [
+    "org.freedesktop.NetworkManager.Device.NotReady",
+    "org.freedesktop.NetworkManager.Device.NotAllowed",
+];
+
+const BUSY_ATTEMPTS: u32 = 4;
+
+/// Doubled after every busy answer
+const BUSY_RETRY_DELAY_MS: u64 = 500;
+
+const DBUS_SERVICE: &str = "org.freedesktop.DBus";
+const DBUS_PATH: &str = "/org/freedesktop/DBus";
+
+pub fn system_bus() -> Result<Connection> {
+    Ok(Connection::get_private(BusType::System)?)
+}
+
+pub fn method_call(
+    destination: &str,
+    path: &str,
+    interface: &str,
+    method: &str,
+) -> Result<Message> {
+    Message::new_method_call(destination, path, interface, method)
+        .map_err(|e| ErrorKind::DBusCall(method.to_string(), e).into())
+}
+
+/// Sends a method call and waits for the reply, keeping the D-Bus error name
+/// in the error so failures remain actionable
+pub fn call_method(
+    connection: &Connection,
+    message: Message,
+    method: &str,
+    timeout_ms: i32,
+) -> Result<Message> {
+    connection
+        .send_with_reply_and_block(message, timeout_ms)
+        .map_err(|e| ErrorKind::DBusCall(method.to_string(), describe_error(&e)).into())
+}
+
+/// Like `call_method`, but sends the call again a few times with growing
+/// delays while the service answers that the device is busy or not ready.
+/// The last error is kept in the chain once the attempts run out.
+pub fn call_method_retrying(
+    connection: &Connection,
+    message: Message,
+    method: &str,
+    timeout_ms: i32,
+) -> Result<Message> {
+    let mut delay = Duration::from_millis(BUSY_RETRY_DELAY_MS);
+    let mut attempt = 1;
+
+    loop {
+        // Sending takes the message, which is needed again for a retry
+        let copy = message
+            .duplicate()
+            .map_err(|e| ErrorKind::DBusCall(method.to_string(), e))?;
+
+        let error = match connection.send_with_reply_and_block(copy, timeout_ms) {
+            Ok(reply) => {
+                if attempt > 1 {
+                    info!(
+                        "D-Bus method call '{}' succeeded on attempt {}",
+                        method, attempt
+                    );
+                }
+
+                return Ok(reply);
+            }
+            Err(error) => error,
+        };
+
+        let failed = Error::from(ErrorKind::DBusCall(
+            method.to_string(),
+            describe_error(&error),
+        ));
+
+        if !error
+            .name()
+            .map_or(false, |name| BUSY_ERRORS.contains(&name))
+        {
+            return Err(failed);
+        }
+
+        if attempt == BUSY_ATTEMPTS {
+            return Err(failed).chain_err(|| ErrorKind::DBusBusy(method.to_string(), attempt));
+        }
+
+        warn!(
+            "D-Bus method call '{}' failed on attempt {} of {}, retrying in {}ms: {}",
+            method,
+            attempt,
+            BUSY_ATTEMPTS,
+            delay.as_millis(),
+            describe_error(&error)
+        );
+
+        thread::sleep(delay);
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+pub fn read1<'a, T: Arg + Get<'a>>(reply: &'a Message, method: &str) -> Result<T> {
+    reply
+        .read1()
+        .map_err(|e| ErrorKind::DBusCall(method.to_string(), format!("{:?}", e)).into())
+}
+
+pub fn describe_error(error: &::dbus::Error) -> String {
+    format!(
+        "{}: {}",
+        error.name().unwrap_or("unknown error"),
+        error.message().unwrap_or("")
+    )
+}
+
+pub fn name_has_owner(connection: &Connection, name: &str) -> Result<bool> {
+    let message = method_call(DBUS_SERVICE, DBUS_PATH, DBUS_SERVICE, "NameHasOwner")?.append1(name);
+
+    let reply = call_method(connection, message, "NameHasOwner", DBUS_TIMEOUT_MS)?;
+
+    read1(&reply, "NameHasOwner")
+}
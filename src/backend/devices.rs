@@ -0,0 +1,420 @@
+use std::fs;
+use std::net::Ipv6Addr;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use backend::{DeviceInfo, RfkillState, WirelessCapabilities};
+use errors::*;
+
+const SYS_CLASS_NET: &str = "/sys/class/net";
+
+/// Longest interface name the kernel takes, `IFNAMSIZ` without the NUL
+const MAX_INTERFACE_NAME_LENGTH: usize = 15;
+
+/// `ARPHRD_` link types of `/sys/class/net/<interface>/type`
+const ARPHRD_ETHER: &str = "1";
+const ARPHRD_LOOPBACK: &str = "772";
+
+/// The network adapters known to the kernel, for network stacks without a
+/// device list of their own
+pub fn list_system_devices() -> Result<Vec<DeviceInfo>> {
+    let mut devices = Vec::new();
+
+    for entry in fs::read_dir(SYS_CLASS_NET)? {
+        let entry = entry?;
+        let interface = entry.file_name().to_string_lossy().into_owned();
+        let path = entry.path();
+
+        let wireless = path.join("phy80211").exists();
+
+        let kind = if wireless {
+            "wifi"
+        } else {
+            match read_attribute(&path.join("type"))
+                .as_ref()
+                .map(String::as_str)
+            {
+                Some(ARPHRD_ETHER) => "ethernet",
+                Some(ARPHRD_LOOPBACK) => "loopback",
+                _ => "other",
+            }
+        };
+
+        devices.push(DeviceInfo {
+            kind: kind.to_string(),
+            mac: read_attribute(&path.join("address")),
+            driver: device_driver(&interface),
+            state: read_attribute(&path.join("operstate")).unwrap_or_else(|| "unknown".into()),
+            wireless: if wireless {
+                Some(wireless_capabilities(&interface))
+            } else {
+                None
+            },
+            rfkill: rfkill_state(&interface),
+            interface,
+        });
+    }
+
+    devices.sort_by(|a, b| a.interface.cmp(&b.interface));
+
+    Ok(devices)
+}
+
+/// Name of the kernel driver bound to the interface, none for virtual ones
+pub fn device_driver(interface: &str) -> Option<String> {
+    let driver = fs::read_link(
+        Path::new(SYS_CLASS_NET)
+            .join(interface)
+            .join("device/driver"),
+    );
+
+    driver.ok().and_then(|driver| {
+        driver
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+    })
+}
+
+/// The rfkill switch of the WiFi adapter of the interface, none if it has
+/// no such switch or is not a WiFi adapter
+pub fn rfkill_state(interface: &str) -> Option<RfkillState> {
+    rfkill_path(interface).map(|path| read_rfkill_state(&path))
+}
+
+/// Fresh OS images often ship with WiFi soft blocked, which is undone here,
+/// while a hard blocked radio takes flipping the physical switch
+pub fn unblock_radio(interface: &str) -> Result<()> {
+    let path = match rfkill_path(interface) {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let state = read_rfkill_state(&path);
+
+    if state.hard_blocked {
+        bail!(ErrorKind::RadioHardBlocked(interface.to_string()));
+    }
+
+    if state.soft_blocked {
+        info!("The radio of {} is soft blocked, unblocking it", interface);
+
+        if let Err(e) = fs::write(path.join("soft"), "0") {
+            warn!("Unblocking the radio of {} failed: {}", interface, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// The `rfkill<n>` device of the PHY of the interface
+fn rfkill_path(interface: &str) -> Option<PathBuf> {
+    let phy = Path::new(SYS_CLASS_NET).join(interface).join("phy80211");
+
+    fs::read_dir(phy)
+        .ok()?
+        .filter_map(|entry| entry.ok())
+        .find(|entry| entry.file_name().to_string_lossy().starts_with("rfkill"))
+        .map(|entry| entry.path())
+}
+
+/// Assigns the IPv6 address of the portal, which none of the backends
+/// configure, skipping duplicate address detection as the portal network
+/// is brand new
+pub fn assign_ipv6_address(interface: &str, address: Ipv6Addr, prefix: u8) -> Result<()> {
+    let output = Command::new("ip")
+        .args(&[
+            "-6",
+            "addr",
+            "replace",
+            &format!("{}/{}", address, prefix),
+            "dev",
+            interface,
+            "nodad",
+        ])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "ip -6 addr replace failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Name of the virtual interface the portal access point is raised on next
+/// to the station interface, like `ap-wlan0`
+pub fn ap_interface_name(interface: &str) -> String {
+    format!("ap-{}", interface)
+        .chars()
+        .take(MAX_INTERFACE_NAME_LENGTH)
+        .collect()
+}
+
+/// Adds a virtual access point interface to the adapter of the interface,
+/// unless one of that name is left from an earlier run
+pub fn add_ap_interface(interface: &str, ap_interface: &str) -> Result<()> {
+    if Path::new(SYS_CLASS_NET).join(ap_interface).exists() {
+        debug!("Reusing the access point interface {}", ap_interface);
+        return Ok(());
+    }
+
+    let output = Command::new("iw")
+        .args(&[
+            "dev",
+            interface,
+            "interface",
+            "add",
+            ap_interface,
+            "type",
+            "__ap",
+        ])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "iw dev {} interface add failed: {}",
+            interface,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+/// Address families of the default routes through the interface, as the
+/// kernel routing tables list them
+pub fn default_routes(interface: &str) -> Vec<&'static str> {
+    let mut families = Vec::new();
+
+    // Iface, Destination, Gateway, Flags, RefCnt, Use, Metric, Mask, ...
+    let ipv4 = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    let ipv4_default = ipv4.lines().skip(1).any(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        fields.len() > 7
+            && fields[0] == interface
+            && fields[1] == "00000000"
+            && fields[7] == "00000000"
+    });
+
+    if ipv4_default {
+        families.push("IPv4");
+    }
+
+    // Destination, prefix length, source, prefix length, next hop, metric,
+    // reference count, use count, flags and interface
+    let ipv6 = fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    let ipv6_default = ipv6.lines().any(|line| {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        fields.len() > 9
+            && fields[9] == interface
+            && fields[1] == "00"
+            && fields[0].chars().all(|c| c == '0')
+    });
+
+    if ipv6_default {
+        families.push("IPv6");
+    }
+
+    families
+}
+
+/// Deletes the default route of the address family through the interface
+pub fn delete_default_route(interface: &str, family: &str) -> Result<()> {
+    let family = if family == "IPv6" { "-6" } else { "-4" };
+
+    let output = Command::new("ip")
+        .args(&[family, "route", "del", "default", "dev", interface])
+        .output()?;
+
+    if !output.status.success() {
+        bail!(
+            "ip {} route del default failed: {}",
+            family,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+
+    Ok(())
+}
+
+fn read_rfkill_state(path: &Path) -> RfkillState {
+    let blocked =
+        |attribute| read_attribute(&path.join(attribute)).map_or(false, |value| value == "1");
+
+    RfkillState {
+        soft_blocked: blocked("soft"),
+        hard_blocked: blocked("hard"),
+    }
+}
+
+/// Capabilities of the WiFi adapter of the interface as `iw` reports them,
+/// all unknown if it is not installed
+pub fn wireless_capabilities(interface: &str) -> WirelessCapabilities {
+    let phy = match read_attribute(
+        &Path::new(SYS_CLASS_NET)
+            .join(interface)
+            .join("phy80211/name"),
+    ) {
+        Some(phy) => phy,
+        None => return WirelessCapabilities::default(),
+    };
+
+    let output = match Command::new("iw").args(&["phy", &phy, "info"]).output() {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Ok(_) | Err(_) => {
+            debug!("Reading the capabilities of {} with iw failed", phy);
+            return WirelessCapabilities::default();
+        }
+    };
+
+    parse_phy_info(&output)
+}
+
+/// The channel the interface operates on, as the `channel 6 (2437 MHz)`
+/// line of `iw dev <interface> info` has it. None while it is down or if
+/// `iw` is not installed.
+pub fn operating_channel(interface: &str) -> Option<u32> {
+    let output = match Command::new("iw")
+        .args(&["dev", interface, "info"])
+        .output()
+    {
+        Ok(ref output) if output.status.success() => {
+            String::from_utf8_lossy(&output.stdout).into_owned()
+        }
+        Ok(_) | Err(_) => return None,
+    };
+
+    output
+        .lines()
+        .map(str::trim)
+        .find(|line| line.starts_with("channel "))
+        .and_then(|line| line["channel ".len()..].split_whitespace().next())
+        .and_then(|channel| channel.parse().ok())
+}
+
+/// Reads the interface modes and combinations, bands and scan limit of
+/// `iw phy <phy> info`
+fn parse_phy_info(info: &str) -> WirelessCapabilities {
+    let ap_sta = interface_combinations(info)
+        .iter()
+        .any(|combination| allows_ap_sta(combination));
+
+    let mut capabilities = WirelessCapabilities {
+        ap_mode: Some(false),
+        ap_sta: Some(ap_sta),
+        band_5ghz: Some(false),
+        max_scan_ssids: None,
+    };
+
+    let mut in_modes = false;
+
+    for line in info.lines() {
+        let line = line.trim();
+
+        if line.starts_with("Supported interface modes:") {
+            in_modes = true;
+            continue;
+        }
+
+        if in_modes {
+            if line.starts_with('*') {
+                if line.trim_start_matches('*').trim() == "AP" {
+                    capabilities.ap_mode = Some(true);
+                }
+                continue;
+            }
+            in_modes = false;
+        }
+
+        if line.starts_with("Band 2:") {
+            capabilities.band_5ghz = Some(true);
+        } else if line.starts_with("max # scan SSIDs:") {
+            capabilities.max_scan_ssids = line
+                .trim_start_matches("max # scan SSIDs:")
+                .trim()
+                .parse()
+                .ok();
+        }
+    }
+
+    capabilities
+}
+
+/// The entries of the `valid interface combinations` list, each starting
+/// with `*` and possibly continued on the following lines
+fn interface_combinations(info: &str) -> Vec<String> {
+    let mut combinations: Vec<String> = Vec::new();
+    let mut in_combinations = false;
+
+    for line in info.lines() {
+        let line = line.trim();
+
+        if line.starts_with("valid interface combinations:") {
+            in_combinations = true;
+        } else if in_combinations && line.starts_with('*') {
+            combinations.push(line.trim_start_matches('*').to_string());
+        } else if in_combinations && (line.starts_with("total") || line.starts_with('#')) {
+            if let Some(combination) = combinations.last_mut() {
+                combination.push(' ');
+                combination.push_str(line);
+            }
+        } else {
+            in_combinations = false;
+        }
+    }
+
+    combinations
+}
+
+/// Whether an interface combination like `#{ managed } <= 1, #{ AP } <= 1,
+/// total <= 2, #channels <= 1` has room for a station and an access point
+fn allows_ap_sta(combination: &str) -> bool {
+    let mut managed = 0;
+    let mut ap = 0;
+    // Interfaces of either mode
+    let mut shared = 0;
+
+    for limit in combination.split("#{").skip(1) {
+        let (modes, count) = match limit.find('}') {
+            Some(end) => (&limit[..end], &limit[end + 1..]),
+            None => continue,
+        };
+
+        let count = parse_limit(count);
+
+        let modes = modes.split(',').map(str::trim).collect::<Vec<_>>();
+
+        match (modes.contains(&"managed"), modes.contains(&"AP")) {
+            (true, true) => shared += count,
+            (true, false) => managed += count,
+            (false, true) => ap += count,
+            (false, false) => {}
+        }
+    }
+
+    let total = combination.find("total").map_or(0, |start| {
+        parse_limit(&combination[start + "total".len()..])
+    });
+
+    managed + shared >= 1 && ap + shared >= 1 && managed + ap + shared >= 2 && total >= 2
+}
+
+/// The number of a `<= 2` limit
+fn parse_limit(limit: &str) -> u32 {
+    limit
+        .trim_start_matches(|c: char| c.is_whitespace() || c == '<' || c == '=')
+        .split(|c: char| !c.is_ascii_digit())
+        .next()
+        .and_then(|limit| limit.parse().ok())
+        .unwrap_or(0)
+}
+
+fn read_attribute(path: &Path) -> Option<String> {
+    fs::read_to_string(path)
+        .ok()
+        .map(|value| value.trim().to_string())
+}
@@ -0,0 +1,790 @@
+use std::cmp;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dbus::arg::{Dict, Iter, Variant};
+use dbus::{Connection, Path};
+
+use backend::bus::{
+    call_method, describe_error, method_call, name_has_owner, read1, system_bus, DBUS_TIMEOUT_MS,
+};
+use backend::{
+    is_hex_psk, rssi_to_strength, select_device, AccessPointInfo, CancelFlag, ConnectFailure,
+    ConnectOptions, ConnectOutcome, ConnectSecurity, Connectivity, Credentials, DeviceCandidate,
+    Metered, NetworkBackend, PortalChannel, SavedProfile, Security, SignalInfo, Ssid,
+};
+use config::Config;
+use errors::*;
+
+pub const IWD_BUS_NAME: &str = "net.connman.iwd";
+
+const DEVICE_INTERFACE: &str = "net.connman.iwd.Device";
+const STATION_INTERFACE: &str = "net.connman.iwd.Station";
+const STATION_DIAGNOSTIC_INTERFACE: &str = "net.connman.iwd.StationDiagnostic";
+const NETWORK_INTERFACE: &str = "net.connman.iwd.Network";
+const KNOWN_NETWORK_INTERFACE: &str = "net.connman.iwd.KnownNetwork";
+const ACCESS_POINT_INTERFACE: &str = "net.connman.iwd.AccessPoint";
+const PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+const OBJECT_MANAGER_INTERFACE: &str = "org.freedesktop.DBus.ObjectManager";
+
+const IWD_STORAGE_DIRECTORY: &str = "/var/lib/iwd";
+
+const SCAN_TIMEOUT_SECS: u64 = 15;
+
+/// Backend for iwd without NetworkManager in front of it. The portal uses the
+/// DHCP server embedded in iwd, which requires `EnableNetworkConfiguration`
+/// in the iwd main configuration, while dnsmasq only answers DNS queries.
+pub struct IwdBackend {
+    connection: Connection,
+    device_path: String,
+    interface: String,
+    networks: Vec<IwdNetwork>,
+    portal_ssid: Option<String>,
+}
+
+struct IwdNetwork {
+    path: String,
+    info: AccessPointInfo,
+}
+
+/// A single interface of an object exported by iwd, with the properties the
+/// backend looks objects up by
+struct IwdObject {
+    path: String,
+    interface: String,
+    name: Option<String>,
+    kind: Option<String>,
+}
+
+impl NetworkBackend for IwdBackend {
+    fn init(config: &Config) -> Result<()> {
+        let connection = system_bus()?;
+
+        if !name_has_owner(&connection, IWD_BUS_NAME)? {
+            bail!(ErrorKind::DBusCall(
+                "NameHasOwner".to_string(),
+                format!("{} is not running", IWD_BUS_NAME)
+            ));
+        }
+
+        remove_file_if_exists(&access_point_profile_path(&config.ssid))
+            .chain_err(|| ErrorKind::DeleteAccessPoint)
+    }
+
+    fn new(config: &Config) -> Result<Self> {
+        let connection = system_bus()?;
+        info!("iwd connection initialized");
+
+        let (device_path, interface) = find_device(&connection, config)?;
+
+        Ok(IwdBackend {
+            connection,
+            device_path,
+            interface,
+            networks: Vec::new(),
+            portal_ssid: None,
+        })
+    }
+
+    fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    fn uses_dnsmasq_dhcp(&self) -> bool {
+        false
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>> {
+        // The device cannot scan while in access point mode
+        if self.portal_ssid.is_none() {
+            self.request_scan()?;
+        }
+
+        let device_prefix = format!("{}/", self.device_path);
+
+        self.networks = managed_objects(&self.connection)?
+            .into_iter()
+            .filter(|object| {
+                object.interface == NETWORK_INTERFACE && object.path.starts_with(&device_prefix)
+            })
+            .filter_map(|object| {
+                let security = get_network_security(object.kind.as_ref()?)?;
+                Some(IwdNetwork {
+                    path: object.path,
+                    info: AccessPointInfo {
+                        ssid: Ssid::from_bytes(object.name?),
+                        security,
+                        // Only reported by the ordered network list of the
+                        // station, which is gone while hosting the portal
+                        strength: None,
+                        // A network may span several access points
+                        bssid: None,
+                    },
+                })
+            })
+            .collect();
+
+        Ok(self
+            .networks
+            .iter()
+            .map(|network| network.info.clone())
+            .collect())
+    }
+
+    fn create_portal(&mut self, config: &Config, channel: PortalChannel) -> Result<()> {
+        let passphrase = match config.passphrase {
+            Some(ref passphrase) => passphrase,
+            None => {
+                bail!("iwd can only create WPA2 protected access points, set a portal passphrase")
+            }
+        };
+
+        info!("Starting access point...");
+
+        write_access_point_profile(config, passphrase, channel)?;
+
+        self.set_device_mode("ap")?;
+
+        let message = method_call(
+            IWD_BUS_NAME,
+            &self.device_path,
+            ACCESS_POINT_INTERFACE,
+            "StartProfile",
+        )?
+        .append1(&config.ssid as &str);
+
+        call_method(&self.connection, message, "StartProfile", DBUS_TIMEOUT_MS)?;
+
+        info!("Access point '{}' created", config.ssid);
+
+        self.portal_ssid = Some(config.ssid.clone());
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self, config: &Config) -> Result<()> {
+        if self.portal_ssid.is_none() {
+            return Ok(());
+        }
+
+        info!("Stopping access point '{}'...", config.ssid);
+
+        let message = method_call(
+            IWD_BUS_NAME,
+            &self.device_path,
+            ACCESS_POINT_INTERFACE,
+            "Stop",
+        )?;
+        call_method(&self.connection, message, "Stop", DBUS_TIMEOUT_MS)?;
+
+        self.set_device_mode("station")?;
+
+        remove_file_if_exists(&access_point_profile_path(&config.ssid))?;
+
+        thread::sleep(Duration::from_secs(1));
+        info!("Access point '{}' stopped", config.ssid);
+
+        self.portal_ssid = None;
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        // iwd only exposes networks with UTF-8 names
+        let ssid = match ssid.as_str() {
+            Some(ssid) => ssid,
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        let (path, security) = match self
+            .networks
+            .iter()
+            .find(|network| network.info.ssid.as_str() == Some(ssid))
+        {
+            Some(network) => (
+                network.path.clone(),
+                credentials.security_or(network.info.security),
+            ),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        // iwd keeps WPA and WPA3 passphrases in the same profile format
+        let uses_passphrase =
+            security == ConnectSecurity::WpaPsk || security == ConnectSecurity::Sae;
+
+        match security {
+            ConnectSecurity::Open => {}
+            ConnectSecurity::WpaPsk | ConnectSecurity::Sae => match credentials.passphrase {
+                Some(ref passphrase) => write_network_passphrase(ssid, security, passphrase)?,
+                None => return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase)),
+            },
+            ConnectSecurity::Wep | ConnectSecurity::WpaEap => {
+                return Ok(ConnectOutcome::Failed(ConnectFailure::UnsupportedSecurity(
+                    security,
+                )))
+            }
+        }
+
+        if options.priority != 0 {
+            warn!("iwd has no network priorities, ignoring the requested priority");
+        }
+
+        if options.metered != Metered::Auto {
+            warn!("iwd has no metered networks, ignoring the requested metered setting");
+        }
+
+        if !options.dns.is_empty() || !options.search_domains.is_empty() {
+            warn!("iwd takes resolvers from DHCP only, ignoring the requested DNS settings");
+        }
+
+        info!("Connecting to access point '{}'...", ssid);
+
+        let message = method_call(IWD_BUS_NAME, &path, NETWORK_INTERFACE, "Connect")?;
+
+        let finished = Arc::new(AtomicBool::new(false));
+        spawn_cancel_watcher(
+            self.device_path.clone(),
+            options.cancel.clone(),
+            finished.clone(),
+        );
+
+        // iwd replies once the connection either succeeded or failed
+        let reply = self
+            .connection
+            .send_with_reply_and_block(message, timeout_ms(options.timeout));
+
+        finished.store(true, Ordering::SeqCst);
+
+        match reply {
+            Ok(_) => {
+                if !options.autoconnect {
+                    if let Err(e) = self.disable_autoconnect(&path) {
+                        warn!("Disabling autoconnect of '{}' failed: {}", ssid, e);
+                    }
+                }
+
+                Ok(ConnectOutcome::Activated)
+            }
+            Err(e) => {
+                let reason = describe_error(&e);
+
+                if uses_passphrase {
+                    if let Err(err) = remove_file_if_exists(&network_passphrase_path(ssid)) {
+                        error!("Deleting network passphrase file failed: {}", err);
+                    }
+                }
+
+                if options.cancel.is_cancelled() {
+                    info!("Connection to access point '{}' cancelled", ssid);
+                    return Ok(ConnectOutcome::Cancelled);
+                }
+
+                warn!(
+                    "Connection to access point not activated '{}': {}",
+                    ssid, reason
+                );
+
+                let failure = match e.name() {
+                    Some("net.connman.iwd.NotFound") => ConnectFailure::AccessPointNotFound,
+                    Some("net.connman.iwd.InvalidFormat") => ConnectFailure::WrongPassphrase,
+                    _ => ConnectFailure::NotActivated(reason),
+                };
+
+                Ok(ConnectOutcome::Failed(failure))
+            }
+        }
+    }
+
+    fn activate_saved(
+        &mut self,
+        profile: &SavedProfile,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let ssid = match profile.ssid.as_str() {
+            Some(ssid) => ssid,
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        let path = match self
+            .networks
+            .iter()
+            .find(|network| network.info.ssid.as_str() == Some(ssid))
+        {
+            Some(network) => network.path.clone(),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        info!("Activating the known network '{}'...", ssid);
+
+        // A known network connects with the stored passphrase
+        let message = method_call(IWD_BUS_NAME, &path, NETWORK_INTERFACE, "Connect")?;
+
+        let finished = Arc::new(AtomicBool::new(false));
+        spawn_cancel_watcher(
+            self.device_path.clone(),
+            options.cancel.clone(),
+            finished.clone(),
+        );
+
+        let reply = self
+            .connection
+            .send_with_reply_and_block(message, timeout_ms(options.timeout));
+
+        finished.store(true, Ordering::SeqCst);
+
+        match reply {
+            Ok(_) => Ok(ConnectOutcome::Activated),
+            Err(_) if options.cancel.is_cancelled() => {
+                info!("Connection to access point '{}' cancelled", ssid);
+                Ok(ConnectOutcome::Cancelled)
+            }
+            Err(e) => {
+                let reason = describe_error(&e);
+
+                warn!(
+                    "Connection to access point not activated '{}': {}",
+                    ssid, reason
+                );
+
+                let failure = match e.name() {
+                    Some("net.connman.iwd.NotFound") => ConnectFailure::AccessPointNotFound,
+                    _ => ConnectFailure::NotActivated(reason),
+                };
+
+                Ok(ConnectOutcome::Failed(failure))
+            }
+        }
+    }
+
+    fn delete_connections(&mut self, ssid: &Ssid) {
+        let objects = match managed_objects(&self.connection) {
+            Ok(objects) => objects,
+            Err(e) => {
+                error!("Getting known networks failed: {}", e);
+                return;
+            }
+        };
+
+        for object in objects {
+            if object.interface == KNOWN_NETWORK_INTERFACE
+                && object.name.as_ref().map(|n| n.as_bytes()) == Some(ssid.as_bytes())
+            {
+                info!("Forgetting existing known network: '{}'", ssid);
+
+                let result = method_call(
+                    IWD_BUS_NAME,
+                    &object.path,
+                    KNOWN_NETWORK_INTERFACE,
+                    "Forget",
+                )
+                .and_then(|message| {
+                    call_method(&self.connection, message, "Forget", DBUS_TIMEOUT_MS)
+                });
+
+                if let Err(e) = result {
+                    error!("Forgetting existing known network failed: {}", e);
+                }
+            }
+        }
+    }
+
+    fn delete_profile(&mut self, profile: &SavedProfile) -> Result<()> {
+        let known_network = managed_objects(&self.connection)?
+            .into_iter()
+            .find(|object| {
+                object.interface == KNOWN_NETWORK_INTERFACE
+                    && object.name.as_ref().map(|n| n.as_bytes()) == Some(profile.ssid.as_bytes())
+            });
+
+        let known_network = match known_network {
+            Some(known_network) => known_network,
+            None => bail!(ErrorKind::DeleteProfile(profile.ssid.to_string())),
+        };
+
+        let message = method_call(
+            IWD_BUS_NAME,
+            &known_network.path,
+            KNOWN_NETWORK_INTERFACE,
+            "Forget",
+        )?;
+
+        call_method(&self.connection, message, "Forget", DBUS_TIMEOUT_MS).map(|_| ())
+    }
+
+    fn saved_profiles(&self) -> Result<Vec<SavedProfile>> {
+        let profiles = managed_objects(&self.connection)?
+            .into_iter()
+            .filter(|object| object.interface == KNOWN_NETWORK_INTERFACE)
+            .filter_map(|object| object.name)
+            .map(|name| SavedProfile {
+                ssid: Ssid::from(&name as &str),
+                priority: 0,
+                search_domains: Vec::new(),
+                uuid: None,
+                last_used: 0,
+            })
+            .collect();
+
+        Ok(profiles)
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        let state = self.get_string_property(STATION_INTERFACE, "State")?;
+
+        // iwd does not check internet reachability on its own
+        let connectivity = match &state as &str {
+            "connected" => Connectivity::Limited,
+            _ => Connectivity::None,
+        };
+
+        Ok(connectivity)
+    }
+
+    fn signal(&self) -> Result<Option<SignalInfo>> {
+        // The Station interface is gone while the device is in AP mode
+        match self.get_string_property(STATION_INTERFACE, "State") {
+            Ok(ref state) if state == "connected" => {}
+            _ => return Ok(None),
+        }
+
+        let message = method_call(IWD_BUS_NAME, &self.device_path, PROPERTIES_INTERFACE, "Get")?
+            .append2(STATION_INTERFACE, "ConnectedNetwork");
+        let reply = call_method(&self.connection, message, "Get", DBUS_TIMEOUT_MS)?;
+        let network: Variant<Path> = read1(&reply, "Get")?;
+
+        let message = method_call(IWD_BUS_NAME, &network.0, PROPERTIES_INTERFACE, "Get")?
+            .append2(NETWORK_INTERFACE, "Name");
+        let reply = call_method(&self.connection, message, "Get", DBUS_TIMEOUT_MS)?;
+        let name: Variant<&str> = read1(&reply, "Get")?;
+
+        let message = method_call(
+            IWD_BUS_NAME,
+            &self.device_path,
+            STATION_DIAGNOSTIC_INTERFACE,
+            "GetDiagnostics",
+        )?;
+        let reply = call_method(&self.connection, message, "GetDiagnostics", DBUS_TIMEOUT_MS)?;
+        let diagnostics: Dict<&str, Variant<Iter>, Iter> = read1(&reply, "GetDiagnostics")?;
+
+        let mut bssid = String::new();
+        let mut rssi = None;
+        let mut bitrate = None;
+        let mut frequency = None;
+
+        for (key, mut value) in diagnostics {
+            match key {
+                "ConnectedBss" => {
+                    bssid = value.0.get::<&str>().map(String::from).unwrap_or_default()
+                }
+                "RSSI" => rssi = value.0.get::<i16>().map(i32::from),
+                // In units of 100 kbit/s
+                "RxBitrate" => bitrate = value.0.get::<u32>().map(|rate| rate * 100),
+                "Frequency" => frequency = value.0.get::<u32>(),
+                _ => {}
+            }
+        }
+
+        Ok(Some(SignalInfo {
+            ssid: Ssid::from(name.0),
+            bssid,
+            strength: rssi.map_or(0, rssi_to_strength),
+            rssi,
+            bitrate,
+            frequency,
+        }))
+    }
+}
+
+impl IwdBackend {
+    fn request_scan(&self) -> Result<()> {
+        let message = method_call(IWD_BUS_NAME, &self.device_path, STATION_INTERFACE, "Scan")?;
+
+        // A scan already in progress is fine, its results are waited for below
+        if let Err(e) = call_method(&self.connection, message, "Scan", DBUS_TIMEOUT_MS) {
+            debug!("Requesting a scan failed: {}", e);
+        }
+
+        let started = Instant::now();
+
+        while self.get_bool_property(STATION_INTERFACE, "Scanning")? {
+            if started.elapsed() > Duration::from_secs(SCAN_TIMEOUT_SECS) {
+                warn!("Scanning did not complete in {} seconds", SCAN_TIMEOUT_SECS);
+                break;
+            }
+
+            thread::sleep(Duration::from_millis(500));
+        }
+
+        Ok(())
+    }
+
+    fn set_device_mode(&self, mode: &str) -> Result<()> {
+        let message = method_call(IWD_BUS_NAME, &self.device_path, PROPERTIES_INTERFACE, "Set")?
+            .append3(DEVICE_INTERFACE, "Mode", Variant(mode));
+
+        call_method(&self.connection, message, "Set", DBUS_TIMEOUT_MS)?;
+
+        Ok(())
+    }
+
+    /// Turns off autoconnect of the known network iwd saved for the network
+    fn disable_autoconnect(&self, network_path: &str) -> Result<()> {
+        let message = method_call(IWD_BUS_NAME, network_path, PROPERTIES_INTERFACE, "Get")?
+            .append2(NETWORK_INTERFACE, "KnownNetwork");
+        let reply = call_method(&self.connection, message, "Get", DBUS_TIMEOUT_MS)?;
+        let known_network: Variant<Path> = read1(&reply, "Get")?;
+
+        let message = method_call(IWD_BUS_NAME, &known_network.0, PROPERTIES_INTERFACE, "Set")?
+            .append3(KNOWN_NETWORK_INTERFACE, "AutoConnect", Variant(false));
+
+        call_method(&self.connection, message, "Set", DBUS_TIMEOUT_MS)?;
+
+        Ok(())
+    }
+
+    fn get_string_property(&self, interface: &str, property: &str) -> Result<String> {
+        let message = method_call(IWD_BUS_NAME, &self.device_path, PROPERTIES_INTERFACE, "Get")?
+            .append2(interface, property);
+
+        let reply = call_method(&self.connection, message, "Get", DBUS_TIMEOUT_MS)?;
+
+        let value: Variant<&str> = read1(&reply, "Get")?;
+
+        Ok(value.0.to_string())
+    }
+
+    fn get_bool_property(&self, interface: &str, property: &str) -> Result<bool> {
+        let message = method_call(IWD_BUS_NAME, &self.device_path, PROPERTIES_INTERFACE, "Get")?
+            .append2(interface, property);
+
+        let reply = call_method(&self.connection, message, "Get", DBUS_TIMEOUT_MS)?;
+
+        let value: Variant<bool> = read1(&reply, "Get")?;
+
+        Ok(value.0)
+    }
+}
+
+fn find_device(connection: &Connection, config: &Config) -> Result<(String, String)> {
+    let objects = managed_objects(connection)?;
+
+    let stations = objects
+        .iter()
+        .filter(|object| object.interface == STATION_INTERFACE)
+        .map(|object| &object.path as &str)
+        .collect::<Vec<_>>();
+
+    let devices = objects
+        .iter()
+        .filter(|object| object.interface == DEVICE_INTERFACE)
+        .collect::<Vec<_>>();
+
+    let interface = &config.interface;
+
+    let device = match *interface {
+        Some(ref interface) => devices
+            .into_iter()
+            .find(|object| object.name.as_ref() == Some(interface)),
+        None => {
+            // Only devices in station mode can scan, devices left in access
+            // point mode by a crashed run are not picked up automatically
+            let candidates = devices
+                .iter()
+                .map(|object| DeviceCandidate {
+                    interface: object.name.as_ref().map_or("", String::as_str),
+                    unusable: if stations.contains(&(&object.path as &str)) {
+                        None
+                    } else {
+                        Some("not in station mode")
+                    },
+                })
+                .collect::<Vec<_>>();
+
+            select_device(config, &candidates).map(|index| devices[index])
+        }
+    };
+
+    match (device, interface) {
+        (Some(device), _) => {
+            let name = device.name.clone().unwrap_or_default();
+            info!("WiFi device: {}", name);
+            Ok((device.path.clone(), name))
+        }
+        (None, &Some(ref interface)) => bail!(ErrorKind::DeviceByInterface(interface.clone())),
+        (None, &None) => bail!(ErrorKind::NoWiFiDevice),
+    }
+}
+
+fn managed_objects(connection: &Connection) -> Result<Vec<IwdObject>> {
+    let message = method_call(
+        IWD_BUS_NAME,
+        "/",
+        OBJECT_MANAGER_INTERFACE,
+        "GetManagedObjects",
+    )?;
+
+    let reply = call_method(connection, message, "GetManagedObjects", DBUS_TIMEOUT_MS)?;
+
+    let objects: Dict<Path, Dict<&str, Dict<&str, Variant<Iter>, Iter>, Iter>, Iter> =
+        read1(&reply, "GetManagedObjects")?;
+
+    let mut result = Vec::new();
+
+    for (path, interfaces) in objects {
+        for (interface, properties) in interfaces {
+            let mut object = IwdObject {
+                path: path.to_string(),
+                interface: interface.to_string(),
+                name: None,
+                kind: None,
+            };
+
+            for (property, mut value) in properties {
+                match property {
+                    "Name" => object.name = value.0.get::<&str>().map(String::from),
+                    "Type" => object.kind = value.0.get::<&str>().map(String::from),
+                    _ => {}
+                }
+            }
+
+            result.push(object);
+        }
+    }
+
+    Ok(result)
+}
+
+fn get_network_security(kind: &str) -> Option<Security> {
+    match kind {
+        "open" => Some(Security::None),
+        "wep" => Some(Security::Wep),
+        "psk" => Some(Security::Wpa),
+        "8021x" => Some(Security::Enterprise),
+        _ => None,
+    }
+}
+
+fn write_access_point_profile(
+    config: &Config,
+    passphrase: &str,
+    channel: PortalChannel,
+) -> Result<()> {
+    // iwd picks the channel of the access point without one
+    let general = match channel {
+        PortalChannel::Default => String::new(),
+        PortalChannel::Band2Ghz(channel) | PortalChannel::Band5Ghz(channel) => {
+            format!("[General]\nChannel={}\n\n", channel)
+        }
+    };
+
+    let profile = format!(
+        "{}[Security]\nPassphrase={}\n\n[IPv4]\nAddress={}\nGateway={}\nNetmask={}\nDNSList={}\n",
+        general,
+        passphrase,
+        config.gateway,
+        config.gateway,
+        config.gateway_netmask(),
+        config.gateway
+    );
+
+    write_storage_file(&access_point_profile_path(&config.ssid), &profile)
+}
+
+fn timeout_ms(timeout: Duration) -> i32 {
+    cmp::min(timeout.as_secs() * 1000, i32::max_value() as u64) as i32
+}
+
+/// iwd only replies to `Network.Connect` once the attempt settled, so a
+/// cancellation is delivered by disconnecting the station from another thread
+fn spawn_cancel_watcher(device_path: String, cancel: CancelFlag, finished: Arc<AtomicBool>) {
+    thread::spawn(move || {
+        while !finished.load(Ordering::SeqCst) {
+            if cancel.is_cancelled() {
+                if let Err(e) = disconnect_station(&device_path) {
+                    warn!("Aborting the connection attempt failed: {}", e);
+                }
+                return;
+            }
+
+            thread::sleep(Duration::from_millis(500));
+        }
+    });
+}
+
+fn disconnect_station(device_path: &str) -> Result<()> {
+    let connection = system_bus()?;
+
+    let message = method_call(IWD_BUS_NAME, device_path, STATION_INTERFACE, "Disconnect")?;
+
+    call_method(&connection, message, "Disconnect", DBUS_TIMEOUT_MS).map(|_| ())
+}
+
+fn write_network_passphrase(ssid: &str, security: ConnectSecurity, passphrase: &str) -> Result<()> {
+    // SAE authenticates with the passphrase itself, so only WPA-PSK profiles
+    // can carry a pre-computed key
+    let settings = if security == ConnectSecurity::WpaPsk && is_hex_psk(passphrase) {
+        format!("[Security]\nPreSharedKey={}\n", passphrase.to_lowercase())
+    } else {
+        format!("[Security]\nPassphrase={}\n", passphrase)
+    };
+
+    write_storage_file(&network_passphrase_path(ssid), &settings)
+}
+
+fn write_storage_file(path: &PathBuf, contents: &str) -> Result<()> {
+    if let Some(directory) = path.parent() {
+        fs::create_dir_all(directory)?;
+    }
+
+    let mut file = fs::File::create(path)?;
+    file.write_all(contents.as_bytes())?;
+
+    Ok(())
+}
+
+fn remove_file_if_exists(path: &PathBuf) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+
+    Ok(())
+}
+
+fn access_point_profile_path(ssid: &str) -> PathBuf {
+    PathBuf::from(IWD_STORAGE_DIRECTORY)
+        .join("ap")
+        .join(format!("{}.ap", storage_name(ssid)))
+}
+
+fn network_passphrase_path(ssid: &str) -> PathBuf {
+    PathBuf::from(IWD_STORAGE_DIRECTORY).join(format!("{}.psk", storage_name(ssid)))
+}
+
+/// iwd stores SSIDs with characters outside of a safe set hex encoded and
+/// prefixed with `=`
+fn storage_name(ssid: &str) -> String {
+    let is_safe = ssid
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == ' ' || c == '_' || c == '-');
+
+    if is_safe {
+        ssid.to_string()
+    } else {
+        let hex = ssid
+            .as_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("={}", hex)
+    }
+}
@@ -0,0 +1,488 @@
+use std::net::IpAddr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use backend::{
+    rssi_to_strength, AccessPointInfo, ConnectFailure, ConnectOptions, ConnectOutcome,
+    ConnectSecurity, Connectivity, Credentials, DeviceInfo, Metered, NetworkBackend, PortalChannel,
+    ProfileExport, SavedProfile, Security, SignalInfo, Ssid, WirelessCapabilities,
+};
+use config::Config;
+use errors::*;
+
+/// Passphrase accepted by every secured mock network
+pub const MOCK_PASSPHRASE: &str = "mock-passphrase";
+
+const MOCK_INTERFACE: &str = "mock0";
+const MOCK_MAC: &str = "02:00:00:00:00:00";
+
+/// RSSI of the mock link, in dBm
+const MOCK_RSSI: i32 = -58;
+
+/// A scripted backend without any network stack behind it, so that the portal
+/// and its UI can be exercised on a development machine
+pub struct MockBackend {
+    access_points: Vec<AccessPointInfo>,
+    portal_active: bool,
+    /// SSID of the active mock connection
+    connected: Option<Ssid>,
+    metered: Metered,
+    dns: Vec<IpAddr>,
+    saved_profiles: Vec<SavedProfile>,
+}
+
+impl NetworkBackend for MockBackend {
+    fn init(_config: &Config) -> Result<()> {
+        warn!("Using the mock network backend, no network changes will be made");
+        Ok(())
+    }
+
+    fn new(_config: &Config) -> Result<Self> {
+        Ok(MockBackend {
+            access_points: mock_access_points(),
+            portal_active: false,
+            connected: None,
+            metered: Metered::Auto,
+            dns: Vec::new(),
+            saved_profiles: Vec::new(),
+        })
+    }
+
+    fn interface(&self) -> &str {
+        MOCK_INTERFACE
+    }
+
+    fn uses_dnsmasq(&self) -> bool {
+        false
+    }
+
+    /// No radio is involved, so the portal can stay up
+    fn concurrent_portal(&self) -> bool {
+        true
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>> {
+        Ok(self.access_points.clone())
+    }
+
+    fn create_portal(&mut self, config: &Config, channel: PortalChannel) -> Result<()> {
+        info!("Mock access point '{}' created on {}", config.ssid, channel);
+        self.portal_active = true;
+        Ok(())
+    }
+
+    fn stop_portal(&mut self, config: &Config) -> Result<()> {
+        if self.portal_active {
+            info!("Mock access point '{}' stopped", config.ssid);
+        }
+
+        self.portal_active = false;
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        if options.cancel.is_cancelled() {
+            return Ok(ConnectOutcome::Cancelled);
+        }
+
+        let security = match self.access_points.iter().find(|ap| ap.ssid == *ssid) {
+            Some(access_point) => credentials.security_or(access_point.security),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        if security != ConnectSecurity::Open && credentials.passphrase() != MOCK_PASSPHRASE {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase));
+        }
+
+        info!("Mock connection to '{}' activated", ssid);
+        self.connected = Some(ssid.clone());
+        self.metered = options.metered;
+        self.dns = options.dns.clone();
+
+        self.delete_connections(ssid);
+        self.saved_profiles.push(SavedProfile {
+            ssid: ssid.clone(),
+            priority: options.priority,
+            search_domains: options.search_domains.clone(),
+            uuid: options.uuid.clone(),
+            last_used: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+        });
+
+        Ok(ConnectOutcome::Activated)
+    }
+
+    fn activate_saved(
+        &mut self,
+        profile: &SavedProfile,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        if options.cancel.is_cancelled() {
+            return Ok(ConnectOutcome::Cancelled);
+        }
+
+        let in_range = self.access_points.iter().any(|ap| ap.ssid == profile.ssid);
+        let saved = self.saved_profiles.iter().any(|p| p.ssid == profile.ssid);
+
+        if !in_range || !saved {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound));
+        }
+
+        info!("Mock saved connection to '{}' activated", profile.ssid);
+        self.connected = Some(profile.ssid.clone());
+        self.metered = options.metered;
+        self.dns = options.dns.clone();
+
+        Ok(ConnectOutcome::Activated)
+    }
+
+    fn delete_connections(&mut self, ssid: &Ssid) {
+        self.saved_profiles.retain(|profile| profile.ssid != *ssid);
+    }
+
+    fn delete_profile(&mut self, profile: &SavedProfile) -> Result<()> {
+        let count = self.saved_profiles.len();
+
+        self.saved_profiles
+            .retain(|saved| saved.ssid != profile.ssid || saved.uuid != profile.uuid);
+
+        if self.saved_profiles.len() == count {
+            bail!(ErrorKind::DeleteProfile(profile.ssid.to_string()));
+        }
+
+        Ok(())
+    }
+
+    fn saved_profiles(&self) -> Result<Vec<SavedProfile>> {
+        Ok(self.saved_profiles.clone())
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        if self.connected.is_some() {
+            Ok(Connectivity::Full)
+        } else {
+            Ok(Connectivity::None)
+        }
+    }
+
+    fn metered(&self) -> Result<Metered> {
+        Ok(self.metered)
+    }
+
+    fn nameservers(&self) -> Result<Vec<IpAddr>> {
+        Ok(self.dns.clone())
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(vec![DeviceInfo {
+            interface: MOCK_INTERFACE.to_string(),
+            kind: "wifi".to_string(),
+            mac: Some(MOCK_MAC.to_string()),
+            driver: None,
+            state: if self.portal_active || self.connected.is_some() {
+                "up".to_string()
+            } else {
+                "down".to_string()
+            },
+            wireless: Some(WirelessCapabilities {
+                ap_mode: Some(true),
+                ap_sta: Some(true),
+                band_5ghz: Some(true),
+                max_scan_ssids: Some(4),
+            }),
+            rfkill: None,
+        }])
+    }
+
+    fn signal(&self) -> Result<Option<SignalInfo>> {
+        Ok(self.connected.as_ref().map(|ssid| SignalInfo {
+            ssid: ssid.clone(),
+            bssid: "02:00:00:00:00:01".to_string(),
+            strength: rssi_to_strength(MOCK_RSSI),
+            rssi: Some(MOCK_RSSI),
+            bitrate: Some(72_200),
+            frequency: Some(2437),
+        }))
+    }
+
+    fn export_profile(
+        &self,
+        profile: &SavedProfile,
+        include_secrets: bool,
+    ) -> Result<ProfileExport> {
+        let security = self
+            .access_points
+            .iter()
+            .find(|ap| ap.ssid == profile.ssid)
+            .map_or(ConnectSecurity::Open, |ap| {
+                ConnectSecurity::from(ap.security)
+            });
+
+        let secured = security != ConnectSecurity::Open;
+
+        Ok(ProfileExport {
+            ssid: profile.ssid.clone(),
+            credentials: Credentials {
+                identity: if security == ConnectSecurity::WpaEap {
+                    Some("mock-identity".to_string())
+                } else {
+                    None
+                },
+                passphrase: if secured && include_secrets {
+                    Some(MOCK_PASSPHRASE.to_string())
+                } else {
+                    None
+                },
+                security: Some(security),
+            },
+            autoconnect: true,
+            priority: profile.priority,
+            metered: Metered::Auto,
+            dns: Vec::new(),
+            search_domains: profile.search_domains.clone(),
+        })
+    }
+
+    fn add_profile(&mut self, profile: &ProfileExport, uuid: Option<&str>) -> Result<()> {
+        info!("Mock profile for '{}' imported", profile.ssid);
+
+        self.saved_profiles.push(SavedProfile {
+            ssid: profile.ssid.clone(),
+            priority: profile.priority,
+            search_domains: profile.search_domains.clone(),
+            uuid: uuid.map(String::from),
+            last_used: 0,
+        });
+
+        Ok(())
+    }
+}
+
+fn mock_access_points() -> Vec<AccessPointInfo> {
+    vec![
+        mock_access_point("Mock Open", Security::None, 90),
+        mock_access_point("Mock WEP", Security::Wep, 35),
+        mock_access_point("Mock WPA", Security::Wpa, 75),
+        mock_access_point("Mock Enterprise", Security::Enterprise, 60),
+        mock_access_point(b"Mock Caf\xe9 Latin-1", Security::Wpa, 10),
+        // The portal access point as some drivers report it
+        AccessPointInfo {
+            bssid: Some(MOCK_MAC.to_string()),
+            ..mock_access_point("Mock Portal", Security::None, 100)
+        },
+    ]
+}
+
+fn mock_access_point<B: AsRef<[u8]>>(ssid: B, security: Security, strength: u8) -> AccessPointInfo {
+    AccessPointInfo {
+        ssid: Ssid::from_bytes(ssid.as_ref()),
+        security,
+        strength: Some(strength),
+        bssid: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    use backend::CancelFlag;
+    use config::PortalBuilder;
+
+    fn backend() -> MockBackend {
+        MockBackend::new(&PortalBuilder::new().build()).unwrap()
+    }
+
+    fn options() -> ConnectOptions {
+        ConnectOptions {
+            timeout: Duration::from_secs(1),
+            cancel: CancelFlag::default(),
+            autoconnect: true,
+            priority: 5,
+            metered: Metered::Yes,
+            dns: vec!["192.168.1.53".parse().unwrap()],
+            search_domains: vec!["lan".to_string()],
+            uuid: Some("8d3f3e1a-4a4f-4c4e-9a0a-5b6a3c2d1e0f".to_string()),
+        }
+    }
+
+    fn passphrase(passphrase: &str) -> Credentials {
+        Credentials {
+            passphrase: Some(passphrase.to_string()),
+            ..Credentials::default()
+        }
+    }
+
+    fn failure(outcome: ConnectOutcome) -> Option<ConnectFailure> {
+        match outcome {
+            ConnectOutcome::Failed(failure) => Some(failure),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn connects_with_the_mock_passphrase() {
+        let mut backend = backend();
+        let ssid = Ssid::from("Mock WPA");
+
+        let outcome = backend
+            .connect(&ssid, &passphrase(MOCK_PASSPHRASE), &options())
+            .unwrap();
+        assert!(matches!(outcome, ConnectOutcome::Activated));
+
+        assert_eq!(backend.connectivity().unwrap(), Connectivity::Full);
+        assert_eq!(backend.metered().unwrap(), Metered::Yes);
+        assert_eq!(backend.nameservers().unwrap(), options().dns);
+        assert_eq!(backend.signal().unwrap().unwrap().ssid, ssid);
+
+        let saved = backend.saved_profiles().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].ssid, ssid);
+        assert_eq!(saved[0].priority, 5);
+        assert_eq!(saved[0].search_domains, vec!["lan".to_string()]);
+        assert_eq!(saved[0].uuid, options().uuid);
+    }
+
+    #[test]
+    fn refuses_a_wrong_passphrase() {
+        let mut backend = backend();
+
+        let outcome = backend
+            .connect(
+                &Ssid::from("Mock WPA"),
+                &passphrase("wrong-passphrase"),
+                &options(),
+            )
+            .unwrap();
+
+        assert_eq!(failure(outcome), Some(ConnectFailure::WrongPassphrase));
+        assert_eq!(backend.connectivity().unwrap(), Connectivity::None);
+        assert!(backend.saved_profiles().unwrap().is_empty());
+    }
+
+    #[test]
+    fn joins_open_networks_without_a_passphrase() {
+        let mut backend = backend();
+
+        let outcome = backend
+            .connect(
+                &Ssid::from("Mock Open"),
+                &Credentials::default(),
+                &options(),
+            )
+            .unwrap();
+
+        assert!(matches!(outcome, ConnectOutcome::Activated));
+    }
+
+    #[test]
+    fn reports_unknown_networks_not_found() {
+        let mut backend = backend();
+
+        let outcome = backend
+            .connect(
+                &Ssid::from("Elsewhere"),
+                &passphrase(MOCK_PASSPHRASE),
+                &options(),
+            )
+            .unwrap();
+
+        assert_eq!(failure(outcome), Some(ConnectFailure::AccessPointNotFound));
+    }
+
+    #[test]
+    fn cancelled_attempts_change_nothing() {
+        let mut backend = backend();
+        let options = options();
+        options.cancel.cancel();
+
+        let outcome = backend
+            .connect(
+                &Ssid::from("Mock WPA"),
+                &passphrase(MOCK_PASSPHRASE),
+                &options,
+            )
+            .unwrap();
+
+        assert!(matches!(outcome, ConnectOutcome::Cancelled));
+        assert_eq!(backend.connectivity().unwrap(), Connectivity::None);
+        assert!(backend.saved_profiles().unwrap().is_empty());
+    }
+
+    #[test]
+    fn activates_and_deletes_saved_profiles() {
+        let mut backend = backend();
+        let ssid = Ssid::from("Mock WPA");
+
+        backend
+            .connect(&ssid, &passphrase(MOCK_PASSPHRASE), &options())
+            .unwrap();
+        let saved = backend.saved_profiles().unwrap().remove(0);
+
+        let outcome = backend.activate_saved(&saved, &options()).unwrap();
+        assert!(matches!(outcome, ConnectOutcome::Activated));
+
+        backend.delete_profile(&saved).unwrap();
+        assert!(backend.saved_profiles().unwrap().is_empty());
+        assert!(backend.delete_profile(&saved).is_err());
+
+        let outcome = backend.activate_saved(&saved, &options()).unwrap();
+        assert_eq!(failure(outcome), Some(ConnectFailure::AccessPointNotFound));
+    }
+
+    #[test]
+    fn exports_and_imports_profiles() {
+        let mut backend = backend();
+        let ssid = Ssid::from("Mock WPA");
+
+        backend
+            .connect(&ssid, &passphrase(MOCK_PASSPHRASE), &options())
+            .unwrap();
+        let saved = backend.saved_profiles().unwrap().remove(0);
+
+        let export = backend.export_profile(&saved, false).unwrap();
+        assert_eq!(export.credentials.passphrase, None);
+        assert_eq!(
+            export.credentials.security,
+            Some(ConnectSecurity::from(Security::Wpa))
+        );
+
+        let export = backend.export_profile(&saved, true).unwrap();
+        assert_eq!(
+            export.credentials.passphrase,
+            Some(MOCK_PASSPHRASE.to_string())
+        );
+
+        backend.delete_connections(&ssid);
+        backend.add_profile(&export, Some("imported")).unwrap();
+
+        let saved = backend.saved_profiles().unwrap();
+        assert_eq!(saved.len(), 1);
+        assert_eq!(saved[0].uuid, Some("imported".to_string()));
+    }
+
+    #[test]
+    fn reports_the_device_up_while_hosting_the_portal() {
+        let config = PortalBuilder::new().build();
+        let mut backend = backend();
+        let state = |backend: &MockBackend| backend.devices().unwrap().remove(0).state;
+
+        assert_eq!(state(&backend), "down");
+
+        backend
+            .create_portal(&config, PortalChannel::Default)
+            .unwrap();
+        assert_eq!(state(&backend), "up");
+
+        backend.stop_portal(&config).unwrap();
+        assert_eq!(state(&backend), "down");
+    }
+}
@@ -0,0 +1,765 @@
+use std::cmp;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::net::IpAddr;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use base64;
+
+use config::{BackendKind, Config};
+use errors::*;
+use glob;
+
+pub mod bus;
+pub mod devices;
+#[cfg(feature = "iwd")]
+pub mod iwd;
+#[cfg(feature = "mock-backend")]
+pub mod mock;
+pub mod nm;
+pub mod secret_agent;
+#[cfg(feature = "wpa")]
+pub mod wpa;
+
+use self::bus::{name_has_owner, system_bus};
+
+const NETWORK_MANAGER_BUS_NAME: &str = "org.freedesktop.NetworkManager";
+
+/// 802.11 limits SSIDs to 32 bytes, regardless of their encoding
+pub const MAX_SSID_LENGTH: usize = 32;
+
+pub const RESOLV_CONF_PATH: &str = "/etc/resolv.conf";
+
+/// Range of the autoconnect priority NetworkManager accepts for a profile
+pub const MIN_PRIORITY: i32 = -999;
+pub const MAX_PRIORITY: i32 = 999;
+
+/// An SSID is an arbitrary byte string, which is usually but not necessarily
+/// UTF-8 encoded. It is displayed with invalid sequences replaced.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Ssid(Vec<u8>);
+
+impl Ssid {
+    pub fn from_bytes<B: Into<Vec<u8>>>(bytes: B) -> Self {
+        Ssid(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// The SSID as a string, if it is valid UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn is_valid_length(&self) -> bool {
+        !self.0.is_empty() && self.0.len() <= MAX_SSID_LENGTH
+    }
+
+    /// Decodes the base64 `ssid_raw` form of an SSID
+    pub fn from_raw(ssid_raw: &str) -> Option<Self> {
+        base64::decode(ssid_raw).ok().map(Ssid)
+    }
+
+    /// The base64 encoded bytes, for SSIDs that are not valid UTF-8
+    pub fn to_raw(&self) -> Option<String> {
+        match self.as_str() {
+            Some(_) => None,
+            None => Some(base64::encode(&self.0)),
+        }
+    }
+}
+
+impl fmt::Display for Ssid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl<'a> From<&'a str> for Ssid {
+    fn from(ssid: &'a str) -> Self {
+        Ssid::from_bytes(ssid.as_bytes())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Security {
+    None,
+    Wep,
+    Wpa,
+    Enterprise,
+}
+
+impl Security {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Security::None => "none",
+            Security::Wep => "wep",
+            Security::Wpa => "wpa",
+            Security::Enterprise => "enterprise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "none" => Some(Security::None),
+            "wep" => Some(Security::Wep),
+            "wpa" => Some(Security::Wpa),
+            "enterprise" => Some(Security::Enterprise),
+            _ => None,
+        }
+    }
+}
+
+/// Security settings written to a connection profile
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectSecurity {
+    Open,
+    Wep,
+    WpaPsk,
+    WpaEap,
+    Sae,
+}
+
+impl ConnectSecurity {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ConnectSecurity::Open => "open",
+            ConnectSecurity::Wep => "wep",
+            ConnectSecurity::WpaPsk => "wpa-psk",
+            ConnectSecurity::WpaEap => "wpa-eap",
+            ConnectSecurity::Sae => "sae",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "open" => Some(ConnectSecurity::Open),
+            "wep" => Some(ConnectSecurity::Wep),
+            "wpa-psk" => Some(ConnectSecurity::WpaPsk),
+            "wpa-eap" => Some(ConnectSecurity::WpaEap),
+            "sae" => Some(ConnectSecurity::Sae),
+            _ => None,
+        }
+    }
+}
+
+impl From<Security> for ConnectSecurity {
+    fn from(security: Security) -> Self {
+        match security {
+            Security::None => ConnectSecurity::Open,
+            Security::Wep => ConnectSecurity::Wep,
+            Security::Wpa => ConnectSecurity::WpaPsk,
+            Security::Enterprise => ConnectSecurity::WpaEap,
+        }
+    }
+}
+
+/// Whether the connection is treated as metered, e.g. a phone hotspot or an
+/// LTE router. `Auto` leaves it to the network stack to guess.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Metered {
+    Yes,
+    No,
+    Auto,
+}
+
+impl Metered {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Metered::Yes => "yes",
+            Metered::No => "no",
+            Metered::Auto => "auto",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "yes" => Some(Metered::Yes),
+            "no" => Some(Metered::No),
+            "auto" => Some(Metered::Auto),
+            _ => None,
+        }
+    }
+}
+
+/// Band and channel the portal access point is raised on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PortalChannel {
+    /// Wherever the network stack puts it
+    Default,
+    /// A channel of the 2.4 GHz band
+    Band2Ghz(u32),
+    /// A channel of the 5 GHz band
+    Band5Ghz(u32),
+}
+
+impl PortalChannel {
+    /// The channel of an attempt at raising the portal, counting from 1:
+    /// the default one first, then alternating between channel 6 of
+    /// 2.4 GHz, which every regulatory domain allows, and channel 36 of
+    /// 5 GHz, which needs no radar detection, on adapters with that band
+    pub fn for_attempt(attempt: u32, band_5ghz: bool) -> Self {
+        match attempt {
+            0 | 1 => PortalChannel::Default,
+            attempt if band_5ghz && attempt % 2 == 1 => PortalChannel::Band5Ghz(36),
+            _ => PortalChannel::Band2Ghz(6),
+        }
+    }
+
+    /// The center frequency in MHz, for network stacks taking those
+    pub fn frequency(&self) -> Option<u32> {
+        match *self {
+            PortalChannel::Default => None,
+            PortalChannel::Band2Ghz(channel) => Some(2407 + 5 * channel),
+            PortalChannel::Band5Ghz(channel) => Some(5000 + 5 * channel),
+        }
+    }
+}
+
+impl fmt::Display for PortalChannel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PortalChannel::Default => write!(f, "the default channel"),
+            PortalChannel::Band2Ghz(channel) => write!(f, "channel {} on 2.4 GHz", channel),
+            PortalChannel::Band5Ghz(channel) => write!(f, "channel {} on 5 GHz", channel),
+        }
+    }
+}
+
+/// Credentials of a connection attempt. Without an explicit security type
+/// the backend infers it from the scanned access point.
+#[derive(Clone, Debug, Default)]
+pub struct Credentials {
+    pub identity: Option<String>,
+    pub passphrase: Option<String>,
+    pub security: Option<ConnectSecurity>,
+}
+
+impl Credentials {
+    pub fn identity(&self) -> &str {
+        self.identity
+            .as_ref()
+            .map_or("", |identity| identity.as_str())
+    }
+
+    pub fn passphrase(&self) -> &str {
+        self.passphrase
+            .as_ref()
+            .map_or("", |passphrase| passphrase.as_str())
+    }
+
+    /// Without any credentials a saved profile may be activated instead
+    pub fn is_empty(&self) -> bool {
+        self.identity.is_none() && self.passphrase.is_none() && self.security.is_none()
+    }
+
+    pub fn security_or(&self, scanned: Security) -> ConnectSecurity {
+        self.security
+            .unwrap_or_else(|| ConnectSecurity::from(scanned))
+    }
+}
+
+/// A scanned access point in a backend independent form
+#[derive(Clone, Debug)]
+pub struct AccessPointInfo {
+    pub ssid: Ssid,
+    pub security: Security,
+    /// Signal quality in percent, if the network stack reports it
+    pub strength: Option<u8>,
+    /// MAC address of the access point, if the network stack reports it
+    pub bssid: Option<String>,
+}
+
+/// The link to the access point the device is associated with as a client
+#[derive(Clone, Debug)]
+pub struct SignalInfo {
+    pub ssid: Ssid,
+    pub bssid: String,
+    /// Signal quality in percent
+    pub strength: u8,
+    /// dBm, if the network stack reports it
+    pub rssi: Option<i32>,
+    /// Kbit/s, if the network stack reports it
+    pub bitrate: Option<u32>,
+    /// MHz, if the network stack reports it
+    pub frequency: Option<u32>,
+}
+
+/// A network adapter of the system
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub interface: String,
+    /// `wifi`, `ethernet`, `loopback` or another link type
+    pub kind: String,
+    pub mac: Option<String>,
+    pub driver: Option<String>,
+    /// As reported by the network stack, or the kernel operational state
+    pub state: String,
+    /// Present for WiFi adapters
+    pub wireless: Option<WirelessCapabilities>,
+    /// Present for adapters with an rfkill switch
+    pub rfkill: Option<RfkillState>,
+}
+
+/// Whether the radio of an adapter is turned off through rfkill
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RfkillState {
+    /// Blocked in software, which can be undone
+    pub soft_blocked: bool,
+    /// Blocked by a physical switch or the firmware
+    pub hard_blocked: bool,
+}
+
+/// What a WiFi adapter supports, each unknown when absent
+#[derive(Clone, Debug, Default)]
+pub struct WirelessCapabilities {
+    /// Whether it can host an access point, as the portal needs
+    pub ap_mode: Option<bool>,
+    /// Whether it can host an access point while joined to another one
+    pub ap_sta: Option<bool>,
+    pub band_5ghz: Option<bool>,
+    pub max_scan_ssids: Option<u32>,
+}
+
+/// Signal quality in percent for an RSSI in dBm, linear from -100 to -50
+#[cfg(any(feature = "iwd", feature = "wpa", feature = "mock-backend"))]
+pub fn rssi_to_strength(rssi: i32) -> u8 {
+    cmp::min(cmp::max(2 * (rssi + 100), 0), 100) as u8
+}
+
+/// A saved client connection profile in a backend independent form
+#[derive(Clone, Debug)]
+pub struct SavedProfile {
+    pub ssid: Ssid,
+    /// Profiles with a higher priority are preferred when several are in range
+    pub priority: i32,
+    pub search_domains: Vec<String>,
+    /// Only network stacks identifying profiles by UUID have one
+    pub uuid: Option<String>,
+    /// Seconds since the epoch the profile was last activated, 0 if unknown
+    pub last_used: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Connectivity {
+    Unknown,
+    None,
+    Portal,
+    Limited,
+    Full,
+}
+
+#[derive(Debug)]
+pub enum ConnectOutcome {
+    Activated,
+    Failed(ConnectFailure),
+    /// Aborted through the `CancelFlag`, with the new profile removed again
+    Cancelled,
+}
+
+/// What recreating a saved profile on another device takes
+#[derive(Clone, Debug)]
+pub struct ProfileExport {
+    pub ssid: Ssid,
+    /// Always with an explicit security type, the secrets only if asked for
+    pub credentials: Credentials,
+    pub autoconnect: bool,
+    pub priority: i32,
+    pub metered: Metered,
+    pub dns: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+}
+
+/// How a connect attempt is carried out, as opposed to what it connects to
+#[derive(Clone, Debug)]
+pub struct ConnectOptions {
+    /// Time the activation may take before the attempt counts as failed
+    pub timeout: Duration,
+    pub cancel: CancelFlag,
+    /// Whether the saved profile is joined again automatically, e.g. on boot
+    pub autoconnect: bool,
+    pub priority: i32,
+    pub metered: Metered,
+    /// Resolvers used instead of the DHCP provided ones, unless empty
+    pub dns: Vec<IpAddr>,
+    /// Domains appended to unqualified host names when resolving them
+    pub search_domains: Vec<String>,
+    /// UUID of the created profile, for network stacks identifying profiles
+    /// by one
+    pub uuid: Option<String>,
+}
+
+/// Raised when the connect attempt in progress should be aborted. Backends
+/// check it while waiting for an activation to settle.
+#[derive(Clone, Debug, Default)]
+pub struct CancelFlag(Arc<AtomicBool>);
+
+impl CancelFlag {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Why a connection attempt did not activate, shared by all backends so the
+/// reasons reported to clients do not depend on the network stack
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectFailure {
+    AccessPointNotFound,
+    WrongPassphrase,
+    #[cfg(feature = "iwd")]
+    UnsupportedSecurity(ConnectSecurity),
+    NotActivated(String),
+}
+
+impl fmt::Display for ConnectFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConnectFailure::AccessPointNotFound => write!(f, "Access point not found"),
+            ConnectFailure::WrongPassphrase => write!(f, "Wrong passphrase"),
+            #[cfg(feature = "iwd")]
+            ConnectFailure::UnsupportedSecurity(security) => write!(
+                f,
+                "Networks with '{}' security are not supported by the network backend",
+                security.as_str()
+            ),
+            ConnectFailure::NotActivated(ref reason) => {
+                write!(f, "Connection not activated: {}", reason)
+            }
+        }
+    }
+}
+
+/// A WPA passphrase is 8 to 63 printable ASCII characters, or the hex encoded
+/// 64 character PSK itself
+pub fn is_valid_wpa_passphrase(passphrase: &str) -> bool {
+    let length = passphrase.len();
+
+    is_hex_psk(passphrase)
+        || length >= 8 && length <= 63 && passphrase.chars().all(|c| c >= ' ' && c <= '~')
+}
+
+/// A pre-computed PSK is exactly 64 hex digits, which no passphrase can be
+pub fn is_hex_psk(passphrase: &str) -> bool {
+    passphrase.len() == 64 && passphrase.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// A random version 4 UUID in its hyphenated form
+pub fn new_uuid() -> Result<String> {
+    let mut bytes = [0u8; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+
+    Ok(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
+
+/// A plausible DNS name: dot separated labels of up to 63 letters, digits and
+/// inner hyphens, at most 253 characters overall
+pub fn is_valid_domain_name(name: &str) -> bool {
+    let name = if name.ends_with('.') {
+        &name[..name.len() - 1]
+    } else {
+        name
+    };
+
+    !name.is_empty()
+        && name.len() <= 253
+        && name.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
+/// Network stack operations needed by the network command thread. The
+/// implementations keep their own handles (devices, connection profiles) so
+/// none of their types leak outside the backend.
+pub trait NetworkBackend: Sized {
+    /// Prepares the network stack from the main thread, before the portal
+    /// thread is started
+    fn init(config: &Config) -> Result<()>;
+
+    /// Selects the WiFi device the portal is going to use
+    fn new(config: &Config) -> Result<Self>;
+
+    /// Interface name of the selected WiFi device
+    fn interface(&self) -> &str;
+
+    /// Whether dnsmasq has to provide DNS for the portal network
+    fn uses_dnsmasq(&self) -> bool {
+        true
+    }
+
+    /// Whether dnsmasq has to hand out DHCP leases as well, which is not the
+    /// case for network stacks with an embedded DHCP server
+    fn uses_dnsmasq_dhcp(&self) -> bool {
+        true
+    }
+
+    /// Whether the portal access point could be created. A device unable to
+    /// host it leaves provisioning to the wired interfaces.
+    fn hosts_portal(&self) -> bool {
+        true
+    }
+
+    /// Whether a connect attempt can be made while the portal stays up. This
+    /// takes an adapter capable of concurrent access point and station modes
+    /// and a portal hosted on an interface of its own.
+    fn concurrent_portal(&self) -> bool {
+        false
+    }
+
+    /// Interface the portal access point is hosted on, the WiFi interface
+    /// itself unless the backend raises it on a virtual one
+    fn portal_interface(&self) -> &str {
+        self.interface()
+    }
+
+    /// Returns the access points visible to the device, including those with
+    /// non UTF-8 SSIDs
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>>;
+
+    /// Raises the portal access point on the channel, which network stacks
+    /// without a channel setting leave to the driver
+    fn create_portal(&mut self, config: &Config, channel: PortalChannel) -> Result<()>;
+
+    /// Deactivates and deletes the portal access point, if it is active
+    fn stop_portal(&mut self, config: &Config) -> Result<()>;
+
+    /// Connects to an access point from the latest scan. Open networks are
+    /// joined without security settings.
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome>;
+
+    /// Activates a saved profile with its stored credentials. The profile is
+    /// kept whether or not it activates.
+    fn activate_saved(
+        &mut self,
+        profile: &SavedProfile,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome>;
+
+    /// Writes new credentials and options to a saved profile, which keeps
+    /// its UUID, and activates it. The profile is kept with the new
+    /// credentials whether or not it activates. Network stacks unable to
+    /// update a profile in place replace it instead.
+    fn update_saved(
+        &mut self,
+        profile: &SavedProfile,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        warn!(
+            "Profiles cannot be updated with this network backend, replacing the one of '{}'",
+            profile.ssid
+        );
+
+        self.delete_connections(&profile.ssid);
+        self.connect(&profile.ssid, credentials, options)
+    }
+
+    /// Deletes saved WiFi connection profiles for the SSID, never the one of
+    /// the portal
+    fn delete_connections(&mut self, ssid: &Ssid);
+
+    /// Deletes a single profile as listed by `saved_profiles`
+    fn delete_profile(&mut self, profile: &SavedProfile) -> Result<()>;
+
+    /// Lists the saved WiFi client connection profiles, without the portal
+    fn saved_profiles(&self) -> Result<Vec<SavedProfile>>;
+
+    fn connectivity(&self) -> Result<Connectivity>;
+
+    /// Whether the active connection of the device is metered, `Auto` when
+    /// the network stack does not know
+    fn metered(&self) -> Result<Metered> {
+        Ok(Metered::Auto)
+    }
+
+    /// The access point the device is associated with as a client, none
+    /// while the device is disconnected or hosts the portal. Reads the current
+    /// link properties without scanning.
+    fn signal(&self) -> Result<Option<SignalInfo>> {
+        Ok(None)
+    }
+
+    /// Every network adapter of the system, not only the selected one
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        devices::list_system_devices()
+    }
+
+    /// Resolvers of the active connection of the device
+    fn nameservers(&self) -> Result<Vec<IpAddr>> {
+        read_resolv_conf()
+    }
+
+    /// The settings of a saved profile for recreating it elsewhere
+    fn export_profile(
+        &self,
+        _profile: &SavedProfile,
+        _include_secrets: bool,
+    ) -> Result<ProfileExport> {
+        bail!(ErrorKind::ProfileTransferUnsupported)
+    }
+
+    /// Saves a profile without activating it, with the UUID if given
+    fn add_profile(&mut self, _profile: &ProfileExport, _uuid: Option<&str>) -> Result<()> {
+        bail!(ErrorKind::ProfileTransferUnsupported)
+    }
+}
+
+/// Resolvers listed in the system wide resolver configuration
+pub fn read_resolv_conf() -> Result<Vec<IpAddr>> {
+    let contents = fs::read_to_string(RESOLV_CONF_PATH)?;
+
+    let nameservers = contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+
+            match fields.next() {
+                Some("nameserver") => fields.next().and_then(|address| address.parse().ok()),
+                _ => None,
+            }
+        })
+        .collect();
+
+    Ok(nameservers)
+}
+
+/// A WiFi device found by the automatic device selection
+pub struct DeviceCandidate<'a> {
+    pub interface: &'a str,
+    /// Why the network stack cannot use the device
+    pub unusable: Option<&'static str>,
+}
+
+/// Index of the first candidate the network stack can use and the interface
+/// patterns let through. Every candidate is logged with the reason it was
+/// selected or skipped, so that the patterns can be checked from the log.
+pub fn select_device(config: &Config, candidates: &[DeviceCandidate]) -> Option<usize> {
+    if candidates.is_empty() {
+        info!("No WiFi devices found");
+    }
+
+    let mut selected: Option<usize> = None;
+
+    for (index, candidate) in candidates.iter().enumerate() {
+        let skipped = candidate
+            .unusable
+            .map(String::from)
+            .or_else(|| filtered_interface(config, candidate.interface))
+            .or_else(|| {
+                selected
+                    .map(|selected| format!("{} selected first", candidates[selected].interface))
+            });
+
+        match skipped {
+            Some(reason) => info!("WiFi device {} skipped: {}", candidate.interface, reason),
+            None => {
+                info!("WiFi device {} selected", candidate.interface);
+                selected = Some(index);
+            }
+        }
+    }
+
+    selected
+}
+
+/// Why the interface patterns skip the interface, excludes winning over
+/// includes
+fn filtered_interface(config: &Config, interface: &str) -> Option<String> {
+    let matching = |patterns: &[String]| {
+        patterns
+            .iter()
+            .find(|pattern| glob::matches(pattern, interface, false))
+            .cloned()
+    };
+
+    if let Some(pattern) = matching(&config.interface_exclude) {
+        return Some(format!("excluded by '{}'", pattern));
+    }
+
+    if !config.interface_include.is_empty() && matching(&config.interface_include).is_none() {
+        return Some(format!(
+            "not included by '{}'",
+            config.interface_include.join(",")
+        ));
+    }
+
+    None
+}
+
+/// Resolves `auto` to the network stack that currently owns its bus name.
+/// NetworkManager is preferred as it can itself use iwd for WiFi, and is the
+/// fallback when neither service runs, as it is started on demand.
+pub fn detect_backend(kind: BackendKind) -> Result<BackendKind> {
+    if kind != BackendKind::Auto {
+        return Ok(kind);
+    }
+
+    let connection = system_bus()?;
+
+    let backend = if name_has_owner(&connection, NETWORK_MANAGER_BUS_NAME)? {
+        BackendKind::NetworkManager
+    } else if let Some(backend) = detect_alternative_backend(&connection)? {
+        backend
+    } else {
+        BackendKind::NetworkManager
+    };
+
+    info!("Detected network backend: {:?}", backend);
+
+    Ok(backend)
+}
+
+#[cfg(feature = "iwd")]
+fn detect_alternative_backend(connection: &::dbus::Connection) -> Result<Option<BackendKind>> {
+    if name_has_owner(connection, iwd::IWD_BUS_NAME)? {
+        Ok(Some(BackendKind::Iwd))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(not(feature = "iwd"))]
+fn detect_alternative_backend(_connection: &::dbus::Connection) -> Result<Option<BackendKind>> {
+    Ok(None)
+}
@@ -0,0 +1,1910 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use dbus::arg::{RefArg, Variant};
+use dbus::{Connection as DBusConnection, Message, Path};
+
+use network_manager::{
+    AccessPoint, Connection, ConnectionState, Connectivity as NmConnectivity, Device, DeviceState,
+    DeviceType, NetworkManager, Security as NmSecurity, ServiceState,
+};
+
+use backend::bus::{
+    call_method, call_method_retrying, method_call, name_has_owner, read1, system_bus,
+    DBUS_TIMEOUT_MS,
+};
+use backend::devices::{add_ap_interface, ap_interface_name, rfkill_state, wireless_capabilities};
+use backend::secret_agent::SecretAgent;
+use backend::{
+    is_valid_wpa_passphrase, select_device, AccessPointInfo, CancelFlag, ConnectFailure,
+    ConnectOptions, ConnectOutcome, ConnectSecurity, Connectivity, Credentials, DeviceCandidate,
+    DeviceInfo, Metered, NetworkBackend, PortalChannel, ProfileExport, SavedProfile, Security,
+    SignalInfo, Ssid, WirelessCapabilities,
+};
+use config::Config;
+use errors::*;
+
+const NM_SERVICE: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_SETTINGS_INTERFACE: &str = "org.freedesktop.NetworkManager.Settings";
+const NM_SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const NM_DEVICE_INTERFACE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_WIRELESS_INTERFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_ACCESS_POINT_INTERFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_IP4_CONFIG_INTERFACE: &str = "org.freedesktop.NetworkManager.IP4Config";
+const NM_IP6_CONFIG_INTERFACE: &str = "org.freedesktop.NetworkManager.IP6Config";
+const NM_ACTIVE_CONNECTION_INTERFACE: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const NM_SETTINGS_CONNECTION_INTERFACE: &str = "org.freedesktop.NetworkManager.Settings.Connection";
+const DBUS_PROPERTIES_INTERFACE: &str = "org.freedesktop.DBus.Properties";
+
+const NM_STATE_UNKNOWN: u32 = 0;
+
+/// Route metric of the portal, above the ones NetworkManager gives to
+/// uplinks, so that its routes never win
+const PORTAL_ROUTE_METRIC: i64 = 20_000;
+
+/// Seconds the portal access point may take to activate on a fallback channel
+const PORTAL_ACTIVATE_TIMEOUT_SECS: u64 = 30;
+
+/// Seconds NetworkManager may take to pick up the virtual access point
+/// interface
+const AP_INTERFACE_TIMEOUT_SECS: u64 = 10;
+
+/// Band and channel the portal access point is raised on once it failed to
+/// activate where NetworkManager put it: a channel every regulatory domain
+/// allows without radar detection, then whatever NetworkManager picks on any
+/// band
+const PORTAL_FALLBACKS: [(Option<&str>, Option<u32>, &str); 2] = [
+    (Some("bg"), Some(6), "channel 6 on 2.4 GHz"),
+    (None, None, "automatic channel selection"),
+];
+
+/// `NMDeviceStateReason` values of an access point the driver or
+/// wpa_supplicant could not start, as on channels restricted by DFS or the
+/// regulatory domain
+const NM_DEVICE_STATE_REASON_SUPPLICANT_CONFIG_FAILED: u32 = 9;
+const NM_DEVICE_STATE_REASON_SUPPLICANT_FAILED: u32 = 10;
+const NM_DEVICE_STATE_REASON_SUPPLICANT_TIMEOUT: u32 = 11;
+
+/// Adding and activating a profile may take NetworkManager a while right
+/// after the portal access point has been torn down
+const NM_ACTIVATE_TIMEOUT_MS: i32 = 20_000;
+
+const NM_ACTIVE_CONNECTION_STATE_ACTIVATED: u32 = 2;
+const NM_ACTIVE_CONNECTION_STATE_DEACTIVATED: u32 = 4;
+
+/// `NM80211Mode` of access points the device joined as a client
+const NM_802_11_MODE_INFRA: u32 = 2;
+
+const NM_DEVICE_TYPE_WIFI: u32 = 2;
+
+/// `NMDeviceWifiCapabilities` flags
+const NM_WIFI_DEVICE_CAP_AP: u32 = 0x40;
+const NM_WIFI_DEVICE_CAP_FREQ_5GHZ: u32 = 0x400;
+
+// NMMetered values, the guessed ones are only reported by devices
+const NM_METERED_UNKNOWN: u32 = 0;
+const NM_METERED_YES: u32 = 1;
+const NM_METERED_NO: u32 = 2;
+const NM_METERED_GUESS_YES: u32 = 3;
+const NM_METERED_GUESS_NO: u32 = 4;
+
+type SettingsSection = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
+type Settings = HashMap<&'static str, SettingsSection>;
+/// Settings of a profile as NetworkManager returns them
+type SavedSettings = HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>;
+
+pub struct NetworkManagerBackend {
+    manager: NetworkManager,
+    device: Device,
+    /// Virtual interface of the adapter hosting the portal, so that the
+    /// device joins networks while it stays up
+    ap_device: Option<Device>,
+    access_points: Vec<AccessPoint>,
+    portal_connection: Option<Connection>,
+}
+
+impl NetworkBackend for NetworkManagerBackend {
+    fn init(config: &Config) -> Result<()> {
+        start_network_manager_service()?;
+
+        wait_for_network_manager(config.nm_wait_timeout)?;
+
+        delete_exising_wifi_connect_ap_profile(&config.ssid)
+            .chain_err(|| ErrorKind::DeleteAccessPoint)
+    }
+
+    fn new(config: &Config) -> Result<Self> {
+        let manager = NetworkManager::new();
+        info!("NetworkManager connection initialized");
+
+        let device = find_device(&manager, config)?;
+
+        let ap_device = concurrent_ap_device(&manager, &device);
+
+        Ok(NetworkManagerBackend {
+            manager,
+            device,
+            ap_device,
+            access_points: Vec::new(),
+            portal_connection: None,
+        })
+    }
+
+    fn interface(&self) -> &str {
+        self.device.interface()
+    }
+
+    fn concurrent_portal(&self) -> bool {
+        self.ap_device.is_some()
+    }
+
+    fn portal_interface(&self) -> &str {
+        self.portal_device().interface()
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>> {
+        let wifi_device = self.device.as_wifi_device().unwrap();
+        let access_points = wifi_device.get_access_points()?;
+
+        let access_point_infos = access_points.iter().map(get_access_point_info).collect();
+
+        self.access_points = access_points;
+
+        Ok(access_point_infos)
+    }
+
+    fn create_portal(&mut self, config: &Config, channel: PortalChannel) -> Result<()> {
+        let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
+
+        let created = create_portal_impl(
+            self.portal_device(),
+            &config.ssid,
+            &config.gateway,
+            &portal_passphrase,
+        );
+
+        let interface = self.portal_interface();
+
+        let connection = match (channel_settings(channel), created) {
+            // The hotspot is raised where NetworkManager puts it, and moved
+            (Some(_), created) => {
+                let connection = match created {
+                    Ok((connection, _)) => connection,
+                    Err(e) => find_portal_profile(&self.manager, &config.ssid).ok_or(e)?,
+                };
+
+                activate_portal_channel(connection, channel, portal_passphrase, interface)?
+            }
+            (None, Ok((connection, ConnectionState::Activated))) => connection,
+            (None, Ok((connection, state))) => {
+                let failure = format!("active connection state {:?}", state);
+
+                if failed_on_channel(interface) {
+                    activate_portal_fallbacks(connection, &failure, portal_passphrase, interface)?
+                } else {
+                    warn!(
+                        "The access point did not report being activated: {}",
+                        failure
+                    );
+                    connection
+                }
+            }
+            // The profile may have been added before the activation failed
+            (None, Err(e)) => match find_portal_profile(&self.manager, &config.ssid) {
+                Some(_) if !failed_on_channel(interface) => return Err(e),
+                Some(connection) => activate_portal_fallbacks(
+                    connection,
+                    &e.to_string(),
+                    portal_passphrase,
+                    interface,
+                )?,
+                None => return Err(e),
+            },
+        };
+
+        let uuid = connection.settings().uuid.clone();
+
+        // The portal still works, NetworkManager may just route through it
+        if let Err(e) = configure_portal_profile(
+            &uuid,
+            portal_passphrase,
+            interface,
+            config.gateway,
+            config.gateway_prefix,
+        ) {
+            warn!(
+                "Updating the portal profile failed, it keeps a /24 subnet and NetworkManager \
+                 may route through it: {}",
+                e
+            );
+        }
+
+        self.portal_connection = Some(connection);
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self, config: &Config) -> Result<()> {
+        if let Some(ref connection) = self.portal_connection {
+            stop_portal_impl(connection, config)?;
+        }
+
+        self.portal_connection = None;
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let security = match find_access_point(&self.access_points, ssid) {
+            Some(access_point) => credentials.security_or(get_access_point_security(access_point)),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        // NetworkManager rejects such a PSK with a generic settings error
+        if security == ConnectSecurity::WpaPsk && !is_valid_wpa_passphrase(credentials.passphrase())
+        {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase));
+        }
+
+        info!(
+            "Connecting to access point '{}' ({})...",
+            ssid,
+            security.as_str()
+        );
+
+        let settings = connection_settings(ssid, security, credentials, options);
+
+        let _agent = register_secret_agent(security, credentials, options);
+
+        activate_connection(self.device.interface(), ssid, settings, options)
+    }
+
+    fn activate_saved(
+        &mut self,
+        profile: &SavedProfile,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let uuid = match profile.uuid {
+            Some(ref uuid) => uuid,
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        info!(
+            "Activating the saved connection to access point '{}' ({})...",
+            profile.ssid, uuid
+        );
+
+        activate_saved_connection(self.device.interface(), &profile.ssid, uuid, options)
+    }
+
+    fn update_saved(
+        &mut self,
+        profile: &SavedProfile,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let uuid = match profile.uuid {
+            Some(ref uuid) => uuid,
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        let security = match find_access_point(&self.access_points, &profile.ssid) {
+            Some(access_point) => credentials.security_or(get_access_point_security(access_point)),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        if security == ConnectSecurity::WpaPsk && !is_valid_wpa_passphrase(credentials.passphrase())
+        {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase));
+        }
+
+        info!(
+            "Updating the saved connection to access point '{}' ({})...",
+            profile.ssid, uuid
+        );
+
+        let options = ConnectOptions {
+            uuid: Some(uuid.clone()),
+            ..options.clone()
+        };
+
+        let settings = connection_settings(&profile.ssid, security, credentials, &options);
+
+        let connection = system_bus()?;
+        let settings_path = get_connection_path(&connection, uuid)?;
+
+        let message = method_call(
+            NM_SERVICE,
+            &settings_path,
+            NM_SETTINGS_CONNECTION_INTERFACE,
+            "Update",
+        )?
+        .append1(settings);
+        call_method(&connection, message, "Update", DBUS_TIMEOUT_MS)?;
+
+        let _agent = register_secret_agent(security, credentials, &options);
+
+        activate_saved_connection(self.device.interface(), &profile.ssid, uuid, &options)
+    }
+
+    fn delete_connections(&mut self, ssid: &Ssid) {
+        delete_existing_connections_to_same_network(&self.manager, ssid.as_bytes());
+    }
+
+    fn delete_profile(&mut self, profile: &SavedProfile) -> Result<()> {
+        let uuid = match profile.uuid {
+            Some(ref uuid) => uuid,
+            None => bail!(ErrorKind::DeleteProfile(profile.ssid.to_string())),
+        };
+
+        let connection = system_bus()?;
+        let settings_path = get_connection_path(&connection, uuid)?;
+
+        delete_connection_profile(&connection, &settings_path)
+    }
+
+    fn saved_profiles(&self) -> Result<Vec<SavedProfile>> {
+        let bus = system_bus()?;
+
+        let mut profiles = Vec::new();
+
+        for connection in self.manager.get_connections()? {
+            if !is_wifi_connection(&connection) || is_access_point_connection(&connection) {
+                continue;
+            }
+
+            let connection_settings = connection.settings();
+            let settings = get_saved_settings(&bus, &connection_settings.uuid)?;
+
+            profiles.push(SavedProfile {
+                ssid: Ssid::from_bytes(connection_settings.ssid.as_bytes()),
+                priority: saved_setting(&settings, "connection", "autoconnect-priority")
+                    .and_then(|value| value.as_i64())
+                    .unwrap_or(0) as i32,
+                search_domains: saved_string_list(&settings, "ipv4", "dns-search"),
+                uuid: Some(connection_settings.uuid.clone()),
+                last_used: saved_setting(&settings, "connection", "timestamp")
+                    .and_then(|value| value.as_i64())
+                    .unwrap_or(0) as u64,
+            });
+        }
+
+        Ok(profiles)
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        let connectivity = match self.manager.get_connectivity()? {
+            NmConnectivity::Unknown => Connectivity::Unknown,
+            NmConnectivity::None => Connectivity::None,
+            NmConnectivity::Portal => Connectivity::Portal,
+            NmConnectivity::Limited => Connectivity::Limited,
+            NmConnectivity::Full => Connectivity::Full,
+        };
+
+        Ok(connectivity)
+    }
+
+    fn metered(&self) -> Result<Metered> {
+        let connection = system_bus()?;
+
+        let device_path = get_device_path(&connection, self.device.interface())?;
+
+        let message = method_call(NM_SERVICE, &device_path, DBUS_PROPERTIES_INTERFACE, "Get")?
+            .append2(NM_DEVICE_INTERFACE, "Metered");
+        let reply = call_method(&connection, message, "Get", DBUS_TIMEOUT_MS)?;
+        let metered: Variant<u32> = read1(&reply, "Get")?;
+
+        let metered = match metered.0 {
+            NM_METERED_YES | NM_METERED_GUESS_YES => Metered::Yes,
+            NM_METERED_NO | NM_METERED_GUESS_NO => Metered::No,
+            _ => Metered::Auto,
+        };
+
+        Ok(metered)
+    }
+
+    fn signal(&self) -> Result<Option<SignalInfo>> {
+        let connection = system_bus()?;
+
+        let device_path = get_device_path(&connection, self.device.interface())?;
+
+        let reply = get_property(
+            &connection,
+            &device_path,
+            NM_WIRELESS_INTERFACE,
+            "ActiveAccessPoint",
+        )?;
+        let access_point: Variant<Path> = read1(&reply, "Get")?;
+        let access_point = access_point.0.into_static();
+
+        if &*access_point == "/" {
+            return Ok(None);
+        }
+
+        // The portal hotspot is listed as the access point of the device too
+        let reply = get_property(
+            &connection,
+            &access_point,
+            NM_ACCESS_POINT_INTERFACE,
+            "Mode",
+        )?;
+        let mode: Variant<u32> = read1(&reply, "Get")?;
+
+        if mode.0 != NM_802_11_MODE_INFRA {
+            return Ok(None);
+        }
+
+        let reply = get_property(
+            &connection,
+            &access_point,
+            NM_ACCESS_POINT_INTERFACE,
+            "Ssid",
+        )?;
+        let ssid: Variant<Vec<u8>> = read1(&reply, "Get")?;
+
+        let reply = get_property(
+            &connection,
+            &access_point,
+            NM_ACCESS_POINT_INTERFACE,
+            "HwAddress",
+        )?;
+        let bssid: Variant<&str> = read1(&reply, "Get")?;
+
+        let reply = get_property(
+            &connection,
+            &access_point,
+            NM_ACCESS_POINT_INTERFACE,
+            "Strength",
+        )?;
+        let strength: Variant<u8> = read1(&reply, "Get")?;
+
+        let reply = get_property(
+            &connection,
+            &access_point,
+            NM_ACCESS_POINT_INTERFACE,
+            "Frequency",
+        )?;
+        let frequency: Variant<u32> = read1(&reply, "Get")?;
+
+        let reply = get_property(&connection, &device_path, NM_WIRELESS_INTERFACE, "Bitrate")?;
+        let bitrate: Variant<u32> = read1(&reply, "Get")?;
+
+        Ok(Some(SignalInfo {
+            ssid: Ssid::from_bytes(ssid.0),
+            bssid: bssid.0.to_string(),
+            strength: strength.0,
+            rssi: None,
+            bitrate: Some(bitrate.0),
+            frequency: Some(frequency.0),
+        }))
+    }
+
+    fn devices(&self) -> Result<Vec<DeviceInfo>> {
+        let connection = system_bus()?;
+
+        let message = method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "GetDevices")?;
+        let reply = call_method(&connection, message, "GetDevices", DBUS_TIMEOUT_MS)?;
+        let paths: Vec<Path> = read1(&reply, "GetDevices")?;
+
+        let mut devices = Vec::with_capacity(paths.len());
+
+        for path in paths {
+            let message = method_call(NM_SERVICE, &path, DBUS_PROPERTIES_INTERFACE, "GetAll")?
+                .append1(NM_DEVICE_INTERFACE);
+            let reply = call_method(&connection, message, "GetAll", DBUS_TIMEOUT_MS)?;
+            let properties: HashMap<String, Variant<Box<dyn RefArg>>> = read1(&reply, "GetAll")?;
+
+            let string = |name: &str| {
+                properties
+                    .get(name)
+                    .and_then(|value| value.0.as_str())
+                    .filter(|value| !value.is_empty())
+                    .map(String::from)
+            };
+            let number = |name: &str| {
+                properties
+                    .get(name)
+                    .and_then(|value| value.0.as_i64())
+                    .unwrap_or(0) as u32
+            };
+
+            let interface = string("Interface").unwrap_or_default();
+            let device_type = number("DeviceType");
+
+            let wireless = if device_type == NM_DEVICE_TYPE_WIFI {
+                let reply = get_property(
+                    &connection,
+                    &path,
+                    NM_WIRELESS_INTERFACE,
+                    "WirelessCapabilities",
+                )?;
+                let flags: Variant<u32> = read1(&reply, "Get")?;
+
+                // Not exposed by NetworkManager
+                let phy = wireless_capabilities(&interface);
+
+                Some(WirelessCapabilities {
+                    ap_mode: Some(flags.0 & NM_WIFI_DEVICE_CAP_AP != 0),
+                    ap_sta: phy.ap_sta,
+                    band_5ghz: Some(flags.0 & NM_WIFI_DEVICE_CAP_FREQ_5GHZ != 0),
+                    max_scan_ssids: phy.max_scan_ssids,
+                })
+            } else {
+                None
+            };
+
+            devices.push(DeviceInfo {
+                kind: device_type_name(device_type).to_string(),
+                mac: string("HwAddress"),
+                driver: string("Driver"),
+                state: device_state_name(number("State")).to_string(),
+                wireless,
+                rfkill: rfkill_state(&interface),
+                interface,
+            });
+        }
+
+        Ok(devices)
+    }
+
+    fn nameservers(&self) -> Result<Vec<IpAddr>> {
+        let connection = system_bus()?;
+
+        let device_path = get_device_path(&connection, self.device.interface())?;
+
+        let mut nameservers = Vec::new();
+
+        let ip4_config = get_path_property(&connection, &device_path, "Ip4Config")?;
+        if &*ip4_config != "/" {
+            let message = method_call(NM_SERVICE, &ip4_config, DBUS_PROPERTIES_INTERFACE, "Get")?
+                .append2(NM_IP4_CONFIG_INTERFACE, "Nameservers");
+            let reply = call_method(&connection, message, "Get", DBUS_TIMEOUT_MS)?;
+            let addresses: Variant<Vec<u32>> = read1(&reply, "Get")?;
+
+            nameservers.extend(
+                addresses
+                    .0
+                    .into_iter()
+                    .map(|address| IpAddr::V4(Ipv4Addr::from(u32::from_be(address)))),
+            );
+        }
+
+        let ip6_config = get_path_property(&connection, &device_path, "Ip6Config")?;
+        if &*ip6_config != "/" {
+            let message = method_call(NM_SERVICE, &ip6_config, DBUS_PROPERTIES_INTERFACE, "Get")?
+                .append2(NM_IP6_CONFIG_INTERFACE, "Nameservers");
+            let reply = call_method(&connection, message, "Get", DBUS_TIMEOUT_MS)?;
+            let addresses: Variant<Vec<Vec<u8>>> = read1(&reply, "Get")?;
+
+            for address in addresses.0 {
+                if address.len() == 16 {
+                    let mut octets = [0; 16];
+                    octets.copy_from_slice(&address);
+                    nameservers.push(IpAddr::V6(Ipv6Addr::from(octets)));
+                }
+            }
+        }
+
+        Ok(nameservers)
+    }
+
+    fn export_profile(
+        &self,
+        profile: &SavedProfile,
+        include_secrets: bool,
+    ) -> Result<ProfileExport> {
+        let uuid = match profile.uuid {
+            Some(ref uuid) => uuid,
+            None => bail!(ErrorKind::ProfileTransferUnsupported),
+        };
+
+        let connection = system_bus()?;
+        let settings = get_saved_settings(&connection, uuid)?;
+
+        let key_mgmt = saved_setting(&settings, "802-11-wireless-security", "key-mgmt")
+            .and_then(|value| value.as_str());
+
+        let (security, secrets_section, secret_key) = match key_mgmt {
+            None => (ConnectSecurity::Open, None, ""),
+            Some("none") => (
+                ConnectSecurity::Wep,
+                Some("802-11-wireless-security"),
+                "wep-key0",
+            ),
+            Some("wpa-psk") => (
+                ConnectSecurity::WpaPsk,
+                Some("802-11-wireless-security"),
+                "psk",
+            ),
+            Some("sae") => (
+                ConnectSecurity::Sae,
+                Some("802-11-wireless-security"),
+                "psk",
+            ),
+            Some("wpa-eap") => (ConnectSecurity::WpaEap, Some("802-1x"), "password"),
+            Some(other) => bail!(ErrorKind::UnsupportedProfileSecurity(other.to_string())),
+        };
+
+        let passphrase = match secrets_section {
+            Some(section) if include_secrets => {
+                let secrets = get_saved_secrets(&connection, uuid, section)?;
+                saved_setting(&secrets, section, secret_key)
+                    .and_then(|value| value.as_str())
+                    .map(String::from)
+            }
+            _ => None,
+        };
+
+        let identity = saved_setting(&settings, "802-1x", "identity")
+            .and_then(|value| value.as_str())
+            .map(String::from);
+
+        // Profiles created by NetworkManager itself may leave these unset
+        let autoconnect = saved_setting(&settings, "connection", "autoconnect")
+            .and_then(|value| value.as_i64())
+            .map_or(true, |value| value != 0);
+
+        let metered = match saved_setting(&settings, "connection", "metered")
+            .and_then(|value| value.as_i64())
+        {
+            Some(value) if value == i64::from(NM_METERED_YES) => Metered::Yes,
+            Some(value) if value == i64::from(NM_METERED_NO) => Metered::No,
+            _ => Metered::Auto,
+        };
+
+        Ok(ProfileExport {
+            ssid: profile.ssid.clone(),
+            credentials: Credentials {
+                identity,
+                passphrase,
+                security: Some(security),
+            },
+            autoconnect,
+            priority: profile.priority,
+            metered,
+            dns: saved_dns(&settings),
+            search_domains: profile.search_domains.clone(),
+        })
+    }
+
+    fn add_profile(&mut self, profile: &ProfileExport, uuid: Option<&str>) -> Result<()> {
+        let security = profile
+            .credentials
+            .security
+            .unwrap_or(ConnectSecurity::Open);
+
+        let options = ConnectOptions {
+            timeout: Duration::from_secs(0),
+            cancel: CancelFlag::default(),
+            autoconnect: profile.autoconnect,
+            priority: profile.priority,
+            metered: profile.metered,
+            dns: profile.dns.clone(),
+            search_domains: profile.search_domains.clone(),
+            uuid: uuid.map(String::from),
+        };
+
+        let settings = connection_settings(&profile.ssid, security, &profile.credentials, &options);
+
+        let connection = system_bus()?;
+
+        let message = method_call(
+            NM_SERVICE,
+            NM_SETTINGS_PATH,
+            NM_SETTINGS_INTERFACE,
+            "AddConnection",
+        )?
+        .append1(settings);
+        call_method_retrying(&connection, message, "AddConnection", DBUS_TIMEOUT_MS).map(|_| ())
+    }
+}
+
+/// Settings of a client connection profile. The security sections are
+/// written from the chosen security type only, so that the profile does not
+/// depend on how the access point advertises itself.
+fn connection_settings(
+    ssid: &Ssid,
+    security: ConnectSecurity,
+    credentials: &Credentials,
+    options: &ConnectOptions,
+) -> Settings {
+    let mut settings = Settings::new();
+
+    let mut connection = SettingsSection::new();
+    connection.insert("id", variant(ssid.to_string()));
+    connection.insert("type", variant("802-11-wireless".to_string()));
+    connection.insert("autoconnect", variant(options.autoconnect));
+    connection.insert("autoconnect-priority", variant(options.priority));
+    connection.insert("metered", variant(nm_metered(options.metered)));
+    if let Some(ref uuid) = options.uuid {
+        connection.insert("uuid", variant(uuid.clone()));
+    }
+    settings.insert("connection", connection);
+
+    let mut wireless = SettingsSection::new();
+    wireless.insert("ssid", variant(ssid.as_bytes().to_vec()));
+    wireless.insert("mode", variant("infrastructure".to_string()));
+    settings.insert("802-11-wireless", wireless);
+
+    let passphrase = credentials.passphrase().to_string();
+
+    let mut wireless_security = SettingsSection::new();
+
+    match security {
+        ConnectSecurity::Open => {}
+        ConnectSecurity::Wep => {
+            wireless_security.insert("key-mgmt", variant("none".to_string()));
+            wireless_security.insert("wep-key0", variant(passphrase));
+            wireless_security.insert(
+                "wep-key-type",
+                variant(wep_key_type(credentials.passphrase())),
+            );
+        }
+        ConnectSecurity::WpaPsk => {
+            // NetworkManager stores 64 hex digits as the raw PSK and hashes
+            // anything shorter as a passphrase
+            wireless_security.insert("key-mgmt", variant("wpa-psk".to_string()));
+            wireless_security.insert("psk", variant(passphrase));
+        }
+        ConnectSecurity::Sae => {
+            wireless_security.insert("key-mgmt", variant("sae".to_string()));
+            wireless_security.insert("psk", variant(passphrase));
+        }
+        ConnectSecurity::WpaEap => {
+            wireless_security.insert("key-mgmt", variant("wpa-eap".to_string()));
+
+            let mut eap = SettingsSection::new();
+            eap.insert("eap", variant(vec!["peap".to_string()]));
+            eap.insert("identity", variant(credentials.identity().to_string()));
+            eap.insert("password", variant(passphrase));
+            eap.insert("phase2-auth", variant("mschapv2".to_string()));
+            settings.insert("802-1x", eap);
+        }
+    }
+
+    if !wireless_security.is_empty() {
+        settings.insert("802-11-wireless-security", wireless_security);
+    }
+
+    let mut ipv4 = SettingsSection::new();
+    ipv4.insert("method", variant("auto".to_string()));
+
+    let mut ipv6 = SettingsSection::new();
+    ipv6.insert("method", variant("auto".to_string()));
+
+    // Explicit resolvers replace those of DHCP and router advertisements for
+    // both address families, so that none of the latter are used
+    if !options.dns.is_empty() {
+        let mut ipv4_dns = Vec::new();
+        let mut ipv6_dns = Vec::new();
+
+        for address in &options.dns {
+            match *address {
+                // NetworkManager expects IPv4 addresses in network byte order
+                IpAddr::V4(address) => ipv4_dns.push(u32::from(address).to_be()),
+                IpAddr::V6(address) => ipv6_dns.push(address.octets().to_vec()),
+            }
+        }
+
+        ipv4.insert("dns", variant(ipv4_dns));
+        ipv4.insert("ignore-auto-dns", variant(true));
+        ipv6.insert("dns", variant(ipv6_dns));
+        ipv6.insert("ignore-auto-dns", variant(true));
+    }
+
+    if !options.search_domains.is_empty() {
+        ipv4.insert("dns-search", variant(options.search_domains.clone()));
+        ipv6.insert("dns-search", variant(options.search_domains.clone()));
+    }
+
+    settings.insert("ipv4", ipv4);
+    settings.insert("ipv6", ipv6);
+
+    settings
+}
+
+/// Name of an `NMDeviceType`
+fn device_type_name(device_type: u32) -> &'static str {
+    match device_type {
+        1 => "ethernet",
+        2 => "wifi",
+        5 => "bluetooth",
+        8 => "modem",
+        10 => "bond",
+        11 => "vlan",
+        13 => "bridge",
+        14 => "generic",
+        16 => "tun",
+        20 => "veth",
+        29 => "wireguard",
+        30 => "wifi-p2p",
+        32 => "loopback",
+        _ => "other",
+    }
+}
+
+/// Name of an `NMDeviceState`
+fn device_state_name(state: u32) -> &'static str {
+    match state {
+        10 => "unmanaged",
+        20 => "unavailable",
+        30 => "disconnected",
+        40 => "prepare",
+        50 => "config",
+        60 => "need-auth",
+        70 => "ip-config",
+        80 => "ip-check",
+        90 => "secondaries",
+        100 => "activated",
+        110 => "deactivating",
+        120 => "failed",
+        _ => "unknown",
+    }
+}
+
+/// Profiles store the metered setting as an int32
+fn nm_metered(metered: Metered) -> i32 {
+    let metered = match metered {
+        Metered::Yes => NM_METERED_YES,
+        Metered::No => NM_METERED_NO,
+        Metered::Auto => NM_METERED_UNKNOWN,
+    };
+
+    metered as i32
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value) as Box<dyn RefArg>)
+}
+
+/// NetworkManager key type 1 is a hex or ASCII key and 2 is a passphrase
+/// hashed into a key
+fn wep_key_type(key: &str) -> u32 {
+    let is_hex = key.chars().all(|c| c.is_ascii_hexdigit());
+
+    match key.len() {
+        5 | 13 => 1,
+        10 | 26 if is_hex => 1,
+        _ => 2,
+    }
+}
+
+/// Serves the submitted credentials of an 802.1x profile while it activates,
+/// for NetworkManager configurations asking an agent for them. None if the
+/// profile has no UUID to scope the agent to, or if registering it failed.
+fn register_secret_agent(
+    security: ConnectSecurity,
+    credentials: &Credentials,
+    options: &ConnectOptions,
+) -> Option<SecretAgent> {
+    if security != ConnectSecurity::WpaEap {
+        return None;
+    }
+
+    let uuid = options.uuid.as_ref()?;
+
+    match SecretAgent::register(uuid, credentials) {
+        Ok(agent) => Some(agent),
+        Err(e) => {
+            warn!("Registering a secret agent failed: {}", e);
+            None
+        }
+    }
+}
+
+/// Adds the connection profile and waits until NetworkManager either activates
+/// it or gives up. Profiles that failed to activate are deleted.
+fn activate_connection(
+    interface: &str,
+    ssid: &Ssid,
+    settings: Settings,
+    options: &ConnectOptions,
+) -> Result<ConnectOutcome> {
+    let connection = system_bus()?;
+
+    let device_path = get_device_path(&connection, interface)?;
+
+    let message = method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "AddAndActivateConnection")?
+        .append3(settings, device_path, Path::from("/"));
+    let reply = call_method_retrying(
+        &connection,
+        message,
+        "AddAndActivateConnection",
+        NM_ACTIVATE_TIMEOUT_MS,
+    )?;
+    let (settings_path, active_path): (Path, Path) = reply.read2().map_err(|e| {
+        Error::from(ErrorKind::DBusCall(
+            "AddAndActivateConnection".to_string(),
+            format!("{:?}", e),
+        ))
+    })?;
+
+    finish_activation(
+        &connection,
+        ssid,
+        &active_path,
+        Some(&settings_path),
+        options,
+    )
+}
+
+/// Activates a saved profile, which is kept whether or not it activates
+fn activate_saved_connection(
+    interface: &str,
+    ssid: &Ssid,
+    uuid: &str,
+    options: &ConnectOptions,
+) -> Result<ConnectOutcome> {
+    let connection = system_bus()?;
+
+    let device_path = get_device_path(&connection, interface)?;
+    let settings_path = get_connection_path(&connection, uuid)?;
+
+    let message = method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "ActivateConnection")?.append3(
+        settings_path,
+        device_path,
+        Path::from("/"),
+    );
+    let reply = call_method_retrying(
+        &connection,
+        message,
+        "ActivateConnection",
+        NM_ACTIVATE_TIMEOUT_MS,
+    )?;
+    let active_path: Path = read1(&reply, "ActivateConnection")?;
+
+    finish_activation(&connection, ssid, &active_path, None, options)
+}
+
+/// Waits for the activation to finish. Profiles created for the attempt,
+/// passed as `created_path`, are deleted if they did not activate.
+fn finish_activation(
+    connection: &DBusConnection,
+    ssid: &Ssid,
+    active_path: &Path,
+    created_path: Option<&Path>,
+    options: &ConnectOptions,
+) -> Result<ConnectOutcome> {
+    let state = wait_for_activation(connection, active_path, options);
+
+    if state == Some(NM_ACTIVE_CONNECTION_STATE_ACTIVATED) {
+        return Ok(ConnectOutcome::Activated);
+    }
+
+    let cancelled = state.is_none() && options.cancel.is_cancelled();
+
+    match created_path {
+        Some(settings_path) => {
+            if cancelled {
+                if let Err(err) = deactivate_connection(connection, active_path) {
+                    warn!("Deactivating cancelled connection failed: {}", err)
+                }
+            }
+
+            if let Err(err) = delete_connection_profile(connection, settings_path) {
+                error!("Deleting connection object failed: {}", err)
+            }
+        }
+        None => {
+            // A saved profile stays, so NetworkManager must not keep trying
+            if state.is_none() {
+                if let Err(err) = deactivate_connection(connection, active_path) {
+                    warn!("Deactivating saved connection failed: {}", err)
+                }
+            }
+        }
+    }
+
+    if cancelled {
+        info!("Connection to access point '{}' cancelled", ssid);
+        return Ok(ConnectOutcome::Cancelled);
+    }
+
+    let reason = match state {
+        Some(state) => format!("active connection state {}", state),
+        None => "activation timed out".to_string(),
+    };
+
+    warn!(
+        "Connection to access point not activated '{}': {}",
+        ssid, reason
+    );
+
+    Ok(ConnectOutcome::Failed(ConnectFailure::NotActivated(reason)))
+}
+
+/// Polls the active connection state. `None` means that the activation did
+/// not settle in time or was cancelled. A vanished active connection counts
+/// as deactivated.
+fn wait_for_activation(
+    connection: &DBusConnection,
+    active_path: &Path,
+    options: &ConnectOptions,
+) -> Option<u32> {
+    let deadline = Instant::now() + options.timeout;
+
+    while Instant::now() < deadline && !options.cancel.is_cancelled() {
+        let state = match active_connection_state(connection, active_path) {
+            Ok(state) => state,
+            Err(_) => return Some(NM_ACTIVE_CONNECTION_STATE_DEACTIVATED),
+        };
+
+        if state == NM_ACTIVE_CONNECTION_STATE_ACTIVATED
+            || state == NM_ACTIVE_CONNECTION_STATE_DEACTIVATED
+        {
+            return Some(state);
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+
+    None
+}
+
+fn get_device_path(connection: &DBusConnection, interface: &str) -> Result<Path<'static>> {
+    let message =
+        method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "GetDeviceByIpIface")?.append1(interface);
+    let reply = call_method(connection, message, "GetDeviceByIpIface", DBUS_TIMEOUT_MS)?;
+    let device_path: Path = read1(&reply, "GetDeviceByIpIface")?;
+
+    Ok(device_path.into_static())
+}
+
+/// Reads a property, the reply holds it as a variant of its D-Bus type
+fn get_property(
+    connection: &DBusConnection,
+    path: &Path,
+    interface: &str,
+    property: &str,
+) -> Result<Message> {
+    let message = method_call(NM_SERVICE, path, DBUS_PROPERTIES_INTERFACE, "Get")?
+        .append2(interface, property);
+
+    call_method(connection, message, "Get", DBUS_TIMEOUT_MS)
+}
+
+/// An object path property of the device, `/` when there is no such object
+fn get_path_property(
+    connection: &DBusConnection,
+    device_path: &Path,
+    property: &str,
+) -> Result<Path<'static>> {
+    let message = method_call(NM_SERVICE, device_path, DBUS_PROPERTIES_INTERFACE, "Get")?
+        .append2(NM_DEVICE_INTERFACE, property);
+    let reply = call_method(connection, message, "Get", DBUS_TIMEOUT_MS)?;
+    let path: Variant<Path> = read1(&reply, "Get")?;
+
+    Ok(path.0.into_static())
+}
+
+fn active_connection_state(connection: &DBusConnection, active_path: &Path) -> Result<u32> {
+    let message = method_call(NM_SERVICE, active_path, DBUS_PROPERTIES_INTERFACE, "Get")?
+        .append2(NM_ACTIVE_CONNECTION_INTERFACE, "State");
+    let reply = call_method(connection, message, "Get", DBUS_TIMEOUT_MS)?;
+    let state: Variant<u32> = read1(&reply, "Get")?;
+
+    Ok(state.0)
+}
+
+fn deactivate_connection(connection: &DBusConnection, active_path: &Path) -> Result<()> {
+    let message = method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "DeactivateConnection")?
+        .append1(active_path.clone());
+
+    call_method(connection, message, "DeactivateConnection", DBUS_TIMEOUT_MS).map(|_| ())
+}
+
+/// The settings NetworkManager keeps for the profile, without its secrets
+fn get_saved_settings(connection: &DBusConnection, uuid: &str) -> Result<SavedSettings> {
+    let settings_path = get_connection_path(connection, uuid)?;
+
+    let message = method_call(
+        NM_SERVICE,
+        &settings_path,
+        NM_SETTINGS_CONNECTION_INTERFACE,
+        "GetSettings",
+    )?;
+    let reply = call_method(connection, message, "GetSettings", DBUS_TIMEOUT_MS)?;
+
+    read1(&reply, "GetSettings")
+}
+
+fn get_connection_path(connection: &DBusConnection, uuid: &str) -> Result<Path<'static>> {
+    let message = method_call(
+        NM_SERVICE,
+        NM_SETTINGS_PATH,
+        NM_SETTINGS_INTERFACE,
+        "GetConnectionByUuid",
+    )?
+    .append1(uuid);
+    let reply = call_method(connection, message, "GetConnectionByUuid", DBUS_TIMEOUT_MS)?;
+    let settings_path: Path = read1(&reply, "GetConnectionByUuid")?;
+
+    Ok(settings_path.into_static())
+}
+
+/// Secrets are not part of the settings and are only handed out per section
+fn get_saved_secrets(
+    connection: &DBusConnection,
+    uuid: &str,
+    section: &str,
+) -> Result<SavedSettings> {
+    let settings_path = get_connection_path(connection, uuid)?;
+
+    let message = method_call(
+        NM_SERVICE,
+        &settings_path,
+        NM_SETTINGS_CONNECTION_INTERFACE,
+        "GetSecrets",
+    )?
+    .append1(section);
+    let reply = call_method(connection, message, "GetSecrets", DBUS_TIMEOUT_MS)?;
+    read1(&reply, "GetSecrets")
+}
+
+/// Explicit resolvers of the profile, IPv4 ones in network byte order
+fn saved_dns(settings: &SavedSettings) -> Vec<IpAddr> {
+    let mut dns = Vec::new();
+
+    if let Some(addresses) = saved_setting(settings, "ipv4", "dns").and_then(|v| v.as_iter()) {
+        dns.extend(
+            addresses
+                .filter_map(|address| address.as_i64())
+                .map(|address| IpAddr::V4(Ipv4Addr::from(u32::from_be(address as u32)))),
+        );
+    }
+
+    if let Some(addresses) = saved_setting(settings, "ipv6", "dns").and_then(|v| v.as_iter()) {
+        for address in addresses {
+            let octets = match address.as_iter() {
+                Some(octets) => octets
+                    .filter_map(|octet| octet.as_i64())
+                    .map(|octet| octet as u8)
+                    .collect::<Vec<_>>(),
+                None => continue,
+            };
+
+            if octets.len() == 16 {
+                let mut address = [0; 16];
+                address.copy_from_slice(&octets);
+                dns.push(IpAddr::V6(Ipv6Addr::from(address)));
+            }
+        }
+    }
+
+    dns
+}
+
+fn saved_setting<'a>(
+    settings: &'a SavedSettings,
+    section: &str,
+    key: &str,
+) -> Option<&'a dyn RefArg> {
+    settings
+        .get(section)
+        .and_then(|section| section.get(key))
+        .map(|value| &*value.0)
+}
+
+fn saved_string_list(settings: &SavedSettings, section: &str, key: &str) -> Vec<String> {
+    saved_setting(settings, section, key)
+        .and_then(|value| value.as_iter())
+        .map(|items| {
+            items
+                .filter_map(|item| item.as_str().map(String::from))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn delete_connection_profile(connection: &DBusConnection, settings_path: &Path) -> Result<()> {
+    let message = method_call(
+        NM_SERVICE,
+        settings_path,
+        NM_SETTINGS_CONNECTION_INTERFACE,
+        "Delete",
+    )?;
+
+    call_method(connection, message, "Delete", DBUS_TIMEOUT_MS).map(|_| ())
+}
+
+impl NetworkManagerBackend {
+    fn portal_device(&self) -> &Device {
+        self.ap_device.as_ref().unwrap_or(&self.device)
+    }
+}
+
+/// The virtual interface the portal is raised on next to the one of the
+/// device, on adapters capable of concurrent access point and station
+/// modes. Without one the portal is hosted on the interface of the device.
+fn concurrent_ap_device(manager: &NetworkManager, device: &Device) -> Option<Device> {
+    let interface = device.interface();
+
+    if wireless_capabilities(interface).ap_sta != Some(true) {
+        return None;
+    }
+
+    let ap_interface = ap_interface_name(interface);
+
+    if let Err(e) = add_ap_interface(interface, &ap_interface) {
+        warn!(
+            "Adding the access point interface {} failed, hosting the portal on {}: {}",
+            ap_interface, interface, e
+        );
+        return None;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(AP_INTERFACE_TIMEOUT_SECS);
+
+    // NetworkManager manages the new interface once it has noticed it
+    loop {
+        if let Ok(ap_device) = manager.get_device_by_interface(&ap_interface) {
+            if ap_device.get_state().ok() == Some(DeviceState::Disconnected) {
+                info!(
+                    "Hosting the portal on {}, next to {}",
+                    ap_interface, interface
+                );
+                return Some(ap_device);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            warn!(
+                "NetworkManager did not manage {} within {}s, hosting the portal on {}",
+                ap_interface, AP_INTERFACE_TIMEOUT_SECS, interface
+            );
+            return None;
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+pub fn find_device(manager: &NetworkManager, config: &Config) -> Result<Device> {
+    if let Some(ref interface) = config.interface {
+        let device = manager
+            .get_device_by_interface(interface)
+            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
+
+        info!("Targeted WiFi device: {}", interface);
+
+        if *device.device_type() != DeviceType::WiFi {
+            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
+        }
+
+        if device.get_state()? == DeviceState::Unmanaged {
+            bail!(ErrorKind::UnmanagedDevice(interface.clone()))
+        }
+
+        Ok(device)
+    } else {
+        let devices = manager.get_devices()?;
+
+        if let Some(device) = find_wifi_managed_device(devices, config)? {
+            info!("WiFi device: {}", device.interface());
+            Ok(device)
+        } else {
+            bail!(ErrorKind::NoWiFiDevice)
+        }
+    }
+}
+
+fn find_wifi_managed_device(devices: Vec<Device>, config: &Config) -> Result<Option<Device>> {
+    let mut wifi_devices = Vec::new();
+    let mut unmanaged = Vec::new();
+
+    for device in devices {
+        if *device.device_type() == DeviceType::WiFi {
+            unmanaged.push(device.get_state()? == DeviceState::Unmanaged);
+            wifi_devices.push(device);
+        }
+    }
+
+    // Left from an earlier run, they share the radio of their adapter
+    let ap_interfaces = wifi_devices
+        .iter()
+        .map(|device| ap_interface_name(device.interface()))
+        .collect::<Vec<_>>();
+
+    let selected = {
+        let candidates = wifi_devices
+            .iter()
+            .zip(&unmanaged)
+            .map(|(device, &unmanaged)| DeviceCandidate {
+                interface: device.interface(),
+                unusable: if unmanaged {
+                    Some("unmanaged by NetworkManager")
+                } else if ap_interfaces.iter().any(|ap| ap == device.interface()) {
+                    Some("portal access point of another device")
+                } else {
+                    None
+                },
+            })
+            .collect::<Vec<_>>();
+
+        select_device(config, &candidates)
+    };
+
+    Ok(selected.map(|index| wifi_devices.swap_remove(index)))
+}
+
+fn get_access_point_info(access_point: &AccessPoint) -> AccessPointInfo {
+    AccessPointInfo {
+        ssid: Ssid::from_bytes(access_point.ssid().as_bytes()),
+        security: get_access_point_security(access_point),
+        strength: Some(cmp::min(access_point.strength, 100) as u8),
+        // Not exposed by the network-manager crate
+        bssid: None,
+    }
+}
+
+fn get_access_point_security(access_point: &AccessPoint) -> Security {
+    if access_point.security.contains(NmSecurity::ENTERPRISE) {
+        Security::Enterprise
+    } else if access_point.security.contains(NmSecurity::WPA2)
+        || access_point.security.contains(NmSecurity::WPA)
+    {
+        Security::Wpa
+    } else if access_point.security.contains(NmSecurity::WEP) {
+        Security::Wep
+    } else {
+        Security::None
+    }
+}
+
+fn find_access_point<'a>(access_points: &'a [AccessPoint], ssid: &Ssid) -> Option<&'a AccessPoint> {
+    access_points
+        .iter()
+        .find(|access_point| access_point.ssid().as_bytes() == ssid.as_bytes())
+}
+
+fn create_portal_impl(
+    device: &Device,
+    ssid: &str,
+    gateway: &Ipv4Addr,
+    passphrase: &Option<&str>,
+) -> Result<(Connection, ConnectionState)> {
+    info!("Starting access point...");
+    let wifi_device = device.as_wifi_device().unwrap();
+    let (portal_connection, state) =
+        wifi_device.create_hotspot(ssid, *passphrase, Some(*gateway))?;
+    info!("Access point '{}' created", ssid);
+    Ok((portal_connection, state))
+}
+
+/// Raises the portal profile that failed to activate on the fallback bands
+/// and channels in turn. Each failing step is logged with its reason, and the
+/// profile is deleted once none of them worked.
+fn activate_portal_fallbacks(
+    portal_connection: Connection,
+    failure: &str,
+    passphrase: Option<&str>,
+    interface: &str,
+) -> Result<Connection> {
+    let connection = system_bus()?;
+    let device_path = get_device_path(&connection, interface)?;
+    let uuid = portal_connection.settings().uuid.clone();
+
+    let failure = format!(
+        "{}, device state reason {}",
+        failure,
+        device_state_reason(&connection, &device_path).unwrap_or(0)
+    );
+
+    let activated = try_portal_fallbacks(failure, |band, channel| {
+        activate_portal_on(&connection, &uuid, passphrase, &device_path, band, channel)
+    });
+
+    match activated {
+        Ok(name) => {
+            info!("Access point activated on {}", name);
+            Ok(portal_connection)
+        }
+        Err(e) => {
+            let _ = portal_connection.delete();
+            Err(e)
+        }
+    }
+}
+
+/// Calls `activate` with each of `PORTAL_FALLBACKS` in turn until it
+/// succeeds, returning the name of the fallback that did
+fn try_portal_fallbacks<F>(mut last_failure: String, mut activate: F) -> Result<&'static str>
+where
+    F: FnMut(Option<&'static str>, Option<u32>) -> Result<()>,
+{
+    for &(band, channel, name) in PORTAL_FALLBACKS.iter() {
+        warn!(
+            "Activating the access point failed ({}), retrying on {}",
+            last_failure, name
+        );
+
+        match activate(band, channel) {
+            Ok(()) => return Ok(name),
+            Err(e) => last_failure = e.to_string(),
+        }
+    }
+
+    bail!(
+        "the access point did not activate on any channel: {}",
+        last_failure
+    )
+}
+
+/// The `band` and `channel` of a profile on the channel, none for the
+/// default one
+fn channel_settings(channel: PortalChannel) -> Option<(&'static str, u32)> {
+    match channel {
+        PortalChannel::Default => None,
+        PortalChannel::Band2Ghz(channel) => Some(("bg", channel)),
+        PortalChannel::Band5Ghz(channel) => Some(("a", channel)),
+    }
+}
+
+/// Moves the portal profile to the channel an attempt at raising it asked
+/// for, deleting the profile if it does not activate there
+fn activate_portal_channel(
+    portal_connection: Connection,
+    channel: PortalChannel,
+    passphrase: Option<&str>,
+    interface: &str,
+) -> Result<Connection> {
+    let connection = system_bus()?;
+    let device_path = get_device_path(&connection, interface)?;
+    let uuid = portal_connection.settings().uuid.clone();
+    let settings = channel_settings(channel);
+
+    match activate_portal_on(
+        &connection,
+        &uuid,
+        passphrase,
+        &device_path,
+        settings.map(|(band, _)| band),
+        settings.map(|(_, number)| number),
+    ) {
+        Ok(()) => {
+            info!("Access point activated on {}", channel);
+            Ok(portal_connection)
+        }
+        Err(e) => {
+            let _ = portal_connection.delete();
+            Err(e)
+        }
+    }
+}
+
+/// Writes the band and channel to the portal profile, none leaving them to
+/// NetworkManager, and activates it again
+fn activate_portal_on(
+    connection: &DBusConnection,
+    uuid: &str,
+    passphrase: Option<&str>,
+    device_path: &Path,
+    band: Option<&str>,
+    channel: Option<u32>,
+) -> Result<()> {
+    let settings_path = get_connection_path(connection, uuid)?;
+    let mut settings = get_saved_settings(connection, uuid)?;
+
+    {
+        let wireless = settings
+            .entry("802-11-wireless".to_string())
+            .or_insert_with(HashMap::new);
+
+        match band {
+            Some(band) => wireless.insert("band".to_string(), variant(band.to_string())),
+            None => wireless.remove("band"),
+        };
+
+        match channel {
+            Some(channel) => wireless.insert("channel".to_string(), variant(channel)),
+            None => wireless.remove("channel"),
+        };
+    }
+
+    // The settings come without their secrets, which the update would drop
+    if let Some(passphrase) = passphrase {
+        if let Some(security) = settings.get_mut("802-11-wireless-security") {
+            security.insert("psk".to_string(), variant(passphrase.to_string()));
+        }
+    }
+
+    let message = method_call(
+        NM_SERVICE,
+        &settings_path,
+        NM_SETTINGS_CONNECTION_INTERFACE,
+        "Update",
+    )?
+    .append1(settings);
+    call_method(connection, message, "Update", DBUS_TIMEOUT_MS)?;
+
+    let message = method_call(NM_SERVICE, NM_PATH, NM_SERVICE, "ActivateConnection")?.append3(
+        settings_path,
+        device_path.clone(),
+        Path::from("/"),
+    );
+    let reply = call_method_retrying(
+        connection,
+        message,
+        "ActivateConnection",
+        NM_ACTIVATE_TIMEOUT_MS,
+    )?;
+    let active_path: Path = read1(&reply, "ActivateConnection")?;
+
+    let options = ConnectOptions {
+        timeout: Duration::from_secs(PORTAL_ACTIVATE_TIMEOUT_SECS),
+        cancel: CancelFlag::default(),
+        autoconnect: false,
+        priority: 0,
+        metered: Metered::Auto,
+        dns: Vec::new(),
+        search_domains: Vec::new(),
+        uuid: None,
+    };
+
+    match wait_for_activation(connection, &active_path, &options) {
+        Some(NM_ACTIVE_CONNECTION_STATE_ACTIVATED) => Ok(()),
+        Some(state) => bail!(
+            "active connection state {}, device state reason {}",
+            state,
+            device_state_reason(connection, device_path).unwrap_or(0)
+        ),
+        None => bail!("activation timed out"),
+    }
+}
+
+/// Why the device left its last state, as `NMDeviceStateReason`
+fn device_state_reason(connection: &DBusConnection, device_path: &Path) -> Option<u32> {
+    let reply = get_property(connection, device_path, NM_DEVICE_INTERFACE, "StateReason").ok()?;
+    let state_reason: Variant<(u32, u32)> = read1(&reply, "Get").ok()?;
+
+    Some((state_reason.0).1)
+}
+
+/// Whether the device failed to raise the access point for a reason the
+/// channel may be behind
+fn failed_on_channel(interface: &str) -> bool {
+    let reason = system_bus().ok().and_then(|connection| {
+        let device_path = get_device_path(&connection, interface).ok()?;
+        device_state_reason(&connection, &device_path)
+    });
+
+    match reason {
+        Some(NM_DEVICE_STATE_REASON_SUPPLICANT_CONFIG_FAILED)
+        | Some(NM_DEVICE_STATE_REASON_SUPPLICANT_FAILED)
+        | Some(NM_DEVICE_STATE_REASON_SUPPLICANT_TIMEOUT) => true,
+        _ => false,
+    }
+}
+
+/// The access point profile of the portal, e.g. left inactive by a failed
+/// activation
+fn find_portal_profile(manager: &NetworkManager, ssid: &str) -> Option<Connection> {
+    manager
+        .get_connections()
+        .ok()?
+        .into_iter()
+        .find(|connection| {
+            is_access_point_connection(connection) && is_same_ssid(connection, ssid.as_bytes())
+        })
+}
+
+/// Gives the portal profile the prefix of the portal subnet, as the
+/// network-manager crate creates hotspots with a /24 one. Marks it
+/// `never-default` for both address families as well, with a route metric
+/// losing to any uplink, and keeps NetworkManager from activating it on its
+/// own. The hotspot is activated right as it is created, so the active
+/// connection is given the updated profile too.
+fn configure_portal_profile(
+    uuid: &str,
+    passphrase: Option<&str>,
+    interface: &str,
+    gateway: Ipv4Addr,
+    gateway_prefix: u8,
+) -> Result<()> {
+    let connection = system_bus()?;
+    let settings_path = get_connection_path(&connection, uuid)?;
+    let mut settings = get_saved_settings(&connection, uuid)?;
+
+    {
+        let mut set = |section: &str, key: &str, value: Variant<Box<dyn RefArg>>| {
+            settings
+                .entry(section.to_string())
+                .or_insert_with(HashMap::new)
+                .insert(key.to_string(), value);
+        };
+
+        set("connection", "autoconnect", variant(false));
+        set("ipv4", "never-default", variant(true));
+        set("ipv4", "route-metric", variant(PORTAL_ROUTE_METRIC));
+        set("ipv6", "never-default", variant(true));
+        set("ipv6", "route-metric", variant(PORTAL_ROUTE_METRIC));
+
+        // Address, prefix and gateway, in network byte order like the
+        // address itself
+        set(
+            "ipv4",
+            "addresses",
+            variant(vec![vec![
+                u32::from(gateway).to_be(),
+                u32::from(gateway_prefix),
+                0u32,
+            ]]),
+        );
+    }
+
+    // NetworkManager ignores `addresses` when given `address-data` as well
+    if let Some(ipv4) = settings.get_mut("ipv4") {
+        ipv4.remove("address-data");
+    }
+
+    // The settings come without their secrets, which the update would drop
+    if let Some(passphrase) = passphrase {
+        if let Some(security) = settings.get_mut("802-11-wireless-security") {
+            security.insert("psk".to_string(), variant(passphrase.to_string()));
+        }
+    }
+
+    let message = method_call(
+        NM_SERVICE,
+        &settings_path,
+        NM_SETTINGS_CONNECTION_INTERFACE,
+        "Update",
+    )?
+    .append1(settings);
+    call_method(&connection, message, "Update", DBUS_TIMEOUT_MS)?;
+
+    // Empty settings reapply those of the updated profile
+    let device_path = get_device_path(&connection, interface)?;
+    let message = method_call(NM_SERVICE, &device_path, NM_DEVICE_INTERFACE, "Reapply")?.append3(
+        SavedSettings::new(),
+        0u64,
+        0u32,
+    );
+    call_method(&connection, message, "Reapply", DBUS_TIMEOUT_MS)?;
+
+    debug!(
+        "Portal profile {} marked never-default, with the subnet {}/{}",
+        uuid, gateway, gateway_prefix
+    );
+
+    Ok(())
+}
+
+fn stop_portal_impl(connection: &Connection, config: &Config) -> Result<()> {
+    info!("Stopping access point '{}'...", config.ssid);
+    connection.deactivate()?;
+    connection.delete()?;
+    thread::sleep(Duration::from_secs(1));
+    info!("Access point '{}' stopped", config.ssid);
+    Ok(())
+}
+
+pub fn start_network_manager_service() -> Result<()> {
+    let state = match NetworkManager::get_service_state() {
+        Ok(state) => state,
+        _ => {
+            info!("Cannot get the NetworkManager service state");
+            return Ok(());
+        }
+    };
+
+    if state != ServiceState::Active {
+        let state =
+            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
+        if state != ServiceState::Active {
+            bail!(ErrorKind::StartActiveNetworkManager);
+        } else {
+            info!("NetworkManager service started successfully");
+        }
+    } else {
+        debug!("NetworkManager service already running");
+    }
+
+    Ok(())
+}
+
+/// At boot NetworkManager may still be coming up while the service is
+/// reported active, so wait until it owns its D-Bus name and knows its state
+fn wait_for_network_manager(timeout: u64) -> Result<()> {
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(timeout);
+    let mut reported = started;
+
+    loop {
+        let waiting_for = match network_manager_state() {
+            Ok(Some(state)) if state != NM_STATE_UNKNOWN => {
+                if reported != started {
+                    info!(
+                        "NetworkManager ready after {}s",
+                        started.elapsed().as_secs()
+                    );
+                }
+                return Ok(());
+            }
+            Ok(Some(_)) => "its state to be known".to_string(),
+            Ok(None) => "its D-Bus name".to_string(),
+            Err(e) => {
+                if Instant::now() >= deadline {
+                    return Err(e);
+                }
+                format!("the system bus: {}", e)
+            }
+        };
+
+        if Instant::now() >= deadline {
+            bail!(ErrorKind::StartActiveNetworkManager);
+        }
+
+        if reported.elapsed() >= Duration::from_secs(5) {
+            info!(
+                "Waiting for NetworkManager for {}s, expecting {}",
+                started.elapsed().as_secs(),
+                waiting_for
+            );
+            reported = Instant::now();
+        }
+
+        thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// The `NMState` of NetworkManager, none while it does not own its name
+fn network_manager_state() -> Result<Option<u32>> {
+    let connection = system_bus()?;
+
+    if !name_has_owner(&connection, NM_SERVICE)? {
+        return Ok(None);
+    }
+
+    let reply = get_property(&connection, &Path::from(NM_PATH), NM_SERVICE, "State")?;
+    let state: Variant<u32> = read1(&reply, "Get")?;
+
+    Ok(Some(state.0))
+}
+
+fn delete_exising_wifi_connect_ap_profile(ssid: &str) -> Result<()> {
+    let manager = NetworkManager::new();
+
+    for connection in &manager.get_connections()? {
+        if is_access_point_connection(connection) && is_same_ssid(connection, ssid.as_bytes()) {
+            info!(
+                "Deleting already created by WiFi Connect access point connection profile: {:?}",
+                connection.settings().ssid,
+            );
+            connection.delete()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn delete_existing_connections_to_same_network(manager: &NetworkManager, ssid: &[u8]) {
+    let connections = match manager.get_connections() {
+        Ok(connections) => connections,
+        Err(e) => {
+            error!("Getting existing connections failed: {}", e);
+            return;
+        }
+    };
+
+    for connection in &connections {
+        // The portal may share the SSID of the network
+        if is_wifi_connection(connection)
+            && !is_access_point_connection(connection)
+            && is_same_ssid(connection, ssid)
+        {
+            info!(
+                "Deleting existing WiFi connection to the same network: {:?} ({})",
+                connection.settings().ssid,
+                connection.settings().uuid,
+            );
+
+            if let Err(e) = connection.delete() {
+                error!("Deleting existing WiFi connection failed: {}", e);
+            }
+        }
+    }
+}
+
+fn is_same_ssid(connection: &Connection, ssid: &[u8]) -> bool {
+    connection.settings().ssid.as_bytes() == ssid
+}
+
+fn is_access_point_connection(connection: &Connection) -> bool {
+    is_wifi_connection(connection) && connection.settings().mode == "ap"
+}
+
+fn is_wifi_connection(connection: &Connection) -> bool {
+    connection.settings().kind == "802-11-wireless"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wpa_psk_settings(passphrase: &str) -> Settings {
+        let credentials = Credentials {
+            passphrase: Some(passphrase.to_string()),
+            ..Credentials::default()
+        };
+        let options = ConnectOptions {
+            timeout: Duration::from_secs(30),
+            cancel: CancelFlag::default(),
+            autoconnect: true,
+            priority: 0,
+            metered: Metered::Auto,
+            dns: Vec::new(),
+            search_domains: Vec::new(),
+            uuid: None,
+        };
+
+        connection_settings(
+            &Ssid::from("Home"),
+            ConnectSecurity::WpaPsk,
+            &credentials,
+            &options,
+        )
+    }
+
+    fn setting<'a>(settings: &'a Settings, section: &str, key: &str) -> Option<&'a str> {
+        settings
+            .get(section)
+            .and_then(|section| section.get(key))
+            .and_then(|value| value.0.as_str())
+    }
+
+    #[test]
+    fn passphrases_and_hex_psks_are_written_as_the_psk() {
+        let hex_psk = "0123456789abcdefABCDEF0123456789abcdef0123456789abcdef0123456789";
+
+        for &passphrase in ["correct horse", hex_psk].iter() {
+            assert!(is_valid_wpa_passphrase(passphrase));
+
+            let settings = wpa_psk_settings(passphrase);
+            assert_eq!(
+                setting(&settings, "802-11-wireless-security", "key-mgmt"),
+                Some("wpa-psk")
+            );
+            assert_eq!(
+                setting(&settings, "802-11-wireless-security", "psk"),
+                Some(passphrase)
+            );
+        }
+
+        // Too long for a passphrase, and not a PSK either
+        assert!(!is_valid_wpa_passphrase(&hex_psk.replace('0', "g")));
+        assert!(!is_valid_wpa_passphrase(&format!("{}0", hex_psk)));
+    }
+
+    #[test]
+    fn portal_fallbacks_are_tried_in_order() {
+        let mut tried = Vec::new();
+        let failure = try_portal_fallbacks("channel 149 refused".to_string(), |band, channel| {
+            tried.push((band, channel));
+            bail!("refused on {:?}", channel)
+        })
+        .unwrap_err();
+
+        assert_eq!(tried, [(Some("bg"), Some(6)), (None, None)]);
+        assert!(failure.to_string().ends_with("refused on None"));
+
+        let mut tried = Vec::new();
+        let activated = try_portal_fallbacks("channel 149 refused".to_string(), |band, channel| {
+            tried.push((band, channel));
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(activated, "channel 6 on 2.4 GHz");
+        assert_eq!(tried, [(Some("bg"), Some(6))]);
+    }
+}
@@ -0,0 +1,262 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use dbus::arg::{RefArg, Variant};
+use dbus::tree::{Factory, MethodErr};
+use dbus::{Connection, Path};
+
+use backend::bus::{call_method, method_call, system_bus, DBUS_TIMEOUT_MS};
+use backend::Credentials;
+use errors::*;
+
+const NM_SERVICE: &str = "org.freedesktop.NetworkManager";
+const NM_AGENT_MANAGER_PATH: &str = "/org/freedesktop/NetworkManager/AgentManager";
+const NM_AGENT_MANAGER_INTERFACE: &str = "org.freedesktop.NetworkManager.AgentManager";
+const SECRET_AGENT_PATH: &str = "/org/freedesktop/NetworkManager/SecretAgent";
+const SECRET_AGENT_INTERFACE: &str = "org.freedesktop.NetworkManager.SecretAgent";
+const NO_SECRETS_ERROR: &str = "org.freedesktop.NetworkManager.SecretAgent.NoSecrets";
+
+/// Identifies the agent to NetworkManager, one per process
+const AGENT_IDENTIFIER: &str = "org.wifi_connect.SecretAgent";
+
+/// `NMSecretAgentGetSecretsFlags` asking for other secrets than the ones
+/// served before, which NetworkManager sets once those were rejected
+const NM_SECRET_AGENT_GET_SECRETS_FLAG_REQUEST_NEW: u32 = 0x2;
+
+/// How often the agent thread checks whether the attempt is over
+const POLL_INTERVAL_MS: u32 = 200;
+
+type SettingsSection = HashMap<&'static str, Variant<Box<dyn RefArg>>>;
+type Settings = HashMap<&'static str, SettingsSection>;
+/// A profile as NetworkManager passes it along with a secrets request
+type RequestSettings = HashMap<String, HashMap<String, Variant<Box<dyn RefArg>>>>;
+
+/// The only secrets the agent hands out, those of the connect request
+struct Secrets {
+    uuid: String,
+    identity: Option<String>,
+    passphrase: Option<String>,
+}
+
+/// A NetworkManager secret agent registered for a single connect attempt.
+/// Some 802.1x profiles have NetworkManager ask the activating client for
+/// their secrets instead of reading them from the profile, which times out
+/// without an agent. Only the profile created for the attempt is served,
+/// requests for any other one are refused. The agent is unregistered once
+/// this is dropped.
+pub struct SecretAgent {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SecretAgent {
+    /// Registers the agent for the profile `uuid` from a thread of its own,
+    /// returning once NetworkManager accepted it
+    pub fn register(uuid: &str, credentials: &Credentials) -> Result<Self> {
+        let secrets = Secrets {
+            uuid: uuid.to_string(),
+            identity: credentials.identity.clone(),
+            passphrase: credentials.passphrase.clone(),
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready_tx, ready_rx) = channel();
+
+        let thread = {
+            let stop = stop.clone();
+            thread::spawn(move || serve(secrets, &stop, &ready_tx))
+        };
+
+        match ready_rx.recv() {
+            Ok(Ok(())) => {
+                debug!("Secret agent registered for {}", uuid);
+
+                Ok(SecretAgent {
+                    stop,
+                    thread: Some(thread),
+                })
+            }
+            Ok(Err(e)) => Err(e),
+            Err(_) => bail!("the secret agent thread ended"),
+        }
+    }
+}
+
+impl Drop for SecretAgent {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(secrets: Secrets, stop: &AtomicBool, ready_tx: &Sender<Result<()>>) {
+    let connection = match system_bus() {
+        Ok(connection) => connection,
+        Err(e) => {
+            let _ = ready_tx.send(Err(e));
+            return;
+        }
+    };
+
+    let factory = Factory::new_fn::<()>();
+
+    let interface = factory
+        .interface(SECRET_AGENT_INTERFACE, ())
+        .add_m(
+            factory
+                .method("GetSecrets", (), move |m| {
+                    let (settings, _, setting_name, _, flags) = m
+                        .msg
+                        .read5::<RequestSettings, Path, &str, Vec<&str>, u32>()
+                        .map_err(|e| MethodErr::invalid_arg(&e))?;
+
+                    let reply = get_secrets(&secrets, &settings, setting_name, flags)?;
+
+                    Ok(vec![m.msg.method_return().append1(reply)])
+                })
+                .inarg::<RequestSettings, _>("connection")
+                .inarg::<Path, _>("connection_path")
+                .inarg::<&str, _>("setting_name")
+                .inarg::<Vec<&str>, _>("hints")
+                .inarg::<u32, _>("flags")
+                .outarg::<Settings, _>("secrets"),
+        )
+        // Nothing is waited on, secrets requests are answered right away
+        .add_m(
+            factory
+                .method("CancelGetSecrets", (), |m| Ok(vec![m.msg.method_return()]))
+                .inarg::<Path, _>("connection_path")
+                .inarg::<&str, _>("setting_name"),
+        )
+        // The secrets are stored with the profile, never by the agent
+        .add_m(
+            factory
+                .method("SaveSecrets", (), |m| Ok(vec![m.msg.method_return()]))
+                .inarg::<RequestSettings, _>("connection")
+                .inarg::<Path, _>("connection_path"),
+        )
+        .add_m(
+            factory
+                .method("DeleteSecrets", (), |m| Ok(vec![m.msg.method_return()]))
+                .inarg::<RequestSettings, _>("connection")
+                .inarg::<Path, _>("connection_path"),
+        );
+
+    let tree = factory.tree(()).add(
+        factory
+            .object_path(SECRET_AGENT_PATH, ())
+            .introspectable()
+            .add(interface),
+    );
+
+    if let Err(e) = tree.set_registered(&connection, true) {
+        let _ = ready_tx.send(Err(e.into()));
+        return;
+    }
+
+    if let Err(e) = agent_manager_call(&connection, "Register") {
+        let _ = ready_tx.send(Err(e));
+        return;
+    }
+
+    let _ = ready_tx.send(Ok(()));
+
+    for _ in tree.run(&connection, connection.iter(POLL_INTERVAL_MS as i32)) {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+    }
+
+    if let Err(e) = agent_manager_call(&connection, "Unregister") {
+        warn!("Unregistering the secret agent failed: {}", e);
+    }
+}
+
+/// Serves the secrets of the connect request for the setting asked for, if
+/// the request is about the profile of the attempt
+fn get_secrets(
+    secrets: &Secrets,
+    settings: &RequestSettings,
+    setting_name: &str,
+    flags: u32,
+) -> ::std::result::Result<Settings, MethodErr> {
+    let uuid = settings
+        .get("connection")
+        .and_then(|section| section.get("uuid"))
+        .and_then(|value| value.0.as_str())
+        .unwrap_or("");
+
+    if uuid != secrets.uuid {
+        warn!(
+            "Refusing the secrets of connection {} to NetworkManager, only {} is served",
+            uuid, secrets.uuid
+        );
+        return Err(no_secrets("Not the connection being activated"));
+    }
+
+    // Serving the same ones again would only have them rejected again
+    if flags & NM_SECRET_AGENT_GET_SECRETS_FLAG_REQUEST_NEW != 0 {
+        info!("NetworkManager rejected the secrets of {}", uuid);
+        return Err(no_secrets("No other secrets than the rejected ones"));
+    }
+
+    let mut section = SettingsSection::new();
+
+    if setting_name == "802-1x" {
+        if let Some(ref identity) = secrets.identity {
+            section.insert("identity", variant(identity.clone()));
+        }
+        if let Some(ref passphrase) = secrets.passphrase {
+            section.insert("password", variant(passphrase.clone()));
+        }
+    }
+
+    if section.is_empty() {
+        warn!(
+            "Refusing the '{}' secrets of connection {} to NetworkManager, none were submitted",
+            setting_name, uuid
+        );
+        return Err(no_secrets("No such secrets were submitted"));
+    }
+
+    info!(
+        "Serving the '{}' secrets of connection {} to NetworkManager",
+        setting_name, uuid
+    );
+
+    let mut reply = Settings::new();
+    reply.insert("802-1x", section);
+
+    Ok(reply)
+}
+
+fn agent_manager_call(connection: &Connection, method: &str) -> Result<()> {
+    let message = method_call(
+        NM_SERVICE,
+        NM_AGENT_MANAGER_PATH,
+        NM_AGENT_MANAGER_INTERFACE,
+        method,
+    )?;
+
+    let message = if method == "Register" {
+        message.append1(AGENT_IDENTIFIER)
+    } else {
+        message
+    };
+
+    call_method(connection, message, method, DBUS_TIMEOUT_MS).map(|_| ())
+}
+
+fn variant<T: RefArg + 'static>(value: T) -> Variant<Box<dyn RefArg>> {
+    Variant(Box::new(value) as Box<dyn RefArg>)
+}
+
+fn no_secrets(message: &str) -> MethodErr {
+    MethodErr::from((NO_SECRETS_ERROR, message))
+}
@@ -0,0 +1,820 @@
+use std::cmp;
+use std::fs;
+use std::net::IpAddr;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use backend::{
+    is_hex_psk, is_valid_wpa_passphrase, read_resolv_conf, rssi_to_strength, select_device,
+    AccessPointInfo, ConnectFailure, ConnectOptions, ConnectOutcome, ConnectSecurity, Connectivity,
+    Credentials, DeviceCandidate, Metered, NetworkBackend, PortalChannel, SavedProfile, Security,
+    SignalInfo, Ssid, RESOLV_CONF_PATH,
+};
+use config::Config;
+use errors::*;
+
+const CONTROL_DIRECTORY: &str = "/var/run/wpa_supplicant";
+
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+const SCAN_TIMEOUT_SECS: u64 = 15;
+
+/// wpa_supplicant network mode for hosting an access point
+const AP_MODE: &str = "2";
+/// Channel 1 of 2.4 GHz, in MHz, unless another channel is asked for
+const AP_FREQUENCY: u32 = 2412;
+
+static CONTROL_SOCKET_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Backend for images with only wpa_supplicant, driven through its control
+/// interface. The portal relies on the access point mode of wpa_supplicant,
+/// which needs driver support; without it provisioning is left to the wired
+/// interfaces. Leases on the joined network come from `udhcpc`.
+pub struct WpaBackend {
+    control: WpaControl,
+    interface: String,
+    access_points: Vec<AccessPointInfo>,
+    portal_network: Option<String>,
+    access_point_supported: bool,
+    /// Networks were removed while the portal network is configured, which
+    /// must not be written to the configuration file
+    unsaved_removals: bool,
+}
+
+impl NetworkBackend for WpaBackend {
+    fn init(config: &Config) -> Result<()> {
+        let interface = find_interface(config)?;
+
+        let control = WpaControl::open(&interface)?;
+
+        remove_networks(&control, config.ssid.as_bytes(), None)
+            .chain_err(|| ErrorKind::DeleteAccessPoint)
+    }
+
+    fn new(config: &Config) -> Result<Self> {
+        let interface = find_interface(config)?;
+
+        let control = WpaControl::open(&interface)?;
+        info!("wpa_supplicant control interface initialized");
+
+        let access_point_supported = match control.request("GET_CAPABILITY modes") {
+            Ok(modes) => modes.split_whitespace().any(|mode| mode == "AP"),
+            Err(_) => false,
+        };
+
+        if !access_point_supported {
+            warn!(
+                "The driver of {} cannot host an access point, the portal is only reachable \
+                 over the wired interfaces",
+                interface
+            );
+        }
+
+        Ok(WpaBackend {
+            control,
+            interface,
+            access_points: Vec::new(),
+            portal_network: None,
+            access_point_supported,
+            unsaved_removals: false,
+        })
+    }
+
+    fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    fn uses_dnsmasq(&self) -> bool {
+        self.access_point_supported
+    }
+
+    fn hosts_portal(&self) -> bool {
+        self.access_point_supported
+    }
+
+    fn scan(&mut self) -> Result<Vec<AccessPointInfo>> {
+        if self.portal_network.is_none() {
+            self.request_scan()?;
+        }
+
+        let results = self.control.request("SCAN_RESULTS")?;
+
+        // bssid / frequency / signal level / flags / ssid
+        self.access_points = results
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let fields = line.split('\t').collect::<Vec<_>>();
+
+                if fields.len() < 5 {
+                    return None;
+                }
+
+                Some(AccessPointInfo {
+                    ssid: Ssid::from_bytes(decode_ssid(fields[4])),
+                    security: get_access_point_security(fields[3]),
+                    strength: fields[2].parse().ok().map(rssi_to_strength),
+                    bssid: Some(fields[0].to_string()),
+                })
+            })
+            .collect();
+
+        Ok(self.access_points.clone())
+    }
+
+    fn create_portal(&mut self, config: &Config, channel: PortalChannel) -> Result<()> {
+        if !self.access_point_supported {
+            return Ok(());
+        }
+
+        info!("Starting access point...");
+
+        let id = self.control.request("ADD_NETWORK")?;
+
+        self.set_network(&id, "ssid", &encode_hex(config.ssid.as_bytes()))?;
+        self.set_network(&id, "mode", AP_MODE)?;
+        let frequency = channel.frequency().unwrap_or(AP_FREQUENCY);
+        self.set_network(&id, "frequency", &frequency.to_string())?;
+
+        if let Some(ref passphrase) = config.passphrase {
+            self.set_network(&id, "key_mgmt", "WPA-PSK")?;
+            self.set_network(&id, "proto", "RSN")?;
+            self.set_network(&id, "pairwise", "CCMP")?;
+            self.set_network(&id, "psk", &quote(passphrase))?;
+        } else {
+            self.set_network(&id, "key_mgmt", "NONE")?;
+        }
+
+        self.control.request_ok(&format!("SELECT_NETWORK {}", id))?;
+
+        run_command(
+            "ip",
+            &[
+                "addr",
+                "add",
+                &format!("{}/{}", config.gateway, config.gateway_prefix),
+                "dev",
+                &self.interface,
+            ],
+        )?;
+
+        info!("Access point '{}' created", config.ssid);
+
+        self.portal_network = Some(id);
+
+        Ok(())
+    }
+
+    fn stop_portal(&mut self, config: &Config) -> Result<()> {
+        let id = match self.portal_network.take() {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+
+        info!("Stopping access point '{}'...", config.ssid);
+
+        self.control.request_ok(&format!("REMOVE_NETWORK {}", id))?;
+
+        if self.unsaved_removals {
+            self.unsaved_removals = false;
+
+            if let Err(err) = self.control.request_ok("SAVE_CONFIG") {
+                warn!("Saving the wpa_supplicant configuration failed: {}", err);
+            }
+        }
+
+        run_command("ip", &["addr", "flush", "dev", &self.interface])?;
+
+        thread::sleep(Duration::from_secs(1));
+        info!("Access point '{}' stopped", config.ssid);
+
+        Ok(())
+    }
+
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let security = match self.access_points.iter().find(|ap| ap.ssid == *ssid) {
+            Some(access_point) => credentials.security_or(access_point.security),
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        if security == ConnectSecurity::WpaPsk && !is_valid_wpa_passphrase(credentials.passphrase())
+        {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase));
+        }
+
+        if options.metered != Metered::Auto {
+            warn!("wpa_supplicant has no metered networks, ignoring the requested metered setting");
+        }
+
+        info!("Connecting to access point '{}'...", ssid);
+
+        let id = self.control.request("ADD_NETWORK")?;
+
+        self.set_network(&id, "ssid", &encode_hex(ssid.as_bytes()))?;
+        self.set_network_credentials(
+            &id,
+            security,
+            credentials.identity(),
+            credentials.passphrase(),
+        )?;
+        self.set_network(&id, "priority", &options.priority.to_string())?;
+
+        // Events are only delivered to an attached socket, so a separate one
+        // keeps them from interleaving with command replies
+        let monitor = WpaControl::open(&self.interface)?;
+        monitor.request_ok("ATTACH")?;
+
+        self.control.request_ok(&format!("SELECT_NETWORK {}", id))?;
+
+        let outcome = wait_for_association(&monitor, options)?;
+
+        let activated = match outcome {
+            ConnectOutcome::Activated => true,
+            ConnectOutcome::Failed(ref failure) => {
+                warn!(
+                    "Connection to access point not activated '{}': {}",
+                    ssid, failure
+                );
+                false
+            }
+            ConnectOutcome::Cancelled => {
+                info!("Connection to access point '{}' cancelled", ssid);
+                false
+            }
+        };
+
+        if !activated {
+            // Removing the selected network also disconnects from it
+            if let Err(err) = self.control.request_ok(&format!("REMOVE_NETWORK {}", id)) {
+                error!("Deleting network configuration failed: {}", err);
+            }
+
+            return Ok(outcome);
+        }
+
+        // Networks that are not saved are forgotten once wpa_supplicant
+        // restarts, so they are not joined again after a reboot
+        if options.autoconnect {
+            if let Err(err) = self.control.request_ok("SAVE_CONFIG") {
+                warn!("Saving the wpa_supplicant configuration failed: {}", err);
+            }
+        }
+
+        if let Err(err) = run_command("udhcpc", &["-i", &self.interface, "-n", "-q"]) {
+            warn!("Obtaining a DHCP lease failed: {}", err);
+        }
+
+        // The udhcpc script writes the resolvers of the lease, which the
+        // explicit ones replace
+        if !options.dns.is_empty() || !options.search_domains.is_empty() {
+            if let Err(err) = write_resolv_conf(&options.dns, &options.search_domains) {
+                warn!("Writing the resolver configuration failed: {}", err);
+            }
+        }
+
+        Ok(outcome)
+    }
+
+    fn activate_saved(
+        &mut self,
+        profile: &SavedProfile,
+        options: &ConnectOptions,
+    ) -> Result<ConnectOutcome> {
+        let id = match list_networks(&self.control)?
+            .into_iter()
+            .find(|&(_, ref ssid)| ssid == profile.ssid.as_bytes())
+        {
+            Some((id, _)) => id,
+            None => return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound)),
+        };
+
+        info!(
+            "Activating the saved network configuration of '{}'...",
+            profile.ssid
+        );
+
+        let monitor = WpaControl::open(&self.interface)?;
+        monitor.request_ok("ATTACH")?;
+
+        self.control.request_ok(&format!("SELECT_NETWORK {}", id))?;
+
+        let outcome = wait_for_association(&monitor, options)?;
+
+        if let ConnectOutcome::Activated = outcome {
+            if let Err(err) = run_command("udhcpc", &["-i", &self.interface, "-n", "-q"]) {
+                warn!("Obtaining a DHCP lease failed: {}", err);
+            }
+        } else if let Err(err) = self.control.request_ok("DISCONNECT") {
+            // The configuration is kept, but must not be retried on its own
+            error!("Disconnecting from the saved network failed: {}", err);
+        }
+
+        Ok(outcome)
+    }
+
+    fn delete_connections(&mut self, ssid: &Ssid) {
+        let portal_network = self.portal_network.as_ref().map(String::as_str);
+
+        if let Err(e) = remove_networks(&self.control, ssid.as_bytes(), portal_network) {
+            error!("Deleting existing network configurations failed: {}", e);
+        }
+    }
+
+    fn delete_profile(&mut self, profile: &SavedProfile) -> Result<()> {
+        let portal_network = self.portal_network.as_ref().map(String::as_str);
+
+        remove_networks(&self.control, profile.ssid.as_bytes(), portal_network)?;
+
+        // Saved once the portal is stopped
+        if self.portal_network.is_some() {
+            self.unsaved_removals = true;
+            return Ok(());
+        }
+
+        self.control.request_ok("SAVE_CONFIG")
+    }
+
+    fn saved_profiles(&self) -> Result<Vec<SavedProfile>> {
+        let mut profiles = Vec::new();
+
+        for (id, ssid) in list_networks(&self.control)? {
+            if self.portal_network.as_ref() == Some(&id) {
+                continue;
+            }
+
+            let priority = self
+                .control
+                .request(&format!("GET_NETWORK {} priority", id))?;
+
+            profiles.push(SavedProfile {
+                ssid: Ssid::from_bytes(ssid),
+                priority: priority.trim().parse().unwrap_or(0),
+                search_domains: Vec::new(),
+                uuid: None,
+                last_used: 0,
+            });
+        }
+
+        Ok(profiles)
+    }
+
+    fn connectivity(&self) -> Result<Connectivity> {
+        let status = self.control.request("STATUS")?;
+
+        let completed = status.lines().any(|line| line == "wpa_state=COMPLETED");
+        let has_address = status.lines().any(|line| line.starts_with("ip_address="));
+
+        // wpa_supplicant does not check internet reachability on its own
+        let connectivity = if completed && has_address {
+            Connectivity::Limited
+        } else {
+            Connectivity::None
+        };
+
+        Ok(connectivity)
+    }
+
+    fn signal(&self) -> Result<Option<SignalInfo>> {
+        let status = self.control.request("STATUS")?;
+
+        // The portal is reported as a completed association in AP mode
+        let associated = reply_field(&status, "wpa_state") == Some("COMPLETED")
+            && reply_field(&status, "mode") == Some("station");
+
+        if !associated {
+            return Ok(None);
+        }
+
+        let poll = self.control.request("SIGNAL_POLL")?;
+
+        let rssi = reply_field(&poll, "RSSI").and_then(|rssi| rssi.parse::<i32>().ok());
+
+        Ok(Some(SignalInfo {
+            ssid: Ssid::from_bytes(decode_ssid(reply_field(&status, "ssid").unwrap_or(""))),
+            bssid: reply_field(&status, "bssid").unwrap_or("").to_string(),
+            strength: rssi.map_or(0, rssi_to_strength),
+            rssi,
+            // In Mbit/s
+            bitrate: reply_field(&poll, "LINKSPEED")
+                .and_then(|speed| speed.parse::<u32>().ok())
+                .map(|speed| speed * 1000),
+            frequency: reply_field(&poll, "FREQUENCY").and_then(|freq| freq.parse().ok()),
+        }))
+    }
+}
+
+impl WpaBackend {
+    fn request_scan(&self) -> Result<()> {
+        let monitor = WpaControl::open(&self.interface)?;
+        monitor.request_ok("ATTACH")?;
+
+        // A scan already in progress is fine, its results are waited for below
+        if let Err(e) = self.control.request_ok("SCAN") {
+            debug!("Requesting a scan failed: {}", e);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(SCAN_TIMEOUT_SECS);
+
+        while let Some(event) = monitor.receive_event(deadline)? {
+            if event.contains("CTRL-EVENT-SCAN-RESULTS") {
+                return Ok(());
+            }
+        }
+
+        warn!("Scanning did not complete in {} seconds", SCAN_TIMEOUT_SECS);
+
+        Ok(())
+    }
+
+    fn set_network(&self, id: &str, name: &str, value: &str) -> Result<()> {
+        self.control
+            .request_ok(&format!("SET_NETWORK {} {} {}", id, name, value))
+    }
+
+    fn set_network_credentials(
+        &self,
+        id: &str,
+        security: ConnectSecurity,
+        identity: &str,
+        passphrase: &str,
+    ) -> Result<()> {
+        match security {
+            ConnectSecurity::Open => self.set_network(id, "key_mgmt", "NONE"),
+            ConnectSecurity::Wep => {
+                self.set_network(id, "key_mgmt", "NONE")?;
+                self.set_network(id, "wep_key0", &wep_key(passphrase))?;
+                self.set_network(id, "wep_tx_keyidx", "0")
+            }
+            ConnectSecurity::WpaPsk => {
+                self.set_network(id, "key_mgmt", "WPA-PSK")?;
+
+                if is_hex_psk(passphrase) {
+                    self.set_network(id, "psk", passphrase)
+                } else {
+                    self.set_network(id, "psk", &quote(passphrase))
+                }
+            }
+            ConnectSecurity::Sae => {
+                self.set_network(id, "key_mgmt", "SAE")?;
+                self.set_network(id, "ieee80211w", "2")?;
+                self.set_network(id, "sae_password", &quote(passphrase))
+            }
+            ConnectSecurity::WpaEap => {
+                self.set_network(id, "key_mgmt", "WPA-EAP")?;
+                self.set_network(id, "eap", "PEAP TTLS")?;
+                self.set_network(id, "identity", &quote(identity))?;
+                self.set_network(id, "password", &quote(passphrase))?;
+                self.set_network(id, "phase2", &quote("auth=MSCHAPV2"))
+            }
+        }
+    }
+}
+
+/// A datagram socket connected to the control interface of wpa_supplicant
+/// for a single network interface
+struct WpaControl {
+    socket: UnixDatagram,
+    local_path: PathBuf,
+}
+
+impl WpaControl {
+    fn open(interface: &str) -> Result<Self> {
+        let local_path = PathBuf::from(format!(
+            "/tmp/wifi-connect-wpa-{}-{}",
+            ::std::process::id(),
+            CONTROL_SOCKET_COUNT.fetch_add(1, Ordering::SeqCst)
+        ));
+
+        let _ = fs::remove_file(&local_path);
+
+        let socket = UnixDatagram::bind(&local_path)?;
+
+        let control = WpaControl { socket, local_path };
+
+        control
+            .socket
+            .connect(Path::new(CONTROL_DIRECTORY).join(interface))
+            .chain_err(|| ErrorKind::DeviceByInterface(interface.to_string()))?;
+
+        control
+            .socket
+            .set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+        Ok(control)
+    }
+
+    fn request(&self, command: &str) -> Result<String> {
+        self.socket.send(command.as_bytes())?;
+
+        let mut buffer = [0; 4096];
+
+        loop {
+            let size = self.socket.recv(&mut buffer)?;
+            let reply = String::from_utf8_lossy(&buffer[..size]).into_owned();
+
+            // Unsolicited event messages start with a priority level like `<3>`
+            if reply.starts_with('<') {
+                continue;
+            }
+
+            if reply.starts_with("FAIL") || reply.starts_with("UNKNOWN COMMAND") {
+                bail!(
+                    "wpa_supplicant command '{}' failed: {}",
+                    command,
+                    reply.trim()
+                );
+            }
+
+            return Ok(reply.trim_end().to_string());
+        }
+    }
+
+    fn request_ok(&self, command: &str) -> Result<()> {
+        let reply = self.request(command)?;
+
+        if reply != "OK" {
+            bail!("wpa_supplicant command '{}' failed: {}", command, reply);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for the next event on an attached socket, returning `None` once
+    /// the deadline passes
+    fn receive_event(&self, deadline: Instant) -> Result<Option<String>> {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return Ok(None);
+        }
+
+        self.socket.set_read_timeout(Some(deadline - now))?;
+
+        let mut buffer = [0; 4096];
+
+        match self.socket.recv(&mut buffer) {
+            Ok(size) => Ok(Some(String::from_utf8_lossy(&buffer[..size]).into_owned())),
+            Err(ref e)
+                if e.kind() == ::std::io::ErrorKind::WouldBlock
+                    || e.kind() == ::std::io::ErrorKind::TimedOut =>
+            {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for WpaControl {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.local_path);
+    }
+}
+
+/// Waits for the association events, in short steps so that a cancellation
+/// is noticed while wpa_supplicant is silent
+fn wait_for_association(monitor: &WpaControl, options: &ConnectOptions) -> Result<ConnectOutcome> {
+    let deadline = Instant::now() + options.timeout;
+
+    while Instant::now() < deadline {
+        if options.cancel.is_cancelled() {
+            return Ok(ConnectOutcome::Cancelled);
+        }
+
+        let step = cmp::min(deadline, Instant::now() + Duration::from_millis(500));
+
+        let event = match monitor.receive_event(step)? {
+            Some(event) => event,
+            None => continue,
+        };
+
+        if event.contains("CTRL-EVENT-CONNECTED") {
+            return Ok(ConnectOutcome::Activated);
+        }
+
+        if event.contains("reason=WRONG_KEY") || event.contains("CTRL-EVENT-EAP-FAILURE") {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::WrongPassphrase));
+        }
+
+        if event.contains("CTRL-EVENT-NETWORK-NOT-FOUND") {
+            return Ok(ConnectOutcome::Failed(ConnectFailure::AccessPointNotFound));
+        }
+    }
+
+    Ok(ConnectOutcome::Failed(ConnectFailure::NotActivated(
+        format!(
+            "not associated within {} seconds",
+            options.timeout.as_secs()
+        ),
+    )))
+}
+
+fn find_interface(config: &Config) -> Result<String> {
+    if let Some(ref interface) = config.interface {
+        return Ok(interface.clone());
+    }
+
+    // wpa_supplicant creates a control socket named after each interface it manages
+    let entries = fs::read_dir(CONTROL_DIRECTORY).chain_err(|| ErrorKind::NoWiFiDevice)?;
+
+    let mut names = Vec::new();
+
+    for entry in entries {
+        names.push(entry?.file_name().to_string_lossy().into_owned());
+    }
+
+    names.sort();
+
+    let candidates = names
+        .iter()
+        .map(|name| DeviceCandidate {
+            interface: name,
+            unusable: if name.starts_with("p2p-dev-") {
+                Some("P2P device")
+            } else {
+                None
+            },
+        })
+        .collect::<Vec<_>>();
+
+    match select_device(config, &candidates) {
+        Some(index) => {
+            info!("WiFi device: {}", names[index]);
+            Ok(names[index].clone())
+        }
+        None => bail!(ErrorKind::NoWiFiDevice),
+    }
+}
+
+/// Ids and SSIDs of the configured networks
+fn list_networks(control: &WpaControl) -> Result<Vec<(String, Vec<u8>)>> {
+    let networks = control.request("LIST_NETWORKS")?;
+
+    // network id / ssid / bssid / flags
+    let networks = networks
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields = line.split('\t').collect::<Vec<_>>();
+
+            if fields.len() < 2 {
+                None
+            } else {
+                Some((fields[0].to_string(), decode_ssid(fields[1])))
+            }
+        })
+        .collect();
+
+    Ok(networks)
+}
+
+/// Removes the network configurations of the SSID, except the `exempt` one
+fn remove_networks(control: &WpaControl, ssid: &[u8], exempt: Option<&str>) -> Result<()> {
+    for (id, network_ssid) in list_networks(control)? {
+        if network_ssid != ssid || exempt == Some(id.as_str()) {
+            continue;
+        }
+
+        info!(
+            "Deleting existing network configuration {}: {:?}",
+            id,
+            String::from_utf8_lossy(ssid)
+        );
+
+        control.request_ok(&format!("REMOVE_NETWORK {}", id))?;
+    }
+
+    Ok(())
+}
+
+fn get_access_point_security(flags: &str) -> Security {
+    if flags.contains("EAP") {
+        Security::Enterprise
+    } else if flags.contains("PSK") || flags.contains("SAE") {
+        Security::Wpa
+    } else if flags.contains("WEP") {
+        Security::Wep
+    } else {
+        Security::None
+    }
+}
+
+/// The value of a `name=value` line of a reply
+fn reply_field<'a>(reply: &'a str, name: &str) -> Option<&'a str> {
+    reply.lines().find_map(|line| {
+        let mut parts = line.splitn(2, '=');
+
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key == name => Some(value),
+            _ => None,
+        }
+    })
+}
+
+/// Reverses the escaping wpa_supplicant applies to SSIDs in its replies
+fn decode_ssid(escaped: &str) -> Vec<u8> {
+    let bytes = escaped.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' || i + 1 >= bytes.len() {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        match bytes[i + 1] {
+            b'x' if i + 3 < bytes.len() => {
+                let hex = String::from_utf8_lossy(&bytes[i + 2..i + 4]).into_owned();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(byte) => decoded.push(byte),
+                    Err(_) => decoded.extend_from_slice(&bytes[i..i + 4]),
+                }
+                i += 4;
+            }
+            b'n' => {
+                decoded.push(b'\n');
+                i += 2;
+            }
+            b'r' => {
+                decoded.push(b'\r');
+                i += 2;
+            }
+            b't' => {
+                decoded.push(b'\t');
+                i += 2;
+            }
+            b'e' => {
+                decoded.push(0x1b);
+                i += 2;
+            }
+            other => {
+                decoded.push(other);
+                i += 2;
+            }
+        }
+    }
+
+    decoded
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value)
+}
+
+/// WEP keys of 10 or 26 hex digits are raw keys, anything else an ASCII key
+fn wep_key(passphrase: &str) -> String {
+    let is_hex = passphrase.chars().all(|c| c.is_ascii_hexdigit());
+
+    if is_hex && (passphrase.len() == 10 || passphrase.len() == 26) {
+        passphrase.to_string()
+    } else {
+        quote(passphrase)
+    }
+}
+
+/// Keeps the resolvers of the lease when there are no explicit ones
+fn write_resolv_conf(nameservers: &[IpAddr], search_domains: &[String]) -> Result<()> {
+    let nameservers = if nameservers.is_empty() {
+        read_resolv_conf()?
+    } else {
+        nameservers.to_vec()
+    };
+
+    let mut contents = String::new();
+
+    if !search_domains.is_empty() {
+        contents.push_str(&format!("search {}\n", search_domains.join(" ")));
+    }
+
+    for address in nameservers {
+        contents.push_str(&format!("nameserver {}\n", address));
+    }
+
+    fs::write(RESOLV_CONF_PATH, contents)?;
+
+    Ok(())
+}
+
+fn run_command(program: &str, args: &[&str]) -> Result<()> {
+    let status = Command::new(program).args(args).status()?;
+
+    if !status.success() {
+        bail!("{} {} failed: {}", program, args.join(" "), status);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,1107 @@
+use clap::{App, Arg, ArgMatches};
+
+use std::env;
+use std::ffi::OsStr;
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use json_events;
+use wifi_connect::config::{parse_gateway, parse_mime_type, parse_static_mount};
+use wifi_connect::config::{
+    DEFAULT_ACTIVITY_TIMEOUT, DEFAULT_AP_RETRY_LIMIT, DEFAULT_AUTOCONNECT, DEFAULT_BACKEND,
+    DEFAULT_CAPTIVE_MODE, DEFAULT_CONNECT_TIMEOUT, DEFAULT_DUPLICATE_POLICY, DEFAULT_GATEWAY,
+    DEFAULT_HTTP_BACKLOG, DEFAULT_HTTP_KEEPALIVE, DEFAULT_HTTP_READ_TIMEOUT, DEFAULT_HTTP_THREADS,
+    DEFAULT_LISTENING_PORT, DEFAULT_LOG_BUFFER_LINES, DEFAULT_LOG_FORMAT,
+    DEFAULT_MAX_ASSET_REQUESTS, DEFAULT_MAX_BODY_SIZE, DEFAULT_MAX_CONNECT_TIMEOUT,
+    DEFAULT_MAX_FAILURES_BEFORE_EXIT, DEFAULT_MAX_REQUESTS, DEFAULT_MAX_UPLOAD_SIZE,
+    DEFAULT_MDNS_NAME, DEFAULT_MIN_SIGNAL, DEFAULT_NETWORKS_CACHE_TTL, DEFAULT_NETWORK_EXPIRY,
+    DEFAULT_NM_WAIT_TIMEOUT, DEFAULT_ON_WORKER_FAILURE, DEFAULT_SCAN_TIMEOUT, DEFAULT_SSID,
+    DEFAULT_UI_DIRECTORY,
+};
+use wifi_connect::{
+    BackendKind, CaptiveMode, Config, DuplicatePolicy, LogBuffer, LogFormat, PortalBuilder,
+    WorkerFailure,
+};
+
+/// What the command line asks for
+pub struct Options {
+    pub config: Config,
+    /// Validate and print the configuration instead of running the flow
+    pub print_config: bool,
+}
+
+pub fn get_options() -> Options {
+    let matches = App::new(crate_name!())
+        .version(crate_version!())
+        .author(crate_authors!())
+        .about(crate_description!())
+        .arg(
+            Arg::with_name("portal-interface")
+                .short("i")
+                .long("portal-interface")
+                .value_name("interface")
+                .help("Wireless network interface to be used by WiFi Connect")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-ssid")
+                .short("s")
+                .long("portal-ssid")
+                .value_name("ssid")
+                .help(&format!(
+                    "SSID of the captive portal WiFi network (default: {})",
+                    DEFAULT_SSID
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-passphrase")
+                .short("p")
+                .long("portal-passphrase")
+                .value_name("passphrase")
+                .help("WPA2 Passphrase of the captive portal WiFi network (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-gateway")
+                .short("g")
+                .long("portal-gateway")
+                .value_name("gateway")
+                .help(&format!(
+                    "Gateway of the captive portal WiFi network, optionally with a prefix length like /24 (default: {})",
+                    DEFAULT_GATEWAY
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-gateway6")
+                .long("portal-gateway6")
+                .value_name("gateway6")
+                .help("Unique local IPv6 address of the captive portal, whose /64 is advertised to clients (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-dhcp-range")
+                .short("d")
+                .long("portal-dhcp-range")
+                .value_name("dhcp_range")
+                .help("DHCP range of the WiFi network (default: the addresses above the gateway)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("portal-listening-port")
+                .short("o")
+                .long("portal-listening-port")
+                .value_name("listening_port")
+                .help(&format!(
+                    "Listening port of the captive portal web server (default: {})",
+                    DEFAULT_LISTENING_PORT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fallback-port")
+                .long("fallback-port")
+                .value_name("port")
+                .help("Listening port used when another program holds the listening port (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("activity-timeout")
+                .short("a")
+                .long("activity-timeout")
+                .value_name("activity_timeout")
+                .help("Exit if no activity for the specified time (seconds) (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ui-directory")
+                .short("u")
+                .long("ui-directory")
+                .value_name("ui_directory")
+                .help(&format!(
+                    "Web UI directory location (default: {})",
+                    DEFAULT_UI_DIRECTORY
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dbus")
+                .long("dbus")
+                .help("Export the org.wifi_connect.Portal1 D-Bus control interface"),
+        )
+        .arg(
+            Arg::with_name("webhook-url")
+                .long("webhook-url")
+                .value_name("url")
+                .help("HTTP URL receiving a POST for each provisioning event (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("webhook-token")
+                .long("webhook-token")
+                .value_name("token")
+                .help("Bearer token sent with webhook requests (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-portal-start")
+                .long("on-portal-start")
+                .value_name("command")
+                .help("Command run with sh -c whenever the portal comes up (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-connect")
+                .long("on-connect")
+                .value_name("command")
+                .help("Command run with sh -c once the device joined a network, with WC_SSID and WC_IP set (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-connect-fail")
+                .long("on-connect-fail")
+                .value_name("command")
+                .help("Command run with sh -c after a failed connect attempt, with WC_SSID and WC_REASON set (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("print-config")
+                .long("print-config")
+                .help("Validate the configuration and print it without starting the portal"),
+        )
+        .arg(
+            Arg::with_name("json-events")
+                .long("json-events")
+                .help("Write a JSON object per line to stdout for each provisioning event"),
+        )
+        .arg(
+            Arg::with_name("state-file")
+                .long("state-file")
+                .value_name("path")
+                .help("JSON file rewritten with the provisioning phase on every change (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .value_name("backend")
+                .help(&format!(
+                    "Network stack used for scanning, the portal and connecting (default: {})",
+                    DEFAULT_BACKEND
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("connect-timeout")
+                .long("connect-timeout")
+                .value_name("connect_timeout")
+                .help(&format!(
+                    "Seconds a connection attempt may take to activate (default: {})",
+                    DEFAULT_CONNECT_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-connect-timeout")
+                .long("max-connect-timeout")
+                .value_name("max_connect_timeout")
+                .help(&format!(
+                    "Upper bound on the connect timeout a request may ask for (default: {})",
+                    DEFAULT_MAX_CONNECT_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("default-autoconnect")
+                .long("default-autoconnect")
+                .value_name("autoconnect")
+                .help(&format!(
+                    "Whether created profiles reconnect automatically, e.g. after a reboot (default: {})",
+                    DEFAULT_AUTOCONNECT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("api-token")
+                .long("api-token")
+                .value_name("token")
+                .help("Bearer token required by privileged HTTP API requests (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-buffer-lines")
+                .long("log-buffer-lines")
+                .value_name("lines")
+                .help(&format!(
+                    "Recent log lines kept for the /logs endpoint, 0 disables it (default: {})",
+                    DEFAULT_LOG_BUFFER_LINES
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-format")
+                .long("log-format")
+                .value_name("format")
+                .help(&format!(
+                    "Write the log records as `text` or as a JSON object per line (`json`) (default: {})",
+                    DEFAULT_LOG_FORMAT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mdns-name")
+                .long("mdns-name")
+                .value_name("name")
+                .help(&format!(
+                    "Name the portal is announced as over mDNS, empty to disable (default: {})",
+                    DEFAULT_MDNS_NAME
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("networks-cache-ttl")
+                .long("networks-cache-ttl")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds the /networks reply is reused for, 0 disables the cache (default: {})",
+                    DEFAULT_NETWORKS_CACHE_TTL
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scan-timeout")
+                .long("scan-timeout")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds the scan before raising the portal may take (default: {})",
+                    DEFAULT_SCAN_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-failures-before-exit")
+                .long("max-failures-before-exit")
+                .value_name("failures")
+                .help("Exit with an error after this many failed connect attempts in a row (default: never)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ap-retry-limit")
+                .long("ap-retry-limit")
+                .value_name("attempts")
+                .help(&format!(
+                    "Attempts at raising the portal access point, with growing delays in between (default: {})",
+                    DEFAULT_AP_RETRY_LIMIT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("nm-wait-timeout")
+                .long("nm-wait-timeout")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds to wait for NetworkManager to come up at startup (default: {})",
+                    DEFAULT_NM_WAIT_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interface-include")
+                .long("interface-include")
+                .value_name("patterns")
+                .help("Comma separated glob patterns of the interfaces the automatic device selection may pick")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("interface-exclude")
+                .long("interface-exclude")
+                .value_name("patterns")
+                .help("Comma separated glob patterns of the interfaces the automatic device selection skips")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssid-allow")
+                .long("ssid-allow")
+                .value_name("patterns")
+                .help("Comma separated glob patterns of the networks the portal offers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ssid-deny")
+                .long("ssid-deny")
+                .value_name("patterns")
+                .help("Comma separated glob patterns of the networks the portal hides and refuses")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-signal")
+                .long("min-signal")
+                .value_name("percent")
+                .help("Signal quality below which networks are not listed (default: all are listed)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("network-expiry")
+                .long("network-expiry")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds a network missing from the scans stays listed for (default: {})",
+                    DEFAULT_NETWORK_EXPIRY
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("captive-mode")
+                .long("captive-mode")
+                .value_name("mode")
+                .help(&format!(
+                    "Answer requests to other hosts with a `redirect` or a `511` page (default: {})",
+                    DEFAULT_CAPTIVE_MODE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allowed-hosts")
+                .long("allowed-hosts")
+                .value_name("hosts")
+                .help("Comma separated host names the API answers to besides the gateway and the mDNS name")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("static-mount")
+                .long("static-mount")
+                .value_name("prefix=directory")
+                .help("URL prefix served from a subdirectory of the UI directory, like /fonts=fonts, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("ui-tree")
+                .long("ui-tree")
+                .help("Serve every file of the UI directory by its path"),
+        )
+        .arg(
+            Arg::with_name("immutable-assets")
+                .long("immutable-assets")
+                .value_name("regex")
+                .help("Regular expression of fingerprinted file names cached for good, may be repeated (default: names with a hex content hash)")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("mime-type")
+                .long("mime-type")
+                .value_name("extension=type")
+                .help("Content type of UI files with the extension, like avif=image/avif, may be repeated")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("trust-proxy")
+                .long("trust-proxy")
+                .value_name("addresses")
+                .help("Comma separated addresses of reverse proxies whose X-Forwarded-For header names the client in the request log")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-threads")
+                .long("http-threads")
+                .value_name("threads")
+                .help(&format!(
+                    "Threads serving HTTP requests, 0 for eight per CPU core. Each reserves a stack of its own, so small devices may want fewer (default: {})",
+                    DEFAULT_HTTP_THREADS
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-backlog")
+                .long("http-backlog")
+                .value_name("connections")
+                .help(&format!(
+                    "Connections queued for the HTTP server before new ones are reset. Queued connections only take kernel memory (default: {})",
+                    DEFAULT_HTTP_BACKLOG
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-read-timeout")
+                .long("http-read-timeout")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds to wait for a request before closing the connection, 0 for no limit (default: {})",
+                    DEFAULT_HTTP_READ_TIMEOUT
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("http-keepalive")
+                .long("http-keepalive")
+                .value_name("seconds")
+                .help(&format!(
+                    "Seconds idle connections are kept open, 0 disables keep-alive (default: {})",
+                    DEFAULT_HTTP_KEEPALIVE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-body-size")
+                .long("max-body-size")
+                .value_name("bytes")
+                .help(&format!(
+                    "Largest request body the HTTP API accepts (default: {})",
+                    DEFAULT_MAX_BODY_SIZE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-upload-size")
+                .long("max-upload-size")
+                .value_name("bytes")
+                .help(&format!(
+                    "Largest request body of the upload endpoints, such as /networks/import (default: {})",
+                    DEFAULT_MAX_UPLOAD_SIZE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-requests")
+                .long("max-requests")
+                .value_name("requests")
+                .help(&format!(
+                    "API requests handled at once before answering 503, 0 for no limit (default: {})",
+                    DEFAULT_MAX_REQUESTS
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-asset-requests")
+                .long("max-asset-requests")
+                .value_name("requests")
+                .help(&format!(
+                    "UI file requests handled at once before answering 503, 0 for no limit (default: {})",
+                    DEFAULT_MAX_ASSET_REQUESTS
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-csrf")
+                .long("no-csrf")
+                .help("Accept requests changing anything without the CSRF token, for legacy clients"),
+        )
+        .arg(
+            Arg::with_name("metrics")
+                .long("metrics")
+                .help("Serve the request counters in the Prometheus text format on /metrics"),
+        )
+        .arg(
+            Arg::with_name("access-log")
+                .long("access-log")
+                .value_name("path")
+                .help("File a line is appended to for every request (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("run-as")
+                .long("run-as")
+                .value_name("user[:group]")
+                .help("Unprivileged user to serve the portal as once it is up (default: none)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow-root")
+                .long("allow-root")
+                .help("Allow serving the portal as root"),
+        )
+        .arg(
+            Arg::with_name("on-worker-failure")
+                .long("on-worker-failure")
+                .value_name("action")
+                .help(&format!(
+                    "Whether a panicked network thread makes the program `restart` or `exit` (default: {})",
+                    DEFAULT_ON_WORKER_FAILURE
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("duplicate-policy")
+                .long("duplicate-policy")
+                .value_name("policy")
+                .help(&format!(
+                    "Whether the saved profiles of a network are deleted (`replace`), updated (`keep`) or refused (`error`) when credentials for it are submitted (default: {})",
+                    DEFAULT_DUPLICATE_POLICY
+                ))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("single-client")
+                .long("single-client")
+                .help("Refuse changes from other clients than the first one opening the portal"),
+        )
+        .arg(
+            Arg::with_name("run-forever")
+                .long("run-forever")
+                .help("Keep running after joining a network, raising the portal again whenever the device goes offline"),
+        )
+        .get_matches();
+
+    let interface: Option<String> = matches.value_of("portal-interface").map_or_else(
+        || env::var("PORTAL_INTERFACE").ok(),
+        |v| Some(v.to_string()),
+    );
+
+    let ssid_prefix: String = matches.value_of("portal-ssid").map_or_else(
+        || env::var("PORTAL_SSID").unwrap_or_else(|_| DEFAULT_SSID.to_string()),
+        String::from,
+    );
+
+    let val = env::var("BALENA_DEVICE_UUID");
+    let ssid_suffix = val
+        .as_ref()
+        .map_or(String::new(), |v| format!("-{}", &v[0..7]));
+    let ssid = format!("{}{}", ssid_prefix, ssid_suffix);
+
+    let passphrase: Option<String> = matches.value_of("portal-passphrase").map_or_else(
+        || env::var("PORTAL_PASSPHRASE").ok(),
+        |v| Some(v.to_string()),
+    );
+
+    let (gateway, gateway_prefix) = parse_gateway(&matches.value_of("portal-gateway").map_or_else(
+        || env::var("PORTAL_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse gateway address");
+
+    let gateway6 = matches
+        .value_of("portal-gateway6")
+        .map(String::from)
+        .or_else(|| env::var("PORTAL_GATEWAY6").ok())
+        .map(|gateway6| {
+            gateway6
+                .parse::<Ipv6Addr>()
+                .expect("Cannot parse IPv6 gateway address")
+        });
+
+    let dhcp_range = matches
+        .value_of("portal-dhcp-range")
+        .map(String::from)
+        .or_else(|| env::var("PORTAL_DHCP_RANGE").ok());
+
+    let listening_port = matches
+        .value_of("portal-listening-port")
+        .map_or_else(
+            || {
+                env::var("PORTAL_LISTENING_PORT")
+                    .unwrap_or_else(|_| DEFAULT_LISTENING_PORT.to_string())
+            },
+            String::from,
+        )
+        .parse::<u16>()
+        .expect("Cannot parse listening port number");
+
+    let fallback_port = matches
+        .value_of("fallback-port")
+        .map(String::from)
+        .or_else(|| env::var("FALLBACK_PORT").ok())
+        .map(|port| {
+            port.parse::<u16>()
+                .expect("Cannot parse fallback port number")
+        });
+
+    let activity_timeout = u64::from_str(&matches.value_of("activity-timeout").map_or_else(
+        || env::var("ACTIVITY_TIMEOUT").unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse activity timeout");
+
+    let ui_directory = get_ui_directory(matches.value_of("ui-directory"));
+
+    let dbus = get_flag(&matches, "dbus", "DBUS_INTERFACE");
+
+    let webhook_url: Option<String> = matches
+        .value_of("webhook-url")
+        .map_or_else(|| env::var("WEBHOOK_URL").ok(), |v| Some(v.to_string()));
+
+    let webhook_token: Option<String> = matches
+        .value_of("webhook-token")
+        .map_or_else(|| env::var("WEBHOOK_TOKEN").ok(), |v| Some(v.to_string()));
+
+    let on_portal_start = matches
+        .value_of("on-portal-start")
+        .map(String::from)
+        .or_else(|| env::var("ON_PORTAL_START").ok());
+
+    let on_connect = matches
+        .value_of("on-connect")
+        .map(String::from)
+        .or_else(|| env::var("ON_CONNECT").ok());
+
+    let on_connect_fail = matches
+        .value_of("on-connect-fail")
+        .map(String::from)
+        .or_else(|| env::var("ON_CONNECT_FAIL").ok());
+
+    let json_events = get_flag(&matches, "json-events", "JSON_EVENTS");
+
+    let print_config = matches.is_present("print-config");
+
+    let state_file = matches
+        .value_of("state-file")
+        .map(PathBuf::from)
+        .or_else(|| env::var("STATE_FILE").ok().map(PathBuf::from));
+
+    let api_token: Option<String> = matches
+        .value_of("api-token")
+        .map_or_else(|| env::var("API_TOKEN").ok(), |v| Some(v.to_string()));
+
+    let log_buffer_lines = usize::from_str(&matches.value_of("log-buffer-lines").map_or_else(
+        || env::var("LOG_BUFFER_LINES").unwrap_or_else(|_| DEFAULT_LOG_BUFFER_LINES.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse log buffer lines");
+
+    let log_format = LogFormat::from_str(&matches.value_of("log-format").map_or_else(
+        || env::var("LOG_FORMAT").unwrap_or_else(|_| DEFAULT_LOG_FORMAT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse log format");
+
+    let mdns_name = matches.value_of("mdns-name").map_or_else(
+        || env::var("MDNS_NAME").unwrap_or_else(|_| DEFAULT_MDNS_NAME.to_string()),
+        String::from,
+    );
+
+    let networks_cache_ttl = u64::from_str(&matches.value_of("networks-cache-ttl").map_or_else(
+        || {
+            env::var("NETWORKS_CACHE_TTL")
+                .unwrap_or_else(|_| DEFAULT_NETWORKS_CACHE_TTL.to_string())
+        },
+        String::from,
+    ))
+    .expect("Cannot parse networks cache TTL");
+
+    let scan_timeout = u64::from_str(&matches.value_of("scan-timeout").map_or_else(
+        || env::var("SCAN_TIMEOUT").unwrap_or_else(|_| DEFAULT_SCAN_TIMEOUT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse scan timeout");
+
+    let max_failures_before_exit =
+        u32::from_str(&matches.value_of("max-failures-before-exit").map_or_else(
+            || {
+                env::var("MAX_FAILURES_BEFORE_EXIT")
+                    .unwrap_or_else(|_| DEFAULT_MAX_FAILURES_BEFORE_EXIT.to_string())
+            },
+            String::from,
+        ))
+        .expect("Cannot parse maximum connect failures");
+
+    let ap_retry_limit = u32::from_str(&matches.value_of("ap-retry-limit").map_or_else(
+        || env::var("AP_RETRY_LIMIT").unwrap_or_else(|_| DEFAULT_AP_RETRY_LIMIT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse access point retry limit");
+
+    let nm_wait_timeout = u64::from_str(&matches.value_of("nm-wait-timeout").map_or_else(
+        || env::var("NM_WAIT_TIMEOUT").unwrap_or_else(|_| DEFAULT_NM_WAIT_TIMEOUT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse NetworkManager wait timeout");
+
+    let interface_include = get_patterns(&matches, "interface-include", "INTERFACE_INCLUDE");
+
+    let interface_exclude = get_patterns(&matches, "interface-exclude", "INTERFACE_EXCLUDE");
+
+    let ssid_allow = get_patterns(&matches, "ssid-allow", "SSID_ALLOW");
+
+    let ssid_deny = get_patterns(&matches, "ssid-deny", "SSID_DENY");
+
+    let min_signal = u8::from_str(&matches.value_of("min-signal").map_or_else(
+        || env::var("MIN_SIGNAL").unwrap_or_else(|_| DEFAULT_MIN_SIGNAL.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse minimum signal strength");
+
+    let network_expiry = u64::from_str(&matches.value_of("network-expiry").map_or_else(
+        || env::var("NETWORK_EXPIRY").unwrap_or_else(|_| DEFAULT_NETWORK_EXPIRY.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse network expiry");
+
+    let captive_mode = CaptiveMode::from_str(&matches.value_of("captive-mode").map_or_else(
+        || env::var("CAPTIVE_MODE").unwrap_or_else(|_| DEFAULT_CAPTIVE_MODE.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse captive mode");
+
+    let allowed_hosts = get_patterns(&matches, "allowed-hosts", "ALLOWED_HOSTS");
+
+    let static_mounts = match matches.values_of("static-mount") {
+        Some(mounts) => mounts.map(String::from).collect(),
+        None => get_patterns(&matches, "static-mount", "STATIC_MOUNTS"),
+    }
+    .iter()
+    .map(|mount| parse_static_mount(mount).expect("Cannot parse static mount"))
+    .collect::<Vec<_>>();
+
+    // Whitespace separated, as commas are part of quantifiers like `{8,}`
+    let immutable_assets = match matches.values_of("immutable-assets") {
+        Some(patterns) => patterns.map(String::from).collect(),
+        None => env::var("IMMUTABLE_ASSETS")
+            .map(|patterns| patterns.split_whitespace().map(String::from).collect())
+            .unwrap_or_default(),
+    };
+
+    let mime_types = match matches.values_of("mime-type") {
+        Some(mime_types) => mime_types.map(String::from).collect(),
+        None => get_patterns(&matches, "mime-type", "MIME_TYPES"),
+    }
+    .iter()
+    .map(|mime_type| parse_mime_type(mime_type).expect("Cannot parse MIME type"))
+    .collect::<Vec<_>>();
+
+    let ui_tree = get_flag(&matches, "ui-tree", "UI_TREE");
+
+    let trusted_proxies = get_patterns(&matches, "trust-proxy", "TRUST_PROXY")
+        .iter()
+        .map(|proxy| IpAddr::from_str(proxy).expect("Cannot parse proxy address"))
+        .collect::<Vec<_>>();
+
+    let http_threads = usize::from_str(&matches.value_of("http-threads").map_or_else(
+        || env::var("HTTP_THREADS").unwrap_or_else(|_| DEFAULT_HTTP_THREADS.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse HTTP thread count");
+
+    let http_backlog = u32::from_str(&matches.value_of("http-backlog").map_or_else(
+        || env::var("HTTP_BACKLOG").unwrap_or_else(|_| DEFAULT_HTTP_BACKLOG.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse HTTP backlog");
+
+    let http_read_timeout = u64::from_str(&matches.value_of("http-read-timeout").map_or_else(
+        || env::var("HTTP_READ_TIMEOUT").unwrap_or_else(|_| DEFAULT_HTTP_READ_TIMEOUT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse HTTP read timeout");
+
+    let http_keepalive = u64::from_str(&matches.value_of("http-keepalive").map_or_else(
+        || env::var("HTTP_KEEPALIVE").unwrap_or_else(|_| DEFAULT_HTTP_KEEPALIVE.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse HTTP keep-alive");
+
+    let max_body_size = u64::from_str(&matches.value_of("max-body-size").map_or_else(
+        || env::var("MAX_BODY_SIZE").unwrap_or_else(|_| DEFAULT_MAX_BODY_SIZE.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse maximum body size");
+
+    let max_upload_size = u64::from_str(&matches.value_of("max-upload-size").map_or_else(
+        || env::var("MAX_UPLOAD_SIZE").unwrap_or_else(|_| DEFAULT_MAX_UPLOAD_SIZE.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse maximum upload size");
+
+    let max_requests = usize::from_str(&matches.value_of("max-requests").map_or_else(
+        || env::var("MAX_REQUESTS").unwrap_or_else(|_| DEFAULT_MAX_REQUESTS.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse maximum requests");
+
+    let max_asset_requests = usize::from_str(&matches.value_of("max-asset-requests").map_or_else(
+        || {
+            env::var("MAX_ASSET_REQUESTS")
+                .unwrap_or_else(|_| DEFAULT_MAX_ASSET_REQUESTS.to_string())
+        },
+        String::from,
+    ))
+    .expect("Cannot parse maximum asset requests");
+
+    let no_csrf = get_flag(&matches, "no-csrf", "NO_CSRF");
+
+    let metrics = get_flag(&matches, "metrics", "METRICS");
+
+    let access_log = matches
+        .value_of("access-log")
+        .map(PathBuf::from)
+        .or_else(|| env::var("ACCESS_LOG").ok().map(PathBuf::from));
+
+    let run_as = matches
+        .value_of("run-as")
+        .map(String::from)
+        .or_else(|| env::var("RUN_AS").ok());
+
+    let allow_root = get_flag(&matches, "allow-root", "ALLOW_ROOT");
+
+    let on_worker_failure =
+        WorkerFailure::from_str(&matches.value_of("on-worker-failure").map_or_else(
+            || {
+                env::var("ON_WORKER_FAILURE")
+                    .unwrap_or_else(|_| DEFAULT_ON_WORKER_FAILURE.to_string())
+            },
+            String::from,
+        ))
+        .expect("Cannot parse worker failure action");
+
+    let duplicate_policy =
+        DuplicatePolicy::from_str(&matches.value_of("duplicate-policy").map_or_else(
+            || {
+                env::var("DUPLICATE_POLICY")
+                    .unwrap_or_else(|_| DEFAULT_DUPLICATE_POLICY.to_string())
+            },
+            String::from,
+        ))
+        .expect("Cannot parse duplicate policy");
+
+    let single_client = get_flag(&matches, "single-client", "SINGLE_CLIENT");
+
+    let run_forever = get_flag(&matches, "run-forever", "RUN_FOREVER");
+
+    let backend = BackendKind::from_str(&matches.value_of("backend").map_or_else(
+        || env::var("NETWORK_BACKEND").unwrap_or_else(|_| DEFAULT_BACKEND.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse network backend");
+
+    let connect_timeout = u64::from_str(&matches.value_of("connect-timeout").map_or_else(
+        || env::var("CONNECT_TIMEOUT").unwrap_or_else(|_| DEFAULT_CONNECT_TIMEOUT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse connect timeout");
+
+    let max_connect_timeout = u64::from_str(&matches.value_of("max-connect-timeout").map_or_else(
+        || {
+            env::var("MAX_CONNECT_TIMEOUT")
+                .unwrap_or_else(|_| DEFAULT_MAX_CONNECT_TIMEOUT.to_string())
+        },
+        String::from,
+    ))
+    .expect("Cannot parse maximum connect timeout");
+
+    let default_autoconnect = bool::from_str(&matches.value_of("default-autoconnect").map_or_else(
+        || env::var("DEFAULT_AUTOCONNECT").unwrap_or_else(|_| DEFAULT_AUTOCONNECT.to_string()),
+        String::from,
+    ))
+    .expect("Cannot parse default autoconnect");
+
+    let mut builder = PortalBuilder::new()
+        .ssid(ssid)
+        .gateway(gateway)
+        .gateway_prefix(gateway_prefix)
+        .listening_port(listening_port)
+        .activity_timeout(activity_timeout)
+        .ui_directory(ui_directory)
+        .dbus(dbus)
+        .backend(backend)
+        .connect_timeout(connect_timeout)
+        .max_connect_timeout(max_connect_timeout)
+        .default_autoconnect(default_autoconnect)
+        .single_client(single_client)
+        .mdns_name(mdns_name)
+        .networks_cache_ttl(networks_cache_ttl)
+        .scan_timeout(scan_timeout)
+        .max_failures_before_exit(max_failures_before_exit)
+        .log_format(log_format)
+        .run_forever(run_forever)
+        .ap_retry_limit(ap_retry_limit)
+        .nm_wait_timeout(nm_wait_timeout)
+        .min_signal(min_signal)
+        .network_expiry(network_expiry)
+        .captive_mode(captive_mode)
+        .http_threads(http_threads)
+        .http_backlog(http_backlog)
+        .http_read_timeout(http_read_timeout)
+        .http_keepalive(http_keepalive)
+        .max_body_size(max_body_size)
+        .max_upload_size(max_upload_size)
+        .max_requests(max_requests)
+        .max_asset_requests(max_asset_requests)
+        .csrf(!no_csrf)
+        .metrics(metrics)
+        .ui_tree(ui_tree)
+        .allow_root(allow_root)
+        .on_worker_failure(on_worker_failure)
+        .duplicate_policy(duplicate_policy);
+
+    for host in allowed_hosts {
+        builder = builder.allowed_host(host);
+    }
+
+    if let Some(fallback_port) = fallback_port {
+        builder = builder.fallback_port(fallback_port);
+    }
+
+    if let Some(run_as) = run_as {
+        builder = builder.run_as(run_as);
+    }
+
+    if let Some(dhcp_range) = dhcp_range {
+        builder = builder.dhcp_range(dhcp_range);
+    }
+
+    if let Some(gateway6) = gateway6 {
+        builder = builder.gateway6(gateway6);
+    }
+
+    for (prefix, directory) in static_mounts {
+        builder = builder.static_mount(prefix, directory);
+    }
+
+    for pattern in immutable_assets {
+        builder = builder.immutable_asset(pattern);
+    }
+
+    for (extension, mime_type) in mime_types {
+        builder = builder.mime_type(extension, mime_type);
+    }
+
+    for proxy in trusted_proxies {
+        builder = builder.trust_proxy(proxy);
+    }
+
+    for pattern in interface_include {
+        builder = builder.interface_include(pattern);
+    }
+
+    for pattern in interface_exclude {
+        builder = builder.interface_exclude(pattern);
+    }
+
+    for pattern in ssid_allow {
+        builder = builder.ssid_allow(pattern);
+    }
+
+    for pattern in ssid_deny {
+        builder = builder.ssid_deny(pattern);
+    }
+
+    if let Some(interface) = interface {
+        builder = builder.interface(interface);
+    }
+
+    if let Some(passphrase) = passphrase {
+        builder = builder.passphrase(passphrase);
+    }
+
+    if let Some(webhook_url) = webhook_url {
+        builder = builder.webhook(webhook_url, webhook_token);
+    }
+
+    if let Some(state_file) = state_file {
+        builder = builder.state_file(state_file);
+    }
+
+    if let Some(access_log) = access_log {
+        builder = builder.access_log(access_log);
+    }
+
+    if let Some(on_portal_start) = on_portal_start {
+        builder = builder.on_portal_start(on_portal_start);
+    }
+
+    if let Some(on_connect) = on_connect {
+        builder = builder.on_connect(on_connect);
+    }
+
+    if let Some(on_connect_fail) = on_connect_fail {
+        builder = builder.on_connect_fail(on_connect_fail);
+    }
+
+    if json_events {
+        json_events::enable();
+        builder = builder.on_event(json_events::write_event);
+    }
+
+    if let Some(api_token) = api_token {
+        builder = builder.api_token(api_token);
+    }
+
+    if log_buffer_lines != 0 {
+        builder = builder.log_buffer(LogBuffer::new(log_buffer_lines));
+    }
+
+    Options {
+        config: builder.build(),
+        print_config,
+    }
+}
+
+/// A flag is set either on the command line or with its environment variable
+/// counterpart equal to `1` or `true`
+fn get_flag(matches: &ArgMatches, name: &str, env_name: &str) -> bool {
+    matches.is_present(name)
+        || env::var(env_name)
+            .map(|value| value == "1" || value.eq_ignore_ascii_case("true"))
+            .unwrap_or(false)
+}
+
+/// Comma separated patterns given either on the command line or with the
+/// environment variable counterpart
+fn get_patterns(matches: &ArgMatches, name: &str, env_name: &str) -> Vec<String> {
+    matches
+        .value_of(name)
+        .map(String::from)
+        .or_else(|| env::var(env_name).ok())
+        .map(|patterns| {
+            patterns
+                .split(',')
+                .map(str::trim)
+                .filter(|pattern| !pattern.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn get_ui_directory(cmd_ui_directory: Option<&str>) -> PathBuf {
+    if let Some(ui_directory) = cmd_ui_directory {
+        return PathBuf::from(ui_directory);
+    }
+
+    if let Ok(ui_directory) = env::var("UI_DIRECTORY") {
+        return PathBuf::from(ui_directory);
+    }
+
+    if let Some(install_ui_directory) = get_install_ui_directory() {
+        return install_ui_directory;
+    }
+
+    PathBuf::from(DEFAULT_UI_DIRECTORY)
+}
+
+/// Checks whether `WiFi Connect` is running from install path and whether the
+/// UI directory is present in a corresponding location
+/// e.g. /usr/local/sbin/wifi-connect -> /usr/local/share/wifi-connect/ui
+fn get_install_ui_directory() -> Option<PathBuf> {
+    if let Ok(exe_path) = env::current_exe() {
+        if let Ok(mut path) = exe_path.canonicalize() {
+            path.pop();
+
+            match path.file_name() {
+                Some(file_name) => {
+                    if file_name != OsStr::new("sbin") {
+                        // not executing from `sbin` folder
+                        return None;
+                    }
+                }
+                None => return None,
+            }
+
+            path.pop();
+            path.push("share");
+            path.push(env!("CARGO_PKG_NAME"));
+            path.push("ui");
+
+            if path.is_dir() {
+                return Some(path);
+            }
+        }
+    }
+
+    None
+}
@@ -1,224 +1,1285 @@
-use clap::{App, Arg};
-
-use std::env;
-use std::ffi::OsStr;
-use std::net::Ipv4Addr;
-use std::path::PathBuf;
+use std::cmp;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Component, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use backend::{is_valid_wpa_passphrase, MAX_SSID_LENGTH};
+use glob;
+use logs::LogBuffer;
+use network::{EventCallback, NetworkEvent};
+
+pub const DEFAULT_GATEWAY: &str = "192.168.42.1";
+pub const DEFAULT_GATEWAY_PREFIX: &str = "24";
+/// Prefix length of the IPv6 portal network, the one SLAAC works with
+pub const GATEWAY6_PREFIX: u8 = 64;
+pub const DEFAULT_SSID: &str = "WiFi Connect";
+pub const DEFAULT_ACTIVITY_TIMEOUT: &str = "0";
+pub const DEFAULT_UI_DIRECTORY: &str = "ui";
+pub const DEFAULT_LISTENING_PORT: &str = "80";
+pub const DEFAULT_BACKEND: &str = "auto";
+pub const DEFAULT_CONNECT_TIMEOUT: &str = "30";
+pub const DEFAULT_MAX_CONNECT_TIMEOUT: &str = "300";
+pub const DEFAULT_AUTOCONNECT: &str = "true";
+pub const DEFAULT_LOG_BUFFER_LINES: &str = "500";
+pub const DEFAULT_MDNS_NAME: &str = "wifi-connect.local";
+pub const DEFAULT_NETWORKS_CACHE_TTL: &str = "10";
+pub const DEFAULT_SCAN_TIMEOUT: &str = "15";
+pub const DEFAULT_MAX_FAILURES_BEFORE_EXIT: &str = "0";
+pub const DEFAULT_AP_RETRY_LIMIT: &str = "5";
+pub const DEFAULT_NM_WAIT_TIMEOUT: &str = "60";
+pub const DEFAULT_MIN_SIGNAL: &str = "0";
+pub const DEFAULT_NETWORK_EXPIRY: &str = "300";
+pub const DEFAULT_CAPTIVE_MODE: &str = "redirect";
+pub const DEFAULT_HTTP_THREADS: &str = "0";
+pub const DEFAULT_HTTP_BACKLOG: &str = "128";
+pub const DEFAULT_HTTP_READ_TIMEOUT: &str = "30";
+pub const DEFAULT_HTTP_KEEPALIVE: &str = "5";
+pub const DEFAULT_MAX_BODY_SIZE: &str = "65536";
+pub const DEFAULT_MAX_UPLOAD_SIZE: &str = "4194304";
+pub const DEFAULT_MAX_REQUESTS: &str = "32";
+pub const DEFAULT_MAX_ASSET_REQUESTS: &str = "32";
+pub const DEFAULT_ON_WORKER_FAILURE: &str = "exit";
+pub const DEFAULT_DUPLICATE_POLICY: &str = "replace";
+pub const DEFAULT_LOG_FORMAT: &str = "text";
+
+/// Printed by `--print-config` in place of the secrets that are set
+const REDACTED: &str = "<redacted>";
+
+/// Network stack the portal is driven through
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BackendKind {
+    /// Picks the network stack running on the system
+    Auto,
+    NetworkManager,
+    #[cfg(feature = "iwd")]
+    Iwd,
+    #[cfg(feature = "wpa")]
+    Wpa,
+    #[cfg(feature = "mock-backend")]
+    Mock,
+}
+
+impl BackendKind {
+    pub fn requires_root(self) -> bool {
+        match self {
+            BackendKind::Auto | BackendKind::NetworkManager => true,
+            #[cfg(feature = "iwd")]
+            BackendKind::Iwd => true,
+            #[cfg(feature = "wpa")]
+            BackendKind::Wpa => true,
+            #[cfg(feature = "mock-backend")]
+            BackendKind::Mock => false,
+        }
+    }
+
+    /// Whether connection profiles are identified by a UUID
+    pub fn has_profile_uuids(self) -> bool {
+        match self {
+            BackendKind::Auto | BackendKind::NetworkManager => true,
+            #[cfg(feature = "iwd")]
+            BackendKind::Iwd => false,
+            #[cfg(feature = "wpa")]
+            BackendKind::Wpa => false,
+            #[cfg(feature = "mock-backend")]
+            BackendKind::Mock => true,
+        }
+    }
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(BackendKind::Auto),
+            "networkmanager" => Ok(BackendKind::NetworkManager),
+            #[cfg(feature = "iwd")]
+            "iwd" => Ok(BackendKind::Iwd),
+            #[cfg(feature = "wpa")]
+            "wpa" => Ok(BackendKind::Wpa),
+            #[cfg(feature = "mock-backend")]
+            "mock" => Ok(BackendKind::Mock),
+            _ => Err(format!("Unknown network backend: {}", s)),
+        }
+    }
+}
+
+/// How requests to other hosts are pointed at the portal
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CaptiveMode {
+    /// `302 Found` to the portal, which phone captive browsers expect
+    Redirect,
+    /// `511 Network Authentication Required` as of RFC 6585, with a page
+    /// linking to the portal
+    NetworkAuthenticationRequired,
+}
+
+impl FromStr for CaptiveMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "redirect" => Ok(CaptiveMode::Redirect),
+            "511" => Ok(CaptiveMode::NetworkAuthenticationRequired),
+            _ => Err(format!("Unknown captive mode: {}", s)),
+        }
+    }
+}
+
+/// What the watchdog does once the network thread panicked or got stuck
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkerFailure {
+    /// Executes the program again, which sets up the portal from scratch
+    Restart,
+    /// Ends the flow with an error
+    Exit,
+}
+
+impl FromStr for WorkerFailure {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "restart" => Ok(WorkerFailure::Restart),
+            "exit" => Ok(WorkerFailure::Exit),
+            _ => Err(format!("Unknown worker failure action: {}", s)),
+        }
+    }
+}
+
+/// What becomes of the saved profiles of a network when credentials for it
+/// are submitted. The profile of the portal is never touched.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DuplicatePolicy {
+    /// Deletes them all before the new profile is created
+    Replace,
+    /// Updates the most recently used one with the new credentials instead
+    /// of creating a profile
+    Keep,
+    /// Refuses the credentials while the network has a saved profile
+    Error,
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replace" => Ok(DuplicatePolicy::Replace),
+            "keep" => Ok(DuplicatePolicy::Keep),
+            "error" => Ok(DuplicatePolicy::Error),
+            _ => Err(format!("Unknown duplicate policy: {}", s)),
+        }
+    }
+}
+
+/// How the log records are written to stderr
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    /// `[file:line:module:level] message`
+    Text,
+    /// A JSON object per record, the state dump of `SIGUSR1` being one with
+    /// the fields of the state
+    Json,
+}
 
-const DEFAULT_GATEWAY: &str = "192.168.42.1";
-const DEFAULT_DHCP_RANGE: &str = "192.168.42.2,192.168.42.254";
-const DEFAULT_SSID: &str = "WiFi Connect";
-const DEFAULT_ACTIVITY_TIMEOUT: &str = "0";
-const DEFAULT_UI_DIRECTORY: &str = "ui";
-const DEFAULT_LISTENING_PORT: &str = "80";
+impl FromStr for LogFormat {
+    type Err = String;
 
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format: {}", s)),
+        }
+    }
+}
+
+/// Settings of the provisioning flow, usually created with a `PortalBuilder`
 #[derive(Clone)]
 pub struct Config {
     pub interface: Option<String>,
     pub ssid: String,
     pub passphrase: Option<String>,
     pub gateway: Ipv4Addr,
-    pub dhcp_range: String,
+    /// Prefix length of the portal subnet
+    pub gateway_prefix: u8,
+    /// Derived from the portal subnet when not set
+    pub dhcp_range: Option<String>,
+    /// IPv6 address of the portal, a ULA whose `/64` is advertised to
+    /// clients alongside the IPv4 subnet
+    pub gateway6: Option<Ipv6Addr>,
     pub listening_port: u16,
     pub activity_timeout: u64,
     pub ui_directory: PathBuf,
+    pub dbus: bool,
+    pub webhook_url: Option<String>,
+    pub webhook_token: Option<String>,
+    /// Rewritten with the phase of the flow on every change
+    pub state_file: Option<PathBuf>,
+    /// Commands run with `sh -c` on the lifecycle events
+    pub on_portal_start: Option<String>,
+    pub on_connect: Option<String>,
+    pub on_connect_fail: Option<String>,
+    pub backend: BackendKind,
+    pub connect_timeout: u64,
+    pub max_connect_timeout: u64,
+    pub default_autoconnect: bool,
+    pub api_token: Option<String>,
+    pub single_client: bool,
+    pub log_buffer: Option<LogBuffer>,
+    pub log_format: LogFormat,
+    pub mdns_name: String,
+    pub networks_cache_ttl: u64,
+    pub scan_timeout: u64,
+    pub max_failures_before_exit: u32,
+    /// Whether the device keeps being watched once it joined a network,
+    /// raising the portal again when it goes offline
+    pub run_forever: bool,
+    /// Attempts at raising the portal access point
+    pub ap_retry_limit: u32,
+    pub nm_wait_timeout: u64,
+    pub interface_include: Vec<String>,
+    pub interface_exclude: Vec<String>,
+    pub ssid_filter: SsidFilter,
+    pub min_signal: u8,
+    pub network_expiry: u64,
+    pub captive_mode: CaptiveMode,
+    pub allowed_hosts: Vec<String>,
+    pub trusted_proxies: Vec<IpAddr>,
+    pub http_threads: usize,
+    pub http_backlog: u32,
+    pub http_read_timeout: u64,
+    pub http_keepalive: u64,
+    pub max_body_size: u64,
+    pub max_upload_size: u64,
+    pub max_requests: usize,
+    pub max_asset_requests: usize,
+    pub fallback_port: Option<u16>,
+    pub csrf: bool,
+    /// Whether `GET /metrics` serves the request counters
+    pub metrics: bool,
+    /// File a line is appended to for every request
+    pub access_log: Option<PathBuf>,
+    /// URL prefixes served from subdirectories of the UI directory, besides
+    /// `/static`, `/css`, `/img` and `/js`
+    pub static_mounts: Vec<(String, PathBuf)>,
+    /// Whether every file of the UI directory is served by its path
+    pub ui_tree: bool,
+    /// Regular expressions of the fingerprinted file names clients may
+    /// cache for good, the built-in detection of content hashes if empty
+    pub immutable_assets: Vec<String>,
+    /// Content types of file extensions, lowercase and without the dot, on
+    /// top of the built-in ones
+    pub mime_types: Vec<(String, String)>,
+    /// `user` or `user:group` the portal is served as once it is up
+    pub run_as: Option<String>,
+    pub allow_root: bool,
+    pub on_worker_failure: WorkerFailure,
+    pub duplicate_policy: DuplicatePolicy,
+    pub event_callbacks: Vec<EventCallback>,
 }
 
-pub fn get_config() -> Config {
-    let matches = App::new(crate_name!())
-        .version(crate_version!())
-        .author(crate_authors!())
-        .about(crate_description!())
-        .arg(
-            Arg::with_name("portal-interface")
-                .short("i")
-                .long("portal-interface")
-                .value_name("interface")
-                .help("Wireless network interface to be used by WiFi Connect")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("portal-ssid")
-                .short("s")
-                .long("portal-ssid")
-                .value_name("ssid")
-                .help(&format!(
-                    "SSID of the captive portal WiFi network (default: {})",
-                    DEFAULT_SSID
-                ))
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("portal-passphrase")
-                .short("p")
-                .long("portal-passphrase")
-                .value_name("passphrase")
-                .help("WPA2 Passphrase of the captive portal WiFi network (default: none)")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("portal-gateway")
-                .short("g")
-                .long("portal-gateway")
-                .value_name("gateway")
-                .help(&format!(
-                    "Gateway of the captive portal WiFi network (default: {})",
-                    DEFAULT_GATEWAY
-                ))
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("portal-dhcp-range")
-                .short("d")
-                .long("portal-dhcp-range")
-                .value_name("dhcp_range")
-                .help(&format!(
-                    "DHCP range of the WiFi network (default: {})",
-                    DEFAULT_DHCP_RANGE
-                ))
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("portal-listening-port")
-                .short("o")
-                .long("portal-listening-port")
-                .value_name("listening_port")
-                .help(&format!(
-                    "Listening port of the captive portal web server (default: {})",
-                    DEFAULT_LISTENING_PORT
-                ))
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("activity-timeout")
-                .short("a")
-                .long("activity-timeout")
-                .value_name("activity_timeout")
-                .help("Exit if no activity for the specified time (seconds) (default: none)")
-                .takes_value(true),
+/// Lists the settings for `--print-config`, without the secrets
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("interface", &self.interface)
+            .field("ssid", &self.ssid)
+            .field("passphrase", &self.passphrase.as_ref().map(|_| REDACTED))
+            .field("gateway", &self.gateway)
+            .field("gateway_prefix", &self.gateway_prefix)
+            .field("dhcp_range", &self.dhcp_range)
+            .field("gateway6", &self.gateway6)
+            .field("listening_port", &self.listening_port)
+            .field("activity_timeout", &self.activity_timeout)
+            .field("ui_directory", &self.ui_directory)
+            .field("dbus", &self.dbus)
+            .field("webhook_url", &self.webhook_url)
+            .field(
+                "webhook_token",
+                &self.webhook_token.as_ref().map(|_| REDACTED),
+            )
+            .field("state_file", &self.state_file)
+            .field("on_portal_start", &self.on_portal_start)
+            .field("on_connect", &self.on_connect)
+            .field("on_connect_fail", &self.on_connect_fail)
+            .field("backend", &self.backend)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("max_connect_timeout", &self.max_connect_timeout)
+            .field("default_autoconnect", &self.default_autoconnect)
+            .field("api_token", &self.api_token.as_ref().map(|_| REDACTED))
+            .field("single_client", &self.single_client)
+            .field("log_buffer", &self.log_buffer.is_some())
+            .field("log_format", &self.log_format)
+            .field("mdns_name", &self.mdns_name)
+            .field("networks_cache_ttl", &self.networks_cache_ttl)
+            .field("scan_timeout", &self.scan_timeout)
+            .field("max_failures_before_exit", &self.max_failures_before_exit)
+            .field("run_forever", &self.run_forever)
+            .field("ap_retry_limit", &self.ap_retry_limit)
+            .field("nm_wait_timeout", &self.nm_wait_timeout)
+            .field("interface_include", &self.interface_include)
+            .field("interface_exclude", &self.interface_exclude)
+            .field("ssid_filter", &self.ssid_filter)
+            .field("min_signal", &self.min_signal)
+            .field("network_expiry", &self.network_expiry)
+            .field("captive_mode", &self.captive_mode)
+            .field("allowed_hosts", &self.allowed_hosts)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("http_threads", &self.http_threads)
+            .field("http_backlog", &self.http_backlog)
+            .field("http_read_timeout", &self.http_read_timeout)
+            .field("http_keepalive", &self.http_keepalive)
+            .field("max_body_size", &self.max_body_size)
+            .field("max_upload_size", &self.max_upload_size)
+            .field("max_requests", &self.max_requests)
+            .field("max_asset_requests", &self.max_asset_requests)
+            .field("fallback_port", &self.fallback_port)
+            .field("csrf", &self.csrf)
+            .field("metrics", &self.metrics)
+            .field("access_log", &self.access_log)
+            .field("static_mounts", &self.static_mounts)
+            .field("ui_tree", &self.ui_tree)
+            .field("immutable_assets", &self.immutable_assets)
+            .field("mime_types", &self.mime_types)
+            .field("run_as", &self.run_as)
+            .field("allow_root", &self.allow_root)
+            .field("on_worker_failure", &self.on_worker_failure)
+            .field("duplicate_policy", &self.duplicate_policy)
+            .field("event_callbacks", &self.event_callbacks.len())
+            .finish()
+    }
+}
+
+impl Config {
+    /// Seconds a connect attempt may take, see `effective_connect_timeout`
+    pub fn effective_connect_timeout(&self, requested: Option<u64>) -> u64 {
+        effective_connect_timeout(requested, self.connect_timeout, self.max_connect_timeout)
+    }
+
+    pub fn gateway_netmask(&self) -> Ipv4Addr {
+        prefix_netmask(self.gateway_prefix)
+    }
+
+    /// The configured DHCP range, or the addresses of the portal subnet on
+    /// the far side of the gateway along with its netmask
+    pub fn effective_dhcp_range(&self) -> String {
+        if let Some(ref dhcp_range) = self.dhcp_range {
+            return dhcp_range.clone();
+        }
+
+        let mask = u32::from(self.gateway_netmask());
+        let gateway = u32::from(self.gateway);
+        let network = gateway & mask;
+        let broadcast = network | !mask;
+
+        let (first, last) = if gateway < broadcast - 1 {
+            (gateway + 1, broadcast - 1)
+        } else {
+            (network + 1, gateway - 1)
+        };
+
+        format!(
+            "{},{},{}",
+            Ipv4Addr::from(first),
+            Ipv4Addr::from(last),
+            self.gateway_netmask()
         )
-        .arg(
-            Arg::with_name("ui-directory")
-                .short("u")
-                .long("ui-directory")
-                .value_name("ui_directory")
-                .help(&format!(
-                    "Web UI directory location (default: {})",
-                    DEFAULT_UI_DIRECTORY
+    }
+
+    /// The `/64` advertised to clients when the portal has an IPv6 address
+    pub fn gateway6_network(&self) -> Option<Ipv6Addr> {
+        self.gateway6.map(|gateway6| {
+            Ipv6Addr::from(u128::from(gateway6) & (!0u128 << (128 - u32::from(GATEWAY6_PREFIX))))
+        })
+    }
+
+    /// Checks that the static mounts have prefixes of their own and that
+    /// their directories exist within the UI directory
+    pub fn validate_static_mounts(&self) -> ::std::result::Result<(), String> {
+        for &(ref prefix, ref directory) in &self.static_mounts {
+            if !prefix.starts_with('/') || prefix.trim_matches('/').is_empty() {
+                return Err(format!(
+                    "The static mount prefix {} has to start with / and name a path",
+                    prefix
+                ));
+            }
+
+            let escapes = directory.is_absolute()
+                || directory
+                    .components()
+                    .any(|component| component == Component::ParentDir);
+
+            if escapes {
+                return Err(format!(
+                    "The static mount directory {} has to lie within the UI directory",
+                    directory.display()
+                ));
+            }
+
+            let path = self.ui_directory.join(directory);
+
+            if !path.is_dir() {
+                return Err(format!(
+                    "The static mount directory {} of {} does not exist",
+                    path.display(),
+                    prefix
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the settings that are invalid on their own or together, which
+    /// would otherwise only fail halfway through raising the portal
+    pub fn validate_settings(&self) -> ::std::result::Result<(), String> {
+        if self.ssid.is_empty() || self.ssid.len() > MAX_SSID_LENGTH {
+            return Err(format!(
+                "The portal SSID '{}' has to be 1 to {} bytes long",
+                self.ssid, MAX_SSID_LENGTH
+            ));
+        }
+
+        if let Some(ref passphrase) = self.passphrase {
+            if !is_valid_wpa_passphrase(passphrase) {
+                return Err(
+                    "The portal passphrase has to be 8 to 63 printable ASCII characters, or 64 hex digits"
+                        .to_string(),
+                );
+            }
+        }
+
+        if self.listening_port == 0 {
+            return Err("The portal listening port cannot be 0".to_string());
+        }
+
+        match self.fallback_port {
+            Some(0) => return Err("The fallback port cannot be 0".to_string()),
+            Some(port) if port == self.listening_port => {
+                return Err(format!(
+                    "The fallback port {} is the portal listening port itself",
+                    port
                 ))
-                .takes_value(true),
-        )
-        .get_matches();
-
-    let interface: Option<String> = matches.value_of("portal-interface").map_or_else(
-        || env::var("PORTAL_INTERFACE").ok(),
-        |v| Some(v.to_string()),
-    );
-
-    let ssid_prefix: String = matches.value_of("portal-ssid").map_or_else(
-        || env::var("PORTAL_SSID").unwrap_or_else(|_| DEFAULT_SSID.to_string()),
-        String::from,
-    );
-
-    let val = env::var("BALENA_DEVICE_UUID");
-    let ssid_suffix = val
-        .as_ref()
-        .map_or(String::new(), |v| format!("-{}", &v[0..7]));
-    let ssid = format!("{}{}", ssid_prefix, ssid_suffix);
-
-    let passphrase: Option<String> = matches.value_of("portal-passphrase").map_or_else(
-        || env::var("PORTAL_PASSPHRASE").ok(),
-        |v| Some(v.to_string()),
-    );
-
-    let gateway = Ipv4Addr::from_str(&matches.value_of("portal-gateway").map_or_else(
-        || env::var("PORTAL_GATEWAY").unwrap_or_else(|_| DEFAULT_GATEWAY.to_string()),
-        String::from,
-    ))
-    .expect("Cannot parse gateway address");
-
-    let dhcp_range = matches.value_of("portal-dhcp-range").map_or_else(
-        || env::var("PORTAL_DHCP_RANGE").unwrap_or_else(|_| DEFAULT_DHCP_RANGE.to_string()),
-        String::from,
-    );
-
-    let listening_port = matches
-        .value_of("portal-listening-port")
-        .map_or_else(
-            || {
-                env::var("PORTAL_LISTENING_PORT")
-                    .unwrap_or_else(|_| DEFAULT_LISTENING_PORT.to_string())
-            },
-            String::from,
-        )
-        .parse::<u16>()
-        .expect("Cannot parse listening port number");
+            }
+            _ => {}
+        }
+
+        for pattern in &self.immutable_assets {
+            if let Err(e) = Regex::new(pattern) {
+                return Err(format!(
+                    "The immutable asset pattern {} is not a regular expression: {}",
+                    pattern, e
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the portal subnet has room for clients, that the DHCP
+    /// range lies within it and that the IPv6 address is a unique local one
+    pub fn validate_portal_network(&self) -> ::std::result::Result<(), String> {
+        if let Some(gateway6) = self.gateway6 {
+            if gateway6.segments()[0] & 0xfe00 != 0xfc00 {
+                return Err(format!(
+                    "The IPv6 gateway {} is not a unique local address (fc00::/7)",
+                    gateway6
+                ));
+            }
+        }
 
-    let activity_timeout = u64::from_str(&matches.value_of("activity-timeout").map_or_else(
-        || env::var("ACTIVITY_TIMEOUT").unwrap_or_else(|_| DEFAULT_ACTIVITY_TIMEOUT.to_string()),
-        String::from,
-    ))
-    .expect("Cannot parse activity timeout");
+        if self.gateway_prefix < 1 || self.gateway_prefix > MAX_GATEWAY_PREFIX {
+            return Err(format!(
+                "The gateway prefix /{} leaves no room for clients",
+                self.gateway_prefix
+            ));
+        }
+
+        let dhcp_range = self.effective_dhcp_range();
+        let mask = u32::from(self.gateway_netmask());
+        let network = u32::from(self.gateway) & mask;
 
-    let ui_directory = get_ui_directory(matches.value_of("ui-directory"));
+        // Ranges using other dnsmasq forms, such as tags, are left to it
+        let addresses = dhcp_range
+            .split(',')
+            .take(2)
+            .map(|address| address.trim().parse::<Ipv4Addr>())
+            .collect::<::std::result::Result<Vec<_>, _>>();
 
-    Config {
-        interface,
-        ssid,
-        passphrase,
-        gateway,
-        dhcp_range,
-        listening_port,
-        activity_timeout,
-        ui_directory,
+        if let Ok(addresses) = addresses {
+            for address in addresses {
+                if u32::from(address) & mask != network {
+                    return Err(format!(
+                        "The DHCP range {} is outside of the portal subnet {}/{}",
+                        dhcp_range,
+                        Ipv4Addr::from(network),
+                        self.gateway_prefix
+                    ));
+                }
+            }
+        }
+
+        Ok(())
     }
 }
 
-fn get_ui_directory(cmd_ui_directory: Option<&str>) -> PathBuf {
-    if let Some(ui_directory) = cmd_ui_directory {
-        return PathBuf::from(ui_directory);
+/// Longest prefix leaving a client address besides the gateway
+pub const MAX_GATEWAY_PREFIX: u8 = 30;
+
+pub fn prefix_netmask(prefix: u8) -> Ipv4Addr {
+    if prefix == 0 {
+        Ipv4Addr::new(0, 0, 0, 0)
+    } else {
+        Ipv4Addr::from(u32::max_value() << (32 - u32::from(cmp::min(prefix, 32))))
     }
+}
+
+/// Parses a gateway given as an address with an optional prefix length,
+/// like `192.168.42.1/24`, which is `/24` when left out
+pub fn parse_gateway(gateway: &str) -> ::std::result::Result<(Ipv4Addr, u8), String> {
+    let mut parts = gateway.splitn(2, '/');
 
-    if let Ok(ui_directory) = env::var("UI_DIRECTORY") {
-        return PathBuf::from(ui_directory);
+    let address = parts
+        .next()
+        .unwrap_or("")
+        .parse::<Ipv4Addr>()
+        .map_err(|e| format!("Invalid gateway address {}: {}", gateway, e))?;
+
+    let prefix = match parts.next() {
+        Some(prefix) => match prefix.parse::<u8>() {
+            Ok(prefix) if prefix >= 1 && prefix <= MAX_GATEWAY_PREFIX => prefix,
+            _ => return Err(format!("Invalid gateway prefix length /{}", prefix)),
+        },
+        None => DEFAULT_GATEWAY_PREFIX.parse().unwrap(),
+    };
+
+    Ok((address, prefix))
+}
+
+/// Parses a static mount given as `/prefix=directory`
+pub fn parse_static_mount(mount: &str) -> ::std::result::Result<(String, PathBuf), String> {
+    let mut parts = mount.splitn(2, '=');
+
+    match (parts.next(), parts.next()) {
+        (Some(prefix), Some(directory)) if !prefix.is_empty() && !directory.is_empty() => {
+            Ok((prefix.to_string(), PathBuf::from(directory)))
+        }
+        _ => Err(format!(
+            "Invalid static mount {}, expected /prefix=directory",
+            mount
+        )),
     }
+}
+
+pub fn parse_mime_type(mime_type: &str) -> ::std::result::Result<(String, String), String> {
+    let mut parts = mime_type.splitn(2, '=').map(str::trim);
 
-    if let Some(install_ui_directory) = get_install_ui_directory() {
-        return install_ui_directory;
+    match (parts.next(), parts.next()) {
+        (Some(extension), Some(content_type))
+            if !extension.trim_start_matches('.').is_empty() && content_type.contains('/') =>
+        {
+            Ok((extension.to_string(), content_type.to_string()))
+        }
+        _ => Err(format!(
+            "Invalid MIME type {}, expected extension=type/subtype",
+            mime_type
+        )),
     }
+}
 
-    PathBuf::from(DEFAULT_UI_DIRECTORY)
+/// Glob patterns of the SSIDs the portal offers and accepts, matched case
+/// insensitively, with denied SSIDs winning over allowed ones
+#[derive(Clone, Debug, Default)]
+pub struct SsidFilter {
+    /// Every SSID is allowed without one
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
 }
 
-/// Checks whether `WiFi Connect` is running from install path and whether the
-/// UI directory is present in a corresponding location
-/// e.g. /usr/local/sbin/wifi-connect -> /usr/local/share/wifi-connect/ui
-fn get_install_ui_directory() -> Option<PathBuf> {
-    if let Ok(exe_path) = env::current_exe() {
-        if let Ok(mut path) = exe_path.canonicalize() {
-            path.pop();
+impl SsidFilter {
+    pub fn permits(&self, ssid: &str) -> bool {
+        let matching = |patterns: &[String]| {
+            patterns
+                .iter()
+                .any(|pattern| glob::matches(pattern, ssid, true))
+        };
 
-            match path.file_name() {
-                Some(file_name) => {
-                    if file_name != OsStr::new("sbin") {
-                        // not executing from `sbin` folder
-                        return None;
-                    }
-                }
-                None => return None,
-            }
+        !matching(&self.deny) && (self.allow.is_empty() || matching(&self.allow))
+    }
+}
 
-            path.pop();
-            path.push("share");
-            path.push(env!("CARGO_PKG_NAME"));
-            path.push("ui");
+/// The requested connect timeout, or the default one, clamped to the maximum
+pub fn effective_connect_timeout(requested: Option<u64>, default: u64, max: u64) -> u64 {
+    cmp::max(cmp::min(requested.unwrap_or(default), max), 1)
+}
 
-            if path.is_dir() {
-                return Some(path);
-            }
+/// Builds a `Config` starting from the same defaults as the command line
+pub struct PortalBuilder {
+    config: Config,
+}
+
+impl Default for PortalBuilder {
+    fn default() -> Self {
+        PortalBuilder {
+            config: Config {
+                interface: None,
+                ssid: DEFAULT_SSID.to_string(),
+                passphrase: None,
+                gateway: Ipv4Addr::from_str(DEFAULT_GATEWAY).unwrap(),
+                gateway_prefix: DEFAULT_GATEWAY_PREFIX.parse().unwrap(),
+                dhcp_range: None,
+                gateway6: None,
+                listening_port: DEFAULT_LISTENING_PORT.parse().unwrap(),
+                activity_timeout: DEFAULT_ACTIVITY_TIMEOUT.parse().unwrap(),
+                ui_directory: PathBuf::from(DEFAULT_UI_DIRECTORY),
+                dbus: false,
+                webhook_url: None,
+                webhook_token: None,
+                state_file: None,
+                on_portal_start: None,
+                on_connect: None,
+                on_connect_fail: None,
+                backend: BackendKind::from_str(DEFAULT_BACKEND).unwrap(),
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT.parse().unwrap(),
+                max_connect_timeout: DEFAULT_MAX_CONNECT_TIMEOUT.parse().unwrap(),
+                default_autoconnect: DEFAULT_AUTOCONNECT.parse().unwrap(),
+                api_token: None,
+                single_client: false,
+                log_buffer: None,
+                log_format: LogFormat::from_str(DEFAULT_LOG_FORMAT).unwrap(),
+                mdns_name: DEFAULT_MDNS_NAME.to_string(),
+                networks_cache_ttl: DEFAULT_NETWORKS_CACHE_TTL.parse().unwrap(),
+                scan_timeout: DEFAULT_SCAN_TIMEOUT.parse().unwrap(),
+                max_failures_before_exit: DEFAULT_MAX_FAILURES_BEFORE_EXIT.parse().unwrap(),
+                run_forever: false,
+                ap_retry_limit: DEFAULT_AP_RETRY_LIMIT.parse().unwrap(),
+                nm_wait_timeout: DEFAULT_NM_WAIT_TIMEOUT.parse().unwrap(),
+                interface_include: Vec::new(),
+                interface_exclude: Vec::new(),
+                ssid_filter: SsidFilter::default(),
+                min_signal: DEFAULT_MIN_SIGNAL.parse().unwrap(),
+                network_expiry: DEFAULT_NETWORK_EXPIRY.parse().unwrap(),
+                captive_mode: CaptiveMode::from_str(DEFAULT_CAPTIVE_MODE).unwrap(),
+                allowed_hosts: Vec::new(),
+                trusted_proxies: Vec::new(),
+                http_threads: DEFAULT_HTTP_THREADS.parse().unwrap(),
+                http_backlog: DEFAULT_HTTP_BACKLOG.parse().unwrap(),
+                http_read_timeout: DEFAULT_HTTP_READ_TIMEOUT.parse().unwrap(),
+                http_keepalive: DEFAULT_HTTP_KEEPALIVE.parse().unwrap(),
+                max_body_size: DEFAULT_MAX_BODY_SIZE.parse().unwrap(),
+                max_upload_size: DEFAULT_MAX_UPLOAD_SIZE.parse().unwrap(),
+                max_requests: DEFAULT_MAX_REQUESTS.parse().unwrap(),
+                max_asset_requests: DEFAULT_MAX_ASSET_REQUESTS.parse().unwrap(),
+                fallback_port: None,
+                csrf: true,
+                metrics: false,
+                access_log: None,
+                static_mounts: Vec::new(),
+                ui_tree: false,
+                immutable_assets: Vec::new(),
+                mime_types: Vec::new(),
+                run_as: None,
+                allow_root: false,
+                on_worker_failure: WorkerFailure::from_str(DEFAULT_ON_WORKER_FAILURE).unwrap(),
+                duplicate_policy: DuplicatePolicy::from_str(DEFAULT_DUPLICATE_POLICY).unwrap(),
+                event_callbacks: Vec::new(),
+            },
         }
     }
+}
+
+impl PortalBuilder {
+    pub fn new() -> Self {
+        PortalBuilder::default()
+    }
+
+    /// Wireless interface of the portal, the first managed WiFi device is
+    /// used otherwise
+    pub fn interface<S: Into<String>>(mut self, interface: S) -> Self {
+        self.config.interface = Some(interface.into());
+        self
+    }
+
+    pub fn ssid<S: Into<String>>(mut self, ssid: S) -> Self {
+        self.config.ssid = ssid.into();
+        self
+    }
+
+    /// WPA2 passphrase of the portal network, which is open otherwise
+    pub fn passphrase<S: Into<String>>(mut self, passphrase: S) -> Self {
+        self.config.passphrase = Some(passphrase.into());
+        self
+    }
 
-    None
+    pub fn gateway(mut self, gateway: Ipv4Addr) -> Self {
+        self.config.gateway = gateway;
+        self
+    }
+
+    /// Prefix length of the portal subnet, from `1` to `30`
+    pub fn gateway_prefix(mut self, gateway_prefix: u8) -> Self {
+        self.config.gateway_prefix = gateway_prefix;
+        self
+    }
+
+    /// Leases handed out on the portal network, in the `first,last` form of
+    /// dnsmasq, the addresses of the portal subnet above the gateway
+    /// otherwise
+    pub fn dhcp_range<S: Into<String>>(mut self, dhcp_range: S) -> Self {
+        self.config.dhcp_range = Some(dhcp_range.into());
+        self
+    }
+
+    /// IPv6 address of the portal, which has to be a unique local address
+    /// so that clients keep preferring their IPv4 route for the internet
+    pub fn gateway6(mut self, gateway6: Ipv6Addr) -> Self {
+        self.config.gateway6 = Some(gateway6);
+        self
+    }
+
+    pub fn listening_port(mut self, listening_port: u16) -> Self {
+        self.config.listening_port = listening_port;
+        self
+    }
+
+    /// Seconds after which the flow ends if no client opened the portal, `0`
+    /// disables the timeout
+    pub fn activity_timeout(mut self, activity_timeout: u64) -> Self {
+        self.config.activity_timeout = activity_timeout;
+        self
+    }
+
+    /// Directory the web UI is served from
+    pub fn ui_directory<P: Into<PathBuf>>(mut self, ui_directory: P) -> Self {
+        self.config.ui_directory = ui_directory.into();
+        self
+    }
+
+    /// Exposes the D-Bus control interface, if built with it
+    pub fn dbus(mut self, dbus: bool) -> Self {
+        self.config.dbus = dbus;
+        self
+    }
+
+    /// Posts provisioning events to the URL, with an optional bearer token
+    pub fn webhook<S: Into<String>>(mut self, url: S, token: Option<String>) -> Self {
+        self.config.webhook_url = Some(url.into());
+        self.config.webhook_token = token;
+        self
+    }
+
+    /// JSON file rewritten with the phase of the flow on every change, for
+    /// processes on the device that cannot reach the portal
+    pub fn state_file<P: Into<PathBuf>>(mut self, state_file: P) -> Self {
+        self.config.state_file = Some(state_file.into());
+        self
+    }
+
+    /// Command run with `sh -c` whenever the portal comes up
+    pub fn on_portal_start<S: Into<String>>(mut self, command: S) -> Self {
+        self.config.on_portal_start = Some(command.into());
+        self
+    }
+
+    /// Command run with `sh -c` once the device joined a network, with
+    /// `WC_SSID` and `WC_IP` in its environment
+    pub fn on_connect<S: Into<String>>(mut self, command: S) -> Self {
+        self.config.on_connect = Some(command.into());
+        self
+    }
+
+    /// Command run with `sh -c` after a failed connect attempt, with
+    /// `WC_SSID` and `WC_REASON` in its environment
+    pub fn on_connect_fail<S: Into<String>>(mut self, command: S) -> Self {
+        self.config.on_connect_fail = Some(command.into());
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    /// Seconds a connect attempt may take unless the request asks otherwise
+    pub fn connect_timeout(mut self, connect_timeout: u64) -> Self {
+        self.config.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Upper bound on the connect timeout a request may ask for
+    pub fn max_connect_timeout(mut self, max_connect_timeout: u64) -> Self {
+        self.config.max_connect_timeout = max_connect_timeout;
+        self
+    }
+
+    /// Whether created profiles autoconnect unless the request asks otherwise
+    pub fn default_autoconnect(mut self, default_autoconnect: bool) -> Self {
+        self.config.default_autoconnect = default_autoconnect;
+        self
+    }
+
+    /// Bearer token authenticating the privileged HTTP requests, such as
+    /// exporting secrets
+    pub fn api_token<S: Into<String>>(mut self, api_token: S) -> Self {
+        self.config.api_token = Some(api_token.into());
+        self
+    }
+
+    /// Locks the portal to the first client opening it, refusing changes
+    /// from others until it releases the session or stays inactive for the
+    /// activity timeout
+    pub fn single_client(mut self, single_client: bool) -> Self {
+        self.config.single_client = single_client;
+        self
+    }
+
+    /// Buffer of the recent log lines served by `/logs`, which the
+    /// application's logger has to fill
+    pub fn log_buffer(mut self, log_buffer: LogBuffer) -> Self {
+        self.config.log_buffer = Some(log_buffer);
+        self
+    }
+
+    /// How the application's logger writes the records, which decides the
+    /// form of the state dump
+    pub fn log_format(mut self, log_format: LogFormat) -> Self {
+        self.config.log_format = log_format;
+        self
+    }
+
+    /// Name the portal is announced as over mDNS while it is up, with the
+    /// `.local` domain appended if missing. An empty name disables mDNS.
+    pub fn mdns_name<S: Into<String>>(mut self, mdns_name: S) -> Self {
+        self.config.mdns_name = mdns_name.into();
+        self
+    }
+
+    /// Seconds a `/networks` reply is served again as is to clients polling
+    /// it, `0` disables the cache
+    pub fn networks_cache_ttl(mut self, networks_cache_ttl: u64) -> Self {
+        self.config.networks_cache_ttl = networks_cache_ttl;
+        self
+    }
+
+    /// Seconds the scans ahead of raising the portal may keep retrying for
+    /// until networks are found
+    pub fn scan_timeout(mut self, scan_timeout: u64) -> Self {
+        self.config.scan_timeout = scan_timeout;
+        self
+    }
+
+    /// Failed connect attempts in a row after which the flow ends with an
+    /// error instead of returning to the portal, `0` for never
+    pub fn max_failures_before_exit(mut self, max_failures_before_exit: u32) -> Self {
+        self.config.max_failures_before_exit = max_failures_before_exit;
+        self
+    }
+
+    /// Keeps running once the device joined a network, checking its
+    /// connectivity and raising the portal again after it stayed offline
+    pub fn run_forever(mut self, run_forever: bool) -> Self {
+        self.config.run_forever = run_forever;
+        self
+    }
+
+    /// Attempts at raising the portal access point, waiting twice as long
+    /// after every failure, before the flow ends with an error
+    pub fn ap_retry_limit(mut self, ap_retry_limit: u32) -> Self {
+        self.config.ap_retry_limit = ap_retry_limit;
+        self
+    }
+
+    /// Seconds NetworkManager may take to come up on the system bus at
+    /// startup, such as right after boot, before giving up
+    pub fn nm_wait_timeout(mut self, nm_wait_timeout: u64) -> Self {
+        self.config.nm_wait_timeout = nm_wait_timeout;
+        self
+    }
+
+    /// Adds a glob pattern of the interfaces the automatic device selection
+    /// may pick, any interface may be picked without one
+    pub fn interface_include<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config.interface_include.push(pattern.into());
+        self
+    }
+
+    /// Adds a glob pattern of the interfaces the automatic device selection
+    /// skips, even if they are included
+    pub fn interface_exclude<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config.interface_exclude.push(pattern.into());
+        self
+    }
+
+    /// Adds a glob pattern of the SSIDs the portal offers, all are offered
+    /// without one
+    pub fn ssid_allow<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config.ssid_filter.allow.push(pattern.into());
+        self
+    }
+
+    /// Adds a glob pattern of the SSIDs the portal neither lists nor connects
+    /// to, even if they are allowed
+    pub fn ssid_deny<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config.ssid_filter.deny.push(pattern.into());
+        self
+    }
+
+    /// Signal quality in percent below which networks are not listed, though
+    /// they can still be connected to by SSID
+    pub fn min_signal(mut self, min_signal: u8) -> Self {
+        self.config.min_signal = min_signal;
+        self
+    }
+
+    /// Seconds an access point missing from the scans stays listed for,
+    /// counted from the latest scan
+    pub fn network_expiry(mut self, network_expiry: u64) -> Self {
+        self.config.network_expiry = network_expiry;
+        self
+    }
+
+    /// How requests to other hosts, such as captive portal probes, are
+    /// answered
+    pub fn captive_mode(mut self, captive_mode: CaptiveMode) -> Self {
+        self.config.captive_mode = captive_mode;
+        self
+    }
+
+    /// Adds a host name the API answers to, besides the gateway address and
+    /// the mDNS name
+    pub fn allowed_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.config.allowed_hosts.push(host.into());
+        self
+    }
+
+    /// Adds the address of a reverse proxy in front of the portal, whose
+    /// `X-Forwarded-For` header names the client in the request log
+    pub fn trust_proxy(mut self, proxy: IpAddr) -> Self {
+        self.config.trusted_proxies.push(proxy);
+        self
+    }
+
+    /// Threads serving HTTP requests, `0` for Iron's eight per CPU core
+    pub fn http_threads(mut self, http_threads: usize) -> Self {
+        self.config.http_threads = http_threads;
+        self
+    }
+
+    /// Connections the kernel queues for the HTTP server before refusing
+    /// more
+    pub fn http_backlog(mut self, http_backlog: u32) -> Self {
+        self.config.http_backlog = http_backlog;
+        self
+    }
+
+    /// Seconds the HTTP server waits for a request to arrive before closing
+    /// the connection, `0` for no limit
+    pub fn http_read_timeout(mut self, http_read_timeout: u64) -> Self {
+        self.config.http_read_timeout = http_read_timeout;
+        self
+    }
+
+    /// Seconds an idle connection is kept open for another request, `0`
+    /// disables keep-alive
+    pub fn http_keepalive(mut self, http_keepalive: u64) -> Self {
+        self.config.http_keepalive = http_keepalive;
+        self
+    }
+
+    /// Largest request body in bytes the API accepts
+    pub fn max_body_size(mut self, max_body_size: u64) -> Self {
+        self.config.max_body_size = max_body_size;
+        self
+    }
+
+    /// Largest body in bytes of the upload endpoints, such as
+    /// `/networks/import`
+    pub fn max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.config.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// API requests handled at once, past which requests are answered with
+    /// 503, `0` for no limit
+    pub fn max_requests(mut self, max_requests: usize) -> Self {
+        self.config.max_requests = max_requests;
+        self
+    }
+
+    /// Requests for UI files handled at once, counted apart from the API
+    /// requests, `0` for no limit
+    pub fn max_asset_requests(mut self, max_asset_requests: usize) -> Self {
+        self.config.max_asset_requests = max_asset_requests;
+        self
+    }
+
+    /// Port listened on when another program holds the listening port,
+    /// which fails the startup otherwise
+    pub fn fallback_port(mut self, fallback_port: u16) -> Self {
+        self.config.fallback_port = Some(fallback_port);
+        self
+    }
+
+    /// Whether requests changing anything have to repeat the CSRF token of
+    /// the cookie the portal sets, which older clients do not
+    pub fn csrf(mut self, csrf: bool) -> Self {
+        self.config.csrf = csrf;
+        self
+    }
+
+    /// Whether `GET /metrics` serves the request counters, durations and
+    /// response sizes in the Prometheus text format
+    pub fn metrics(mut self, metrics: bool) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// File a line is appended to for every request, in the Common Log
+    /// Format with the duration and the `User-Agent` added
+    pub fn access_log<P: Into<PathBuf>>(mut self, access_log: P) -> Self {
+        self.config.access_log = Some(access_log.into());
+        self
+    }
+
+    /// Serves the URL prefix, like `/fonts`, from the directory, relative to
+    /// the UI directory
+    pub fn static_mount<S: Into<String>, P: Into<PathBuf>>(
+        mut self,
+        prefix: S,
+        directory: P,
+    ) -> Self {
+        self.config
+            .static_mounts
+            .push((prefix.into(), directory.into()));
+        self
+    }
+
+    /// Serves every file of the UI directory by its path, the way bundlers
+    /// lay out their output, with the UI still loaded from `/` only
+    pub fn ui_tree(mut self, ui_tree: bool) -> Self {
+        self.config.ui_tree = ui_tree;
+        self
+    }
+
+    /// Adds a regular expression of fingerprinted file names, like
+    /// `\.[0-9a-f]{8}\.js$`, which clients may cache for good. It matches
+    /// anywhere in the name unless anchored. File names with a run of eight
+    /// or more hex digits between dots or dashes count without any.
+    pub fn immutable_asset<S: Into<String>>(mut self, pattern: S) -> Self {
+        self.config.immutable_assets.push(pattern.into());
+        self
+    }
+
+    /// Serves the files with the extension, like `avif`, as the content type,
+    /// over the built-in one if there is one
+    pub fn mime_type<S: Into<String>, T: Into<String>>(
+        mut self,
+        extension: S,
+        mime_type: T,
+    ) -> Self {
+        let extension = extension.into();
+        let extension = extension.trim_start_matches('.').to_lowercase();
+        self.config.mime_types.push((extension, mime_type.into()));
+        self
+    }
+
+    /// Unprivileged user, as `user` or `user:group`, to switch to once the
+    /// access point is up and the listener is bound. Only the network
+    /// capabilities are kept, NetworkManager has to grant the user its
+    /// actions through polkit.
+    pub fn run_as<S: Into<String>>(mut self, run_as: S) -> Self {
+        self.config.run_as = Some(run_as.into());
+        self
+    }
+
+    /// Whether the portal may be served as root, which is refused otherwise
+    pub fn allow_root(mut self, allow_root: bool) -> Self {
+        self.config.allow_root = allow_root;
+        self
+    }
+
+    /// Whether the program is executed again once the network thread
+    /// panicked, instead of exiting. A stuck thread always ends the flow,
+    /// as does a panic once root has been dropped.
+    pub fn on_worker_failure(mut self, on_worker_failure: WorkerFailure) -> Self {
+        self.config.on_worker_failure = on_worker_failure;
+        self
+    }
+
+    /// How the saved profiles of a network are dealt with when credentials
+    /// for it are submitted, see `DuplicatePolicy`
+    pub fn duplicate_policy(mut self, duplicate_policy: DuplicatePolicy) -> Self {
+        self.config.duplicate_policy = duplicate_policy;
+        self
+    }
+
+    /// Registers a callback invoked from the network thread on every event
+    pub fn on_event<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&NetworkEvent) + Send + Sync + 'static,
+    {
+        self.config.event_callbacks.push(Arc::new(callback));
+        self
+    }
+
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{env, fs, process};
+
+    fn settings_error(builder: PortalBuilder) -> String {
+        builder.build().validate_settings().unwrap_err()
+    }
+
+    fn portal_network_error(builder: PortalBuilder) -> String {
+        builder.build().validate_portal_network().unwrap_err()
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        let config = PortalBuilder::new().build();
+
+        assert_eq!(config.validate_settings(), Ok(()));
+        assert_eq!(config.validate_portal_network(), Ok(()));
+        assert_eq!(config.validate_static_mounts(), Ok(()));
+    }
+
+    #[test]
+    fn the_portal_ssid_has_to_fit_an_ssid() {
+        assert!(settings_error(PortalBuilder::new().ssid("")).contains("1 to 32 bytes"));
+        assert!(settings_error(PortalBuilder::new().ssid("x".repeat(33))).contains("1 to 32 bytes"));
+        assert_eq!(
+            PortalBuilder::new()
+                .ssid("x".repeat(32))
+                .build()
+                .validate_settings(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn the_portal_passphrase_has_to_be_a_wpa_passphrase() {
+        assert!(
+            settings_error(PortalBuilder::new().passphrase("1234")).contains("portal passphrase")
+        );
+        assert_eq!(
+            PortalBuilder::new()
+                .passphrase("12345678")
+                .build()
+                .validate_settings(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn the_listening_port_cannot_be_0() {
+        assert!(settings_error(PortalBuilder::new().listening_port(0)).contains("cannot be 0"));
+    }
+
+    #[test]
+    fn the_fallback_port_cannot_be_0() {
+        assert!(settings_error(PortalBuilder::new().fallback_port(0))
+            .contains("fallback port cannot be 0"));
+    }
+
+    #[test]
+    fn the_fallback_port_differs_from_the_listening_port() {
+        let error = settings_error(
+            PortalBuilder::new()
+                .listening_port(8080)
+                .fallback_port(8080),
+        );
+        assert!(error.contains("listening port itself"));
+    }
+
+    #[test]
+    fn immutable_assets_have_to_be_regular_expressions() {
+        assert!(
+            settings_error(PortalBuilder::new().immutable_asset("\\.(js$"))
+                .contains("not a regular expression")
+        );
+    }
+
+    #[test]
+    fn the_ipv6_gateway_has_to_be_a_unique_local_address() {
+        let error =
+            portal_network_error(PortalBuilder::new().gateway6("2001:db8::1".parse().unwrap()));
+        assert!(error.contains("unique local address"));
+        assert_eq!(
+            PortalBuilder::new()
+                .gateway6("fd00::1".parse().unwrap())
+                .build()
+                .validate_portal_network(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn the_gateway_prefix_leaves_room_for_clients() {
+        for &prefix in [0, MAX_GATEWAY_PREFIX + 1].iter() {
+            let error = portal_network_error(PortalBuilder::new().gateway_prefix(prefix));
+            assert!(error.contains("leaves no room for clients"), "/{}", prefix);
+        }
+    }
+
+    #[test]
+    fn the_dhcp_range_lies_within_the_portal_subnet() {
+        let error =
+            portal_network_error(PortalBuilder::new().dhcp_range("192.168.43.2,192.168.43.254"));
+        assert!(error.contains("outside of the portal subnet 192.168.42.0/24"));
+
+        // Ranges in the other forms of dnsmasq are left to it
+        assert_eq!(
+            PortalBuilder::new()
+                .dhcp_range("set:portal,192.168.42.2,192.168.42.254")
+                .build()
+                .validate_portal_network(),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn static_mounts_are_directories_within_the_ui_directory() {
+        let ui_directory = env::temp_dir().join(format!("wifi-connect-ui-{}", process::id()));
+        fs::create_dir_all(ui_directory.join("fonts")).unwrap();
+        let mount = |prefix: &str, directory: &str| {
+            PortalBuilder::new()
+                .ui_directory(&ui_directory)
+                .static_mount(prefix, directory)
+                .build()
+                .validate_static_mounts()
+        };
+
+        assert_eq!(mount("/fonts", "fonts"), Ok(()));
+
+        for prefix in ["fonts", "/", "//"].iter() {
+            assert!(mount(prefix, "fonts")
+                .unwrap_err()
+                .contains("has to start with /"));
+        }
+
+        for directory in ["/usr/share/fonts", "../fonts", "fonts/../../etc"].iter() {
+            assert!(mount("/fonts", directory)
+                .unwrap_err()
+                .contains("has to lie within the UI directory"));
+        }
+
+        assert!(mount("/icons", "icons")
+            .unwrap_err()
+            .contains("does not exist"));
+
+        fs::remove_dir_all(&ui_directory).unwrap();
+    }
 }
@@ -0,0 +1,259 @@
+use std::cell::RefCell;
+use std::net::IpAddr;
+use std::rc::Rc;
+use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
+
+use dbus::arg::{Dict, Iter};
+use dbus::tree::{Factory, MethodErr};
+use dbus::{BusType, Connection, Interface, NameFlag, Path};
+
+use backend::{
+    is_valid_domain_name, ConnectSecurity, Credentials, Metered, Ssid, MAX_PRIORITY, MIN_PRIORITY,
+};
+use errors::*;
+use network::{
+    response_channel, EventReceiver, NetworkCommand, NetworkCommandResponse, NetworkCommandSender,
+    NetworkEvent, ProfileOptions,
+};
+
+const BUS_NAME: &str = "org.wifi_connect.Portal1";
+const OBJECT_PATH: &str = "/org/wifi_connect/Portal1";
+const INTERFACE: &str = "org.wifi_connect.Portal1";
+
+type MethodResult<T> = ::std::result::Result<T, MethodErr>;
+
+/// Provisioning phase as reported by `Status` and `StateChanged`
+struct PortalState {
+    name: &'static str,
+    ssid: String,
+}
+
+impl PortalState {
+    fn new(name: &'static str, ssid: &str) -> Self {
+        PortalState {
+            name,
+            ssid: ssid.to_string(),
+        }
+    }
+
+    fn from_event(event: &NetworkEvent) -> Option<Self> {
+        match *event {
+            NetworkEvent::PortalStarted => Some(PortalState::new("portal", "")),
+            NetworkEvent::ConnectAttempt { ref ssid, .. } => {
+                Some(PortalState::new("connecting", ssid))
+            }
+            NetworkEvent::Connected { ref ssid, .. } => Some(PortalState::new("connected", ssid)),
+            NetworkEvent::ClientConnected { .. }
+            | NetworkEvent::ConnectFailed { .. }
+            | NetworkEvent::ConnectCancelled { .. }
+            | NetworkEvent::BulkConnectFinished { .. }
+            | NetworkEvent::NetworksRefreshed => None,
+            NetworkEvent::Exiting => Some(PortalState::new("exiting", "")),
+        }
+    }
+}
+
+/// Serves the `org.wifi_connect.Portal1` interface on the system bus until the
+/// network thread goes away. This is independent of the HTTP server, so other
+/// daemons on the device may drive provisioning without reaching the portal.
+pub fn run(network_tx: NetworkCommandSender, event_rx: EventReceiver) -> Result<()> {
+    let connection = Connection::get_private(BusType::System)?;
+
+    connection.register_name(BUS_NAME, NameFlag::ReplaceExisting as u32)?;
+
+    let state = Rc::new(RefCell::new(PortalState::new("portal", "")));
+
+    let factory = Factory::new_fn::<()>();
+
+    let state_changed = Arc::new(
+        factory
+            .signal("StateChanged", ())
+            .sarg::<&str, _>("state")
+            .sarg::<&str, _>("ssid"),
+    );
+
+    let list_networks_tx = network_tx.clone();
+    let connect_tx = network_tx;
+    let status_state = state.clone();
+
+    let interface = factory
+        .interface(INTERFACE, ())
+        .add_m(
+            factory
+                .method("ListNetworks", (), move |m| {
+                    let networks = list_networks(&list_networks_tx)?;
+                    Ok(vec![m.msg.method_return().append1(networks)])
+                })
+                .outarg::<Vec<(String, String)>, _>("networks"),
+        )
+        .add_m(
+            factory
+                .method("Connect", (), move |m| {
+                    let (ssid, options) = m
+                        .msg
+                        .read2::<&str, Dict<&str, &str, Iter>>()
+                        .map_err(|e| MethodErr::invalid_arg(&e))?;
+
+                    connect(&connect_tx, ssid, options)?;
+
+                    Ok(vec![m.msg.method_return()])
+                })
+                .inarg::<&str, _>("ssid")
+                .inarg::<Dict<&str, &str, Iter>, _>("options"),
+        )
+        .add_m(
+            factory
+                .method("Status", (), move |m| {
+                    let state = status_state.borrow();
+                    let reply = m
+                        .msg
+                        .method_return()
+                        .append2(state.name, &state.ssid as &str);
+                    Ok(vec![reply])
+                })
+                .outarg::<&str, _>("state")
+                .outarg::<&str, _>("ssid"),
+        )
+        .add_s(state_changed.clone());
+
+    let tree = factory.tree(()).add(
+        factory
+            .object_path(OBJECT_PATH, ())
+            .introspectable()
+            .add(interface),
+    );
+
+    tree.set_registered(&connection, true)?;
+
+    let path = Path::from(OBJECT_PATH);
+    let iface = Interface::from(INTERFACE);
+
+    info!("D-Bus control interface available as {}", BUS_NAME);
+
+    for _ in tree.run(&connection, connection.iter(1000)) {
+        loop {
+            match event_rx.try_recv() {
+                Ok(event) => {
+                    let new_state = match PortalState::from_event(&event) {
+                        Some(new_state) => new_state,
+                        None => continue,
+                    };
+
+                    let signal = state_changed
+                        .msg(&path, &iface)
+                        .append2(new_state.name, &new_state.ssid as &str);
+
+                    if connection.send(signal).is_err() {
+                        warn!("Emitting D-Bus StateChanged signal failed");
+                    }
+
+                    *state.borrow_mut() = new_state;
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn list_networks(network_tx: &NetworkCommandSender) -> MethodResult<Vec<(String, String)>> {
+    let (response_tx, response_rx) = response_channel();
+
+    network_tx
+        .send(NetworkCommand::ListNetworks(response_tx))
+        .map_err(|_| network_thread_gone())?;
+
+    match response_rx.recv() {
+        Ok(NetworkCommandResponse::Networks { networks, .. }) => Ok(networks
+            .into_iter()
+            .map(|network| (network.ssid, network.security))
+            .collect()),
+        _ => Err(network_thread_gone()),
+    }
+}
+
+fn connect(
+    network_tx: &NetworkCommandSender,
+    ssid: &str,
+    options: Dict<&str, &str, Iter>,
+) -> MethodResult<()> {
+    let mut credentials = Credentials::default();
+    let mut timeout = None;
+    let mut profile = ProfileOptions::default();
+
+    for (key, value) in options {
+        match key {
+            "identity" => credentials.identity = non_empty(value),
+            "uuid" => profile.uuid = non_empty(value),
+            "passphrase" => credentials.passphrase = non_empty(value),
+            "security" => match ConnectSecurity::from_name(value) {
+                Some(security) => credentials.security = Some(security),
+                None => return Err(MethodErr::invalid_arg(&value)),
+            },
+            "timeout" => match value.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => timeout = Some(seconds),
+                _ => return Err(MethodErr::invalid_arg(&value)),
+            },
+            "autoconnect" => match value.parse::<bool>() {
+                Ok(value) => profile.autoconnect = Some(value),
+                Err(_) => return Err(MethodErr::invalid_arg(&value)),
+            },
+            "priority" => match value.parse::<i32>() {
+                Ok(value) if value >= MIN_PRIORITY && value <= MAX_PRIORITY => {
+                    profile.priority = Some(value)
+                }
+                _ => return Err(MethodErr::invalid_arg(&value)),
+            },
+            "metered" => match Metered::from_name(value) {
+                Some(metered) => profile.metered = Some(metered),
+                None => return Err(MethodErr::invalid_arg(&value)),
+            },
+            "search_domains" => {
+                for domain in value.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+                    if !is_valid_domain_name(domain) {
+                        return Err(MethodErr::invalid_arg(&domain));
+                    }
+                    profile.search_domains.push(domain.to_string());
+                }
+            }
+            "dns" if value.is_empty() => {}
+            "dns" => {
+                profile.dns = value
+                    .split(',')
+                    .map(|address| address.trim().parse::<IpAddr>())
+                    .collect::<::std::result::Result<_, _>>()
+                    .map_err(|_| MethodErr::invalid_arg(&value))?
+            }
+            _ => return Err(MethodErr::invalid_arg(&key)),
+        }
+    }
+
+    info!(
+        "Incoming D-Bus `Connect` to access point `{}` request",
+        ssid
+    );
+
+    let command = NetworkCommand::Connect {
+        ssid: Ssid::from(ssid),
+        credentials,
+        timeout,
+        profile,
+    };
+
+    network_tx.send(command).map_err(|_| network_thread_gone())
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn network_thread_gone() -> MethodErr {
+    MethodErr::failed(&"Network command thread is not running")
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+
+use serde_json;
+
+use network::{unix_time, NetworkEvent, QueueDepth};
+
+/// Target of the state dump in the JSON log format, whose message is the
+/// JSON object of the state rather than text
+pub const STATE_DUMP_TARGET: &str = "wifi_connect::state_dump";
+
+/// What the network thread is up to, kept up to date by it and dumped to
+/// the log on `SIGUSR1` by the signal trap thread. The dump does not wait on
+/// the network thread, so that it still works while that one is busy
+/// connecting or stuck.
+#[derive(Clone)]
+pub struct Diagnostics {
+    started: Instant,
+    snapshot: Arc<Mutex<Snapshot>>,
+}
+
+struct Snapshot {
+    /// Named like the phases of `--state-file`
+    phase: &'static str,
+    portal_active: bool,
+    interface: Option<String>,
+    networks: usize,
+    /// Seconds since the epoch of the latest scan
+    scanned_at: u64,
+    /// SSID of the connect attempt in progress
+    connecting: Option<String>,
+    last_error: Option<String>,
+    /// Events each listener did not receive yet
+    listeners: Vec<(&'static str, QueueDepth)>,
+}
+
+/// The state as `dump_json` writes it, absent values being `null`
+#[derive(Serialize)]
+struct StateDump<'a> {
+    phase: &'a str,
+    portal_active: bool,
+    interface: Option<&'a str>,
+    networks: usize,
+    /// Seconds since the latest scan
+    scan_age: Option<u64>,
+    connecting: Option<&'a str>,
+    queued_commands: usize,
+    queued_events: BTreeMap<&'a str, usize>,
+    /// Seconds
+    uptime: u64,
+    last_error: Option<&'a str>,
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Diagnostics {
+            started: Instant::now(),
+            snapshot: Arc::new(Mutex::new(Snapshot {
+                phase: "starting",
+                portal_active: false,
+                interface: None,
+                networks: 0,
+                scanned_at: 0,
+                connecting: None,
+                last_error: None,
+                listeners: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn record_interface(&self, interface: &str) {
+        self.update(|snapshot| snapshot.interface = Some(interface.to_string()));
+    }
+
+    pub fn record_portal(&self, active: bool) {
+        self.update(|snapshot| snapshot.portal_active = active);
+    }
+
+    pub fn record_scan(&self, networks: usize, scanned_at: u64) {
+        self.update(|snapshot| {
+            snapshot.networks = networks;
+            snapshot.scanned_at = scanned_at;
+        });
+    }
+
+    pub fn record_listener(&self, name: &'static str, depth: QueueDepth) {
+        self.update(|snapshot| snapshot.listeners.push((name, depth)));
+    }
+
+    pub fn record_error(&self, error: &str) {
+        self.update(|snapshot| snapshot.last_error = Some(error.to_string()));
+    }
+
+    pub fn record_event(&self, event: &NetworkEvent) {
+        self.update(|snapshot| match *event {
+            NetworkEvent::PortalStarted => {
+                snapshot.phase = "portal";
+                snapshot.connecting = None;
+            }
+            NetworkEvent::ConnectAttempt { ref ssid, .. } => {
+                snapshot.phase = "connecting";
+                snapshot.connecting = Some(ssid.clone());
+            }
+            NetworkEvent::Connected { .. } => {
+                snapshot.phase = "connected";
+                snapshot.connecting = None;
+            }
+            NetworkEvent::ConnectFailed { ref reason, .. } => {
+                snapshot.phase = "failed";
+                snapshot.connecting = None;
+                snapshot.last_error = Some(reason.clone());
+            }
+            NetworkEvent::ConnectCancelled { .. } => {
+                snapshot.phase = "portal";
+                snapshot.connecting = None;
+            }
+            NetworkEvent::Exiting => snapshot.phase = "exiting",
+            NetworkEvent::ClientConnected { .. }
+            | NetworkEvent::BulkConnectFinished { .. }
+            | NetworkEvent::NetworksRefreshed => {}
+        });
+    }
+
+    /// A single multi-line entry, so that log shipping keeps it together
+    pub fn dump(&self, queued_commands: usize) -> String {
+        let snapshot = self.lock();
+
+        let mut dump = String::from("State dump:");
+
+        let _ = write!(dump, "\n  phase: {}", snapshot.phase);
+        let _ = write!(
+            dump,
+            "\n  portal: {}",
+            if snapshot.portal_active { "up" } else { "down" }
+        );
+        let _ = write!(
+            dump,
+            "\n  interface: {}",
+            snapshot.interface.as_ref().map_or("-", String::as_str)
+        );
+
+        if snapshot.scanned_at == 0 {
+            let _ = write!(dump, "\n  networks: {}, never scanned", snapshot.networks);
+        } else {
+            let _ = write!(
+                dump,
+                "\n  networks: {}, scanned {}s ago",
+                snapshot.networks,
+                unix_time().saturating_sub(snapshot.scanned_at)
+            );
+        }
+
+        let _ = write!(
+            dump,
+            "\n  connecting to: {}",
+            snapshot.connecting.as_ref().map_or("-", String::as_str)
+        );
+        let _ = write!(dump, "\n  queued commands: {}", queued_commands);
+
+        let queued_events = snapshot
+            .listeners
+            .iter()
+            .map(|&(name, ref depth)| format!("{} {}", name, depth.get()))
+            .collect::<Vec<_>>();
+        let _ = write!(dump, "\n  queued events: {}", queued_events.join(", "));
+
+        let _ = write!(dump, "\n  uptime: {}s", self.started.elapsed().as_secs());
+        let _ = write!(
+            dump,
+            "\n  last error: {}",
+            snapshot.last_error.as_ref().map_or("-", String::as_str)
+        );
+
+        dump
+    }
+
+    /// The fields of `dump` as a single JSON object, for the JSON log format
+    pub fn dump_json(&self, queued_commands: usize) -> String {
+        let snapshot = self.lock();
+
+        let dump = StateDump {
+            phase: snapshot.phase,
+            portal_active: snapshot.portal_active,
+            interface: snapshot.interface.as_ref().map(String::as_str),
+            networks: snapshot.networks,
+            scan_age: if snapshot.scanned_at == 0 {
+                None
+            } else {
+                Some(unix_time().saturating_sub(snapshot.scanned_at))
+            },
+            connecting: snapshot.connecting.as_ref().map(String::as_str),
+            queued_commands,
+            queued_events: snapshot
+                .listeners
+                .iter()
+                .map(|&(name, ref depth)| (name, depth.get()))
+                .collect(),
+            uptime: self.started.elapsed().as_secs(),
+            last_error: snapshot.last_error.as_ref().map(String::as_str),
+        };
+
+        serde_json::to_string(&dump).unwrap_or_default()
+    }
+
+    fn lock(&self) -> MutexGuard<Snapshot> {
+        match self.snapshot.lock() {
+            Ok(snapshot) => snapshot,
+            // Only a panic while updating poisons it, the fields are whole
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Snapshot)>(&self, update: F) {
+        update(&mut self.lock());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use network::event_channel;
+
+    #[test]
+    fn dumps_the_queued_events_of_each_listener() {
+        let diagnostics = Diagnostics::new();
+        let (event_tx, event_rx) = event_channel();
+        diagnostics.record_listener("server", event_tx.depth());
+
+        event_tx.send(NetworkEvent::PortalStarted).unwrap();
+        event_tx.send(NetworkEvent::NetworksRefreshed).unwrap();
+        event_rx.recv().unwrap();
+
+        assert!(diagnostics
+            .dump(0)
+            .contains("\n  queued events: server 1\n"));
+
+        let dump: serde_json::Value = serde_json::from_str(&diagnostics.dump_json(3)).unwrap();
+        assert_eq!(dump["queued_commands"], 3);
+        assert_eq!(dump["queued_events"]["server"], 1);
+        assert_eq!(dump["scan_age"], serde_json::Value::Null);
+    }
+}
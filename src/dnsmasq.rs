@@ -1,27 +1,143 @@
-use std::process::{Child, Command};
+use std::io::{BufRead, BufReader};
+use std::net::Ipv4Addr;
+use std::process::{Child, ChildStderr, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use network_manager::Device;
-
-use config::Config;
+use config::{Config, GATEWAY6_PREFIX};
 use errors::*;
+use poison::recover_poisoned;
+
+/// A device that took a DHCP lease on the portal network
+#[derive(Clone, Debug, Serialize)]
+pub struct PortalClient {
+    pub mac: String,
+    pub ip: Ipv4Addr,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Seconds since the epoch the client took its first lease
+    pub associated_at: u64,
+}
+
+/// Clients of the portal network as seen in the DHCP log of dnsmasq, kept up
+/// to date from its output so that they can be listed right away
+#[derive(Clone, Debug, Default)]
+pub struct PortalClients {
+    clients: Arc<Mutex<Vec<PortalClient>>>,
+    /// Told about every client taking its first lease
+    joined_tx: Arc<Mutex<Option<Sender<PortalClient>>>>,
+}
+
+impl PortalClients {
+    pub fn list(&self) -> Vec<PortalClient> {
+        self.clients.lock().unwrap_or_else(recover_poisoned).clone()
+    }
+
+    /// Receives the clients joining the portal network from now on
+    pub fn subscribe(&self) -> Receiver<PortalClient> {
+        let (joined_tx, joined_rx) = channel();
+        *self.joined_tx.lock().unwrap_or_else(recover_poisoned) = Some(joined_tx);
+        joined_rx
+    }
+
+    fn lease(&self, mac: &str, ip: Ipv4Addr, hostname: Option<String>) {
+        let mut clients = self.clients.lock().unwrap_or_else(recover_poisoned);
+
+        match clients.iter_mut().find(|client| client.mac == mac) {
+            // Renewals keep the time of the first lease
+            Some(client) => {
+                client.ip = ip;
+                if hostname.is_some() {
+                    client.hostname = hostname;
+                }
+            }
+            None => {
+                info!("Portal client {} joined with {}", mac, ip);
+
+                let client = PortalClient {
+                    mac: mac.to_string(),
+                    ip,
+                    hostname,
+                    associated_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|elapsed| elapsed.as_secs())
+                        .unwrap_or(0),
+                };
+
+                let joined_tx = self.joined_tx.lock().unwrap_or_else(recover_poisoned);
+
+                if let Some(ref joined_tx) = *joined_tx {
+                    let _ = joined_tx.send(client.clone());
+                }
+
+                clients.push(client);
+            }
+        }
+    }
+
+    fn release(&self, mac: &str) {
+        self.clients
+            .lock()
+            .unwrap_or_else(recover_poisoned)
+            .retain(|client| client.mac != mac);
+    }
+}
 
-pub fn start_dnsmasq(config: &Config, device: &Device) -> Result<Child> {
-    let args = [
-        &format!("--address=/#/{}", config.gateway),
-        &format!("--dhcp-range={}", config.dhcp_range),
-        &format!("--dhcp-option=option:router,{}", config.gateway),
-        &format!("--interface={}", device.interface()),
-        "--keep-in-foreground",
-        "--bind-interfaces",
-        "--except-interface=lo",
-        "--conf-file",
-        "--no-hosts",
+/// Spawns dnsmasq as the captive DNS server of the portal network and, unless
+/// the backend hands out leases itself, as its DHCP server too
+pub fn start_dnsmasq(
+    config: &Config,
+    interface: &str,
+    dhcp: bool,
+    clients: &PortalClients,
+) -> Result<Child> {
+    let mut args = vec![
+        format!("--address=/#/{}", config.gateway),
+        format!("--interface={}", interface),
+        "--keep-in-foreground".to_string(),
+        "--bind-interfaces".to_string(),
+        "--except-interface=lo".to_string(),
+        "--conf-file".to_string(),
+        "--no-hosts".to_string(),
     ];
 
-    Command::new("dnsmasq")
+    if let (Some(gateway6), Some(network6)) = (config.gateway6, config.gateway6_network()) {
+        // Clients configure themselves from the advertised prefix, with no
+        // default route so that their IPv4 one keeps carrying the rest
+        args.push(format!("--address=/#/{}", gateway6));
+        args.push("--enable-ra".to_string());
+        args.push(format!(
+            "--dhcp-range={},ra-only,{}",
+            network6, GATEWAY6_PREFIX
+        ));
+        // Advertised every 10 seconds with a router lifetime of 0
+        args.push(format!("--ra-param={},10,0", interface));
+    }
+
+    if dhcp {
+        args.push(format!("--dhcp-range={}", config.effective_dhcp_range()));
+        args.push(format!("--dhcp-option=option:router,{}", config.gateway));
+        // The DHCP log is read for the portal clients
+        args.push("--log-facility=-".to_string());
+    }
+
+    let mut dnsmasq = Command::new("dnsmasq")
         .args(&args)
+        .stderr(if dhcp {
+            Stdio::piped()
+        } else {
+            Stdio::inherit()
+        })
         .spawn()
-        .chain_err(|| ErrorKind::Dnsmasq)
+        .chain_err(|| ErrorKind::Dnsmasq)?;
+
+    if let Some(stderr) = dnsmasq.stderr.take() {
+        spawn_log_reader(stderr, clients.clone());
+    }
+
+    Ok(dnsmasq)
 }
 
 pub fn stop_dnsmasq(dnsmasq: &mut Child) -> Result<()> {
@@ -31,3 +147,52 @@ pub fn stop_dnsmasq(dnsmasq: &mut Child) -> Result<()> {
 
     Ok(())
 }
+
+/// Follows the log of dnsmasq until it exits
+fn spawn_log_reader(stderr: ChildStderr, clients: PortalClients) {
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+
+            debug!("dnsmasq: {}", line);
+
+            parse_dhcp_event(&line, &clients);
+        }
+    });
+}
+
+/// Lines look like `dnsmasq-dhcp[42]: DHCPACK(wlan0) 192.168.42.10
+/// 00:11:22:33:44:55 phone`, the hostname being optional
+fn parse_dhcp_event(line: &str, clients: &PortalClients) {
+    let (event, rest) = match line.find("DHCPACK(").or_else(|| line.find("DHCPRELEASE(")) {
+        Some(start) => {
+            let event = &line[start..];
+            match event.find(')') {
+                Some(end) => (&event[..end], &event[end + 1..]),
+                None => return,
+            }
+        }
+        None => return,
+    };
+
+    let mut fields = rest.split_whitespace();
+
+    let ip = match fields.next().and_then(|ip| ip.parse::<Ipv4Addr>().ok()) {
+        Some(ip) => ip,
+        None => return,
+    };
+
+    let mac = match fields.next() {
+        Some(mac) => mac.to_lowercase(),
+        None => return,
+    };
+
+    if event.starts_with("DHCPACK") {
+        clients.lease(&mac, ip, fields.next().map(String::from));
+    } else {
+        clients.release(&mac);
+    }
+}
@@ -8,6 +8,7 @@ error_chain! {
         Recv(::std::sync::mpsc::RecvError);
         SendNetworkCommand(::std::sync::mpsc::SendError<network::NetworkCommand>);
         Nix(::nix::Error);
+        DBus(::dbus::Error);
     }
 
     links {
@@ -39,6 +40,80 @@ error_chain! {
             description("Sending NetworkCommand::Connect failed")
         }
 
+        SendNetworkCommandCancel {
+            description("Sending NetworkCommand::Cancel failed")
+        }
+
+        SendNetworkCommandListSavedNetworks {
+            description("Sending NetworkCommand::ListSavedNetworks failed")
+        }
+
+        RecvSavedNetworks {
+            description("Receiving saved networks failed")
+        }
+
+        SendNetworkCommandStatus {
+            description("Sending NetworkCommand::Status failed")
+        }
+
+        RecvStatus {
+            description("Receiving the connection status failed")
+        }
+
+        SendNetworkCommandForgetAll {
+            description("Sending NetworkCommand::ForgetAll failed")
+        }
+
+        RecvForgetAll {
+            description("Receiving the forgotten profiles failed")
+        }
+
+        SendNetworkCommandExportProfile {
+            description("Sending NetworkCommand::ExportProfile failed")
+        }
+
+        RecvProfileExport {
+            description("Receiving the exported profile failed")
+        }
+
+        SendNetworkCommandImportProfile {
+            description("Sending NetworkCommand::ImportProfile failed")
+        }
+
+        RecvProfileImport {
+            description("Receiving the imported profile failed")
+        }
+
+        SendNetworkCommandSignal {
+            description("Sending NetworkCommand::Signal failed")
+        }
+
+        RecvSignal {
+            description("Receiving the signal strength failed")
+        }
+
+        SendNetworkCommandListDevices {
+            description("Sending NetworkCommand::ListDevices failed")
+        }
+
+        RecvDevices {
+            description("Receiving the network devices failed")
+        }
+
+        ProfileTransferUnsupported {
+            description("The network stack cannot export or import profiles")
+        }
+
+        UnsupportedProfileSecurity(key_mgmt: String) {
+            description("Unsupported security settings of the saved profile")
+            display("Unsupported key management of the saved profile: {}", key_mgmt)
+        }
+
+        DeleteProfile(ssid: String) {
+            description("Deleting the saved profile failed")
+            display("Deleting the saved profile of '{}' failed", ssid)
+        }
+
         DeviceByInterface(interface: String) {
             description("Cannot find network device with interface name")
             display("Cannot find network device with interface name '{}'", interface)
@@ -91,6 +166,11 @@ error_chain! {
             description("Spawning dnsmasq failed")
         }
 
+        Mdns(interface: String) {
+            description("Starting the mDNS responder failed")
+            display("Starting the mDNS responder on '{}' failed", interface)
+        }
+
         BlockExitSignals {
             description("Blocking exit signals failed")
         }
@@ -103,6 +183,76 @@ error_chain! {
             description("Root privileges required")
             display("You need root privileges to run {}", app)
         }
+
+        DBusCall(method: String, reason: String) {
+            description("D-Bus method call failed")
+            display("D-Bus method call '{}' failed: {}", method, reason)
+        }
+
+        TooManyConnectFailures(failures: u32) {
+            description("Too many connect attempts failed")
+            display("{} connect attempts failed in a row", failures)
+        }
+
+        RadioHardBlocked(interface: String) {
+            description("The WiFi radio is hard blocked")
+            display("The radio of '{}' is hard blocked by rfkill, turn it on with the physical WiFi switch of the device", interface)
+        }
+
+        PortInUse(address: String, holder: String) {
+            description("The HTTP port is in use")
+            display("Cannot start HTTP server on '{}', the port is in use by {}. Stop it, or set --fallback-port or --portal-listening-port", address, holder)
+        }
+
+        InvalidPortalNetwork(reason: String) {
+            description("Invalid portal network configuration")
+            display("Invalid portal network configuration: {}", reason)
+        }
+
+        InvalidStaticMount(reason: String) {
+            description("Invalid static mount")
+            display("Invalid static mount: {}", reason)
+        }
+
+        DropPrivileges(user: String, reason: String) {
+            description("Dropping root privileges failed")
+            display("Dropping root privileges to '{}' failed: {}", user, reason)
+        }
+
+        ServingAsRoot {
+            description("Refusing to serve the portal as root")
+            display("Refusing to serve the portal as root, set --run-as to an unprivileged user or pass --allow-root")
+        }
+
+        DBusBusy(method: String, attempts: u32) {
+            description("D-Bus method call failed while the device was busy")
+            display("D-Bus method call '{}' failed while the device was busy, giving up after {} attempts", method, attempts)
+        }
+
+        NetworkWorkerFailed(reason: String) {
+            description("The network thread failed")
+            display("The network thread {}", reason)
+        }
+
+        InvalidConfig(reason: String) {
+            description("Invalid configuration")
+            display("Invalid configuration: {}", reason)
+        }
+
+        OpenAccessLog(path: String) {
+            description("Opening the access log failed")
+            display("Opening the access log '{}' failed", path)
+        }
+
+        ActivityTimeout(timeout: u64) {
+            description("No activity before the timeout")
+            display("No client used the portal for {} seconds, exiting without joining a network", timeout)
+        }
+
+        ExitRequested {
+            description("Exit requested")
+            display("Exit requested before a network was joined")
+        }
     }
 }
 
@@ -129,6 +279,35 @@ pub fn exit_code(e: &Error) -> i32 {
         ErrorKind::TrapExitSignals => 22,
         ErrorKind::RootPrivilegesRequired(_) => 23,
         ErrorKind::UnmanagedDevice(_) => 24,
+        ErrorKind::DBusCall(_, _) => 25,
+        ErrorKind::SendNetworkCommandCancel => 26,
+        ErrorKind::SendNetworkCommandListSavedNetworks => 27,
+        ErrorKind::RecvSavedNetworks => 28,
+        ErrorKind::SendNetworkCommandStatus => 29,
+        ErrorKind::RecvStatus => 30,
+        ErrorKind::SendNetworkCommandForgetAll => 31,
+        ErrorKind::RecvForgetAll => 32,
+        ErrorKind::SendNetworkCommandExportProfile => 33,
+        ErrorKind::RecvProfileExport => 34,
+        ErrorKind::SendNetworkCommandImportProfile => 35,
+        ErrorKind::RecvProfileImport => 36,
+        ErrorKind::SendNetworkCommandSignal => 37,
+        ErrorKind::RecvSignal => 38,
+        ErrorKind::SendNetworkCommandListDevices => 39,
+        ErrorKind::RecvDevices => 40,
+        ErrorKind::TooManyConnectFailures(_) => 41,
+        ErrorKind::RadioHardBlocked(_) => 42,
+        ErrorKind::InvalidPortalNetwork(_) => 43,
+        ErrorKind::PortInUse(_, _) => 44,
+        ErrorKind::DropPrivileges(_, _) => 45,
+        ErrorKind::ServingAsRoot => 46,
+        ErrorKind::InvalidStaticMount(_) => 47,
+        ErrorKind::NetworkWorkerFailed(_) => 48,
+        ErrorKind::DBusBusy(_, _) => 49,
+        ErrorKind::ActivityTimeout(_) => 50,
+        ErrorKind::ExitRequested => 51,
+        ErrorKind::InvalidConfig(_) => 52,
+        ErrorKind::OpenAccessLog(_) => 53,
         _ => 1,
     }
 }
@@ -1,31 +1,61 @@
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{channel, Receiver, Sender};
 
-use nix::sys::signal::{SigSet, SIGHUP, SIGINT, SIGQUIT, SIGTERM};
+use nix::sys::signal::{SigSet, Signal, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1};
 
 use errors::*;
 
+/// Outcome of the provisioning flow reported to the thread waiting for it
 pub type ExitResult = Result<()>;
 
-pub fn exit(exit_tx: &Sender<ExitResult>, error: Error) {
-    let _ = exit_tx.send(Err(error));
+/// Reports the outcome of the provisioning flow, shared by the threads that
+/// may end it
+#[derive(Clone)]
+pub struct ExitSender(Sender<ExitResult>);
+
+impl ExitSender {
+    pub fn send(&self, result: ExitResult) {
+        // The receiving end only goes away once an outcome has been reported
+        let _ = self.0.send(result);
+    }
+}
+
+/// Waits for the outcome of the provisioning flow
+pub struct ExitReceiver(Receiver<ExitResult>);
+
+impl ExitReceiver {
+    pub fn recv(&self) -> Result<()> {
+        self.0.recv()?
+    }
+}
+
+pub fn exit_channel() -> (ExitSender, ExitReceiver) {
+    let (exit_tx, exit_rx) = channel();
+    (ExitSender(exit_tx), ExitReceiver(exit_rx))
+}
+
+pub fn exit(exit_tx: &ExitSender, error: Error) {
+    exit_tx.send(Err(error));
 }
 
-/// Block exit signals from the main thread with mask inherited by children
+/// Block exit signals from the main thread with mask inherited by children.
+/// The network thread relies on this to trap them.
 pub fn block_exit_signals() -> Result<()> {
     let mask = create_exit_sigmask();
     mask.thread_block()
         .chain_err(|| ErrorKind::BlockExitSignals)
 }
 
-/// Trap exit signals from a signal handling thread
-pub fn trap_exit_signals() -> Result<()> {
+/// Trap exit signals from a signal handling thread. `SIGHUP` is trapped as
+/// well, which asks for a refresh instead of exiting, and `SIGUSR1`, which
+/// asks for a state dump.
+pub fn trap_exit_signals() -> Result<Signal> {
     let mask = create_exit_sigmask();
 
     let sig = mask.wait().chain_err(|| ErrorKind::TrapExitSignals)?;
 
     info!("\nReceived {:?}", sig);
 
-    Ok(())
+    Ok(sig)
 }
 
 fn create_exit_sigmask() -> SigSet {
@@ -35,6 +65,7 @@ fn create_exit_sigmask() -> SigSet {
     mask.add(SIGQUIT);
     mask.add(SIGTERM);
     mask.add(SIGHUP);
+    mask.add(SIGUSR1);
 
     mask
 }
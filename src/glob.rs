@@ -0,0 +1,42 @@
+/// Whether the text matches a shell style pattern, where `*` stands for any
+/// run of characters and `?` for a single one
+pub fn matches(pattern: &str, text: &str, ignore_case: bool) -> bool {
+    let normalize = |value: &str| -> Vec<char> {
+        if ignore_case {
+            value.to_lowercase().chars().collect()
+        } else {
+            value.chars().collect()
+        }
+    };
+
+    let pattern = normalize(pattern);
+    let text = normalize(text);
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and of the text it was tried against
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` swallow one more character
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
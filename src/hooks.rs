@@ -0,0 +1,190 @@
+use std::io::{BufRead, BufReader, Read};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::Config;
+use network::{event_channel, EventReceiver, EventSender, NetworkEvent};
+
+/// A hook still running after this long is killed
+const HOOK_TIMEOUT_SECS: u64 = 30;
+
+/// How often a running hook is checked for having exited
+const HOOK_POLL_INTERVAL_MS: u64 = 100;
+
+/// Enough for the hooks of the last events to run before exiting
+const FLUSH_TIMEOUT_SECS: u64 = HOOK_TIMEOUT_SECS + 5;
+
+/// Settings that may have been passed through the environment, which the
+/// hooks are not to see
+const SECRET_ENV: [&str; 3] = ["PORTAL_PASSPHRASE", "WEBHOOK_TOKEN", "API_TOKEN"];
+
+/// Runs the `--on-portal-start`, `--on-connect` and `--on-connect-fail`
+/// commands from a dedicated thread, one at a time. Their failures are
+/// logged, they never block or fail the provisioning flow itself.
+pub struct Hooks {
+    event_tx: EventSender,
+    done_rx: Receiver<()>,
+}
+
+struct HookCommands {
+    on_portal_start: Option<String>,
+    on_connect: Option<String>,
+    on_connect_fail: Option<String>,
+}
+
+impl Hooks {
+    pub fn spawn(config: &Config) -> Option<Self> {
+        let commands = HookCommands {
+            on_portal_start: config.on_portal_start.clone(),
+            on_connect: config.on_connect.clone(),
+            on_connect_fail: config.on_connect_fail.clone(),
+        };
+
+        if commands.on_portal_start.is_none()
+            && commands.on_connect.is_none()
+            && commands.on_connect_fail.is_none()
+        {
+            return None;
+        }
+
+        let (event_tx, event_rx) = event_channel();
+        let (done_tx, done_rx) = channel();
+
+        thread::spawn(move || {
+            run_hooks(&commands, &event_rx);
+            let _ = done_tx.send(());
+        });
+
+        Some(Hooks { event_tx, done_rx })
+    }
+
+    pub fn event_tx(&self) -> EventSender {
+        self.event_tx.clone()
+    }
+
+    /// Waits for the hooks of queued events to finish before exiting
+    pub fn flush(self) {
+        let Hooks { event_tx, done_rx } = self;
+
+        drop(event_tx);
+
+        if done_rx
+            .recv_timeout(Duration::from_secs(FLUSH_TIMEOUT_SECS))
+            .is_err()
+        {
+            warn!("Giving up on pending hooks");
+        }
+    }
+}
+
+fn run_hooks(commands: &HookCommands, event_rx: &EventReceiver) {
+    while let Ok(event) = event_rx.recv() {
+        let (name, command, env) = match event {
+            NetworkEvent::PortalStarted => ("on-portal-start", &commands.on_portal_start, vec![]),
+            NetworkEvent::Connected { ref ssid, ip, .. } => (
+                "on-connect",
+                &commands.on_connect,
+                vec![
+                    ("WC_SSID", ssid.clone()),
+                    ("WC_IP", ip.map_or_else(String::new, |ip| ip.to_string())),
+                ],
+            ),
+            NetworkEvent::ConnectFailed {
+                ref ssid,
+                ref reason,
+            } => (
+                "on-connect-fail",
+                &commands.on_connect_fail,
+                vec![("WC_SSID", ssid.clone()), ("WC_REASON", reason.clone())],
+            ),
+            _ => continue,
+        };
+
+        if let Some(ref command) = *command {
+            run_hook(name, command, &env);
+        }
+    }
+}
+
+/// Runs the command with `sh -c`, logging its output line by line and
+/// killing it once it overran the timeout
+fn run_hook(name: &str, command: &str, env: &[(&str, String)]) {
+    debug!("Running the {} hook: {}", name, command);
+
+    let mut hook = Command::new("sh");
+
+    for key in SECRET_ENV.iter() {
+        hook.env_remove(key);
+    }
+
+    let mut child = match hook
+        .arg("-c")
+        .arg(command)
+        .envs(env.iter().map(|&(key, ref value)| (key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            warn!("Running the {} hook failed: {}", name, e);
+            return;
+        }
+    };
+
+    // Not waited for, as processes the hook leaves running in the
+    // background may keep its output open
+    if let Some(stdout) = child.stdout.take() {
+        log_output(name, stdout);
+    }
+
+    if let Some(stderr) = child.stderr.take() {
+        log_output(name, stderr);
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(HOOK_TIMEOUT_SECS);
+
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() < deadline => {
+                thread::sleep(Duration::from_millis(HOOK_POLL_INTERVAL_MS))
+            }
+            Ok(None) => {
+                warn!(
+                    "The {} hook is still running after {}s, killing it",
+                    name, HOOK_TIMEOUT_SECS
+                );
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
+            }
+            Err(e) => {
+                warn!("Waiting for the {} hook failed: {}", name, e);
+                break None;
+            }
+        }
+    };
+
+    match status {
+        Some(ref status) if status.success() => debug!("The {} hook succeeded", name),
+        Some(status) => warn!("The {} hook failed: {}", name, status),
+        None => {}
+    }
+}
+
+fn log_output<R: Read + Send + 'static>(name: &str, output: R) {
+    let name = name.to_string();
+
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            match line {
+                Ok(line) => info!("{} hook: {}", name, line),
+                Err(_) => break,
+            }
+        }
+    });
+}
@@ -0,0 +1,54 @@
+use backend::bus::{call_method, method_call, system_bus, DBUS_TIMEOUT_MS};
+use backend::is_valid_domain_name;
+use errors::*;
+
+const HOSTNAMED_SERVICE: &str = "org.freedesktop.hostname1";
+const HOSTNAMED_PATH: &str = "/org/freedesktop/hostname1";
+
+/// The kernel limits host names to 64 bytes
+const MAX_HOSTNAME_LENGTH: usize = 64;
+
+/// An RFC 1123 host name, without a trailing dot
+pub fn is_valid_hostname(hostname: &str) -> bool {
+    hostname.len() <= MAX_HOSTNAME_LENGTH
+        && !hostname.ends_with('.')
+        && is_valid_domain_name(hostname)
+}
+
+/// Sets the host name through systemd-hostnamed, which NetworkManager saves
+/// it with as well. A transient name only lasts until the next boot.
+pub fn set_hostname(hostname: &str, transient: bool) -> Result<()> {
+    let connection = system_bus()?;
+
+    if !transient {
+        let message = method_call(
+            HOSTNAMED_SERVICE,
+            HOSTNAMED_PATH,
+            HOSTNAMED_SERVICE,
+            "SetStaticHostname",
+        )?
+        .append2(hostname, false);
+
+        call_method(&connection, message, "SetStaticHostname", DBUS_TIMEOUT_MS)?;
+    }
+
+    // Applies the name right away, as a static one only takes effect when no
+    // transient name is set
+    let message = method_call(
+        HOSTNAMED_SERVICE,
+        HOSTNAMED_PATH,
+        HOSTNAMED_SERVICE,
+        "SetHostname",
+    )?
+    .append2(hostname, false);
+
+    call_method(&connection, message, "SetHostname", DBUS_TIMEOUT_MS)?;
+
+    info!(
+        "Host name set to '{}'{}",
+        hostname,
+        if transient { " until reboot" } else { "" }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde_json::{self, Value};
+
+use wifi_connect::NetworkEvent;
+
+/// Bumped whenever a field changes meaning or goes away, new events and
+/// fields do not change it
+const SCHEMA_VERSION: u32 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Writes the events to stdout from now on, logs going to stderr anyway
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+/// Event callback writing a line for each lifecycle event
+pub fn write_event(event: &NetworkEvent) {
+    let line = match *event {
+        NetworkEvent::PortalStarted => json!({ "event": "portal-started" }),
+        NetworkEvent::ClientConnected { ref mac, ip } => json!({
+            "event": "client-connected",
+            "mac": mac,
+            "ip": ip,
+        }),
+        // The credentials themselves are never written
+        NetworkEvent::ConnectAttempt { ref ssid, timeout } => json!({
+            "event": "credentials-received",
+            "ssid": ssid,
+            "timeout": timeout,
+        }),
+        NetworkEvent::Connected { ref ssid, ip, .. } => json!({
+            "event": "connected",
+            "ssid": ssid,
+            "ip": ip,
+        }),
+        NetworkEvent::ConnectFailed {
+            ref ssid,
+            ref reason,
+        } => json!({
+            "event": "failed",
+            "ssid": ssid,
+            "reason": reason,
+        }),
+        NetworkEvent::ConnectCancelled { ref ssid } => json!({
+            "event": "cancelled",
+            "ssid": ssid,
+        }),
+        // Its attempts were written one by one
+        NetworkEvent::BulkConnectFinished { .. } | NetworkEvent::NetworksRefreshed => return,
+        // Written with the exit code once the flow is over
+        NetworkEvent::Exiting => return,
+    };
+
+    write_line(line);
+}
+
+/// The network joined, written right before `exiting` on success
+pub fn write_summary(mut line: Value) {
+    line["event"] = json!("summary");
+    write_line(line);
+}
+
+/// The last event, with the exit code of the process
+pub fn write_exiting(code: i32) {
+    if is_enabled() {
+        write_line(json!({ "event": "exiting", "code": code }));
+    }
+}
+
+fn write_line(mut line: Value) {
+    line["version"] = json!(SCHEMA_VERSION);
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    // The reader going away must not end provisioning
+    let _ = serde_json::to_writer(&mut stdout, &line)
+        .map_err(io::Error::from)
+        .and_then(|()| writeln!(stdout))
+        .and_then(|()| stdout.flush());
+}
@@ -0,0 +1,165 @@
+//! The WiFi Connect provisioning flow: a captive portal access point with a
+//! web UI for choosing the WiFi network the device should join.
+//!
+//! Applications embedding the flow build a `Config` with a `PortalBuilder`
+//! and call `run`, after blocking the exit signals from their main thread:
+//!
+//! ```no_run
+//! extern crate wifi_connect;
+//!
+//! use wifi_connect::{block_exit_signals, PortalBuilder};
+//!
+//! fn main() {
+//!     block_exit_signals().unwrap();
+//!
+//!     let config = PortalBuilder::new()
+//!         .ssid("Device Setup")
+//!         .activity_timeout(600)
+//!         .on_event(|event| println!("{}", event.name()))
+//!         .build();
+//!
+//!     wifi_connect::run(config).unwrap();
+//! }
+//! ```
+
+#![recursion_limit = "1024"]
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate serde_derive;
+
+extern crate base64;
+extern crate dbus;
+extern crate hyper;
+extern crate iron;
+extern crate iron_cors;
+extern crate mount;
+extern crate network_manager;
+extern crate nix;
+extern crate params;
+extern crate persistent;
+extern crate qrcode;
+extern crate regex;
+extern crate router;
+extern crate serde;
+extern crate serde_json;
+extern crate staticfile;
+
+#[cfg(test)]
+extern crate jsonschema;
+
+mod access_log;
+mod backend;
+pub mod config;
+#[cfg(feature = "dbus-interface")]
+mod dbus_interface;
+mod diagnostics;
+mod dnsmasq;
+mod errors;
+mod exit;
+mod glob;
+mod hooks;
+mod hostname;
+mod locale;
+mod logs;
+mod mdns;
+mod metrics;
+mod network;
+mod poison;
+mod privileges;
+mod server;
+mod state_file;
+mod static_files;
+mod watchdog;
+mod webhook;
+
+use config::GATEWAY6_PREFIX;
+
+pub use backend::{ConnectSecurity, Credentials, Metered, Ssid};
+pub use config::{
+    BackendKind, CaptiveMode, Config, DuplicatePolicy, LogFormat, PortalBuilder, WorkerFailure,
+};
+pub use diagnostics::STATE_DUMP_TARGET;
+pub use dnsmasq::{PortalClient, PortalClients};
+pub use errors::{exit_code, Error, ErrorKind, Result};
+pub use exit::{block_exit_signals, exit_channel, ExitReceiver, ExitResult, ExitSender};
+pub use logs::{LogBuffer, LogLine};
+pub use network::{
+    command_channel, event_channel, init_networking, process_network_commands, response_channel,
+    ConnectStrategy, EventCallback, EventReceiver, EventSender, Network, NetworkCommand,
+    NetworkCommandReceiver, NetworkCommandResponse, NetworkCommandSender, NetworkEvent,
+    NetworkInterface, NetworkResponseReceiver, NetworkResponseSender, ProfileDocument,
+    ProfileOptions, ProfileSettings, QueueDepth, RfkillInfo, SavedNetwork, Signal, WirelessInfo,
+};
+pub use server::{start_server, ServerConfig, ServerHandle, ServerStopper};
+
+use backend::detect_backend;
+use privileges::{check_root_allowed, require_root};
+use watchdog::spawn_network_thread;
+
+/// Checks the configuration without touching the network stack, which `run`
+/// does first as well
+pub fn validate_config(config: &Config) -> Result<()> {
+    if let Err(reason) = config.validate_settings() {
+        bail!(ErrorKind::InvalidConfig(reason));
+    }
+
+    if let Err(reason) = config.validate_portal_network() {
+        bail!(ErrorKind::InvalidPortalNetwork(reason));
+    }
+
+    if let Err(reason) = config.validate_static_mounts() {
+        bail!(ErrorKind::InvalidStaticMount(reason));
+    }
+
+    Ok(())
+}
+
+/// Runs the provisioning flow until the device joins a network, the activity
+/// timeout elapses or an exit signal is received. Only joining a network
+/// returns `Ok`, the other two return `ActivityTimeout` and `ExitRequested`.
+/// With `run_forever` joining a network does not end the flow, which then
+/// only ends on an exit signal or an error.
+pub fn run(config: Config) -> Result<()> {
+    let mut config = config;
+
+    validate_config(&config)?;
+
+    if config.backend.requires_root() {
+        require_root()?;
+    }
+
+    check_root_allowed(
+        config.run_as.as_ref().map(String::as_str),
+        config.allow_root,
+    )?;
+
+    info!(
+        "Portal network {}/{} with the DHCP range {}",
+        config.gateway,
+        config.gateway_prefix,
+        config.effective_dhcp_range()
+    );
+
+    if let (Some(gateway6), Some(network6)) = (config.gateway6, config.gateway6_network()) {
+        info!(
+            "Portal IPv6 network {}/{} with the gateway {}",
+            network6, GATEWAY6_PREFIX, gateway6
+        );
+    }
+
+    config.backend = detect_backend(config.backend)?;
+
+    init_networking(&config)?;
+
+    let (exit_tx, exit_rx) = exit_channel();
+
+    spawn_network_thread(config, exit_tx);
+
+    exit_rx.recv()
+}
@@ -0,0 +1,172 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Map, Value};
+
+/// Language of the strings built into the UI, served when no translation
+/// matches or a translation cannot be read
+pub const DEFAULT_LANGUAGE: &str = "en";
+
+/// Translations of the UI, one `<language>.json` object of strings per
+/// language, like `de.json` or `pt-br.json`, in `locales/` of the UI
+/// directory. The directory is read on every request, so that translations
+/// can be added without restarting the portal.
+pub struct Locales {
+    directory: PathBuf,
+}
+
+impl Locales {
+    pub fn new<P: AsRef<Path>>(ui_directory: P) -> Self {
+        Locales {
+            directory: ui_directory.as_ref().join("locales"),
+        }
+    }
+
+    /// The language of the translation matching the `lang` override best,
+    /// or else `Accept-Language`, English if none does
+    pub fn select(&self, lang: Option<&str>, accept_language: Option<&str>) -> String {
+        let available = self.available();
+
+        let ranges = lang
+            .into_iter()
+            .map(String::from)
+            .chain(accept_language.map_or_else(Vec::new, language_ranges));
+
+        for range in ranges {
+            if let Some(language) = lookup(&range, &available) {
+                return language;
+            }
+        }
+
+        DEFAULT_LANGUAGE.to_string()
+    }
+
+    /// The strings of the language, and the language they are in, which is
+    /// English if its translation cannot be read. Without an English one
+    /// either, the UI keeps the strings it is built with.
+    pub fn strings(&self, language: &str) -> (String, Map<String, Value>) {
+        match self.read(language) {
+            Ok(strings) => return (language.to_string(), strings),
+            Err(reason) => {
+                if language != DEFAULT_LANGUAGE || self.path(language).exists() {
+                    warn!("Serving English strings, as {}", reason);
+                }
+            }
+        }
+
+        if language != DEFAULT_LANGUAGE {
+            match self.read(DEFAULT_LANGUAGE) {
+                Ok(strings) => return (DEFAULT_LANGUAGE.to_string(), strings),
+                Err(reason) => {
+                    if self.path(DEFAULT_LANGUAGE).exists() {
+                        warn!("Serving no strings, as {}", reason);
+                    }
+                }
+            }
+        }
+
+        (DEFAULT_LANGUAGE.to_string(), Map::new())
+    }
+
+    /// Languages of the translations in the directory, as their files are
+    /// named
+    fn available(&self) -> Vec<String> {
+        let entries = match fs::read_dir(&self.directory) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| {
+                path.extension()
+                    .map_or(false, |extension| extension == "json")
+            })
+            .filter_map(|path| {
+                path.file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .filter(|stem| is_language_tag(stem))
+                    .map(String::from)
+            })
+            .collect()
+    }
+
+    fn path(&self, language: &str) -> PathBuf {
+        self.directory.join(format!("{}.json", language))
+    }
+
+    fn read(&self, language: &str) -> ::std::result::Result<Map<String, Value>, String> {
+        let path = self.path(language);
+
+        let json = fs::read_to_string(&path)
+            .map_err(|e| format!("reading {} failed: {}", path.display(), e))?;
+
+        match serde_json::from_str(&json) {
+            Ok(Value::Object(strings)) => Ok(strings),
+            Ok(_) => Err(format!("{} is not a JSON object", path.display())),
+            Err(e) => Err(format!("{} is malformed: {}", path.display(), e)),
+        }
+    }
+}
+
+/// The language ranges of `Accept-Language`, most preferred first, without
+/// the wildcard and the ones refused with `q=0`
+fn language_ranges(accept_language: &str) -> Vec<String> {
+    let mut ranges = accept_language
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let range = parts.next()?;
+
+            let quality = parts
+                .filter_map(|param| {
+                    let param = param.replace(' ', "");
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+
+            if range.is_empty() || range == "*" || quality <= 0.0 {
+                None
+            } else {
+                Some((range.to_string(), quality))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Stable, so that ranges of the same quality keep their order
+    ranges.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+
+    ranges.into_iter().map(|(range, _)| range).collect()
+}
+
+/// RFC 4647 lookup: the range, then the range with its last subtag cut off
+/// in turn, like `de-ch` for `de-CH-1996` and then `de`, ignoring case
+fn lookup(range: &str, available: &[String]) -> Option<String> {
+    let mut range = range.to_lowercase();
+
+    loop {
+        if let Some(language) = available
+            .iter()
+            .find(|language| language.to_lowercase() == range)
+        {
+            return Some(language.clone());
+        }
+
+        match range.rfind('-') {
+            Some(end) => range.truncate(end),
+            None => return None,
+        }
+    }
+}
+
+/// Letters, digits and dashes only, which keeps the file names of the
+/// translations a request can pick within the directory
+fn is_language_tag(tag: &str) -> bool {
+    !tag.is_empty() && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
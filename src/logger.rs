@@ -1,27 +1,100 @@
-use env_logger::LogBuilder;
-use log::{LogLevelFilter, LogRecord};
+use env_logger::{LogBuilder, Logger};
+use log::{self, Log, LogLevelFilter, LogMetadata, LogRecord};
+use serde_json::{self, Value};
 use std::env;
 
-pub fn init() {
+use wifi_connect::{LogBuffer, LogFormat, STATE_DUMP_TARGET};
+
+/// Copies the records written to stderr into the buffer of `/logs`
+struct BufferedLogger {
+    logger: Logger,
+    buffer: Option<LogBuffer>,
+}
+
+impl Log for BufferedLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        Log::enabled(&self.logger, metadata)
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        self.logger.log(record);
+
+        if let Some(ref buffer) = self.buffer {
+            buffer.push(record.level(), &record.args().to_string());
+        }
+    }
+}
+
+fn text_record(record: &LogRecord) -> String {
+    format!(
+        "[{}:{}:{}:{}] {}",
+        record.location().file(),
+        record.location().line(),
+        record.location().module_path(),
+        record.level(),
+        record.args()
+    )
+}
+
+/// A single line even for a multi-line message. The message of the state
+/// dump is its JSON object, which goes in `state` instead.
+fn json_record(record: &LogRecord) -> String {
+    let message = record.args().to_string();
+
+    let (message, state) = if record.target() == STATE_DUMP_TARGET {
+        let state = serde_json::from_str(&message).unwrap_or(Value::Null);
+        ("State dump".to_string(), Some(state))
+    } else {
+        (message, None)
+    };
+
+    let mut line = json!({
+        "level": record.level().to_string(),
+        "module": record.location().module_path(),
+        "file": record.location().file(),
+        "line": record.location().line(),
+        "message": message,
+    });
+
+    if let Some(state) = state {
+        line["state"] = state;
+    }
+
+    line.to_string()
+}
+
+pub fn init(buffer: Option<LogBuffer>, log_format: LogFormat) {
     let mut builder = LogBuilder::new();
 
-    if env::var("RUST_LOG").is_ok() {
+    let from_env = env::var("RUST_LOG").is_ok();
+
+    if from_env {
         builder.parse(&env::var("RUST_LOG").unwrap());
     } else {
-        let format = |record: &LogRecord| {
-            format!(
-                "[{}:{}:{}:{}] {}",
-                record.location().file(),
-                record.location().line(),
-                record.location().module_path(),
-                record.level(),
-                record.args()
-            )
-        };
-
-        builder.format(format).filter(None, LogLevelFilter::Info);
+        builder.filter(None, LogLevelFilter::Info);
         builder.parse("wifi-connect=info,iron::iron=info");
     }
 
-    builder.init().unwrap();
+    // A `$RUST_LOG` filter keeps the format of env_logger for text
+    match log_format {
+        LogFormat::Json => {
+            builder.format(json_record);
+        }
+        LogFormat::Text if !from_env => {
+            builder.format(text_record);
+        }
+        LogFormat::Text => {}
+    }
+
+    let logger = builder.build();
+
+    log::set_logger(|max_level| {
+        max_level.set(logger.filter());
+        Box::new(BufferedLogger { logger, buffer })
+    })
+    .unwrap();
 }
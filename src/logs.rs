@@ -0,0 +1,104 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::LogLevel;
+
+use poison::recover_poisoned_quietly;
+
+/// A line of the log buffer
+#[derive(Clone, Debug, Serialize)]
+pub struct LogLine {
+    /// Seconds since the epoch the line was logged
+    pub timestamp: u64,
+    /// `error`, `warn`, `info`, `debug` or `trace`
+    pub level: String,
+    pub message: String,
+    #[serde(skip)]
+    severity: LogLevel,
+}
+
+#[derive(Debug)]
+struct Lines {
+    capacity: usize,
+    lines: VecDeque<LogLine>,
+}
+
+/// The most recent log lines, kept in memory for `/logs`. Once full, the
+/// oldest line is dropped for each new one.
+#[derive(Clone, Debug)]
+pub struct LogBuffer(Arc<Mutex<Lines>>);
+
+impl LogBuffer {
+    pub fn new(capacity: usize) -> Self {
+        LogBuffer(Arc::new(Mutex::new(Lines {
+            capacity,
+            lines: VecDeque::with_capacity(capacity),
+        })))
+    }
+
+    pub fn push(&self, level: LogLevel, message: &str) {
+        let line = LogLine {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or(0),
+            level: level.to_string().to_lowercase(),
+            message: redact_passphrases(message),
+            severity: level,
+        };
+
+        let mut buffer = self.0.lock().unwrap_or_else(recover_poisoned_quietly);
+
+        if buffer.capacity == 0 {
+            return;
+        }
+
+        if buffer.lines.len() == buffer.capacity {
+            buffer.lines.pop_front();
+        }
+
+        buffer.lines.push_back(line);
+    }
+
+    /// Up to `count` of the latest lines at least as severe as `level`,
+    /// oldest first
+    pub fn recent(&self, count: usize, level: LogLevel) -> Vec<LogLine> {
+        let buffer = self.0.lock().unwrap_or_else(recover_poisoned_quietly);
+
+        let mut lines = buffer
+            .lines
+            .iter()
+            .rev()
+            .filter(|line| line.severity <= level)
+            .take(count)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        lines.reverse();
+        lines
+    }
+}
+
+/// Request URLs are logged with their query string, which may carry the
+/// credentials of a `/connect` request
+fn redact_passphrases(message: &str) -> String {
+    const KEY: &str = "passphrase=";
+
+    let mut redacted = String::with_capacity(message.len());
+    let mut rest = message;
+
+    while let Some(start) = rest.find(KEY) {
+        let value = &rest[start + KEY.len()..];
+        let end = value
+            .find(|c: char| c == '&' || c.is_whitespace())
+            .unwrap_or_else(|| value.len());
+
+        redacted.push_str(&rest[..start + KEY.len()]);
+        redacted.push_str("***");
+        rest = &value[end..];
+    }
+
+    redacted.push_str(rest);
+    redacted
+}
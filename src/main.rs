@@ -1,52 +1,35 @@
-#![recursion_limit = "1024"]
-
 #[macro_use]
 extern crate log;
 
 #[macro_use]
-extern crate error_chain;
-
-#[macro_use]
-extern crate serde_derive;
+extern crate clap;
 
 #[macro_use]
-extern crate clap;
+extern crate serde_json;
 
 extern crate env_logger;
-extern crate iron;
-extern crate iron_cors;
-extern crate mount;
-extern crate network_manager;
-extern crate nix;
-extern crate params;
-extern crate persistent;
-extern crate router;
-extern crate serde_json;
-extern crate staticfile;
+extern crate wifi_connect;
 
-mod config;
-mod dnsmasq;
-mod errors;
-mod exit;
+mod cli;
+mod json_events;
 mod logger;
-mod network;
-mod privileges;
-mod server;
+mod summary;
 
 use std::io::Write;
-use std::path;
 use std::process;
-use std::sync::mpsc::channel;
-use std::thread;
+use std::sync::Arc;
 
-use config::get_config;
-use errors::*;
-use exit::block_exit_signals;
-use network::{init_networking, process_network_commands};
-use privileges::require_root;
+use wifi_connect::{block_exit_signals, exit_code, validate_config, Result};
+
+use cli::get_options;
+use summary::Summary;
 
 fn main() {
-    if let Err(ref e) = run() {
+    let result = run();
+
+    json_events::write_exiting(result.as_ref().map_or_else(exit_code, |_| 0));
+
+    if let Err(ref e) = result {
         let stderr = &mut ::std::io::stderr();
         let errmsg = "Error writing to stderr";
 
@@ -65,26 +48,27 @@ fn run() -> Result<()> {
 
     block_exit_signals()?;
 
-    logger::init();
+    let options = get_options();
+    let mut config = options.config;
 
-    let config = get_config();
+    // Before anything is logged or the network stack is touched
+    validate_config(&config)?;
 
-    require_root()?;
+    if options.print_config {
+        println!("{:#?}", config);
+        return Ok(());
+    }
 
-    init_networking(&config)?;
+    logger::init(config.log_buffer.clone(), config.log_format);
 
-    let (exit_tx, exit_rx) = channel();
+    let summary = Summary::default();
+    config.event_callbacks.push(Arc::new(summary.recorder()));
 
-    thread::spawn(move || {
-        process_network_commands(&config, &exit_tx);
-    });
+    // Only joining a network returns `Ok`, the only exit of the flow with
+    // code 0
+    wifi_connect::run(config)?;
 
-    match exit_rx.recv() {
-        Ok(result) => result?,
-        Err(e) => {
-            return Err(e.into());
-        }
-    }
+    summary.print();
 
     Ok(())
 }
@@ -0,0 +1,468 @@
+use std::ffi::OsString;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::io::FromRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use nix::sys::socket::{
+    bind, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn,
+};
+
+use errors::*;
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Set on the class of records only this responder answers for
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set on the class of questions asking for a unicast reply
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+/// TTLs recommended by RFC 6762 for host and service records
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+
+const HTTP_SERVICE: &str = "_http._tcp.local";
+const SERVICES_ENUMERATION: &str = "_services._dns-sd._udp.local";
+
+/// Answers the mDNS queries for the portal's host name and its `_http._tcp`
+/// service on the portal interface only, so that it never competes with
+/// Avahi on the other networks of the device
+pub struct MdnsResponder {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl MdnsResponder {
+    pub fn start(name: &str, interface: &str, address: Ipv4Addr, port: u16) -> Result<Self> {
+        let socket =
+            bind_socket(interface, address).chain_err(|| ErrorKind::Mdns(interface.to_string()))?;
+
+        let records = Records::new(name, address, port);
+
+        info!(
+            "Announcing the portal as {} on {}",
+            records.hostname, interface
+        );
+
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let thread = {
+            let stopped = stopped.clone();
+            thread::spawn(move || respond(&socket, &records, &stopped))
+        };
+
+        Ok(MdnsResponder { stopped, thread })
+    }
+
+    /// Withdraws the records and waits for the responder to exit
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+
+        let _ = self.thread.join();
+    }
+}
+
+fn bind_socket(interface: &str, address: Ipv4Addr) -> Result<UdpSocket> {
+    let fd = socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    // Owns the descriptor from here on, closing it on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    // Avahi may be bound to the port for the other interfaces
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+    setsockopt(fd, sockopt::ReusePort, &true)?;
+    setsockopt(fd, sockopt::BindToDevice, &OsString::from(interface))?;
+
+    let any = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), MDNS_PORT);
+    bind(fd, &SockaddrIn::from(any))?;
+
+    socket.join_multicast_v4(&MDNS_GROUP, &address)?;
+    socket.set_multicast_ttl_v4(255)?;
+    // Lets the responder notice it was stopped
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    Ok(socket)
+}
+
+fn respond(socket: &UdpSocket, records: &Records, stopped: &AtomicBool) {
+    let group = SocketAddr::V4(SocketAddrV4::new(MDNS_GROUP, MDNS_PORT));
+
+    send(socket, &records.announcement(false), group);
+
+    let mut buffer = [0; 9000];
+
+    while !stopped.load(Ordering::SeqCst) {
+        let (size, source) = match socket.recv_from(&mut buffer) {
+            Ok(received) => received,
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                continue
+            }
+            Err(e) => {
+                warn!("Receiving mDNS queries failed: {}", e);
+                break;
+            }
+        };
+
+        let query = match Query::parse(&buffer[..size]) {
+            Some(query) => query,
+            None => continue,
+        };
+
+        // Resolvers not implementing mDNS query from an ephemeral port
+        let legacy = source.port() != MDNS_PORT;
+
+        if let Some(reply) = records.reply(&query, legacy) {
+            let unicast = legacy || query.questions.iter().all(|question| question.unicast);
+            send(socket, &reply, if unicast { source } else { group });
+        }
+    }
+
+    send(socket, &records.announcement(true), group);
+}
+
+fn send(socket: &UdpSocket, packet: &Packet, destination: SocketAddr) {
+    if let Err(e) = socket.send_to(&packet.encode(), destination) {
+        warn!("Sending mDNS reply to {} failed: {}", destination, e);
+    }
+}
+
+/// The records of the portal host and its web UI service
+struct Records {
+    hostname: String,
+    instance: String,
+    address: Ipv4Addr,
+    port: u16,
+}
+
+impl Records {
+    /// `name` gets the `.local` domain appended unless it already ends in it
+    fn new(name: &str, address: Ipv4Addr, port: u16) -> Self {
+        let mut hostname = name.trim_end_matches('.').to_lowercase();
+
+        if !hostname.ends_with(".local") {
+            hostname.push_str(".local");
+        }
+
+        let label = hostname[..hostname.len() - ".local".len()].replace('.', "-");
+
+        Records {
+            instance: format!("{}.{}", label, HTTP_SERVICE),
+            hostname,
+            address,
+            port,
+        }
+    }
+
+    fn a(&self, ttl: u32) -> Record {
+        Record::unique(&self.hostname, TYPE_A, ttl, self.address.octets().to_vec())
+    }
+
+    fn ptr(&self, ttl: u32) -> Record {
+        Record::shared(HTTP_SERVICE, TYPE_PTR, ttl, encode_name(&self.instance))
+    }
+
+    fn srv(&self, ttl: u32) -> Record {
+        let mut data = vec![0, 0, 0, 0];
+        data.extend_from_slice(&[(self.port >> 8) as u8, self.port as u8]);
+        data.extend(encode_name(&self.hostname));
+
+        Record::unique(&self.instance, TYPE_SRV, ttl, data)
+    }
+
+    fn txt(&self, ttl: u32) -> Record {
+        let path = b"path=/";
+
+        let mut data = vec![path.len() as u8];
+        data.extend_from_slice(path);
+
+        Record::unique(&self.instance, TYPE_TXT, ttl, data)
+    }
+
+    fn enumeration(&self, ttl: u32) -> Record {
+        Record::shared(
+            SERVICES_ENUMERATION,
+            TYPE_PTR,
+            ttl,
+            encode_name(HTTP_SERVICE),
+        )
+    }
+
+    /// All records, with a zero TTL for the goodbye sent when stopping
+    fn announcement(&self, goodbye: bool) -> Packet {
+        let (host_ttl, service_ttl) = if goodbye {
+            (0, 0)
+        } else {
+            (HOST_TTL, SERVICE_TTL)
+        };
+
+        Packet {
+            id: 0,
+            questions: Vec::new(),
+            answers: vec![
+                self.a(host_ttl),
+                self.ptr(service_ttl),
+                self.srv(host_ttl),
+                self.txt(service_ttl),
+            ],
+            additionals: Vec::new(),
+        }
+    }
+
+    fn reply(&self, query: &Query, legacy: bool) -> Option<Packet> {
+        let mut answers = Vec::new();
+        let mut additionals = Vec::new();
+
+        for question in &query.questions {
+            let any = question.qtype == TYPE_ANY;
+
+            if question.name == self.hostname {
+                if any || question.qtype == TYPE_A {
+                    answers.push(self.a(HOST_TTL));
+                }
+            } else if question.name == HTTP_SERVICE {
+                if any || question.qtype == TYPE_PTR {
+                    answers.push(self.ptr(SERVICE_TTL));
+                    additionals.push(self.srv(HOST_TTL));
+                    additionals.push(self.txt(SERVICE_TTL));
+                    additionals.push(self.a(HOST_TTL));
+                }
+            } else if question.name == self.instance {
+                if any || question.qtype == TYPE_SRV {
+                    answers.push(self.srv(HOST_TTL));
+                    additionals.push(self.a(HOST_TTL));
+                }
+                if any || question.qtype == TYPE_TXT {
+                    answers.push(self.txt(SERVICE_TTL));
+                }
+            } else if question.name == SERVICES_ENUMERATION && (any || question.qtype == TYPE_PTR) {
+                answers.push(self.enumeration(SERVICE_TTL));
+            }
+        }
+
+        if answers.is_empty() {
+            return None;
+        }
+
+        let mut packet = Packet {
+            id: 0,
+            questions: Vec::new(),
+            answers,
+            additionals,
+        };
+
+        // Legacy replies echo the query and must not set the cache flush bit
+        if legacy {
+            packet.id = query.id;
+            packet.questions = query
+                .questions
+                .iter()
+                .map(|question| (question.name.clone(), question.qtype))
+                .collect();
+
+            for record in packet.answers.iter_mut().chain(&mut packet.additionals) {
+                record.unique = false;
+            }
+        }
+
+        Some(packet)
+    }
+}
+
+struct Record {
+    name: String,
+    rtype: u16,
+    /// Whether the cache flush bit is set
+    unique: bool,
+    ttl: u32,
+    data: Vec<u8>,
+}
+
+impl Record {
+    fn unique(name: &str, rtype: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Record {
+            name: name.to_string(),
+            rtype,
+            unique: true,
+            ttl,
+            data,
+        }
+    }
+
+    fn shared(name: &str, rtype: u16, ttl: u32, data: Vec<u8>) -> Self {
+        Record {
+            name: name.to_string(),
+            rtype,
+            unique: false,
+            ttl,
+            data,
+        }
+    }
+}
+
+struct Packet {
+    id: u16,
+    questions: Vec<(String, u16)>,
+    answers: Vec<Record>,
+    additionals: Vec<Record>,
+}
+
+impl Packet {
+    /// Names are written in full, replies are far below the size that would
+    /// make compressing them worthwhile
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(512);
+
+        push_u16(&mut bytes, self.id);
+        // An authoritative answer
+        push_u16(&mut bytes, 0x8400);
+        push_u16(&mut bytes, self.questions.len() as u16);
+        push_u16(&mut bytes, self.answers.len() as u16);
+        push_u16(&mut bytes, 0);
+        push_u16(&mut bytes, self.additionals.len() as u16);
+
+        for &(ref name, qtype) in &self.questions {
+            bytes.extend(encode_name(name));
+            push_u16(&mut bytes, qtype);
+            push_u16(&mut bytes, CLASS_IN);
+        }
+
+        for record in self.answers.iter().chain(&self.additionals) {
+            bytes.extend(encode_name(&record.name));
+            push_u16(&mut bytes, record.rtype);
+            push_u16(
+                &mut bytes,
+                if record.unique {
+                    CLASS_IN | CACHE_FLUSH
+                } else {
+                    CLASS_IN
+                },
+            );
+            push_u16(&mut bytes, (record.ttl >> 16) as u16);
+            push_u16(&mut bytes, record.ttl as u16);
+            push_u16(&mut bytes, record.data.len() as u16);
+            bytes.extend_from_slice(&record.data);
+        }
+
+        bytes
+    }
+}
+
+struct Question {
+    /// Lowercase, without the trailing dot
+    name: String,
+    qtype: u16,
+    unicast: bool,
+}
+
+struct Query {
+    id: u16,
+    questions: Vec<Question>,
+}
+
+impl Query {
+    /// Responses and malformed packets yield `None`
+    fn parse(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 12 || read_u16(packet, 2)? & 0x8000 != 0 {
+            return None;
+        }
+
+        let count = read_u16(packet, 4)?;
+        let mut offset = 12;
+        let mut questions = Vec::with_capacity(count as usize);
+
+        for _ in 0..count {
+            let (name, end) = read_name(packet, offset)?;
+            let qclass = read_u16(packet, end + 2)?;
+
+            questions.push(Question {
+                name,
+                qtype: read_u16(packet, end)?,
+                unicast: qclass & UNICAST_RESPONSE != 0,
+            });
+
+            offset = end + 4;
+        }
+
+        Some(Query {
+            id: read_u16(packet, 0)?,
+            questions,
+        })
+    }
+}
+
+/// Reads the possibly compressed name at `offset`, returning it with the
+/// offset following it
+fn read_name(packet: &[u8], offset: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    let mut position = offset;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let length = *packet.get(position)? as usize;
+
+        if length == 0 {
+            position += 1;
+            break;
+        } else if length & 0xc0 == 0xc0 {
+            // Guards against pointer loops
+            jumps += 1;
+            if jumps > 16 {
+                return None;
+            }
+
+            if end.is_none() {
+                end = Some(position + 2);
+            }
+
+            position = ((length & 0x3f) << 8) | *packet.get(position + 1)? as usize;
+        } else {
+            let label = packet.get(position + 1..position + 1 + length)?;
+            labels.push(String::from_utf8_lossy(label).to_lowercase());
+            position += 1 + length;
+        }
+    }
+
+    Some((labels.join("."), end.unwrap_or(position)))
+}
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(name.len() + 2);
+
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        bytes.push(label.len() as u8);
+        bytes.extend_from_slice(label);
+    }
+
+    bytes.push(0);
+    bytes
+}
+
+fn read_u16(packet: &[u8], offset: usize) -> Option<u16> {
+    let bytes = packet.get(offset..offset + 2)?;
+    Some((u16::from(bytes[0]) << 8) | u16::from(bytes[1]))
+}
+
+fn push_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push((value >> 8) as u8);
+    bytes.push(value as u8);
+}
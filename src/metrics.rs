@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use access_log::AccessEntry;
+use poison::{self, recover_poisoned};
+
+/// Upper bounds of the request duration buckets, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct Counters {
+    /// Requests by method and status code
+    requests: BTreeMap<(String, u16), u64>,
+    /// Requests by the first bucket of `DURATION_BUCKETS` they fit in,
+    /// the last one counting those taking longer than all
+    durations: [u64; 12],
+    duration_ms_sum: u128,
+    response_bytes: u64,
+}
+
+/// Counters of the requests answered, served by `GET /metrics` in the
+/// Prometheus text format. They are fed the same entries as the access
+/// log, so that both agree with the `RES` log lines.
+#[derive(Default)]
+pub struct Metrics(Mutex<Counters>);
+
+impl Metrics {
+    pub fn record(&self, entry: &AccessEntry) {
+        let mut counters = self.0.lock().unwrap_or_else(recover_poisoned);
+
+        *counters
+            .requests
+            .entry((entry.method.to_string(), entry.status))
+            .or_insert(0) += 1;
+
+        let seconds = entry.duration_ms as f64 / 1000.0;
+        let bucket = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+
+        counters.durations[bucket] += 1;
+        counters.duration_ms_sum += entry.duration_ms;
+        counters.response_bytes += entry.size.unwrap_or(0);
+    }
+
+    pub fn render(&self) -> String {
+        let counters = self.0.lock().unwrap_or_else(recover_poisoned);
+        let mut text = String::new();
+
+        text.push_str(
+            "# HELP wifi_connect_http_requests_total HTTP requests answered by the portal\n\
+             # TYPE wifi_connect_http_requests_total counter\n",
+        );
+        for (&(ref method, status), count) in &counters.requests {
+            let _ = writeln!(
+                text,
+                "wifi_connect_http_requests_total{{method=\"{}\",status=\"{}\"}} {}",
+                method, status, count
+            );
+        }
+
+        text.push_str(
+            "# HELP wifi_connect_http_request_duration_seconds Time taken to answer HTTP requests\n\
+             # TYPE wifi_connect_http_request_duration_seconds histogram\n",
+        );
+        let mut cumulative = 0;
+        for (bound, count) in DURATION_BUCKETS.iter().zip(counters.durations.iter()) {
+            cumulative += count;
+            let _ = writeln!(
+                text,
+                "wifi_connect_http_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, cumulative
+            );
+        }
+        cumulative += counters.durations[DURATION_BUCKETS.len()];
+        let _ = writeln!(
+            text,
+            "wifi_connect_http_request_duration_seconds_bucket{{le=\"+Inf\"}} {}\n\
+             wifi_connect_http_request_duration_seconds_sum {}\n\
+             wifi_connect_http_request_duration_seconds_count {}",
+            cumulative,
+            counters.duration_ms_sum as f64 / 1000.0,
+            cumulative
+        );
+
+        let _ = writeln!(
+            text,
+            "# HELP wifi_connect_http_response_bytes_total Bytes of the HTTP response bodies of a known length\n\
+             # TYPE wifi_connect_http_response_bytes_total counter\n\
+             wifi_connect_http_response_bytes_total {}",
+            counters.response_bytes
+        );
+
+        let _ = writeln!(
+            text,
+            "# HELP wifi_connect_lock_poison_recoveries_total Locks left poisoned by a panicking thread and taken regardless\n\
+             # TYPE wifi_connect_lock_poison_recoveries_total counter\n\
+             wifi_connect_lock_poison_recoveries_total {}",
+            poison::recoveries()
+        );
+
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        method: &'static str,
+        status: u16,
+        duration_ms: u128,
+        size: Option<u64>,
+    ) -> AccessEntry<'static> {
+        AccessEntry {
+            client: "192.168.42.10:50000",
+            method,
+            path: "/networks",
+            version: "HTTP/1.1",
+            status,
+            size,
+            duration_ms,
+            user_agent: "-",
+        }
+    }
+
+    #[test]
+    fn renders_the_recorded_requests() {
+        let metrics = Metrics::default();
+        metrics.record(&entry("GET", 200, 3, Some(512)));
+        metrics.record(&entry("GET", 200, 40, None));
+        metrics.record(&entry("POST", 400, 12_000, Some(88)));
+
+        let text = metrics.render();
+
+        assert!(
+            text.contains("wifi_connect_http_requests_total{method=\"GET\",status=\"200\"} 2\n")
+        );
+        assert!(
+            text.contains("wifi_connect_http_requests_total{method=\"POST\",status=\"400\"} 1\n")
+        );
+        assert!(
+            text.contains("wifi_connect_http_request_duration_seconds_bucket{le=\"0.005\"} 1\n")
+        );
+        assert!(text.contains("wifi_connect_http_request_duration_seconds_bucket{le=\"0.05\"} 2\n"));
+        assert!(text.contains("wifi_connect_http_request_duration_seconds_bucket{le=\"10\"} 2\n"));
+        assert!(text.contains("wifi_connect_http_request_duration_seconds_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("wifi_connect_http_request_duration_seconds_sum 12.043\n"));
+        assert!(text.contains("wifi_connect_http_request_duration_seconds_count 3\n"));
+        assert!(text.contains("wifi_connect_http_response_bytes_total 600\n"));
+        assert!(text.contains("# TYPE wifi_connect_lock_poison_recoveries_total counter\n"));
+    }
+}
@@ -1,302 +1,2179 @@
-use std::collections::HashSet;
-use std::net::Ipv4Addr;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, SocketAddrV4};
 use std::process;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{
+    channel, Receiver, RecvError, RecvTimeoutError, SendError, Sender, TryRecvError,
+};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use network_manager::{
-    AccessPoint, AccessPointCredentials, Connection, ConnectionState, Connectivity, Device,
-    DeviceState, DeviceType, NetworkManager, Security, ServiceState,
-};
+use nix::ifaddrs::getifaddrs;
+use nix::sys::signal::Signal;
 
-use config::Config;
-use dnsmasq::{start_dnsmasq, stop_dnsmasq};
+use backend::devices::{
+    assign_ipv6_address, default_routes, delete_default_route, operating_channel, unblock_radio,
+    wireless_capabilities,
+};
+#[cfg(feature = "iwd")]
+use backend::iwd::IwdBackend;
+#[cfg(feature = "mock-backend")]
+use backend::mock::MockBackend;
+use backend::nm::NetworkManagerBackend;
+#[cfg(feature = "wpa")]
+use backend::wpa::WpaBackend;
+use backend::{
+    new_uuid, AccessPointInfo, CancelFlag, ConnectOptions, ConnectOutcome, ConnectSecurity,
+    Connectivity, Credentials, DeviceInfo, Metered, NetworkBackend, PortalChannel, ProfileExport,
+    SavedProfile, Security, SignalInfo, Ssid, WirelessCapabilities,
+};
+use config::{BackendKind, Config, DuplicatePolicy, LogFormat, GATEWAY6_PREFIX};
+#[cfg(feature = "dbus-interface")]
+use dbus_interface;
+use diagnostics::{Diagnostics, STATE_DUMP_TARGET};
+use dnsmasq::{start_dnsmasq, stop_dnsmasq, PortalClient, PortalClients};
 use errors::*;
-use exit::{exit, trap_exit_signals, ExitResult};
-use server::start_server;
-
+use exit::{exit, trap_exit_signals, ExitResult, ExitSender};
+use hooks::Hooks;
+use mdns::MdnsResponder;
+use privileges::drop_privileges;
+use server::{start_server, ServerConfig, ServerHandle};
+use state_file::StateFile;
+use watchdog::{Heartbeat, HEARTBEAT_INTERVAL};
+use webhook::Webhook;
+
+/// Longest delay between attempts at raising the portal access point
+const AP_RETRY_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Time between the connectivity checks of `--run-forever`
+const CONNECTIVITY_CHECK_INTERVAL: Duration = Duration::from_secs(20);
+
+/// Checks in a row finding the device offline before the portal is raised
+/// again, so that a short drop does not take it off the network
+const OFFLINE_CHECKS: u32 = 3;
+
+/// Requests handled by the network thread. Those replied to carry a
+/// response channel of their own, so a reply only ever reaches the request
+/// it answers, and one whose requester gave up is logged and dropped.
 pub enum NetworkCommand {
-    Activate,
+    /// A client opened the portal, replied to with the scanned networks
+    Activate(NetworkResponseSender),
+    /// Like `Activate`, after scanning again where the backend allows it
+    Rescan(NetworkResponseSender),
+    #[cfg(feature = "dbus-interface")]
+    ListNetworks(NetworkResponseSender),
+    /// Replied to with the saved client connection profiles
+    ListSavedNetworks(NetworkResponseSender),
+    /// Replied to with the state of the active connection of the device
+    Status(NetworkResponseSender),
+    /// Replied to with the link to the access point the device joined
+    Signal(NetworkResponseSender),
+    /// Replied to with the network adapters of the system
+    ListDevices(NetworkResponseSender),
+    /// Deletes every saved client connection profile, replied to with the
+    /// deleted ones. A dry run only lists them.
+    ForgetAll {
+        dry_run: bool,
+        response_tx: NetworkResponseSender,
+    },
+    /// Scans again where the backend allows it, or refreshes the scan
+    /// results, so that clients get fresh networks. Sent on `SIGHUP`.
+    Refresh,
+    /// Checks whether the joined network is still online, sent periodically
+    /// with `--run-forever`
+    CheckConnectivity,
+    /// The activity timeout elapsed
     Timeout,
     Exit,
+    /// Stops the portal and tries to join a network
     Connect {
-        ssid: String,
-        identity: String,
-        passphrase: String,
+        ssid: Ssid,
+        credentials: Credentials,
+        /// Seconds the attempt may take, clamped to the configured maximum
+        timeout: Option<u64>,
+        profile: ProfileOptions,
+    },
+    /// Like `Connect` for several networks at once, see `BulkNetwork`
+    ConnectBulk(Vec<BulkNetwork>),
+    /// Aborts the connect attempt in progress and brings the portal back
+    Cancel,
+    /// A device took its first DHCP lease on the portal network
+    ClientJoined(PortalClient),
+    /// Replied to with the saved profile of the UUID in its portable form
+    ExportProfile {
+        uuid: String,
+        include_secrets: bool,
+        response_tx: NetworkResponseSender,
+    },
+    /// Saves a profile without activating it, replied to with its UUID
+    ImportProfile {
+        document: ProfileDocument,
+        response_tx: NetworkResponseSender,
     },
 }
 
+/// A scanned network as listed to clients. An SSID that is not valid UTF-8
+/// is listed with replacement characters, and in full as base64 `ssid_raw`.
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct Network {
-    ssid: String,
-    security: String,
+    pub ssid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_raw: Option<String>,
+    pub security: String,
+    /// Whether a saved profile exists for the SSID
+    #[serde(default)]
+    pub known: bool,
+    /// UUID of the most recently used saved profile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Seconds since the epoch of the latest scan the network was found by
+    #[serde(default)]
+    pub last_seen: u64,
+    /// Whether the device is connected to the network, left out otherwise
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub in_use: bool,
+}
+
+impl Network {
+    pub fn new(ssid: &Ssid, security: Security) -> Self {
+        Network {
+            ssid: ssid.to_string(),
+            ssid_raw: ssid.to_raw(),
+            security: security.as_str().to_string(),
+            known: false,
+            uuid: None,
+            last_seen: 0,
+            in_use: false,
+        }
+    }
+
+    /// The SSID exactly as scanned
+    pub fn raw_ssid(&self) -> Ssid {
+        self.ssid_raw
+            .as_ref()
+            .and_then(|ssid_raw| Ssid::from_raw(ssid_raw))
+            .unwrap_or_else(|| Ssid::from(&self.ssid as &str))
+    }
+}
+
+/// A saved client connection profile as listed to clients
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+pub struct SavedNetwork {
+    pub ssid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_raw: Option<String>,
+    pub priority: i32,
+    pub search_domains: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    /// Seconds since the epoch the profile was last activated, 0 if unknown
+    #[serde(default)]
+    pub last_used: u64,
+}
+
+impl SavedNetwork {
+    fn new(profile: &SavedProfile) -> Self {
+        SavedNetwork {
+            ssid: profile.ssid.to_string(),
+            ssid_raw: profile.ssid.to_raw(),
+            priority: profile.priority,
+            search_domains: profile.search_domains.clone(),
+            uuid: profile.uuid.clone(),
+            last_used: profile.last_used,
+        }
+    }
+
+    /// The SSID exactly as saved
+    pub fn raw_ssid(&self) -> Ssid {
+        self.ssid_raw
+            .as_ref()
+            .and_then(|ssid_raw| Ssid::from_raw(ssid_raw))
+            .unwrap_or_else(|| Ssid::from(&self.ssid as &str))
+    }
+}
+
+/// A saved profile in the portable form exported from and imported into
+/// devices. Unknown fields are rejected, so an imported document only sets
+/// what a connect request could.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileDocument {
+    pub ssid: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ssid_raw: Option<String>,
+    pub settings: ProfileSettings,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileSettings {
+    /// A `ConnectSecurity` name
+    pub security: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    /// Only exported on request
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<String>,
+    /// The configured default autoconnect when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub autoconnect: Option<bool>,
+    #[serde(default)]
+    pub priority: i32,
+    /// A `Metered` name, `auto` when absent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metered: Option<String>,
+    #[serde(default)]
+    pub dns: Vec<IpAddr>,
+    #[serde(default)]
+    pub search_domains: Vec<String>,
+}
+
+impl ProfileDocument {
+    fn new(profile: &ProfileExport) -> Self {
+        let credentials = &profile.credentials;
+
+        ProfileDocument {
+            ssid: profile.ssid.to_string(),
+            ssid_raw: profile.ssid.to_raw(),
+            settings: ProfileSettings {
+                security: credentials
+                    .security
+                    .unwrap_or(ConnectSecurity::Open)
+                    .as_str()
+                    .to_string(),
+                identity: credentials.identity.clone(),
+                passphrase: credentials.passphrase.clone(),
+                autoconnect: Some(profile.autoconnect),
+                priority: profile.priority,
+                metered: Some(profile.metered.as_str().to_string()),
+                dns: profile.dns.clone(),
+                search_domains: profile.search_domains.clone(),
+            },
+        }
+    }
+
+    /// The SSID exactly as exported
+    pub fn raw_ssid(&self) -> Ssid {
+        self.ssid_raw
+            .as_ref()
+            .and_then(|ssid_raw| Ssid::from_raw(ssid_raw))
+            .unwrap_or_else(|| Ssid::from(&self.ssid as &str))
+    }
+
+    /// The profile to save, none if a setting has an unknown value
+    fn to_export(&self, default_autoconnect: bool) -> Option<ProfileExport> {
+        let settings = &self.settings;
+
+        let metered = match settings.metered {
+            Some(ref metered) => Metered::from_name(metered)?,
+            None => Metered::Auto,
+        };
+
+        Some(ProfileExport {
+            ssid: self.raw_ssid(),
+            credentials: Credentials {
+                identity: settings.identity.clone(),
+                passphrase: settings.passphrase.clone(),
+                security: Some(ConnectSecurity::from_name(&settings.security)?),
+            },
+            autoconnect: settings.autoconnect.unwrap_or(default_autoconnect),
+            priority: settings.priority,
+            metered,
+            dns: settings.dns.clone(),
+            search_domains: settings.search_domains.clone(),
+        })
+    }
+}
+
+/// Settings of the connection profile created by `NetworkCommand::Connect`,
+/// absent ones take their defaults
+#[derive(Clone, Debug, Default)]
+pub struct ProfileOptions {
+    /// Overrides the configured default autoconnect
+    pub autoconnect: Option<bool>,
+    /// Autoconnect priority, 0 when absent
+    pub priority: Option<i32>,
+    /// `Auto` when absent
+    pub metered: Option<Metered>,
+    /// Resolvers replacing the DHCP provided ones, none keeps the latter
+    pub dns: Vec<IpAddr>,
+    pub search_domains: Vec<String>,
+    /// Without credentials, selects the saved profile to activate. Otherwise
+    /// the UUID the profile is created with, generated when absent.
+    pub uuid: Option<String>,
+}
+
+/// A network of `NetworkCommand::ConnectBulk`. Its profile is created
+/// before any of the networks is attempted, so the credentials carry the
+/// security type, and the UUID is generated ahead where the network stack
+/// identifies profiles by one.
+#[derive(Clone, Debug)]
+pub struct BulkNetwork {
+    pub ssid: Ssid,
+    pub credentials: Credentials,
+    /// Seconds the attempt may take, clamped to the configured maximum
+    pub timeout: Option<u64>,
+    pub profile: ProfileOptions,
+}
+
+/// The link to the access point the device joined, as listed to clients
+#[derive(Debug, Serialize)]
+pub struct Signal {
+    pub ssid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ssid_raw: Option<String>,
+    pub bssid: String,
+    /// Signal quality in percent
+    pub strength: u8,
+    /// dBm, if the network stack reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i32>,
+    /// Kbit/s, if the network stack reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bitrate: Option<u32>,
+    /// MHz, if the network stack reports it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub frequency: Option<u32>,
+}
+
+impl Signal {
+    fn new(signal: &SignalInfo) -> Self {
+        Signal {
+            ssid: signal.ssid.to_string(),
+            ssid_raw: signal.ssid.to_raw(),
+            bssid: signal.bssid.clone(),
+            strength: signal.strength,
+            rssi: signal.rssi,
+            bitrate: signal.bitrate,
+            frequency: signal.frequency,
+        }
+    }
+}
+
+/// A network adapter as listed to clients
+#[derive(Debug, Serialize)]
+pub struct NetworkInterface {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mac: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver: Option<String>,
+    pub state: String,
+    /// Whether the portal network is hosted on it
+    pub portal: bool,
+    /// Gateway address and prefix length of the portal network, on the
+    /// interface hosting it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    /// Channel the portal access point operates on, where it ended up after
+    /// any fallbacks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wireless: Option<WirelessInfo>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rfkill: Option<RfkillInfo>,
+}
+
+/// Capabilities of a WiFi adapter, unknown ones are left out
+#[derive(Debug, Serialize)]
+pub struct WirelessInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ap_mode: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ap_sta: Option<bool>,
+    #[serde(rename = "5ghz", skip_serializing_if = "Option::is_none")]
+    pub band_5ghz: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_scan_ssids: Option<u32>,
+}
+
+impl NetworkInterface {
+    fn new(device: &DeviceInfo, portal: Option<&Config>) -> Self {
+        NetworkInterface {
+            name: device.interface.clone(),
+            kind: device.kind.clone(),
+            mac: device.mac.clone(),
+            driver: device.driver.clone(),
+            state: device.state.clone(),
+            portal: portal.is_some(),
+            address: portal.map(|config| format!("{}/{}", config.gateway, config.gateway_prefix)),
+            channel: portal.and_then(|_| operating_channel(&device.interface)),
+            wireless: device.wireless.as_ref().map(WirelessInfo::new),
+            rfkill: device.rfkill.map(|rfkill| RfkillInfo {
+                soft_blocked: rfkill.soft_blocked,
+                hard_blocked: rfkill.hard_blocked,
+            }),
+        }
+    }
+}
+
+impl WirelessInfo {
+    fn new(capabilities: &WirelessCapabilities) -> Self {
+        WirelessInfo {
+            ap_mode: capabilities.ap_mode,
+            ap_sta: capabilities.ap_sta,
+            band_5ghz: capabilities.band_5ghz,
+            max_scan_ssids: capabilities.max_scan_ssids,
+        }
+    }
+}
+
+/// The rfkill switch of an adapter
+#[derive(Debug, Serialize)]
+pub struct RfkillInfo {
+    pub soft_blocked: bool,
+    pub hard_blocked: bool,
+}
+
+/// State of the active connection of the device
+#[derive(Debug)]
+pub struct ConnectionStatus {
+    pub metered: Metered,
+}
+
+/// Provisioning lifecycle events reported to consumers other than the HTTP
+/// server, e.g. the D-Bus interface, webhooks and library callbacks
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum NetworkEvent {
+    PortalStarted,
+    ClientConnected {
+        mac: String,
+        ip: Ipv4Addr,
+    },
+    ConnectAttempt {
+        ssid: String,
+        /// Seconds the attempt may take, after clamping the requested one
+        timeout: u64,
+    },
+    Connected {
+        ssid: String,
+        ip: Option<Ipv4Addr>,
+        /// Resolvers in effect after the activation
+        dns: Vec<IpAddr>,
+        /// UUID of the connection profile, if the network stack has one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        uuid: Option<String>,
+        interface: String,
+    },
+    ConnectFailed {
+        ssid: String,
+        reason: String,
+    },
+    ConnectCancelled {
+        ssid: String,
+    },
+    /// The attempts of a bulk connect are over, `ssid` being the network
+    /// that activated, if any
+    BulkConnectFinished {
+        ssid: Option<String>,
+    },
+    /// The networks were scanned again on request, invalidating the lists
+    /// clients were given
+    NetworksRefreshed,
+    Exiting,
+}
+
+impl NetworkEvent {
+    pub fn name(&self) -> &str {
+        match *self {
+            NetworkEvent::PortalStarted => "portal-started",
+            NetworkEvent::ClientConnected { .. } => "client-connected",
+            NetworkEvent::ConnectAttempt { .. } => "connect-attempt",
+            NetworkEvent::Connected { .. } => "connected",
+            NetworkEvent::ConnectFailed { .. } => "connect-failed",
+            NetworkEvent::ConnectCancelled { .. } => "connect-cancelled",
+            NetworkEvent::BulkConnectFinished { .. } => "bulk-connect-finished",
+            NetworkEvent::NetworksRefreshed => "networks-refreshed",
+            NetworkEvent::Exiting => "exiting",
+        }
+    }
+}
+
+/// How the portal is handled while a connect attempt is made
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConnectStrategy {
+    /// The portal is stopped before the attempt and brought back if it fails
+    TeardownFirst,
+    /// The portal stays up until the device joined the network
+    Concurrent,
+}
+
+/// Replies of the network thread to `NetworkCommand`s
+pub enum NetworkCommandResponse {
+    /// The networks of the latest scan, which ran `scanned_at` seconds since
+    /// the epoch, without the `weak_hidden` ones below the minimum signal.
+    /// The network the device is connected to is `active_ssid`, whether it
+    /// was scanned or not.
+    Networks {
+        networks: Vec<Network>,
+        scanned_at: u64,
+        weak_hidden: usize,
+        active_ssid: Option<Ssid>,
+    },
+    /// The saved profiles, or why they could not be listed
+    SavedNetworks(result::Result<Vec<SavedNetwork>, String>),
+    Status(result::Result<ConnectionStatus, String>),
+    /// None while no client connection is active
+    Signal(result::Result<Option<Signal>, String>),
+    Devices(result::Result<Vec<NetworkInterface>, String>),
+    /// The deleted profiles, or those that would be deleted by a dry run
+    Forgotten(result::Result<Vec<SavedNetwork>, String>),
+    /// None if no saved profile has the UUID
+    ProfileExport(result::Result<Option<ProfileDocument>, String>),
+    /// The UUID of the imported profile, if the network stack has one
+    ProfileImport(result::Result<Option<String>, String>),
+}
+
+impl NetworkCommandResponse {
+    /// What the reply carries, for logging it
+    pub fn name(&self) -> &str {
+        match *self {
+            NetworkCommandResponse::Networks { .. } => "network list",
+            NetworkCommandResponse::SavedNetworks(_) => "saved network list",
+            NetworkCommandResponse::Status(_) => "connection status",
+            NetworkCommandResponse::Signal(_) => "signal strength",
+            NetworkCommandResponse::Devices(_) => "network devices",
+            NetworkCommandResponse::Forgotten(_) => "reset",
+            NetworkCommandResponse::ProfileExport(_) => "profile export",
+            NetworkCommandResponse::ProfileImport(_) => "profile import",
+        }
+    }
+}
+
+/// Invoked from the network thread for every `NetworkEvent`
+pub type EventCallback = Arc<dyn Fn(&NetworkEvent) + Send + Sync>;
+
+/// Sending end of the command channel of the network thread. `Cancel` also
+/// raises the cancel flag right away, as the network thread does not read
+/// commands while it is busy connecting.
+#[derive(Clone)]
+pub struct NetworkCommandSender(Sender<NetworkCommand>, CancelFlag, QueueDepth);
+
+impl NetworkCommandSender {
+    pub fn send(&self, command: NetworkCommand) -> result::Result<(), SendError<NetworkCommand>> {
+        if let NetworkCommand::Cancel = command {
+            self.1.cancel();
+        }
+
+        self.2.sent();
+
+        self.0.send(command).map_err(|e| {
+            self.2.received();
+            e
+        })
+    }
+
+    /// Commands sent that the network thread did not receive yet
+    pub fn queued(&self) -> usize {
+        self.2.get()
+    }
+}
+
+/// Count of the messages in a channel, which `Receiver` does not tell
+#[derive(Clone, Default)]
+pub struct QueueDepth(Arc<AtomicUsize>);
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Counted ahead of sending, so that the receiver never counts it first
+    fn sent(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn received(&self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Receiving end of the command channel, owned by the network thread
+pub struct NetworkCommandReceiver(Receiver<NetworkCommand>, CancelFlag, QueueDepth);
+
+impl NetworkCommandReceiver {
+    pub fn recv(&self) -> result::Result<NetworkCommand, RecvError> {
+        let command = self.0.recv()?;
+        self.2.received();
+        Ok(command)
+    }
+
+    pub fn recv_timeout(
+        &self,
+        timeout: Duration,
+    ) -> result::Result<NetworkCommand, RecvTimeoutError> {
+        let command = self.0.recv_timeout(timeout)?;
+        self.2.received();
+        Ok(command)
+    }
+
+    /// Raised by sending `Cancel`, until that command is received
+    pub fn cancel_flag(&self) -> &CancelFlag {
+        &self.1
+    }
+}
+
+/// Sending end of the response channel of a single command, owned by the
+/// network thread
+#[derive(Clone)]
+pub struct NetworkResponseSender(Sender<NetworkCommandResponse>);
+
+impl NetworkResponseSender {
+    pub fn send(
+        &self,
+        response: NetworkCommandResponse,
+    ) -> result::Result<(), SendError<NetworkCommandResponse>> {
+        self.0.send(response)
+    }
+
+    /// Sends the reply, which is logged and dropped if its requester is gone,
+    /// such as a request that gave up waiting for a late scan
+    pub fn reply(&self, response: NetworkCommandResponse) {
+        let name = response.name();
+
+        if self.send(response).is_err() {
+            warn!("Dropping the {} reply, its requester is gone", name);
+        }
+    }
+}
+
+/// Receiving end of the response channel, owned by the command issuer
+pub struct NetworkResponseReceiver(Receiver<NetworkCommandResponse>);
+
+impl NetworkResponseReceiver {
+    pub fn recv(&self) -> result::Result<NetworkCommandResponse, RecvError> {
+        self.0.recv()
+    }
+}
+
+/// Sending end of the channel of an event listener, owned by the network
+/// thread. The events the listener did not receive yet are counted for the
+/// state dump.
+#[derive(Clone)]
+pub struct EventSender(Sender<NetworkEvent>, QueueDepth);
+
+impl EventSender {
+    pub fn send(&self, event: NetworkEvent) -> result::Result<(), SendError<NetworkEvent>> {
+        self.1.sent();
+
+        self.0.send(event).map_err(|e| {
+            self.1.received();
+            e
+        })
+    }
+
+    /// Shares the count of the events the listener did not receive yet
+    pub fn depth(&self) -> QueueDepth {
+        self.1.clone()
+    }
+}
+
+/// Receiving end of the channel of an event listener
+pub struct EventReceiver(Receiver<NetworkEvent>, QueueDepth);
+
+impl EventReceiver {
+    pub fn recv(&self) -> result::Result<NetworkEvent, RecvError> {
+        let event = self.0.recv()?;
+        self.1.received();
+        Ok(event)
+    }
+
+    pub fn try_recv(&self) -> result::Result<NetworkEvent, TryRecvError> {
+        let event = self.0.try_recv()?;
+        self.1.received();
+        Ok(event)
+    }
+}
+
+pub fn event_channel() -> (EventSender, EventReceiver) {
+    let (event_tx, event_rx) = channel();
+    let queued = QueueDepth::default();
+    (
+        EventSender(event_tx, queued.clone()),
+        EventReceiver(event_rx, queued),
+    )
+}
+
+pub fn command_channel() -> (NetworkCommandSender, NetworkCommandReceiver) {
+    let (command_tx, command_rx) = channel();
+    let cancel = CancelFlag::default();
+    let queued = QueueDepth::default();
+    (
+        NetworkCommandSender(command_tx, cancel.clone(), queued.clone()),
+        NetworkCommandReceiver(command_rx, cancel, queued),
+    )
 }
 
-pub enum NetworkCommandResponse {
-    Networks(Vec<Network>),
-}
+pub fn response_channel() -> (NetworkResponseSender, NetworkResponseReceiver) {
+    let (response_tx, response_rx) = channel();
+    (
+        NetworkResponseSender(response_tx),
+        NetworkResponseReceiver(response_rx),
+    )
+}
+
+struct NetworkCommandHandler<B: NetworkBackend> {
+    backend: B,
+    /// Merged from the latest scans until they expire
+    access_points: Vec<AccessPointInfo>,
+    /// Seconds since the epoch each of the access points was last scanned
+    last_seen: HashMap<Ssid, u64>,
+    /// Seconds since the epoch of the latest scan
+    scanned_at: u64,
+    /// Whether the portal access point is up, which `Activate` leaves alone
+    portal_active: bool,
+    /// MAC address of the adapter, which the portal access point has too
+    mac: Option<String>,
+    connect_strategy: ConnectStrategy,
+    /// Marks the known networks, fetched again once the profiles changed
+    saved_profiles: Option<Vec<SavedProfile>>,
+    config: Config,
+    dnsmasq: Option<process::Child>,
+    /// Filled from the DHCP log of dnsmasq, shared with the server
+    clients: PortalClients,
+    mdns: Option<MdnsResponder>,
+    server: ServerHandle,
+    network_rx: NetworkCommandReceiver,
+    event_listeners: Vec<EventSender>,
+    webhook: Option<Webhook>,
+    state_file: Option<StateFile>,
+    hooks: Option<Hooks>,
+    activated: bool,
+    /// Connect attempts that failed in a row
+    connect_failures: u32,
+    /// Whether a network was joined with `--run-forever`, after which the
+    /// activity timeout no longer applies
+    joined: bool,
+    /// Checks in a row that found the joined network offline, none while
+    /// the portal is up
+    offline_checks: Option<u32>,
+    /// Beaten while waiting for commands, for the watchdog
+    heartbeat: Heartbeat,
+    /// Dumped on `SIGUSR1`
+    diagnostics: Diagnostics,
+}
+
+impl<B: NetworkBackend> NetworkCommandHandler<B> {
+    fn new(config: &Config, exit_tx: &ExitSender, heartbeat: &Heartbeat) -> Result<Self> {
+        let (network_tx, network_rx) = command_channel();
+
+        let diagnostics = Diagnostics::new();
+
+        Self::spawn_trap_exit_signals(
+            exit_tx,
+            network_tx.clone(),
+            diagnostics.clone(),
+            config.log_format,
+        );
+
+        let mut backend = B::new(config)?;
+
+        diagnostics.record_interface(backend.interface());
+
+        unblock_radio(backend.interface())?;
+
+        let mac = adapter_mac(&backend);
+
+        // Scanned ahead of the portal, as the adapter cannot scan while it
+        // hosts the access point on most hardware
+        let access_points = get_access_points(&mut backend, config, mac.as_ref())?;
+        let scanned_at = unix_time();
+        let last_seen = access_points
+            .iter()
+            .map(|access_point| (access_point.ssid.clone(), scanned_at))
+            .collect();
+
+        diagnostics.record_scan(access_points.len(), scanned_at);
+
+        create_portal(&mut backend, config)?;
+
+        diagnostics.record_portal(true);
+
+        let mdns = start_mdns(&backend, config);
+
+        let clients = PortalClients::default();
+
+        Self::spawn_client_events(&clients, network_tx.clone());
+
+        let dnsmasq = spawn_dnsmasq(&backend, config, &clients)?;
+
+        let connect_strategy = connect_strategy(&backend);
+
+        // Without the portal access point the server listens on every
+        // interface, so the device can still be provisioned over Ethernet
+        let (listening_address, listening_interface) = if backend.hosts_portal() {
+            (config.gateway, Some(backend.portal_interface().to_string()))
+        } else {
+            (Ipv4Addr::new(0, 0, 0, 0), None)
+        };
+
+        let server = Self::start_server(
+            config,
+            listening_address,
+            listening_interface,
+            clients.clone(),
+            connect_strategy,
+            exit_tx,
+            network_tx.clone(),
+        )?;
+
+        // Everything needing root is done by now, raising the portal again
+        // only takes the network capabilities kept
+        if let Some(ref run_as) = config.run_as {
+            drop_privileges(run_as, config.allow_root)?;
+        }
+
+        let mut event_listeners = Vec::new();
+
+        let mut listen = |name, event_tx: EventSender| {
+            diagnostics.record_listener(name, event_tx.depth());
+            event_listeners.push(event_tx);
+        };
+
+        listen("server", server.event_tx());
+
+        if let Some(event_tx) = Self::spawn_dbus_interface(config, network_tx.clone()) {
+            listen("dbus", event_tx);
+        }
+
+        let webhook = Webhook::spawn(config);
+
+        if let Some(ref webhook) = webhook {
+            listen("webhook", webhook.event_tx());
+        }
+
+        let state_file = StateFile::spawn(config);
+
+        if let Some(ref state_file) = state_file {
+            listen("state-file", state_file.event_tx());
+        }
+
+        let hooks = Hooks::spawn(config);
+
+        if let Some(ref hooks) = hooks {
+            listen("hooks", hooks.event_tx());
+        }
+
+        if config.run_forever {
+            Self::spawn_connectivity_checks(network_tx.clone());
+        }
+
+        Self::spawn_activity_timeout(config, network_tx);
+
+        let config = config.clone();
+        let activated = false;
+
+        let handler = NetworkCommandHandler {
+            backend,
+            access_points,
+            last_seen,
+            scanned_at,
+            portal_active: true,
+            mac,
+            connect_strategy,
+            saved_profiles: None,
+            config,
+            dnsmasq,
+            clients,
+            mdns,
+            server,
+            network_rx,
+            event_listeners,
+            webhook,
+            state_file,
+            hooks,
+            activated,
+            connect_failures: 0,
+            joined: false,
+            offline_checks: None,
+            heartbeat: heartbeat.clone(),
+            diagnostics,
+        };
+
+        handler.notify(NetworkEvent::PortalStarted);
+
+        Ok(handler)
+    }
+
+    #[cfg(feature = "dbus-interface")]
+    fn spawn_dbus_interface(
+        config: &Config,
+        network_tx: NetworkCommandSender,
+    ) -> Option<EventSender> {
+        if !config.dbus {
+            return None;
+        }
+
+        let (event_tx, event_rx) = event_channel();
+
+        thread::spawn(move || {
+            if let Err(e) = dbus_interface::run(network_tx, event_rx) {
+                error!("D-Bus control interface stopped: {}", e);
+            }
+        });
+
+        Some(event_tx)
+    }
+
+    #[cfg(not(feature = "dbus-interface"))]
+    fn spawn_dbus_interface(
+        config: &Config,
+        _network_tx: NetworkCommandSender,
+    ) -> Option<EventSender> {
+        if config.dbus {
+            warn!("Built without the `dbus-interface` feature, ignoring --dbus");
+        }
+
+        None
+    }
+
+    fn start_server(
+        config: &Config,
+        listening_address: Ipv4Addr,
+        listening_interface: Option<String>,
+        clients: PortalClients,
+        connect_strategy: ConnectStrategy,
+        exit_tx: &ExitSender,
+        network_tx: NetworkCommandSender,
+    ) -> Result<ServerHandle> {
+        let server_config = ServerConfig {
+            listening_address,
+            listening_interface,
+            clients,
+            connect_strategy,
+            ..ServerConfig::from_config(config)
+        };
+
+        start_server(&server_config, network_tx, exit_tx.clone())
+    }
+
+    fn spawn_activity_timeout(config: &Config, network_tx: NetworkCommandSender) {
+        let activity_timeout = config.activity_timeout;
+
+        if activity_timeout == 0 {
+            return;
+        }
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs(activity_timeout));
+
+            if let Err(err) = network_tx.send(NetworkCommand::Timeout) {
+                error!(
+                    "Sending NetworkCommand::Timeout failed: {}",
+                    err.to_string()
+                );
+            }
+        });
+    }
+
+    fn spawn_connectivity_checks(network_tx: NetworkCommandSender) {
+        thread::spawn(move || loop {
+            thread::sleep(CONNECTIVITY_CHECK_INTERVAL);
+
+            if let Err(err) = network_tx.send(NetworkCommand::CheckConnectivity) {
+                error!(
+                    "Sending NetworkCommand::CheckConnectivity failed: {}",
+                    err.to_string()
+                );
+                return;
+            }
+        });
+    }
+
+    /// Passes the clients joining the portal network to the network thread,
+    /// which reports them as events
+    fn spawn_client_events(clients: &PortalClients, network_tx: NetworkCommandSender) {
+        let joined_rx = clients.subscribe();
+
+        thread::spawn(move || {
+            for client in joined_rx.iter() {
+                if network_tx
+                    .send(NetworkCommand::ClientJoined(client))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn spawn_trap_exit_signals(
+        exit_tx: &ExitSender,
+        network_tx: NetworkCommandSender,
+        diagnostics: Diagnostics,
+        log_format: LogFormat,
+    ) {
+        let exit_tx_trap = exit_tx.clone();
+
+        thread::spawn(move || loop {
+            let signal = match trap_exit_signals() {
+                Ok(signal) => signal,
+                Err(e) => {
+                    exit(&exit_tx_trap, e);
+                    return;
+                }
+            };
+
+            // Logged from the trap thread rather than the network thread,
+            // which may be the one misbehaving
+            if signal == Signal::SIGUSR1 {
+                match log_format {
+                    LogFormat::Text => info!("{}", diagnostics.dump(network_tx.queued())),
+                    LogFormat::Json => info!(
+                        target: STATE_DUMP_TARGET,
+                        "{}",
+                        diagnostics.dump_json(network_tx.queued())
+                    ),
+                }
+                continue;
+            }
+
+            // Sent from the trap thread, as nothing may be done from within
+            // a signal handler
+            if signal == Signal::SIGHUP {
+                if let Err(err) = network_tx.send(NetworkCommand::Refresh) {
+                    error!(
+                        "Sending NetworkCommand::Refresh failed: {}",
+                        err.to_string()
+                    );
+                    return;
+                }
+
+                continue;
+            }
+
+            if let Err(err) = network_tx.send(NetworkCommand::Exit) {
+                error!("Sending NetworkCommand::Exit failed: {}", err.to_string());
+            }
+
+            return;
+        });
+    }
+
+    fn run(&mut self, exit_tx: &ExitSender) {
+        let result = self.run_loop();
+        self.stop(exit_tx, result);
+    }
+
+    fn run_loop(&mut self) -> ExitResult {
+        loop {
+            let command = self.receive_network_command()?;
+
+            match command {
+                NetworkCommand::Activate(response_tx) => {
+                    self.activate(&response_tx);
+                }
+                NetworkCommand::Rescan(response_tx) => {
+                    self.rescan_networks(&response_tx);
+                }
+                #[cfg(feature = "dbus-interface")]
+                NetworkCommand::ListNetworks(response_tx) => {
+                    self.list_networks(&response_tx);
+                }
+                NetworkCommand::ListSavedNetworks(response_tx) => {
+                    self.list_saved_networks(&response_tx);
+                }
+                NetworkCommand::Status(response_tx) => {
+                    self.status(&response_tx);
+                }
+                NetworkCommand::Signal(response_tx) => {
+                    self.signal(&response_tx);
+                }
+                NetworkCommand::ListDevices(response_tx) => {
+                    self.list_devices(&response_tx);
+                }
+                NetworkCommand::ForgetAll {
+                    dry_run,
+                    response_tx,
+                } => {
+                    self.forget_all(dry_run, &response_tx);
+                }
+                NetworkCommand::Refresh => {
+                    self.refresh();
+                    self.server.reopen_logs();
+                    self.check_connectivity(true)?;
+                }
+                NetworkCommand::CheckConnectivity => {
+                    self.check_connectivity(false)?;
+                }
+                NetworkCommand::Timeout => {
+                    if !self.activated && !self.joined {
+                        info!("Timeout reached. Exiting...");
+                        bail!(ErrorKind::ActivityTimeout(self.config.activity_timeout));
+                    }
+                }
+                NetworkCommand::Exit => {
+                    info!("Exiting...");
+                    bail!(ErrorKind::ExitRequested);
+                }
+                NetworkCommand::Connect {
+                    ssid,
+                    credentials,
+                    timeout,
+                    profile,
+                } => {
+                    if self.connect(&ssid, &credentials, timeout, &profile)? && self.ends_on_join()
+                    {
+                        return Ok(());
+                    }
+                }
+                NetworkCommand::ConnectBulk(networks) => {
+                    if self.connect_bulk(&networks)? && self.ends_on_join() {
+                        return Ok(());
+                    }
+                }
+                NetworkCommand::Cancel => {
+                    // Any attempt it applied to has returned by now
+                    self.network_rx.cancel_flag().reset();
+                }
+                NetworkCommand::ClientJoined(client) => {
+                    self.notify(NetworkEvent::ClientConnected {
+                        mac: client.mac,
+                        ip: client.ip,
+                    });
+                }
+                NetworkCommand::ExportProfile {
+                    uuid,
+                    include_secrets,
+                    response_tx,
+                } => {
+                    self.export_profile(&uuid, include_secrets, &response_tx);
+                }
+                NetworkCommand::ImportProfile {
+                    document,
+                    response_tx,
+                } => {
+                    self.import_profile(&document, &response_tx);
+                }
+            }
+        }
+    }
+
+    fn receive_network_command(&self) -> Result<NetworkCommand> {
+        loop {
+            self.heartbeat.beat();
+
+            match self.network_rx.recv_timeout(HEARTBEAT_INTERVAL) {
+                Ok(command) => return Ok(command),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    // Sleep for a second, so that other threads may log error info.
+                    thread::sleep(Duration::from_secs(1));
+                    return Err(RecvError).chain_err(|| ErrorKind::RecvNetworkCommand);
+                }
+            }
+        }
+    }
+
+    fn stop(&mut self, exit_tx: &ExitSender, result: ExitResult) {
+        self.notify(NetworkEvent::Exiting);
+        self.teardown();
+        exit_tx.send(result);
+    }
+
+    /// Takes down the portal, dnsmasq and the server
+    fn teardown(&mut self) {
+        if let Some(ref mut dnsmasq) = self.dnsmasq {
+            let _ = stop_dnsmasq(dnsmasq);
+        }
+
+        if let Some(mdns) = self.mdns.take() {
+            mdns.stop();
+        }
+
+        let _ = self.backend.stop_portal(&self.config);
+
+        self.server.stop();
+
+        // Dropping the event senders lets the webhook thread drain its queue
+        self.event_listeners.clear();
+
+        if let Some(webhook) = self.webhook.take() {
+            webhook.flush();
+        }
+
+        if let Some(state_file) = self.state_file.take() {
+            state_file.flush();
+        }
+
+        if let Some(hooks) = self.hooks.take() {
+            hooks.flush();
+        }
+    }
+
+    /// Sent for every `/networks` request, so it must not disturb the portal
+    /// clients are associated with
+    fn activate(&mut self, response_tx: &NetworkResponseSender) {
+        self.activated = true;
+
+        if self.portal_active {
+            info!("Portal already active, refreshing scan results");
+            self.refresh_access_points();
+        }
+
+        let response = self.networks();
+
+        response_tx.reply(response);
+    }
+
+    /// Most adapters cannot scan while hosting the portal, for those only the
+    /// results the backend gathered meanwhile are picked up
+    fn rescan_networks(&mut self, response_tx: &NetworkResponseSender) {
+        self.activated = true;
+
+        self.scan_or_refresh();
+
+        let response = self.networks();
+
+        response_tx.reply(response);
+    }
+
+    fn refresh(&mut self) {
+        self.scan_or_refresh();
+        self.notify(NetworkEvent::NetworksRefreshed);
+    }
+
+    fn scan_or_refresh(&mut self) {
+        if !self.portal_active || self.backend.concurrent_portal() {
+            info!("Scanning for networks");
+
+            if let Err(e) = self.rescan() {
+                warn!("Scanning for networks failed: {}", e);
+                self.diagnostics
+                    .record_error(&format!("scanning for networks failed: {}", e));
+            }
+        } else {
+            info!("Cannot scan while hosting the portal, refreshing scan results");
+            self.refresh_access_points();
+        }
+    }
+
+    #[cfg(feature = "dbus-interface")]
+    fn list_networks(&mut self, response_tx: &NetworkResponseSender) {
+        self.activated = true;
+
+        let response = self.networks();
+
+        response_tx.reply(response);
+    }
+
+    /// The scanned networks, with those having a saved profile and the one
+    /// the device is connected to marked
+    fn networks(&mut self) -> NetworkCommandResponse {
+        if self.saved_profiles.is_none() {
+            match self.backend.saved_profiles() {
+                Ok(profiles) => self.saved_profiles = Some(profiles),
+                Err(e) => warn!("Getting the saved profiles failed: {}", e),
+            }
+        }
+
+        let saved_profiles = self.saved_profiles.as_ref().map_or(&[][..], Vec::as_slice);
+
+        // Weak networks stay in the scan results, so that they can still be
+        // connected to by SSID
+        let (listed, weak): (Vec<_>, Vec<_>) = self.access_points.iter().partition(|ap| {
+            ap.strength
+                .map_or(true, |strength| strength >= self.config.min_signal)
+        });
+
+        let mut networks = get_networks(&listed, saved_profiles, &self.last_seen);
+
+        let active_ssid = self.active_ssid();
+
+        if let Some(ref active_ssid) = active_ssid {
+            for network in &mut networks {
+                network.in_use = network.raw_ssid() == *active_ssid;
+            }
+        }
+
+        NetworkCommandResponse::Networks {
+            networks,
+            scanned_at: self.scanned_at,
+            weak_hidden: weak.len(),
+            active_ssid,
+        }
+    }
+
+    /// SSID of the network the device is connected to, never the portal
+    fn active_ssid(&self) -> Option<Ssid> {
+        match self.backend.signal() {
+            Ok(Some(signal)) => {
+                if signal.ssid == Ssid::from(self.config.ssid.as_str()) {
+                    None
+                } else {
+                    Some(signal.ssid)
+                }
+            }
+            Ok(None) => None,
+            Err(e) => {
+                debug!("Reading the active connection failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Picks up the results the backend has gathered since the last scan.
+    /// Backends do not start a scan while hosting the portal, so these count
+    /// as seen by the last one.
+    fn refresh_access_points(&mut self) {
+        match self.backend.scan() {
+            Ok(mut access_points) => {
+                filter_access_points(&mut access_points, &self.config, self.mac.as_ref());
+
+                let scanned_at = self.scanned_at;
+                self.merge_access_points(access_points, scanned_at);
+            }
+            Err(e) => {
+                warn!("Refreshing the scan results failed: {}", e);
+                self.diagnostics
+                    .record_error(&format!("refreshing the scan results failed: {}", e));
+            }
+        }
+    }
+
+    /// Only called while the portal is down, or with a backend that can scan
+    /// while hosting it
+    fn rescan(&mut self) -> Result<()> {
+        let access_points = get_access_points(&mut self.backend, &self.config, self.mac.as_ref())?;
+        self.scanned_at = unix_time();
+
+        let scanned_at = self.scanned_at;
+        self.merge_access_points(access_points, scanned_at);
+
+        Ok(())
+    }
+
+    /// Access points missing from the scan results are kept until they were
+    /// not seen for the network expiry, so that a scan missing a beacon does
+    /// not drop them. The expiry counts from the scan rather than the current
+    /// time, as the adapter does not scan while hosting the portal.
+    fn merge_access_points(&mut self, access_points: Vec<AccessPointInfo>, seen_at: u64) {
+        for access_point in &access_points {
+            self.last_seen.insert(access_point.ssid.clone(), seen_at);
+        }
+
+        let expiry = self.config.network_expiry;
+        self.last_seen
+            .retain(|_, last_seen| seen_at.saturating_sub(*last_seen) <= expiry);
+
+        let mut merged = access_points;
+
+        for access_point in self.access_points.drain(..) {
+            if self.last_seen.contains_key(&access_point.ssid)
+                && !merged.iter().any(|ap| ap.ssid == access_point.ssid)
+            {
+                merged.push(access_point);
+            }
+        }
+
+        self.access_points = merged;
+
+        self.diagnostics
+            .record_scan(self.access_points.len(), self.scanned_at);
+    }
+
+    fn list_saved_networks(&mut self, response_tx: &NetworkResponseSender) {
+        let saved_networks = match self.backend.saved_profiles() {
+            Ok(profiles) => {
+                let saved_networks = profiles.iter().map(SavedNetwork::new).collect();
+                self.saved_profiles = Some(profiles);
+                Ok(saved_networks)
+            }
+            Err(e) => {
+                error!("Listing saved connection profiles failed: {}", e);
+                Err(e.to_string())
+            }
+        };
+
+        response_tx.reply(NetworkCommandResponse::SavedNetworks(saved_networks));
+    }
+
+    /// The portal's own profile is never listed by the backend, so the
+    /// device stays in portal mode
+    fn forget_all(&mut self, dry_run: bool, response_tx: &NetworkResponseSender) {
+        let forgotten = match self.backend.saved_profiles() {
+            Ok(profiles) if dry_run => {
+                info!(
+                    "Reset dry run, {} saved profiles would be deleted",
+                    profiles.len()
+                );
+                Ok(profiles)
+            }
+            Ok(profiles) => Ok(self.delete_profiles(profiles)),
+            Err(e) => {
+                error!("Listing saved connection profiles failed: {}", e);
+                Err(e.to_string())
+            }
+        };
+
+        let forgotten = forgotten.map(|profiles| profiles.iter().map(SavedNetwork::new).collect());
+
+        response_tx.reply(NetworkCommandResponse::Forgotten(forgotten));
+    }
+
+    /// Returns the profiles actually deleted
+    fn delete_profiles(&mut self, profiles: Vec<SavedProfile>) -> Vec<SavedProfile> {
+        self.saved_profiles = None;
+
+        let backend = &mut self.backend;
+
+        profiles
+            .into_iter()
+            .filter(|profile| match backend.delete_profile(profile) {
+                Ok(()) => {
+                    match profile.uuid {
+                        Some(ref uuid) => {
+                            info!("Deleted saved profile '{}' ({})", profile.ssid, uuid)
+                        }
+                        None => info!("Deleted saved profile '{}'", profile.ssid),
+                    }
+                    true
+                }
+                Err(e) => {
+                    error!("Deleting saved profile '{}' failed: {}", profile.ssid, e);
+                    false
+                }
+            })
+            .collect()
+    }
+
+    fn export_profile(
+        &self,
+        uuid: &str,
+        include_secrets: bool,
+        response_tx: &NetworkResponseSender,
+    ) {
+        let backend = &self.backend;
+
+        let exported = backend
+            .saved_profiles()
+            .and_then(|profiles| {
+                match profiles
+                    .iter()
+                    .find(|profile| profile.uuid.as_ref().map(String::as_str) == Some(uuid))
+                {
+                    Some(profile) => backend
+                        .export_profile(profile, include_secrets)
+                        .map(|exported| Some(ProfileDocument::new(&exported))),
+                    None => Ok(None),
+                }
+            })
+            .map_err(|e| {
+                error!("Exporting saved profile {} failed: {}", uuid, e);
+                e.to_string()
+            });
+
+        response_tx.reply(NetworkCommandResponse::ProfileExport(exported));
+    }
+
+    fn import_profile(&mut self, document: &ProfileDocument, response_tx: &NetworkResponseSender) {
+        let uuid = self.profile_uuid(&ProfileOptions::default());
+
+        let imported = match document.to_export(self.config.default_autoconnect) {
+            Some(profile) => self
+                .backend
+                .add_profile(&profile, uuid.as_ref().map(String::as_str))
+                .map(|()| uuid)
+                .map_err(|e| {
+                    error!("Importing a profile for '{}' failed: {}", profile.ssid, e);
+                    e.to_string()
+                }),
+            None => Err("Invalid profile settings".to_string()),
+        };
+
+        if let Ok(ref uuid) = imported {
+            self.saved_profiles = None;
+
+            match *uuid {
+                Some(ref uuid) => info!("Imported saved profile '{}' ({})", document.ssid, uuid),
+                None => info!("Imported saved profile '{}'", document.ssid),
+            }
+        }
+
+        response_tx.reply(NetworkCommandResponse::ProfileImport(imported));
+    }
+
+    fn status(&self, response_tx: &NetworkResponseSender) {
+        let status = self
+            .backend
+            .metered()
+            .map(|metered| ConnectionStatus { metered })
+            .map_err(|e| {
+                error!("Getting the connection status failed: {}", e);
+                e.to_string()
+            });
+
+        response_tx.reply(NetworkCommandResponse::Status(status));
+    }
+
+    fn signal(&self, response_tx: &NetworkResponseSender) {
+        let signal = self
+            .backend
+            .signal()
+            .map(|signal| signal.as_ref().map(Signal::new))
+            .map_err(|e| {
+                error!("Getting the signal strength failed: {}", e);
+                e.to_string()
+            });
+
+        response_tx.reply(NetworkCommandResponse::Signal(signal));
+    }
+
+    fn list_devices(&self, response_tx: &NetworkResponseSender) {
+        let portal_interface = if self.backend.hosts_portal() {
+            Some(self.backend.portal_interface())
+        } else {
+            None
+        };
+
+        let devices = self
+            .backend
+            .devices()
+            .map(|devices| {
+                devices
+                    .iter()
+                    .map(|device| {
+                        let portal = portal_interface == Some(&device.interface as &str);
+                        NetworkInterface::new(
+                            device,
+                            if portal { Some(&self.config) } else { None },
+                        )
+                    })
+                    .collect()
+            })
+            .map_err(|e| {
+                error!("Listing the network devices failed: {}", e);
+                e.to_string()
+            });
+
+        response_tx.reply(NetworkCommandResponse::Devices(devices));
+    }
+
+    fn notify(&self, event: NetworkEvent) {
+        debug!("Network event: {:?}", event);
+
+        self.diagnostics.record_event(&event);
+
+        for event_tx in &self.event_listeners {
+            let _ = event_tx.send(event.clone());
+        }
+
+        for callback in &self.config.event_callbacks {
+            callback(&event);
+        }
+    }
+
+    fn connect_failed(&mut self, ssid: &Ssid, reason: String) {
+        self.connect_failures += 1;
+
+        self.notify(NetworkEvent::ConnectFailed {
+            ssid: ssid.to_string(),
+            reason,
+        });
+    }
+
+    fn connect_cancelled(&self, ssid: &Ssid) {
+        self.notify(NetworkEvent::ConnectCancelled {
+            ssid: ssid.to_string(),
+        });
+    }
+
+    /// The requested profile UUID, or a new one for network stacks that
+    /// identify profiles by UUID
+    fn profile_uuid(&self, profile: &ProfileOptions) -> Option<String> {
+        if profile.uuid.is_some() || !self.config.backend.has_profile_uuids() {
+            return profile.uuid.clone();
+        }
+
+        match new_uuid() {
+            Ok(uuid) => Some(uuid),
+            Err(e) => {
+                warn!("Generating a connection profile UUID failed: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Without credentials a saved profile of the network is activated, the
+    /// one selected by UUID or else the most recently used one
+    fn saved_profile(
+        &self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        profile: &ProfileOptions,
+    ) -> Option<SavedProfile> {
+        if !credentials.is_empty() {
+            return None;
+        }
+
+        let profiles = match self.backend.saved_profiles() {
+            Ok(profiles) => profiles,
+            Err(e) => {
+                warn!("Getting the saved profiles failed: {}", e);
+                return None;
+            }
+        };
+
+        profiles
+            .into_iter()
+            .filter(|saved| {
+                saved.ssid == *ssid
+                    && profile
+                        .uuid
+                        .as_ref()
+                        .map_or(true, |uuid| saved.uuid.as_ref() == Some(uuid))
+            })
+            .max_by_key(|saved| saved.last_used)
+    }
+
+    /// Saved profiles of the network, the portal not being listed
+    fn duplicate_profiles(&self, ssid: &Ssid) -> Vec<SavedProfile> {
+        match self.backend.saved_profiles() {
+            Ok(profiles) => profiles
+                .into_iter()
+                .filter(|saved| saved.ssid == *ssid)
+                .collect(),
+            Err(e) => {
+                warn!("Getting the saved profiles failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Fails the attempt with `--duplicate-policy error` if the network has
+    /// saved profiles, before anything is torn down
+    fn refuse_duplicates(&mut self, ssid: &Ssid) -> bool {
+        if self.config.duplicate_policy != DuplicatePolicy::Error {
+            return false;
+        }
+
+        let duplicates = self.duplicate_profiles(ssid);
+
+        if duplicates.is_empty() {
+            return false;
+        }
+
+        let uuids = duplicates
+            .iter()
+            .filter_map(|saved| saved.uuid.clone())
+            .collect::<Vec<_>>();
+
+        let reason = if uuids.is_empty() {
+            format!("{} saved profiles exist for the network", duplicates.len())
+        } else {
+            format!("saved profiles exist for the network: {}", uuids.join(", "))
+        };
+
+        warn!("Refusing the credentials for '{}': {}", ssid, reason);
+
+        self.connect_failed(ssid, reason);
+
+        true
+    }
+
+    /// Whether the flow ends now that the device joined a network. With
+    /// `--run-forever` it goes on watching the connectivity instead.
+    fn ends_on_join(&mut self) -> bool {
+        if !self.config.run_forever {
+            return true;
+        }
+
+        info!("Watching the connectivity of the joined network");
+
+        self.joined = true;
+        self.offline_checks = Some(0);
+
+        false
+    }
+
+    /// Raises the portal again once the joined network was found offline by
+    /// `OFFLINE_CHECKS` checks in a row, or by a single one `at_once`
+    fn check_connectivity(&mut self, at_once: bool) -> Result<()> {
+        let offline_checks = match self.offline_checks {
+            Some(offline_checks) => offline_checks,
+            None => return Ok(()),
+        };
+
+        match self.backend.connectivity() {
+            Ok(Connectivity::None) => {}
+            Ok(_) => {
+                self.offline_checks = Some(0);
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("Getting Internet connectivity failed: {}", e);
+                return Ok(());
+            }
+        }
+
+        let offline_checks = offline_checks + 1;
+
+        if !at_once && offline_checks < OFFLINE_CHECKS {
+            info!(
+                "The device is offline, check {} of {} before raising the portal",
+                offline_checks, OFFLINE_CHECKS
+            );
+            self.offline_checks = Some(offline_checks);
+            return Ok(());
+        }
+
+        warn!("The device is offline, raising the portal again");
+
+        self.diagnostics
+            .record_error("the joined network went offline");
+
+        self.return_to_portal()
+    }
+
+    fn connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        timeout: Option<u64>,
+        profile: &ProfileOptions,
+    ) -> Result<bool> {
+        if self.attempt_connect(ssid, credentials, timeout, profile)? {
+            return Ok(true);
+        }
+
+        self.return_to_portal()?;
+
+        Ok(false)
+    }
+
+    /// Creates the profiles of all the networks, then activates them in
+    /// order until one of them succeeds, raising the portal again if none
+    /// does. `BulkConnectFinished` reports the outcome either way.
+    fn connect_bulk(&mut self, networks: &[BulkNetwork]) -> Result<bool> {
+        let mut outcome = self.activate_bulk(networks);
+
+        if let Ok(None) = outcome {
+            if let Err(e) = self.return_to_portal() {
+                outcome = Err(e);
+            }
+        }
+
+        let ssid = match outcome {
+            Ok(Some(ssid)) => Some(ssid.to_string()),
+            _ => None,
+        };
+
+        self.notify(NetworkEvent::BulkConnectFinished { ssid });
+
+        outcome.map(|activated| activated.is_some())
+    }
+
+    /// The network of a bulk connect that activated, if any. With
+    /// `--duplicate-policy keep` a network with saved profiles gets none
+    /// created, the most recently used one being updated once it is
+    /// attempted. The saved profiles the created ones replace are only
+    /// deleted once a network activated, and the created ones are deleted
+    /// again if none did or the attempts ended with an error.
+    fn activate_bulk<'a>(&mut self, networks: &'a [BulkNetwork]) -> Result<Option<&'a Ssid>> {
+        for network in networks {
+            if self.refuse_duplicates(&network.ssid) {
+                return Ok(None);
+            }
+        }
+
+        let keep = self.config.duplicate_policy == DuplicatePolicy::Keep;
 
-struct NetworkCommandHandler {
-    manager: NetworkManager,
-    device: Device,
-    access_points: Vec<AccessPoint>,
-    portal_connection: Option<Connection>,
-    config: Config,
-    dnsmasq: process::Child,
-    server_tx: Sender<NetworkCommandResponse>,
-    network_rx: Receiver<NetworkCommand>,
-    activated: bool,
-}
+        let mut created: Vec<&BulkNetwork> = Vec::new();
+        let mut replaced = Vec::new();
 
-impl NetworkCommandHandler {
-    fn new(config: &Config, exit_tx: &Sender<ExitResult>) -> Result<Self> {
-        let (network_tx, network_rx) = channel();
+        for network in networks {
+            let duplicates = self.duplicate_profiles(&network.ssid);
 
-        Self::spawn_trap_exit_signals(exit_tx, network_tx.clone());
+            if keep && !duplicates.is_empty() {
+                continue;
+            }
 
-        let manager = NetworkManager::new();
-        info!("NetworkManager connection initialized");
+            let profile = ProfileExport {
+                ssid: network.ssid.clone(),
+                credentials: network.credentials.clone(),
+                autoconnect: network
+                    .profile
+                    .autoconnect
+                    .unwrap_or(self.config.default_autoconnect),
+                priority: network.profile.priority.unwrap_or(0),
+                metered: network.profile.metered.unwrap_or(Metered::Auto),
+                dns: network.profile.dns.clone(),
+                search_domains: network.profile.search_domains.clone(),
+            };
 
-        let device = find_device(&manager, &config.interface)?;
+            let uuid = network.profile.uuid.as_ref().map(String::as_str);
 
-        let access_points = get_access_points(&device)?;
+            if let Err(e) = self.backend.add_profile(&profile, uuid) {
+                error!("Creating a profile for '{}' failed: {}", network.ssid, e);
 
-        let portal_connection = Some(create_portal(&device, config)?);
+                self.delete_created(&created);
+                self.connect_failed(&network.ssid, format!("creating the profile failed: {}", e));
 
-        let dnsmasq = start_dnsmasq(config, &device)?;
+                return Ok(None);
+            }
 
-        let (server_tx, server_rx) = channel();
+            created.push(network);
+            replaced.extend(duplicates);
+        }
 
-        Self::spawn_server(config, exit_tx, server_rx, network_tx.clone());
+        self.saved_profiles = None;
 
-        Self::spawn_activity_timeout(config, network_tx);
+        info!(
+            "Created {} profiles, activating them in order",
+            created.len()
+        );
 
-        let config = config.clone();
-        let activated = false;
+        let mut activated = None;
 
-        Ok(NetworkCommandHandler {
-            manager,
-            device,
-            access_points,
-            portal_connection,
-            config,
-            dnsmasq,
-            server_tx,
-            network_rx,
-            activated,
-        })
-    }
+        for network in networks {
+            let is_created = created.iter().any(|created| created.ssid == network.ssid);
 
-    fn spawn_server(
-        config: &Config,
-        exit_tx: &Sender<ExitResult>,
-        server_rx: Receiver<NetworkCommandResponse>,
-        network_tx: Sender<NetworkCommand>,
-    ) {
-        let gateway = config.gateway;
-        let listening_port = config.listening_port;
-        let exit_tx_server = exit_tx.clone();
-        let ui_directory = config.ui_directory.clone();
+            // Without credentials the profile just created is activated, a
+            // kept one is updated with them
+            let credentials = if is_created {
+                Credentials::default()
+            } else {
+                network.credentials.clone()
+            };
 
-        thread::spawn(move || {
-            start_server(
-                gateway,
-                listening_port,
-                server_rx,
-                network_tx,
-                exit_tx_server,
-                &ui_directory,
+            let attempt = self.attempt_connect(
+                &network.ssid,
+                &credentials,
+                network.timeout,
+                &network.profile,
             );
-        });
-    }
 
-    fn spawn_activity_timeout(config: &Config, network_tx: Sender<NetworkCommand>) {
-        let activity_timeout = config.activity_timeout;
+            match attempt {
+                Ok(true) => {
+                    activated = Some(&network.ssid);
+                    break;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    self.delete_created(&created);
+                    return Err(e);
+                }
+            }
 
-        if activity_timeout == 0 {
-            return;
+            // The remaining networks are not attempted either
+            if self.network_rx.cancel_flag().is_cancelled() {
+                break;
+            }
         }
 
-        thread::spawn(move || {
-            thread::sleep(Duration::from_secs(activity_timeout));
+        if activated.is_some() {
+            for saved in &replaced {
+                if let Err(e) = self.backend.delete_profile(saved) {
+                    warn!(
+                        "Deleting a replaced profile of '{}' failed: {}",
+                        saved.ssid, e
+                    );
+                }
+            }
 
-            if let Err(err) = network_tx.send(NetworkCommand::Timeout) {
-                error!(
-                    "Sending NetworkCommand::Timeout failed: {}",
-                    err.to_string()
+            self.saved_profiles = None;
+        } else {
+            // Joined again on their own otherwise
+            self.delete_created(&created);
+        }
+
+        Ok(activated)
+    }
+
+    /// Deletes the profiles a bulk connect created, by UUID where the network
+    /// stack has one, so that the saved profiles of the networks stay
+    fn delete_created(&mut self, created: &[&BulkNetwork]) {
+        for network in created {
+            let uuid = match network.profile.uuid {
+                Some(ref uuid) => uuid.clone(),
+                None => {
+                    self.backend.delete_connections(&network.ssid);
+                    continue;
+                }
+            };
+
+            let profile = SavedProfile {
+                ssid: network.ssid.clone(),
+                priority: network.profile.priority.unwrap_or(0),
+                search_domains: network.profile.search_domains.clone(),
+                uuid: Some(uuid),
+                last_used: 0,
+            };
+
+            if let Err(e) = self.backend.delete_profile(&profile) {
+                warn!(
+                    "Deleting the profile created for '{}' failed: {}",
+                    network.ssid, e
                 );
             }
-        });
+        }
+
+        self.saved_profiles = None;
     }
 
-    fn spawn_trap_exit_signals(exit_tx: &Sender<ExitResult>, network_tx: Sender<NetworkCommand>) {
-        let exit_tx_trap = exit_tx.clone();
+    /// A single connect attempt, which leaves the portal down after failing
+    /// with the `teardown-first` strategy
+    fn attempt_connect(
+        &mut self,
+        ssid: &Ssid,
+        credentials: &Credentials,
+        timeout: Option<u64>,
+        profile: &ProfileOptions,
+    ) -> Result<bool> {
+        let cancel = self.network_rx.cancel_flag().clone();
+
+        // Cancelled before it was dequeued, the portal is still up
+        if cancel.is_cancelled() {
+            info!("Connection to access point '{}' cancelled", ssid);
+            self.connect_cancelled(ssid);
+            return Ok(false);
+        }
 
-        thread::spawn(move || {
-            if let Err(e) = trap_exit_signals() {
-                exit(&exit_tx_trap, e);
-                return;
-            }
+        let saved = self.saved_profile(ssid, credentials, profile);
 
-            if let Err(err) = network_tx.send(NetworkCommand::Exit) {
-                error!("Sending NetworkCommand::Exit failed: {}", err.to_string());
+        // Updated in place instead of creating a profile
+        let mut kept = None;
+
+        if saved.is_none() {
+            match self.config.duplicate_policy {
+                DuplicatePolicy::Replace => self.backend.delete_connections(ssid),
+                DuplicatePolicy::Keep => {
+                    kept = self
+                        .duplicate_profiles(ssid)
+                        .into_iter()
+                        .max_by_key(|saved| saved.last_used);
+                }
+                DuplicatePolicy::Error => {
+                    if self.refuse_duplicates(ssid) {
+                        return Ok(false);
+                    }
+                }
             }
+        }
+
+        // Created, replaced or reactivated, the cached profiles are outdated
+        self.saved_profiles = None;
+
+        let teardown = self.connect_strategy == ConnectStrategy::TeardownFirst;
+
+        // Already down after an earlier attempt of a bulk connect
+        if teardown && self.portal_active {
+            self.stop_portal()?;
+        }
+
+        let timeout = self.config.effective_connect_timeout(timeout);
+
+        self.notify(NetworkEvent::ConnectAttempt {
+            ssid: ssid.to_string(),
+            timeout,
         });
-    }
 
-    fn run(&mut self, exit_tx: &Sender<ExitResult>) {
-        let result = self.run_loop();
-        self.stop(exit_tx, result);
-    }
+        if teardown {
+            self.rescan()?;
+        }
 
-    fn run_loop(&mut self) -> ExitResult {
-        loop {
-            let command = self.receive_network_command()?;
+        info!(
+            "Connecting to access point '{}' with a {} second timeout",
+            ssid, timeout
+        );
 
-            match command {
-                NetworkCommand::Activate => {
-                    self.activate()?;
-                }
-                NetworkCommand::Timeout => {
-                    if !self.activated {
-                        info!("Timeout reached. Exiting...");
-                        return Ok(());
+        let options = ConnectOptions {
+            timeout: Duration::from_secs(timeout),
+            cancel,
+            autoconnect: profile
+                .autoconnect
+                .unwrap_or(self.config.default_autoconnect),
+            priority: profile.priority.unwrap_or(0),
+            metered: profile.metered.unwrap_or(Metered::Auto),
+            dns: profile.dns.clone(),
+            search_domains: profile.search_domains.clone(),
+            uuid: match saved.as_ref().or_else(|| kept.as_ref()) {
+                Some(saved) => saved.uuid.clone(),
+                None => self.profile_uuid(profile),
+            },
+        };
+
+        let outcome = match (saved.as_ref(), kept.as_ref()) {
+            (Some(saved), _) => {
+                info!("Activating the saved profile of '{}'", ssid);
+                self.backend.activate_saved(saved, &options)
+            }
+            (None, Some(kept)) => {
+                info!("Updating the saved profile of '{}'", ssid);
+                self.backend.update_saved(kept, credentials, &options)
+            }
+            (None, None) => self.backend.connect(ssid, credentials, &options),
+        };
+
+        match outcome {
+            Ok(ConnectOutcome::Activated) => {
+                match wait_for_connectivity(&self.backend, 20) {
+                    Ok(has_connectivity) => {
+                        if has_connectivity {
+                            info!("Internet connectivity established");
+                        } else {
+                            warn!("Cannot establish Internet connectivity");
+                        }
                     }
+                    Err(err) => error!("Getting Internet connectivity failed: {}", err),
                 }
-                NetworkCommand::Exit => {
-                    info!("Exiting...");
-                    return Ok(());
+
+                if !teardown {
+                    info!("Stopping the portal, the device joined '{}'", ssid);
+
+                    if let Err(e) = self.stop_portal() {
+                        warn!("Stopping the portal failed: {}", e);
+                    }
                 }
-                NetworkCommand::Connect {
-                    ssid,
-                    identity,
-                    passphrase,
-                } => {
-                    if self.connect(&ssid, &identity, &passphrase)? {
-                        return Ok(());
+
+                let dns = match self.backend.nameservers() {
+                    Ok(dns) => dns,
+                    Err(err) => {
+                        error!("Getting the resolvers failed: {}", err);
+                        Vec::new()
                     }
+                };
+
+                info!("Using the resolvers {:?}", dns);
+
+                match options.uuid {
+                    Some(ref uuid) => info!("Connected to access point '{}' ({})", ssid, uuid),
+                    None => info!("Connected to access point '{}'", ssid),
                 }
-            }
-        }
-    }
 
-    fn receive_network_command(&self) -> Result<NetworkCommand> {
-        match self.network_rx.recv() {
-            Ok(command) => Ok(command),
+                self.notify(NetworkEvent::Connected {
+                    ssid: ssid.to_string(),
+                    ip: get_interface_ipv4(self.backend.interface()),
+                    dns,
+                    uuid: options.uuid.clone(),
+                    interface: self.backend.interface().to_string(),
+                });
+
+                return Ok(true);
+            }
+            Ok(ConnectOutcome::Failed(failure)) => {
+                self.connect_failed(ssid, failure.to_string());
+            }
+            Ok(ConnectOutcome::Cancelled) => {
+                self.connect_cancelled(ssid);
+            }
             Err(e) => {
-                // Sleep for a second, so that other threads may log error info.
-                thread::sleep(Duration::from_secs(1));
-                Err(e).chain_err(|| ErrorKind::RecvNetworkCommand)
+                warn!("Error connecting to access point '{}': {}", ssid, e);
+
+                self.connect_failed(ssid, e.to_string());
             }
         }
-    }
 
-    fn stop(&mut self, exit_tx: &Sender<ExitResult>, result: ExitResult) {
-        let _ = stop_dnsmasq(&mut self.dnsmasq);
+        // A profile created for the attempt would be joined again on its own
+        // otherwise, the saved ones of the network were deleted beforehand
+        if saved.is_none() && kept.is_none() {
+            self.backend.delete_connections(ssid);
+        }
 
-        if let Some(ref connection) = self.portal_connection {
-            let _ = stop_portal_impl(connection, &self.config);
+        let max_failures = self.config.max_failures_before_exit;
+        if max_failures != 0 && self.connect_failures >= max_failures {
+            bail!(ErrorKind::TooManyConnectFailures(self.connect_failures));
         }
 
-        let _ = exit_tx.send(result);
+        Ok(false)
     }
 
-    fn activate(&mut self) -> ExitResult {
-        self.activated = true;
+    /// Raises the portal again once the attempts are over, if they took it
+    /// down
+    fn return_to_portal(&mut self) -> Result<()> {
+        if !self.portal_active {
+            info!("Returning to portal mode");
 
-        let networks = get_networks(&self.access_points);
+            self.rescan()?;
 
-        self.server_tx
-            .send(NetworkCommandResponse::Networks(networks))
-            .chain_err(|| ErrorKind::SendAccessPointSSIDs)
-    }
+            self.start_portal()?;
+        }
 
-    fn connect(&mut self, ssid: &str, identity: &str, passphrase: &str) -> Result<bool> {
-        delete_existing_connections_to_same_network(&self.manager, ssid);
+        Ok(())
+    }
 
-        if let Some(ref connection) = self.portal_connection {
-            stop_portal(connection, &self.config)?;
+    fn stop_portal(&mut self) -> Result<()> {
+        // Once the portal is down the interface joins other networks, where
+        // the announced address is wrong
+        if let Some(mdns) = self.mdns.take() {
+            mdns.stop();
         }
 
-        self.portal_connection = None;
+        stop_portal(&mut self.backend, &self.config)?;
+        self.portal_active = false;
+        self.diagnostics.record_portal(false);
 
-        self.access_points = get_access_points(&self.device)?;
+        // Restarted along with the portal, as it is bound to its address
+        if let Some(mut dnsmasq) = self.dnsmasq.take() {
+            let _ = stop_dnsmasq(&mut dnsmasq);
+        }
 
-        if let Some(access_point) = find_access_point(&self.access_points, ssid) {
-            let wifi_device = self.device.as_wifi_device().unwrap();
+        Ok(())
+    }
 
-            info!("Connecting to access point '{}'...", ssid);
+    fn start_portal(&mut self) -> Result<()> {
+        create_portal(&mut self.backend, &self.config)?;
+        self.portal_active = true;
+        self.offline_checks = None;
+        self.diagnostics.record_portal(true);
 
-            let credentials = init_access_point_credentials(access_point, identity, passphrase);
+        self.dnsmasq = spawn_dnsmasq(&self.backend, &self.config, &self.clients)?;
 
-            match wifi_device.connect(access_point, &credentials) {
-                Ok((connection, state)) => {
-                    if state == ConnectionState::Activated {
-                        match wait_for_connectivity(&self.manager, 20) {
-                            Ok(has_connectivity) => {
-                                if has_connectivity {
-                                    info!("Internet connectivity established");
-                                } else {
-                                    warn!("Cannot establish Internet connectivity");
-                                }
-                            }
-                            Err(err) => error!("Getting Internet connectivity failed: {}", err),
-                        }
+        self.mdns = start_mdns(&self.backend, &self.config);
 
-                        return Ok(true);
-                    }
+        self.notify(NetworkEvent::PortalStarted);
 
-                    if let Err(err) = connection.delete() {
-                        error!("Deleting connection object failed: {}", err)
-                    }
+        Ok(())
+    }
+}
 
-                    warn!(
-                        "Connection to access point not activated '{}': {:?}",
-                        ssid, state
-                    );
-                }
-                Err(e) => {
-                    warn!("Error connecting to access point '{}': {}", ssid, e);
-                }
-            }
+impl<B: NetworkBackend> Drop for NetworkCommandHandler<B> {
+    fn drop(&mut self) {
+        // A panic skips `stop`, which would leave dnsmasq and the access
+        // point behind for the restarted program
+        if thread::panicking() {
+            self.teardown();
         }
-
-        self.access_points = get_access_points(&self.device)?;
-
-        self.portal_connection = Some(create_portal(&self.device, &self.config)?);
-
-        Ok(false)
     }
 }
 
-fn init_access_point_credentials(
-    access_point: &AccessPoint,
-    identity: &str,
-    passphrase: &str,
-) -> AccessPointCredentials {
-    if access_point.security.contains(Security::ENTERPRISE) {
-        AccessPointCredentials::Enterprise {
-            identity: identity.to_string(),
-            passphrase: passphrase.to_string(),
-        }
-    } else if access_point.security.contains(Security::WPA2)
-        || access_point.security.contains(Security::WPA)
-    {
-        AccessPointCredentials::Wpa {
-            passphrase: passphrase.to_string(),
-        }
-    } else if access_point.security.contains(Security::WEP) {
-        AccessPointCredentials::Wep {
-            passphrase: passphrase.to_string(),
+/// Entry point of the network thread: scans, starts the portal and the HTTP
+/// server, then handles commands until the flow ends. The outcome is reported
+/// through `exit_tx`.
+pub fn process_network_commands(config: &Config, exit_tx: &ExitSender) {
+    supervise_network_commands(config, exit_tx, &Heartbeat::new());
+}
+
+/// Like `process_network_commands`, beating the heartbeat the watchdog
+/// looks at
+pub fn supervise_network_commands(config: &Config, exit_tx: &ExitSender, heartbeat: &Heartbeat) {
+    match config.backend {
+        BackendKind::Auto | BackendKind::NetworkManager => {
+            process_backend_commands::<NetworkManagerBackend>(config, exit_tx, heartbeat)
         }
-    } else {
-        AccessPointCredentials::None
+        #[cfg(feature = "iwd")]
+        BackendKind::Iwd => process_backend_commands::<IwdBackend>(config, exit_tx, heartbeat),
+        #[cfg(feature = "wpa")]
+        BackendKind::Wpa => process_backend_commands::<WpaBackend>(config, exit_tx, heartbeat),
+        #[cfg(feature = "mock-backend")]
+        BackendKind::Mock => process_backend_commands::<MockBackend>(config, exit_tx, heartbeat),
     }
 }
 
-pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
-    let mut command_handler = match NetworkCommandHandler::new(config, exit_tx) {
+fn process_backend_commands<B: NetworkBackend>(
+    config: &Config,
+    exit_tx: &ExitSender,
+    heartbeat: &Heartbeat,
+) {
+    let mut command_handler = match NetworkCommandHandler::<B>::new(config, exit_tx, heartbeat) {
         Ok(command_handler) => command_handler,
         Err(e) => {
             exit(exit_tx, e);
@@ -307,75 +2184,52 @@ pub fn process_network_commands(config: &Config, exit_tx: &Sender<ExitResult>) {
     command_handler.run(exit_tx);
 }
 
+/// Prepares the network stack before the network thread is started
 pub fn init_networking(config: &Config) -> Result<()> {
-    start_network_manager_service()?;
-
-    delete_exising_wifi_connect_ap_profile(&config.ssid).chain_err(|| ErrorKind::DeleteAccessPoint)
-}
-
-pub fn find_device(manager: &NetworkManager, interface: &Option<String>) -> Result<Device> {
-    if let Some(ref interface) = *interface {
-        let device = manager
-            .get_device_by_interface(interface)
-            .chain_err(|| ErrorKind::DeviceByInterface(interface.clone()))?;
-
-        info!("Targeted WiFi device: {}", interface);
-
-        if *device.device_type() != DeviceType::WiFi {
-            bail!(ErrorKind::NotAWiFiDevice(interface.clone()))
-        }
-
-        if device.get_state()? == DeviceState::Unmanaged {
-            bail!(ErrorKind::UnmanagedDevice(interface.clone()))
-        }
-
-        Ok(device)
-    } else {
-        let devices = manager.get_devices()?;
-
-        if let Some(device) = find_wifi_managed_device(devices)? {
-            info!("WiFi device: {}", device.interface());
-            Ok(device)
-        } else {
-            bail!(ErrorKind::NoWiFiDevice)
-        }
+    match config.backend {
+        BackendKind::Auto | BackendKind::NetworkManager => NetworkManagerBackend::init(config),
+        #[cfg(feature = "iwd")]
+        BackendKind::Iwd => IwdBackend::init(config),
+        #[cfg(feature = "wpa")]
+        BackendKind::Wpa => WpaBackend::init(config),
+        #[cfg(feature = "mock-backend")]
+        BackendKind::Mock => MockBackend::init(config),
     }
 }
 
-fn find_wifi_managed_device(devices: Vec<Device>) -> Result<Option<Device>> {
-    for device in devices {
-        if *device.device_type() == DeviceType::WiFi
-            && device.get_state()? != DeviceState::Unmanaged
-        {
-            return Ok(Some(device));
-        }
-    }
-
-    Ok(None)
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
 }
 
-fn get_access_points(device: &Device) -> Result<Vec<AccessPoint>> {
-    get_access_points_impl(device).chain_err(|| ErrorKind::NoAccessPoints)
+fn get_access_points<B: NetworkBackend>(
+    backend: &mut B,
+    config: &Config,
+    mac: Option<&String>,
+) -> Result<Vec<AccessPointInfo>> {
+    get_access_points_impl(backend, config, mac).chain_err(|| ErrorKind::NoAccessPoints)
 }
 
-fn get_access_points_impl(device: &Device) -> Result<Vec<AccessPoint>> {
-    let retries_allowed = 10;
+/// Scans again until some access points are found or the scan timeout
+/// passed. A scan in progress is not interrupted, so the backend's own scan
+/// timeout may add to it.
+fn get_access_points_impl<B: NetworkBackend>(
+    backend: &mut B,
+    config: &Config,
+    mac: Option<&String>,
+) -> Result<Vec<AccessPointInfo>> {
+    let timeout = config.scan_timeout;
+    let deadline = Instant::now() + Duration::from_secs(timeout);
     let mut retries = 0;
 
     // After stopping the hotspot we may have to wait a bit for the list
     // of access points to become available
-    while retries < retries_allowed {
-        let wifi_device = device.as_wifi_device().unwrap();
-        let mut access_points = wifi_device.get_access_points()?;
-
-        access_points.retain(|ap| ap.ssid().as_str().is_ok());
-
-        // Purge access points with duplicate SSIDs
-        let mut inserted = HashSet::new();
-        access_points.retain(|ap| inserted.insert(ap.ssid.clone()));
+    loop {
+        let mut access_points = backend.scan()?;
 
-        // Remove access points without SSID (hidden)
-        access_points.retain(|ap| !ap.ssid().as_str().unwrap().is_empty());
+        filter_access_points(&mut access_points, config, mac);
 
         if !access_points.is_empty() {
             info!(
@@ -385,97 +2239,287 @@ fn get_access_points_impl(device: &Device) -> Result<Vec<AccessPoint>> {
             return Ok(access_points);
         }
 
+        if Instant::now() + Duration::from_secs(1) >= deadline {
+            break;
+        }
+
         retries += 1;
         debug!("No access points found - retry #{}", retries);
         thread::sleep(Duration::from_secs(1));
     }
 
-    warn!("No access points found - giving up...");
+    warn!(
+        "No access points found within {} seconds - giving up...",
+        timeout
+    );
     Ok(vec![])
 }
 
-fn get_access_points_ssids(access_points: &[AccessPoint]) -> Vec<&str> {
+fn filter_access_points(
+    access_points: &mut Vec<AccessPointInfo>,
+    config: &Config,
+    mac: Option<&String>,
+) {
+    // Remove the portal access point
+    access_points.retain(|ap| !is_portal_access_point(ap, config, mac));
+
+    // Purge access points with duplicate SSIDs, keeping the strongest one
+    access_points.sort_by(|a, b| b.strength.cmp(&a.strength));
+    let mut inserted = HashSet::new();
+    access_points.retain(|ap| inserted.insert(ap.ssid.clone()));
+
+    // Remove access points without SSID (hidden)
+    access_points.retain(|ap| !ap.ssid.is_empty());
+
+    // Remove access points the SSID filters hide
+    access_points.retain(|ap| config.ssid_filter.permits(&ap.ssid.to_string()));
+}
+
+/// Some drivers report the access point of the portal itself, which would
+/// send clients joining it in a loop
+fn is_portal_access_point(
+    access_point: &AccessPointInfo,
+    config: &Config,
+    mac: Option<&String>,
+) -> bool {
+    if access_point.ssid == Ssid::from(config.ssid.as_str()) {
+        return true;
+    }
+
+    match (&access_point.bssid, mac) {
+        (&Some(ref bssid), Some(mac)) => bssid.eq_ignore_ascii_case(mac),
+        _ => false,
+    }
+}
+
+/// MAC address of the adapter the backend drives, if it can be listed
+fn adapter_mac<B: NetworkBackend>(backend: &B) -> Option<String> {
+    match backend.devices() {
+        Ok(devices) => devices
+            .into_iter()
+            .find(|device| device.interface == backend.interface())
+            .and_then(|device| device.mac),
+        Err(e) => {
+            warn!("Reading the MAC address of the adapter failed: {}", e);
+            None
+        }
+    }
+}
+
+fn get_access_points_ssids(access_points: &[AccessPointInfo]) -> Vec<String> {
+    access_points.iter().map(|ap| ap.ssid.to_string()).collect()
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+fn get_networks(
+    access_points: &[&AccessPointInfo],
+    saved_profiles: &[SavedProfile],
+    last_seen: &HashMap<Ssid, u64>,
+) -> Vec<Network> {
     access_points
         .iter()
-        .map(|ap| ap.ssid().as_str().unwrap())
+        .map(|access_point| {
+            let mut network = get_network_info(access_point, saved_profiles);
+            network.last_seen = last_seen.get(&access_point.ssid).cloned().unwrap_or(0);
+            network
+        })
         .collect()
 }
 
-fn get_networks(access_points: &[AccessPoint]) -> Vec<Network> {
-    access_points.iter().map(get_network_info).collect()
+fn get_network_info(access_point: &AccessPointInfo, saved_profiles: &[SavedProfile]) -> Network {
+    let mut network = Network::new(&access_point.ssid, access_point.security);
+
+    // Of several profiles for the SSID the most recently used one is listed
+    let profile = saved_profiles
+        .iter()
+        .filter(|profile| profile.ssid == access_point.ssid)
+        .max_by_key(|profile| profile.last_used);
+
+    if let Some(profile) = profile {
+        network.known = true;
+        network.uuid = profile.uuid.clone();
+    }
+
+    network
 }
 
-fn get_network_info(access_point: &AccessPoint) -> Network {
-    Network {
-        ssid: access_point.ssid().as_str().unwrap().to_string(),
-        security: get_network_security(access_point).to_string(),
+/// Logged and reported to clients, so that the UI can tell whether it stays
+/// reachable during the attempt
+fn connect_strategy<B: NetworkBackend>(backend: &B) -> ConnectStrategy {
+    if backend.concurrent_portal() {
+        info!("Connect strategy: keeping the portal up until the device joined the network");
+        return ConnectStrategy::Concurrent;
+    }
+
+    if wireless_capabilities(backend.interface()).ap_sta == Some(true) {
+        info!(
+            "{} supports concurrent access point and station modes, but the portal is \
+             hosted on the same interface",
+            backend.interface()
+        );
     }
+
+    info!("Connect strategy: stopping the portal before connecting");
+
+    ConnectStrategy::TeardownFirst
 }
 
-fn get_network_security(access_point: &AccessPoint) -> &str {
-    if access_point.security.contains(Security::ENTERPRISE) {
-        "enterprise"
-    } else if access_point.security.contains(Security::WPA2)
-        || access_point.security.contains(Security::WPA)
-    {
-        "wpa"
-    } else if access_point.security.contains(Security::WEP) {
-        "wep"
-    } else {
-        "none"
+fn spawn_dnsmasq<B: NetworkBackend>(
+    backend: &B,
+    config: &Config,
+    clients: &PortalClients,
+) -> Result<Option<process::Child>> {
+    if !backend.uses_dnsmasq() {
+        return Ok(None);
     }
+
+    let dnsmasq = start_dnsmasq(
+        config,
+        backend.portal_interface(),
+        backend.uses_dnsmasq_dhcp(),
+        clients,
+    )?;
+
+    Ok(Some(dnsmasq))
 }
 
-fn find_access_point<'a>(access_points: &'a [AccessPoint], ssid: &str) -> Option<&'a AccessPoint> {
-    for access_point in access_points.iter() {
-        if let Ok(access_point_ssid) = access_point.ssid().as_str() {
-            if access_point_ssid == ssid {
-                return Some(access_point);
-            }
+/// Raising the access point fails on some adapters for a while after boot,
+/// so it is tried again with doubling delays, which also gives the radio a
+/// rest between attempts. Later attempts alternate between bands, as some
+/// drivers refuse the channel picked for them.
+fn create_portal<B: NetworkBackend>(backend: &mut B, config: &Config) -> Result<()> {
+    let attempts = cmp::max(config.ap_retry_limit, 1);
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 1;
+    let mut channel = PortalChannel::Default;
+
+    while let Err(e) = backend.create_portal(config, channel) {
+        if attempt >= attempts {
+            return Err(e).chain_err(|| ErrorKind::CreateCaptivePortal);
+        }
+
+        let reason = e.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        let band_5ghz = wireless_capabilities(backend.interface()).band_5ghz == Some(true);
+        let next_channel = PortalChannel::for_attempt(attempt + 1, band_5ghz);
+
+        warn!(
+            "Raising the portal on {} failed on attempt {} of {}, retrying on {} in {}s: {}",
+            channel,
+            attempt,
+            attempts,
+            next_channel,
+            delay.as_secs(),
+            reason.join(": ")
+        );
+
+        thread::sleep(delay);
+        delay = cmp::min(delay * 2, AP_RETRY_MAX_DELAY);
+        attempt += 1;
+        channel = next_channel;
+    }
+
+    let interface = backend.portal_interface();
+
+    match operating_channel(interface) {
+        Some(channel) => info!("Portal raised on {}, channel {}", interface, channel),
+        None => info!("Portal raised on {}", interface),
+    }
+
+    // An interface the device joins networks through at the same time
+    // carries the default route of that network
+    let joins_networks = backend.concurrent_portal() && interface == backend.interface();
+
+    if backend.hosts_portal() && !joins_networks {
+        remove_portal_default_routes(interface);
+    }
+
+    if let (Some(gateway6), true) = (config.gateway6, backend.hosts_portal()) {
+        // Clients still reach the portal over IPv4 without it
+        match assign_ipv6_address(interface, gateway6, GATEWAY6_PREFIX) {
+            Ok(()) => info!("Portal IPv6 address: {}/{}", gateway6, GATEWAY6_PREFIX),
+            Err(e) => warn!(
+                "Assigning the portal IPv6 address {} failed: {}",
+                gateway6, e
+            ),
         }
     }
 
-    None
+    Ok(())
 }
 
-fn create_portal(device: &Device, config: &Config) -> Result<Connection> {
-    let portal_passphrase = config.passphrase.as_ref().map(|p| p as &str);
+/// The portal network leads nowhere, a default route through it would take
+/// the traffic of the device away from its real uplink
+fn remove_portal_default_routes(interface: &str) {
+    for family in default_routes(interface) {
+        warn!(
+            "The portal interface {} has an {} default route, deleting it",
+            interface, family
+        );
 
-    create_portal_impl(device, &config.ssid, &config.gateway, &portal_passphrase)
-        .chain_err(|| ErrorKind::CreateCaptivePortal)
+        if let Err(e) = delete_default_route(interface, family) {
+            warn!("Deleting the {} default route failed: {}", family, e);
+        }
+    }
 }
 
-fn create_portal_impl(
-    device: &Device,
-    ssid: &str,
-    gateway: &Ipv4Addr,
-    passphrase: &Option<&str>,
-) -> Result<Connection> {
-    info!("Starting access point...");
-    let wifi_device = device.as_wifi_device().unwrap();
-    let (portal_connection, _) = wifi_device.create_hotspot(ssid, *passphrase, Some(*gateway))?;
-    info!("Access point '{}' created", ssid);
-    Ok(portal_connection)
+fn stop_portal<B: NetworkBackend>(backend: &mut B, config: &Config) -> Result<()> {
+    backend
+        .stop_portal(config)
+        .chain_err(|| ErrorKind::StopAccessPoint)
 }
 
-fn stop_portal(connection: &Connection, config: &Config) -> Result<()> {
-    stop_portal_impl(connection, config).chain_err(|| ErrorKind::StopAccessPoint)
+/// Failing to announce the portal is not fatal, it is still reachable by its
+/// address
+fn start_mdns<B: NetworkBackend>(backend: &B, config: &Config) -> Option<MdnsResponder> {
+    if config.mdns_name.is_empty() || !backend.hosts_portal() {
+        return None;
+    }
+
+    match MdnsResponder::start(
+        &config.mdns_name,
+        backend.portal_interface(),
+        config.gateway,
+        config.listening_port,
+    ) {
+        Ok(mdns) => Some(mdns),
+        Err(e) => {
+            warn!("{}", e);
+            None
+        }
+    }
 }
 
-fn stop_portal_impl(connection: &Connection, config: &Config) -> Result<()> {
-    info!("Stopping access point '{}'...", config.ssid);
-    connection.deactivate()?;
-    connection.delete()?;
-    thread::sleep(Duration::from_secs(1));
-    info!("Access point '{}' stopped", config.ssid);
-    Ok(())
+/// The first IPv4 address assigned to the interface, if any
+fn get_interface_ipv4(interface: &str) -> Option<Ipv4Addr> {
+    let addresses = match getifaddrs() {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            warn!("Getting interface addresses failed: {}", e);
+            return None;
+        }
+    };
+
+    for address in addresses {
+        if address.interface_name != interface {
+            continue;
+        }
+
+        if let Some(sockaddr) = address.address.as_ref().and_then(|a| a.as_sockaddr_in()) {
+            return Some(*SocketAddrV4::from(*sockaddr).ip());
+        }
+    }
+
+    None
 }
 
-fn wait_for_connectivity(manager: &NetworkManager, timeout: u64) -> Result<bool> {
+fn wait_for_connectivity<B: NetworkBackend>(backend: &B, timeout: u64) -> Result<bool> {
     let mut total_time = 0;
 
     loop {
-        let connectivity = manager.get_connectivity()?;
+        let connectivity = backend.connectivity()?;
 
         if connectivity == Connectivity::Full || connectivity == Connectivity::Limited {
             debug!(
@@ -504,82 +2548,57 @@ fn wait_for_connectivity(manager: &NetworkManager, timeout: u64) -> Result<bool>
     }
 }
 
-pub fn start_network_manager_service() -> Result<()> {
-    let state = match NetworkManager::get_service_state() {
-        Ok(state) => state,
-        _ => {
-            info!("Cannot get the NetworkManager service state");
-            return Ok(());
-        }
-    };
-
-    if state != ServiceState::Active {
-        let state =
-            NetworkManager::start_service(15).chain_err(|| ErrorKind::StartNetworkManager)?;
-        if state != ServiceState::Active {
-            bail!(ErrorKind::StartActiveNetworkManager);
-        } else {
-            info!("NetworkManager service started successfully");
-        }
-    } else {
-        debug!("NetworkManager service already running");
-    }
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(())
-}
+    use config::PortalBuilder;
 
-fn delete_exising_wifi_connect_ap_profile(ssid: &str) -> Result<()> {
-    let manager = NetworkManager::new();
+    const ADAPTER_MAC: &str = "02:00:00:00:00:01";
 
-    for connection in &manager.get_connections()? {
-        if is_access_point_connection(connection) && is_same_ssid(connection, ssid) {
-            info!(
-                "Deleting already created by WiFi Connect access point connection profile: {:?}",
-                connection.settings().ssid,
-            );
-            connection.delete()?;
+    fn access_point(ssid: &str, bssid: &str, strength: u8) -> AccessPointInfo {
+        AccessPointInfo {
+            ssid: Ssid::from(ssid),
+            security: Security::Wpa,
+            strength: Some(strength),
+            bssid: Some(bssid.to_string()),
         }
     }
 
-    Ok(())
-}
-
-fn delete_existing_connections_to_same_network(manager: &NetworkManager, ssid: &str) {
-    let connections = match manager.get_connections() {
-        Ok(connections) => connections,
-        Err(e) => {
-            error!("Getting existing connections failed: {}", e);
-            return;
-        }
-    };
+    fn filtered(access_points: &[AccessPointInfo], mac: Option<&str>) -> Vec<String> {
+        let config = PortalBuilder::new().ssid("Portal").build();
+        let mac = mac.map(String::from);
+        let mut access_points = access_points.to_vec();
 
-    for connection in &connections {
-        if is_wifi_connection(connection) && is_same_ssid(connection, ssid) {
-            info!(
-                "Deleting existing WiFi connection to the same network: {:?}",
-                connection.settings().ssid,
-            );
+        filter_access_points(&mut access_points, &config, mac.as_ref());
 
-            if let Err(e) = connection.delete() {
-                error!("Deleting existing WiFi connection failed: {}", e);
-            }
-        }
+        access_points
+            .iter()
+            .map(|access_point| access_point.ssid.to_string())
+            .collect()
     }
-}
 
-fn is_same_ssid(connection: &Connection, ssid: &str) -> bool {
-    connection_ssid_as_str(connection) == Some(ssid)
-}
-
-fn connection_ssid_as_str(connection: &Connection) -> Option<&str> {
-    // An access point SSID could be random bytes and not a UTF-8 encoded string
-    connection.settings().ssid.as_str().ok()
-}
-
-fn is_access_point_connection(connection: &Connection) -> bool {
-    is_wifi_connection(connection) && connection.settings().mode == "ap"
-}
+    #[test]
+    fn the_portal_is_hidden_from_the_scan_results() {
+        let access_points = [
+            access_point("Home", "0a:00:00:00:00:01", 80),
+            // The portal SSID, whoever broadcasts it
+            access_point("Portal", "0a:00:00:00:00:02", 90),
+            // The adapter of the portal itself under another name, as some
+            // drivers report it
+            access_point("Portal-5G", &ADAPTER_MAC.to_uppercase(), 70),
+            access_point("Cafe", "0a:00:00:00:00:03", 60),
+        ];
+
+        assert_eq!(
+            filtered(&access_points, Some(ADAPTER_MAC)),
+            ["Home", "Cafe"]
+        );
 
-fn is_wifi_connection(connection: &Connection) -> bool {
-    connection.settings().kind == "802-11-wireless"
+        // Without the MAC only the SSID tells the portal apart
+        assert_eq!(
+            filtered(&access_points, None),
+            ["Home", "Portal-5G", "Cafe"]
+        );
+    }
 }
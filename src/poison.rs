@@ -0,0 +1,38 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::PoisonError;
+
+/// Times a lock poisoned by a panicking thread was taken regardless
+static RECOVERIES: AtomicUsize = AtomicUsize::new(0);
+
+/// A thread panicking while holding a lock leaves it poisoned, which would
+/// bring down every later user of the lock. The state behind the locks of
+/// the portal stays consistent between its updates, so it is used
+/// regardless. Takes the guards of condition variable waits as well.
+pub fn recover_poisoned<G>(poisoned: PoisonError<G>) -> G {
+    let recoveries = count_recovery();
+
+    if recoveries == 1 {
+        error!("A thread panicked holding a lock, recovering it");
+    } else {
+        debug!("Recovered a poisoned lock {} times", recoveries);
+    }
+
+    poisoned.into_inner()
+}
+
+/// For the lock of the log buffer, which is taken while logging, so that
+/// logging the recovery would wait for the lock being recovered
+pub fn recover_poisoned_quietly<G>(poisoned: PoisonError<G>) -> G {
+    count_recovery();
+
+    poisoned.into_inner()
+}
+
+/// Served by `/metrics`
+pub fn recoveries() -> usize {
+    RECOVERIES.load(Ordering::SeqCst)
+}
+
+fn count_recovery() -> usize {
+    RECOVERIES.fetch_add(1, Ordering::SeqCst) + 1
+}
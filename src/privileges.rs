@@ -1,7 +1,36 @@
-use nix::unistd::Uid;
+use nix::libc;
+use nix::unistd::{
+    getegid, geteuid, getgid, getuid, setgid, setgroups, setuid, Gid, Group, Uid, User,
+};
 
 use errors::*;
 
+/// `CAP_NET_BIND_SERVICE`, `CAP_NET_ADMIN` and `CAP_NET_RAW` of
+/// `linux/capability.h`
+const CAP_NET_BIND_SERVICE: u32 = 10;
+const CAP_NET_ADMIN: u32 = 12;
+const CAP_NET_RAW: u32 = 13;
+
+/// Kept once root is dropped, for raising the portal again, which spawns
+/// dnsmasq, assigns addresses and binds privileged ports
+const RETAINED_CAPABILITIES: [u32; 3] = [CAP_NET_BIND_SERVICE, CAP_NET_ADMIN, CAP_NET_RAW];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapabilityHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapabilityData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
 pub fn require_root() -> Result<()> {
     if !Uid::effective().is_root() {
         bail!(ErrorKind::RootPrivilegesRequired(
@@ -11,3 +40,155 @@ pub fn require_root() -> Result<()> {
         Ok(())
     }
 }
+
+/// Serving the portal as root takes `--allow-root`, or `--run-as` for the
+/// user to continue as once the portal is up
+pub fn check_root_allowed(run_as: Option<&str>, allow_root: bool) -> Result<()> {
+    if Uid::effective().is_root() && run_as.is_none() && !allow_root {
+        bail!(ErrorKind::ServingAsRoot);
+    }
+
+    Ok(())
+}
+
+/// Switches to the user, given as `user` or `user:group`, keeping only the
+/// network capabilities, which the spawned helpers inherit too.
+/// NetworkManager keeps answering as long as polkit grants the user its
+/// actions.
+///
+/// Capabilities belong to threads, so this is called from the thread
+/// managing the network, the others losing all of them.
+pub fn drop_privileges(run_as: &str, allow_root: bool) -> Result<()> {
+    if !Uid::effective().is_root() {
+        return Ok(());
+    }
+
+    let (uid, gid) = lookup_user(run_as)?;
+
+    if uid.is_root() {
+        if allow_root {
+            warn!("--run-as names root, serving the portal as root");
+            return Ok(());
+        }
+
+        bail!(ErrorKind::ServingAsRoot);
+    }
+
+    let reason = |step: &str, e: ::nix::Error| {
+        ErrorKind::DropPrivileges(run_as.to_string(), format!("{} failed: {}", step, e))
+    };
+
+    prctl(libc::PR_SET_KEEPCAPS, 1).map_err(|e| reason("PR_SET_KEEPCAPS", e))?;
+    setgroups(&[gid]).map_err(|e| reason("setgroups", e))?;
+    setgid(gid).map_err(|e| reason("setgid", e))?;
+    setuid(uid).map_err(|e| reason("setuid", e))?;
+    prctl(libc::PR_SET_KEEPCAPS, 0).map_err(|e| reason("PR_SET_KEEPCAPS", e))?;
+
+    set_capabilities(&RETAINED_CAPABILITIES).map_err(|e| reason("capset", e))?;
+
+    for &capability in RETAINED_CAPABILITIES.iter() {
+        if let Err(e) = prctl_ambient_raise(capability) {
+            warn!(
+                "Raising the ambient capability {} failed, helper programs run without it: {}",
+                capability, e
+            );
+        }
+    }
+
+    verify_dropped(run_as, uid, gid)?;
+
+    info!(
+        "Serving the portal as {} (UID {}, GID {})",
+        run_as, uid, gid
+    );
+
+    Ok(())
+}
+
+/// The IDs of `user` or `user:group`, the primary group of the user when
+/// no group is given
+fn lookup_user(run_as: &str) -> Result<(Uid, Gid)> {
+    let mut parts = run_as.splitn(2, ':');
+    let user_name = parts.next().unwrap_or("");
+    let group_name = parts.next();
+
+    let unknown =
+        |what: &str| ErrorKind::DropPrivileges(run_as.to_string(), format!("unknown {}", what));
+
+    let user = User::from_name(user_name)?.ok_or_else(|| unknown("user"))?;
+
+    let gid = match group_name {
+        Some(group_name) => {
+            Group::from_name(group_name)?
+                .ok_or_else(|| unknown("group"))?
+                .gid
+        }
+        None => user.gid,
+    };
+
+    Ok((user.uid, gid))
+}
+
+/// Root is gone for good only if every ID changed and it cannot be regained
+fn verify_dropped(run_as: &str, uid: Uid, gid: Gid) -> Result<()> {
+    let dropped = getuid() == uid && geteuid() == uid && getgid() == gid && getegid() == gid;
+
+    if !dropped || setuid(Uid::from_raw(0)).is_ok() {
+        bail!(ErrorKind::DropPrivileges(
+            run_as.to_string(),
+            "root privileges are still held".to_string()
+        ));
+    }
+
+    Ok(())
+}
+
+fn prctl(option: libc::c_int, value: libc::c_ulong) -> ::nix::Result<()> {
+    let result = unsafe { libc::prctl(option, value, 0, 0, 0) };
+
+    ::nix::errno::Errno::result(result).map(drop)
+}
+
+fn prctl_ambient_raise(capability: u32) -> ::nix::Result<()> {
+    let result = unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_RAISE as libc::c_ulong,
+            libc::c_ulong::from(capability),
+            0,
+            0,
+        )
+    };
+
+    ::nix::errno::Errno::result(result).map(drop)
+}
+
+/// Makes the capabilities the permitted, effective and inheritable ones of
+/// the calling thread, dropping all others
+fn set_capabilities(capabilities: &[u32]) -> ::nix::Result<()> {
+    let mut header = CapabilityHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+
+    let mut data = [CapabilityData::default(); 2];
+
+    for &capability in capabilities {
+        let set = &mut data[(capability / 32) as usize];
+        let bit = 1 << (capability % 32);
+
+        set.effective |= bit;
+        set.permitted |= bit;
+        set.inheritable |= bit;
+    }
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_capset,
+            &mut header as *mut CapabilityHeader,
+            data.as_mut_ptr(),
+        )
+    };
+
+    ::nix::errno::Errno::result(result).map(drop)
+}
@@ -1,271 +1,6263 @@
+use std::cmp;
+use std::collections::HashMap;
 use std::error::Error as StdError;
+use std::ffi::OsString;
 use std::fmt;
-use std::net::Ipv4Addr;
-use std::sync::mpsc::{Receiver, Sender};
+use std::fs;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, SocketAddrV4, TcpListener};
+use std::os::unix::io::FromRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use hyper;
+use hyper::net::{HttpListener, HttpStream, NetworkListener, NetworkStream};
+use iron::method::Method;
+use iron::mime::{Attr, Mime, SubLevel, TopLevel, Value};
 use iron::modifiers::Redirect;
 use iron::prelude::*;
 use iron::{
-    headers, status, typemap, AfterMiddleware, BeforeMiddleware, Iron, IronError, IronResult,
-    Request, Response, Url,
+    headers, status, typemap, AfterMiddleware, AroundMiddleware, BeforeMiddleware, Handler, Iron,
+    IronError, IronResult, Listening, Protocol, Request, Response, Timeouts, Url,
 };
 use iron_cors::CorsMiddleware;
+use log::LogLevel;
 use mount::Mount;
-use params::{FromValue, Params};
-use path::PathBuf;
+use nix;
+use nix::errno::Errno;
+use nix::sys::socket::{
+    bind, listen, setsockopt, socket, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn,
+    SockaddrIn6,
+};
+use params::{self, FromValue, Map, Params};
 use persistent::Write;
+use qrcode::render::svg;
+use qrcode::QrCode;
+use regex::Regex;
 use router::Router;
+use serde::ser::SerializeMap;
+use serde::{Serialize, Serializer};
 use serde_json;
-use staticfile::Static;
 
+use access_log::{AccessEntry, AccessLog};
+use backend::{
+    is_valid_domain_name, is_valid_wpa_passphrase, new_uuid, ConnectSecurity, Credentials, Metered,
+    Security, Ssid, MAX_PRIORITY, MAX_SSID_LENGTH, MIN_PRIORITY,
+};
+use config::{effective_connect_timeout, CaptiveMode, Config, DuplicatePolicy, SsidFilter};
+use dnsmasq::PortalClients;
 use errors::*;
-use exit::{exit, ExitResult};
-use network::{NetworkCommand, NetworkCommandResponse};
+use exit::{exit, ExitSender};
+use hostname::{is_valid_hostname, set_hostname};
+use locale::Locales;
+use logs::LogBuffer;
+use metrics::Metrics;
+use network::{
+    event_channel, response_channel, BulkNetwork, ConnectStrategy, EventReceiver, EventSender,
+    Network, NetworkCommand, NetworkCommandResponse, NetworkCommandSender, NetworkEvent,
+    NetworkResponseSender, ProfileDocument, ProfileOptions, SavedNetwork,
+};
+use poison::recover_poisoned;
+use static_files::StaticFiles;
 
-struct RequestSharedState {
-    gateway: Ipv4Addr,
-    server_rx: Receiver<NetworkCommandResponse>,
-    network_tx: Sender<NetworkCommand>,
-    exit_tx: Sender<ExitResult>,
+/// Version of the HTTP API schema, bump on incompatible changes
+const API_VERSION: &str = "1.0.0";
+
+/// Version of the `/api/v1/networks` envelope
+const NETWORKS_ENVELOPE_VERSION: u32 = 1;
+
+const OPENAPI_DOCUMENT: &str = include_str!("../docs/openapi.json");
+
+/// Served for the icons browsers load from the root when the UI directory
+/// has none of its own
+const DEFAULT_ICON: &[u8] = include_bytes!("icon.png");
+
+/// `Cache-Control` of the root icons, which browsers request on every page
+/// load otherwise
+const ICON_CACHE_CONTROL: &str = "public, max-age=604800";
+
+/// Served from `/` when the UI directory has no `index.html`, so that a
+/// broken or missing UI install still lets the device be provisioned
+const FALLBACK_PAGE: &str = include_str!("fallback.html");
+
+/// Time a connect attempt takes on top of the activation timeout: the portal
+/// teardown, the connectivity check and bringing the portal back up
+const CONNECT_ATTEMPT_OVERHEAD_SECS: u64 = 90;
+
+/// Settings of the captive portal HTTP server
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Network the portal is hosted on, advertised by `/qr`
+    pub portal_ssid: String,
+    pub portal_passphrase: Option<String>,
+    /// Address of the portal, requests to other hosts are redirected to it
+    pub gateway: Ipv4Addr,
+    /// IPv6 address of the portal, to which IPv6 clients are redirected
+    pub gateway6: Option<Ipv6Addr>,
+    /// Address the server binds to, usually the gateway itself
+    pub listening_address: Ipv4Addr,
+    /// Interface the listener is pinned to, the one hosting the portal
+    pub listening_interface: Option<String>,
+    /// Listened on instead when another program holds the listening port
+    pub fallback_port: Option<u16>,
+    pub listening_port: u16,
+    /// Directory the web UI is served from
+    pub ui_directory: PathBuf,
+    /// Default and maximum seconds of the `timeout` of `/connect`
+    pub connect_timeout: u64,
+    pub max_connect_timeout: u64,
+    /// Autoconnect of the profile when `/connect` does not ask otherwise
+    pub default_autoconnect: bool,
+    /// Whether `/connect` reports the UUID of the created profile
+    pub profile_uuids: bool,
+    /// Whether `/connect` refuses networks that have a saved profile
+    pub duplicate_policy: DuplicatePolicy,
+    /// Bearer token of the privileged requests, which are refused without one
+    pub api_token: Option<String>,
+    /// Listed by `/clients`, empty unless shared with the DHCP server
+    pub clients: PortalClients,
+    /// Whether the first client opening the portal locks out the others
+    pub single_client: bool,
+    /// Seconds of inactivity after which the lock expires, `0` for never
+    pub session_timeout: u64,
+    /// Served by `/logs`, which is not found without one
+    pub log_buffer: Option<LogBuffer>,
+    /// Seconds a `/networks` reply is reused for, `0` for never
+    pub networks_cache_ttl: u64,
+    /// Seconds `/networks?wait=true` waits on the scan of another request
+    pub scan_timeout: u64,
+    /// Reported by `/config.json`
+    pub connect_strategy: ConnectStrategy,
+    /// Networks `/connect` refuses to join, reported by `/config.json`
+    pub ssid_filter: SsidFilter,
+    /// How requests to other hosts are answered
+    pub captive_mode: CaptiveMode,
+    /// Host names besides the gateway the API answers to
+    pub allowed_hosts: Vec<String>,
+    /// Proxies whose `X-Forwarded-For` names the client in the request log
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Threads serving requests, `0` for Iron's default
+    pub http_threads: usize,
+    /// Connections queued by the kernel for the listening socket
+    pub http_backlog: u32,
+    /// Seconds to wait for a request, `0` for no limit
+    pub http_read_timeout: u64,
+    /// Seconds idle connections are kept open, `0` disables keep-alive
+    pub http_keepalive: u64,
+    /// Largest request body in bytes of the API
+    pub max_body_size: u64,
+    /// Largest request body in bytes of the upload endpoints
+    pub max_upload_size: u64,
+    /// API requests handled at once, `0` for no limit
+    pub max_requests: usize,
+    /// Whether requests changing anything have to repeat the CSRF cookie
+    pub csrf: bool,
+    /// Whether `/metrics` is served
+    pub metrics: bool,
+    /// File a line is appended to for every request
+    pub access_log: Option<PathBuf>,
+    /// URL prefixes served from the directories besides the built-in ones
+    pub static_mounts: Vec<(String, PathBuf)>,
+    /// Whether every file of the UI directory is served by its path
+    pub ui_tree: bool,
+    /// Regular expressions of the fingerprinted file names clients may
+    /// cache for good, the built-in detection of content hashes if empty
+    pub immutable_assets: Vec<Regex>,
+    /// Content types of file extensions over the built-in ones
+    pub mime_types: Vec<(String, String)>,
+    /// UI file requests handled at once, `0` for no limit
+    pub max_asset_requests: usize,
 }
 
-impl typemap::Key for RequestSharedState {
-    type Value = RequestSharedState;
+impl ServerConfig {
+    pub fn from_config(config: &Config) -> Self {
+        ServerConfig {
+            portal_ssid: config.ssid.clone(),
+            portal_passphrase: config.passphrase.clone(),
+            gateway: config.gateway,
+            gateway6: config.gateway6,
+            listening_address: config.gateway,
+            listening_interface: None,
+            fallback_port: config.fallback_port,
+            listening_port: config.listening_port,
+            ui_directory: config.ui_directory.clone(),
+            connect_timeout: config.connect_timeout,
+            max_connect_timeout: config.max_connect_timeout,
+            default_autoconnect: config.default_autoconnect,
+            profile_uuids: config.backend.has_profile_uuids(),
+            duplicate_policy: config.duplicate_policy,
+            api_token: config.api_token.clone(),
+            clients: PortalClients::default(),
+            single_client: config.single_client,
+            session_timeout: config.activity_timeout,
+            log_buffer: config.log_buffer.clone(),
+            networks_cache_ttl: config.networks_cache_ttl,
+            scan_timeout: config.scan_timeout,
+            connect_strategy: ConnectStrategy::TeardownFirst,
+            ssid_filter: config.ssid_filter.clone(),
+            captive_mode: config.captive_mode,
+            allowed_hosts: portal_host_names(config),
+            trusted_proxies: config.trusted_proxies.clone(),
+            http_threads: config.http_threads,
+            http_backlog: config.http_backlog,
+            http_read_timeout: config.http_read_timeout,
+            http_keepalive: config.http_keepalive,
+            max_body_size: config.max_body_size,
+            max_upload_size: config.max_upload_size,
+            max_requests: config.max_requests,
+            csrf: config.csrf,
+            metrics: config.metrics,
+            access_log: config.access_log.clone(),
+            static_mounts: config
+                .static_mounts
+                .iter()
+                .map(|&(ref prefix, ref directory)| {
+                    (prefix.clone(), config.ui_directory.join(directory))
+                })
+                .collect(),
+            ui_tree: config.ui_tree,
+            // Checked by `validate_settings`
+            immutable_assets: config
+                .immutable_assets
+                .iter()
+                .filter_map(|pattern| Regex::new(pattern).ok())
+                .collect(),
+            mime_types: config.mime_types.clone(),
+            max_asset_requests: config.max_asset_requests,
+        }
+    }
 }
 
-#[derive(Debug)]
-struct StringError(String);
+/// A running portal HTTP server
+pub struct ServerHandle {
+    listening: Listening,
+    shutdown: Arc<ShutdownState>,
+    event_tx: EventSender,
+    access_log: Option<Arc<AccessLog>>,
+}
 
-impl fmt::Display for StringError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+impl ServerHandle {
+    /// The bound address, with the actual port when listening on port 0
+    pub fn address(&self) -> SocketAddr {
+        self.listening.socket
+    }
+
+    /// Stops serving requests, which are answered with 503 from then on.
+    /// hyper cannot close its listening socket, so the address stays bound
+    /// until the process exits.
+    pub fn stop(&self) {
+        self.shutdown.stop();
+    }
+
+    /// Opens the access log again, if there is one
+    pub fn reopen_logs(&self) {
+        if let Some(ref access_log) = self.access_log {
+            access_log.reopen();
+        }
+    }
+
+    /// Returns a sender for the provisioning events, which tell the server
+    /// when a connect attempt is over
+    pub fn event_tx(&self) -> EventSender {
+        self.event_tx.clone()
+    }
+
+    /// Returns a handle for stopping the server from another thread
+    pub fn stopper(&self) -> ServerStopper {
+        ServerStopper {
+            shutdown: self.shutdown.clone(),
+        }
+    }
+
+    /// Blocks until the server is stopped
+    pub fn join(self) {
+        self.shutdown.wait();
     }
 }
 
-impl StdError for StringError {
-    fn description(&self) -> &str {
-        &self.0
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        // Detaches the listening thread, which hyper would join otherwise
+        let _ = self.listening.close();
     }
 }
 
-macro_rules! get_request_ref {
-    ($req:ident, $ty:ty, $err:expr) => {
-        match $req.get_ref::<$ty>() {
-            Ok(val) => val,
-            Err(err) => {
-                error!($err);
-                return Err(IronError::new(err, status::InternalServerError));
+/// Stops a server owned by another thread
+#[derive(Clone)]
+pub struct ServerStopper {
+    shutdown: Arc<ShutdownState>,
+}
+
+impl ServerStopper {
+    pub fn stop(&self) {
+        self.shutdown.stop();
+    }
+}
+
+#[derive(Default)]
+struct ShutdownState {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+/// Coalesces `/networks?refresh=true` and `?wait=true` requests, so that
+/// those arriving while a scan runs share its results instead of queueing
+/// scans of their own
+#[derive(Default)]
+struct RefreshGate {
+    state: Mutex<RefreshState>,
+    condvar: Condvar,
+}
+
+#[derive(Default)]
+struct RefreshState {
+    running: bool,
+    /// Scans finished so far
+    finished: u64,
+}
+
+/// The scan of the request that entered the gate first, ended on drop
+struct RefreshTurn(Arc<RefreshGate>);
+
+impl RefreshGate {
+    /// The turn to scan, or none once the scan already running finished or
+    /// the timeout elapsed waiting for it
+    fn enter(gate: &Arc<RefreshGate>, timeout: Duration) -> Option<RefreshTurn> {
+        let mut state = gate.state.lock().unwrap_or_else(recover_poisoned);
+
+        if !state.running {
+            state.running = true;
+            return Some(RefreshTurn(gate.clone()));
+        }
+
+        let running = state.finished;
+        let deadline = Instant::now() + timeout;
+
+        while state.finished == running {
+            let now = Instant::now();
+
+            if now >= deadline {
+                debug!("Gave up waiting for the scan of another request");
+                break;
             }
+
+            state = gate
+                .condvar
+                .wait_timeout(state, deadline - now)
+                .unwrap_or_else(recover_poisoned)
+                .0;
         }
-    };
+
+        None
+    }
 }
 
-macro_rules! get_param {
-    ($params:ident, $param:expr, $ty:ty) => {
-        match $params.get($param) {
-            Some(value) => match <$ty as FromValue>::from_value(value) {
-                Some(converted) => converted,
-                None => {
-                    let err = format!("Unexpected type for '{}'", $param);
-                    error!("{}", err);
-                    return Err(IronError::new(
-                        StringError(err),
-                        status::InternalServerError,
-                    ));
+impl Drop for RefreshTurn {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap_or_else(recover_poisoned);
+        state.running = false;
+        state.finished += 1;
+        self.0.condvar.notify_all();
+    }
+}
+
+impl ShutdownState {
+    fn is_stopped(&self) -> bool {
+        *self.stopped.lock().unwrap_or_else(recover_poisoned)
+    }
+
+    fn stop(&self) {
+        *self.stopped.lock().unwrap_or_else(recover_poisoned) = true;
+        self.condvar.notify_all();
+    }
+
+    fn wait(&self) {
+        let mut stopped = self.stopped.lock().unwrap_or_else(recover_poisoned);
+
+        while !*stopped {
+            stopped = self.condvar.wait(stopped).unwrap_or_else(recover_poisoned);
+        }
+    }
+}
+
+struct RequestSharedState {
+    /// Where other hosts are redirected to, built at startup with the port
+    /// actually listened on, which may be the fallback port. None should
+    /// the gateway not make a URL, which leaves requests unredirected.
+    portal_url: Option<Url>,
+    /// Where IPv6 clients are redirected to if the portal has an IPv6
+    /// address
+    portal_url6: Option<Url>,
+    /// Refused by `/connect`, as joining it would loop back to the portal
+    portal_ssid: Ssid,
+    /// Payload of the QR code joining the portal network
+    portal_qr: String,
+    /// Networks of the latest `/networks` response, used for validation
+    networks: Vec<Network>,
+    /// The serialized `/networks` reply, for clients polling it
+    networks_cache: Option<NetworksCache>,
+    networks_cache_ttl: u64,
+    scan_timeout: u64,
+    event_rx: EventReceiver,
+    connect_attempt: Option<ConnectAttempt>,
+    /// Reported by `/status` until another attempt starts
+    last_failure: Option<ConnectFailure>,
+    /// The networks of the latest bulk connect and how each of them fared,
+    /// reported by `/status` until another attempt starts
+    bulk: Option<BulkProgress>,
+    /// Set once a network is joined, which lets the captive probes succeed
+    connected: bool,
+    connect_timeout: u64,
+    max_connect_timeout: u64,
+    default_autoconnect: bool,
+    profile_uuids: bool,
+    duplicate_policy: DuplicatePolicy,
+    api_token: Option<String>,
+    clients: PortalClients,
+    single_client: bool,
+    session_timeout: u64,
+    session: Option<ClientSession>,
+    log_buffer: Option<LogBuffer>,
+    ssid_filter: SsidFilter,
+    refresh_gate: Arc<RefreshGate>,
+}
+
+impl RequestSharedState {
+    fn new(server_config: &ServerConfig, event_rx: EventReceiver, listening_port: u16) -> Self {
+        RequestSharedState {
+            portal_url: portal_url(IpAddr::V4(server_config.gateway), listening_port),
+            portal_url6: server_config
+                .gateway6
+                .and_then(|gateway6| portal_url(IpAddr::V6(gateway6), listening_port)),
+            portal_ssid: Ssid::from(server_config.portal_ssid.as_str()),
+            portal_qr: wifi_qr_payload(
+                &server_config.portal_ssid,
+                server_config.portal_passphrase.as_ref().map(String::as_str),
+            ),
+            networks: Vec::new(),
+            networks_cache: None,
+            networks_cache_ttl: server_config.networks_cache_ttl,
+            scan_timeout: server_config.scan_timeout,
+            event_rx,
+            connect_attempt: None,
+            last_failure: None,
+            bulk: None,
+            connected: false,
+            connect_timeout: server_config.connect_timeout,
+            max_connect_timeout: server_config.max_connect_timeout,
+            default_autoconnect: server_config.default_autoconnect,
+            profile_uuids: server_config.profile_uuids,
+            duplicate_policy: server_config.duplicate_policy,
+            api_token: server_config.api_token.clone(),
+            clients: server_config.clients.clone(),
+            single_client: server_config.single_client,
+            session_timeout: server_config.session_timeout,
+            session: None,
+            log_buffer: server_config.log_buffer.clone(),
+            ssid_filter: server_config.ssid_filter.clone(),
+            refresh_gate: Arc::new(RefreshGate::default()),
+        }
+    }
+
+    /// The latest `/networks` reply, unless it is too old to be reused
+    fn cached_networks(&self, format: NetworksFormat) -> Option<Response> {
+        let ttl = Duration::from_secs(self.networks_cache_ttl);
+
+        match self.networks_cache {
+            Some(ref cache) if cache.cached_at.elapsed() < ttl => {
+                let json = match format {
+                    NetworksFormat::Legacy => &cache.json,
+                    NetworksFormat::V1 => &cache.v1_json,
+                };
+
+                Some(json_response(json.clone()))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the latest `/networks` reply can be reused and lists any
+    /// network, which `?wait=true` requests are served right away
+    fn has_fresh_networks(&self) -> bool {
+        let ttl = Duration::from_secs(self.networks_cache_ttl);
+
+        match self.networks_cache {
+            Some(ref cache) => cache.cached_at.elapsed() < ttl && !self.networks.is_empty(),
+            None => false,
+        }
+    }
+
+    fn effective_connect_timeout(&self, requested: Option<u64>) -> u64 {
+        effective_connect_timeout(requested, self.connect_timeout, self.max_connect_timeout)
+    }
+
+    /// The dispatched connect attempt the network thread has not reported an
+    /// outcome for yet. Overdue attempts are dropped, so that a wedged one
+    /// cannot block `/connect` forever.
+    fn connect_attempt_in_progress(&mut self) -> Option<&ConnectAttempt> {
+        while let Ok(event) = self.event_rx.try_recv() {
+            match event {
+                // Attempts started over D-Bus are tracked as well
+                NetworkEvent::ConnectAttempt { ssid, timeout } => {
+                    self.last_failure = None;
+                    self.connected = false;
+                    self.record_bulk(&ssid, "connecting", None);
+
+                    match self.connect_attempt {
+                        // The next network of a bulk connect, whose deadline
+                        // covers all of them
+                        Some(ref mut attempt) if attempt.bulk => attempt.ssid = ssid,
+                        Some(_) => {}
+                        None => self.connect_attempt = Some(ConnectAttempt::new(ssid, timeout)),
+                    }
                 }
-            },
-            None => {
-                let err = format!("'{}' not found in request params: {:?}", $param, $params);
-                error!("{}", err);
-                return Err(IronError::new(
-                    StringError(err),
-                    status::InternalServerError,
-                ));
+                NetworkEvent::ConnectFailed { ssid, reason } => {
+                    self.record_bulk(&ssid, "failed", Some(reason.clone()));
+                    self.end_attempt();
+                    self.networks_cache = None;
+                    self.last_failure = Some(ConnectFailure { ssid, reason });
+                }
+                // The attempt may have left a saved profile behind, which
+                // marks its network known
+                NetworkEvent::Connected { ssid, .. } => {
+                    self.record_bulk(&ssid, "connected", None);
+                    self.connect_attempt = None;
+                    self.networks_cache = None;
+                    self.connected = true;
+                }
+                NetworkEvent::ConnectCancelled { ssid } => {
+                    self.record_bulk(&ssid, "cancelled", None);
+                    self.end_attempt();
+                    self.networks_cache = None;
+                }
+                NetworkEvent::BulkConnectFinished { ssid } => {
+                    if let Some(ref mut bulk) = self.bulk {
+                        bulk.finish(ssid);
+                    }
+
+                    self.connect_attempt = None;
+                    self.networks_cache = None;
+                }
+                NetworkEvent::NetworksRefreshed => self.networks_cache = None,
+                NetworkEvent::PortalStarted => self.connected = false,
+                _ => {}
             }
         }
-    };
+
+        let overdue = self
+            .connect_attempt
+            .as_ref()
+            .map_or(false, |attempt| Instant::now() > attempt.deadline);
+
+        if overdue {
+            warn!("Connect attempt reported no outcome in time, accepting new attempts");
+            self.connect_attempt = None;
+        }
+
+        self.connect_attempt.as_ref()
+    }
+
+    /// Ends a single connect attempt, a bulk one going on with the next
+    /// network until `BulkConnectFinished`
+    fn end_attempt(&mut self) {
+        let bulk = self
+            .connect_attempt
+            .as_ref()
+            .map_or(false, |attempt| attempt.bulk);
+
+        if !bulk {
+            self.connect_attempt = None;
+        }
+    }
+
+    fn record_bulk(&mut self, ssid: &str, state: &'static str, reason: Option<String>) {
+        if let Some(ref mut bulk) = self.bulk {
+            bulk.record(ssid, state, reason);
+        }
+    }
+
+    /// The portal URL of the client's address family, IPv6 clients being
+    /// sent to the IPv4 gateway without an IPv6 one
+    fn portal_url(&self, client: IpAddr) -> Option<Url> {
+        match (client, &self.portal_url6) {
+            (IpAddr::V6(_), &Some(ref portal_url6)) => Some(portal_url6.clone()),
+            _ => self.portal_url.clone(),
+        }
+    }
+
+    /// Whether the device has joined a network since the portal started
+    fn is_connected(&mut self) -> bool {
+        self.connect_attempt_in_progress();
+        self.connected
+    }
+
+    /// The client holding the provisioning session, dropping the session
+    /// once it has been inactive for too long
+    fn session_owner(&mut self) -> Option<IpAddr> {
+        let timeout = self.session_timeout;
+
+        let expired = self.session.as_ref().map_or(false, |session| {
+            timeout != 0 && session.last_seen.elapsed() > Duration::from_secs(timeout)
+        });
+
+        if expired {
+            info!(
+                "Provisioning session of {} expired",
+                self.session.as_ref().unwrap().owner
+            );
+            self.session = None;
+        }
+
+        self.session.as_ref().map(|session| session.owner)
+    }
 }
 
-macro_rules! get_request_state {
-    ($req:ident) => {
-        get_request_ref!(
-            $req,
-            Write<RequestSharedState>,
-            "Getting reference to request shared state failed"
-        )
-        .as_ref()
-        .lock()
-        .unwrap()
-    };
+impl typemap::Key for RequestSharedState {
+    type Value = RequestSharedState;
 }
 
-fn exit_with_error<E>(state: &RequestSharedState, e: E, e_kind: ErrorKind) -> IronResult<Response>
-where
-    E: ::std::error::Error + Send + 'static,
-{
-    let description = e_kind.description().into();
-    let err = Err::<Response, E>(e).chain_err(|| e_kind);
-    exit(&state.exit_tx, err.unwrap_err());
-    Err(IronError::new(
-        StringError(description),
-        status::InternalServerError,
-    ))
+/// The channels to the network thread, kept apart from the request shared
+/// state so that its lock is not held while waiting for the network thread.
+/// A slow scan would stall every other request, captive probes included.
+#[derive(Clone)]
+struct NetworkLink {
+    network_tx: NetworkCommandSender,
+    exit_tx: ExitSender,
 }
 
-struct RequestLogger;
+impl NetworkLink {
+    /// Enqueues a command the network thread does not reply to
+    fn send(
+        &self,
+        command: NetworkCommand,
+        send_error: ErrorKind,
+    ) -> ::std::result::Result<(), IronResult<Response>> {
+        self.network_tx
+            .send(command)
+            .map_err(|e| exit_with_error(&self.exit_tx, e, send_error))
+    }
 
-impl BeforeMiddleware for RequestLogger {
-    fn before(&self, req: &mut Request) -> IronResult<()> {
-        let request_id = &req as *const _ as usize;
+    /// Sends the command with a response channel of its own and waits for
+    /// the reply
+    fn request<F>(
+        &self,
+        command: F,
+        send_error: ErrorKind,
+        recv_error: ErrorKind,
+    ) -> ::std::result::Result<NetworkCommandResponse, IronResult<Response>>
+    where
+        F: FnOnce(NetworkResponseSender) -> NetworkCommand,
+    {
+        let (response_tx, response_rx) = response_channel();
 
-        info!("REQ ({}): {} {}", request_id, req.method, req.url);
-        Ok(())
+        self.send(command(response_tx), send_error)?;
+
+        response_rx
+            .recv()
+            .map_err(|e| exit_with_error(&self.exit_tx, e, recv_error))
     }
 }
 
-impl AfterMiddleware for RequestLogger {
-    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
-        let request_id = &req as *const _ as usize;
-        let mut opt_code = res.status.map(|status| status.to_u16());
-        let return_code = opt_code.get_or_insert(0);
-        info!(
-            "RES ({}): {} {} ({})",
-            request_id, req.method, req.url, return_code
-        );
+impl typemap::Key for NetworkLink {
+    type Value = NetworkLink;
+}
 
-        Ok(res)
+struct ConnectAttempt {
+    ssid: String,
+    deadline: Instant,
+    /// Whether it is a bulk connect, which only ends once all its networks
+    /// have been attempted
+    bulk: bool,
+}
+
+impl ConnectAttempt {
+    fn new(ssid: String, timeout: u64) -> Self {
+        ConnectAttempt {
+            ssid,
+            deadline: Instant::now() + Duration::from_secs(timeout + CONNECT_ATTEMPT_OVERHEAD_SECS),
+            bulk: false,
+        }
     }
 
-    fn catch(&self, _: &mut Request, err: IronError) -> IronResult<Response> {
-        error!("Error encountered: {:?}", err);
-        Err(err)
+    /// Attempts of `networks` networks taking `timeout` seconds in total
+    fn for_bulk(ssid: String, timeout: u64, networks: u64) -> Self {
+        let overhead = CONNECT_ATTEMPT_OVERHEAD_SECS * networks;
+
+        ConnectAttempt {
+            ssid,
+            deadline: Instant::now() + Duration::from_secs(timeout + overhead),
+            bulk: true,
+        }
     }
 }
 
-struct RedirectMiddleware;
+/// The networks of a bulk connect, in the order they are attempted in
+#[derive(Clone, Serialize)]
+struct BulkProgress {
+    /// Whether all the attempts are over
+    finished: bool,
+    /// The network that activated, once finished
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_ssid: Option<String>,
+    networks: Vec<BulkNetworkStatus>,
+}
 
-impl AfterMiddleware for RedirectMiddleware {
-    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
-        let gateway = {
-            let request_state = get_request_state!(req);
-            format!("{}", request_state.gateway)
-        };
+impl BulkProgress {
+    fn new(results: &[ConnectResult]) -> Self {
+        BulkProgress {
+            finished: false,
+            active_ssid: None,
+            networks: results
+                .iter()
+                .map(|result| BulkNetworkStatus {
+                    ssid: result.ssid.clone(),
+                    ssid_raw: result.ssid_raw.clone(),
+                    uuid: result.uuid.clone(),
+                    state: "pending",
+                    reason: None,
+                })
+                .collect(),
+        }
+    }
 
-        if let Some(host) = req.headers.get::<headers::Host>() {
-            if host.hostname != gateway {
-                info!(
-                    "Redirecting Request to {} to gateway: {}",
-                    req.url.host(),
-                    gateway
-                );
+    fn record(&mut self, ssid: &str, state: &'static str, reason: Option<String>) {
+        if self.finished {
+            return;
+        }
+
+        if let Some(network) = self
+            .networks
+            .iter_mut()
+            .find(|network| network.ssid == ssid)
+        {
+            network.state = state;
+            network.reason = reason;
+        }
+    }
 
-                let url = Url::parse(&format!("http://{}/", gateway)).unwrap();
-                return Ok(Response::with((status::Found, Redirect(url))));
+    /// The networks never attempted, such as after a cancellation, are
+    /// `skipped`
+    fn finish(&mut self, active_ssid: Option<String>) {
+        for network in &mut self.networks {
+            if network.state == "pending" || network.state == "connecting" {
+                network.state = "skipped";
             }
         }
 
-        Err(err)
+        self.finished = true;
+        self.active_ssid = active_ssid;
     }
 }
 
-pub fn start_server(
-    gateway: Ipv4Addr,
-    listening_port: u16,
-    server_rx: Receiver<NetworkCommandResponse>,
-    network_tx: Sender<NetworkCommand>,
-    exit_tx: Sender<ExitResult>,
-    ui_directory: &PathBuf,
-) {
-    let exit_tx_clone = exit_tx.clone();
-    let gateway_clone = gateway;
-    let request_state = RequestSharedState {
-        gateway,
-        server_rx,
-        network_tx,
-        exit_tx,
-    };
+#[derive(Clone, Serialize)]
+struct BulkNetworkStatus {
+    ssid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid_raw: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    /// `pending`, `connecting`, `connected`, `failed`, `cancelled` or
+    /// `skipped`
+    state: &'static str,
+    /// Why the attempt failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
 
-    let mut router = Router::new();
-    router.get("/", Static::new(ui_directory), "index");
-    router.get("/networks", networks, "networks");
-    router.post("/connect", connect, "connect");
+struct NetworksCache {
+    json: String,
+    /// The same reply in its `/api/v1/networks` envelope
+    v1_json: String,
+    cached_at: Instant,
+}
 
-    let mut assets = Mount::new();
-    assets.mount("/", router);
-    assets.mount("/static", Static::new(ui_directory.join("static")));
-    assets.mount("/css", Static::new(ui_directory.join("css")));
-    assets.mount("/img", Static::new(ui_directory.join("img")));
-    assets.mount("/js", Static::new(ui_directory.join("js")));
+/// The shapes the network list is served in
+#[derive(Clone, Copy, PartialEq)]
+enum NetworksFormat {
+    /// The bare array of `/networks` the stock UI reads
+    Legacy,
+    /// The envelope of `/api/v1/networks`, with the metadata of the list
+    V1,
+}
 
-    let cors_middleware = CorsMiddleware::with_allow_any();
+/// Reply to `/api/v1/networks`, which carries the metadata of the list
+#[derive(Serialize)]
+struct NetworksEnvelope<'a> {
+    version: u32,
+    /// Seconds since the epoch of the latest scan, which may have run before
+    /// the portal was raised
+    scanned_at: u64,
+    /// Networks left out for a signal below the minimum
+    weak_hidden: usize,
+    /// The network the device is connected to, even if it was not scanned
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_ssid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    active_ssid_raw: Option<String>,
+    networks: &'a [Network],
+}
 
-    let mut chain = Chain::new(assets);
-    chain.link_before(RequestLogger);
-    chain.link_after(RequestLogger);
-    chain.link(Write::<RequestSharedState>::both(request_state));
-    chain
-        .link_after(RedirectMiddleware)
-        .link_after(RequestLogger);
-    chain.link_around(cors_middleware);
+/// Why the latest connect attempt failed, once the portal is back up
+#[derive(Clone, Serialize)]
+struct ConnectFailure {
+    ssid: String,
+    reason: String,
+}
 
-    let address = format!("{}:{}", gateway_clone, listening_port);
+/// Client the provisioning session is locked to in single client mode
+struct ClientSession {
+    owner: IpAddr,
+    last_seen: Instant,
+}
 
-    info!("Starting HTTP server on {}", &address);
+/// Reply to `/status`
+#[derive(Serialize)]
+struct StatusResult {
+    /// `portal` or `connecting`
+    state: &'static str,
+    /// SSID of the connect attempt in progress
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid: Option<String>,
+    /// Metered setting of the active connection, unknown while connecting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metered: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_failure: Option<ConnectFailure>,
+    /// How the networks of the latest bulk connect fared so far
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bulk: Option<BulkProgress>,
+}
 
-    if let Err(e) = Iron::new(chain).http(&address) {
-        exit(
-            &exit_tx_clone,
-            ErrorKind::StartHTTPServer(address, e.to_string()).into(),
-        );
-    }
+/// Reply to `/connect/cancel`
+#[derive(Serialize)]
+struct CancelResult {
+    /// Whether a connect attempt was in progress
+    cancelled: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid: Option<String>,
 }
 
-fn networks(req: &mut Request) -> IronResult<Response> {
-    info!("User connected to the captive portal");
+/// Reply to `/session/release`
+#[derive(Serialize)]
+struct ReleaseResult {
+    /// Whether a session was held
+    released: bool,
+}
 
-    let request_state = get_request_state!(req);
+/// Reply to `/config.json`, settings the UI adapts to
+#[derive(Clone, Serialize)]
+struct UiConfig {
+    connect_strategy: ConnectStrategy,
+    ssid_allow: Vec<String>,
+    ssid_deny: Vec<String>,
+    /// Of the strings `/locale` serves the request
+    language: String,
+}
+
+/// Reply to `/hostname`
+#[derive(Serialize)]
+struct HostnameResult {
+    hostname: String,
+    transient: bool,
+}
+
+/// Reply to `/reset`
+#[derive(Serialize)]
+struct ResetResult {
+    dry_run: bool,
+    /// Number of profiles deleted, or that would be deleted by a dry run
+    count: usize,
+    networks: Vec<SavedNetwork>,
+}
+
+/// Reply to `/networks/import`
+#[derive(Serialize)]
+struct ImportResult {
+    ssid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid_raw: Option<String>,
+    /// UUID of the created profile, if the network stack has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+}
+
+/// Body of `409` responses to `/connect` while an attempt is in progress
+#[derive(Serialize)]
+struct ConnectConflict {
+    #[serde(flatten)]
+    code: ErrorCode,
+    error: String,
+    ssid: String,
+}
+
+/// Body of `409` responses to `/connect` for networks with saved profiles,
+/// with `--duplicate-policy error`
+#[derive(Serialize)]
+struct DuplicateProfiles {
+    #[serde(flatten)]
+    code: ErrorCode,
+    error: String,
+    ssid: String,
+    /// Of the saved profiles, for network stacks identifying them by one
+    uuids: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct VersionInfo {
+    version: &'static str,
+    api_version: &'static str,
+}
+
+/// What went wrong with a request, for clients to tell errors apart by and
+/// show a `message` for, in English unless the translation of the UI in
+/// the language of the client has one. A message for every code is built
+/// in, so that none ships without one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ErrorCode {
+    MissingParameter,
+    WrongType,
+    OutOfRange,
+    InvalidChoice,
+    InvalidParameter,
+    MalformedRequest,
+    PassphraseRequired,
+    IdentityRequired,
+    InvalidPassphrase,
+    InvalidSsid,
+    UnknownProfile,
+    DuplicateNetwork,
+    SavedProfileExists,
+    InvalidNetworks,
+    PortalNetwork,
+    NetworkNotOffered,
+    ConnectInProgress,
+    NotConnected,
+    SessionLocked,
+    CsrfToken,
+    Unauthorized,
+    ApiTokenNotConfigured,
+    UnknownHost,
+    BodyTooLarge,
+    LengthRequired,
+    Busy,
+    NetworkThread,
+    NetworkStack,
+    HostnameFailed,
+    InvalidProfile,
+    LogBufferDisabled,
+    QrTooLarge,
+}
 
-    if let Err(e) = request_state.network_tx.send(NetworkCommand::Activate) {
-        return exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandActivate);
+impl ErrorCode {
+    fn as_str(self) -> &'static str {
+        match self {
+            ErrorCode::MissingParameter => "missing-parameter",
+            ErrorCode::WrongType => "wrong-type",
+            ErrorCode::OutOfRange => "out-of-range",
+            ErrorCode::InvalidChoice => "invalid-choice",
+            ErrorCode::InvalidParameter => "invalid-parameter",
+            ErrorCode::MalformedRequest => "malformed-request",
+            ErrorCode::PassphraseRequired => "passphrase-required",
+            ErrorCode::IdentityRequired => "identity-required",
+            ErrorCode::InvalidPassphrase => "invalid-passphrase",
+            ErrorCode::InvalidSsid => "invalid-ssid",
+            ErrorCode::UnknownProfile => "unknown-profile",
+            ErrorCode::DuplicateNetwork => "duplicate-network",
+            ErrorCode::SavedProfileExists => "saved-profile-exists",
+            ErrorCode::InvalidNetworks => "invalid-networks",
+            ErrorCode::PortalNetwork => "portal-network",
+            ErrorCode::NetworkNotOffered => "network-not-offered",
+            ErrorCode::ConnectInProgress => "connect-in-progress",
+            ErrorCode::NotConnected => "not-connected",
+            ErrorCode::SessionLocked => "session-locked",
+            ErrorCode::CsrfToken => "csrf-token",
+            ErrorCode::Unauthorized => "unauthorized",
+            ErrorCode::ApiTokenNotConfigured => "api-token-not-configured",
+            ErrorCode::UnknownHost => "unknown-host",
+            ErrorCode::BodyTooLarge => "body-too-large",
+            ErrorCode::LengthRequired => "length-required",
+            ErrorCode::Busy => "busy",
+            ErrorCode::NetworkThread => "network-thread",
+            ErrorCode::NetworkStack => "network-stack",
+            ErrorCode::HostnameFailed => "hostname-failed",
+            ErrorCode::InvalidProfile => "invalid-profile",
+            ErrorCode::LogBufferDisabled => "log-buffer-disabled",
+            ErrorCode::QrTooLarge => "qr-too-large",
+        }
     }
 
-    let networks = match request_state.server_rx.recv() {
-        Ok(result) => match result {
-            NetworkCommandResponse::Networks(networks) => networks,
-        },
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::RecvAccessPointSSIDs),
-    };
+    /// The built-in English message, for people rather than clients
+    fn message(self) -> &'static str {
+        match self {
+            ErrorCode::MissingParameter => "Some of the required details are missing.",
+            ErrorCode::WrongType
+            | ErrorCode::OutOfRange
+            | ErrorCode::InvalidChoice
+            | ErrorCode::InvalidParameter => "Some of the details are not valid.",
+            ErrorCode::MalformedRequest => "The request could not be read.",
+            ErrorCode::PassphraseRequired => "This network requires a password.",
+            ErrorCode::IdentityRequired => "This network requires a user name.",
+            ErrorCode::InvalidPassphrase => {
+                "A WiFi password has 8 to 63 characters, or 64 hexadecimal digits."
+            }
+            ErrorCode::InvalidSsid => "A network name has 1 to 32 bytes.",
+            ErrorCode::UnknownProfile => "This saved network does not exist.",
+            ErrorCode::DuplicateNetwork => "A network is listed more than once.",
+            ErrorCode::SavedProfileExists => "This network is saved already.",
+            ErrorCode::InvalidNetworks => "Some of the networks are not valid.",
+            ErrorCode::PortalNetwork => "This is the network of the device itself.",
+            ErrorCode::NetworkNotOffered => "This network cannot be joined from here.",
+            ErrorCode::ConnectInProgress => "The device is connecting to a network.",
+            ErrorCode::NotConnected => "The device is not connected to a network.",
+            ErrorCode::SessionLocked => "Another device is setting up this one.",
+            ErrorCode::CsrfToken => "The page has expired, reload it and try again.",
+            ErrorCode::Unauthorized | ErrorCode::ApiTokenNotConfigured => {
+                "This request is not allowed."
+            }
+            ErrorCode::UnknownHost => "This address does not belong to the device.",
+            ErrorCode::BodyTooLarge | ErrorCode::LengthRequired => {
+                "The request could not be accepted."
+            }
+            ErrorCode::Busy => "The device is busy, try again shortly.",
+            ErrorCode::NetworkThread | ErrorCode::NetworkStack => {
+                "Something went wrong on the device, try again."
+            }
+            ErrorCode::HostnameFailed => "The name of the device could not be changed.",
+            ErrorCode::InvalidProfile => "This network profile is not valid.",
+            ErrorCode::LogBufferDisabled => "The device keeps no logs.",
+            ErrorCode::QrTooLarge => "The network details do not fit in a QR code.",
+        }
+    }
+}
 
-    let access_points_json = match serde_json::to_string(&networks) {
-        Ok(json) => json,
-        Err(e) => return exit_with_error(&request_state, e, ErrorKind::SerializeAccessPointSSIDs),
-    };
+/// Flattened into error bodies as their `code` and English `message`
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("code", self.as_str())?;
+        map.serialize_entry("message", self.message())?;
+        map.end()
+    }
+}
 
-    Ok(Response::with((status::Ok, access_points_json)))
+/// Body of client error responses, naming the offending parameter if any
+#[derive(Debug, Serialize)]
+struct ApiError {
+    #[serde(flatten)]
+    code: ErrorCode,
+    error: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    param: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected: Option<&'static str>,
 }
 
-fn connect(req: &mut Request) -> IronResult<Response> {
-    let (ssid, identity, passphrase) = {
-        let params = get_request_ref!(req, Params, "Getting request params failed");
-        let ssid = get_param!(params, "ssid", String);
-        let identity = get_param!(params, "identity", String);
-        let passphrase = get_param!(params, "passphrase", String);
-        (ssid, identity, passphrase)
-    };
+impl ApiError {
+    fn new<S: Into<String>>(code: ErrorCode, error: S) -> Self {
+        ApiError {
+            code,
+            error: error.into(),
+            param: None,
+            expected: None,
+        }
+    }
 
-    info!("Incoming `connect` to access point `{}` request", ssid);
+    fn missing(param: &'static str, expected: &'static str) -> Self {
+        ApiError {
+            code: ErrorCode::MissingParameter,
+            error: format!("Missing parameter '{}'", param),
+            param: Some(param),
+            expected: Some(expected),
+        }
+    }
 
-    let request_state = get_request_state!(req);
+    fn wrong_type(param: &'static str, expected: &'static str) -> Self {
+        ApiError {
+            code: ErrorCode::WrongType,
+            error: format!("Parameter '{}' must be {}", param, with_article(expected)),
+            param: Some(param),
+            expected: Some(expected),
+        }
+    }
 
-    let command = NetworkCommand::Connect {
-        ssid,
-        identity,
-        passphrase,
-    };
+    /// A number of the right type outside of the range in `expected`
+    fn out_of_range(param: &'static str, expected: &'static str) -> Self {
+        ApiError {
+            code: ErrorCode::OutOfRange,
+            error: format!("Parameter '{}' must be {}", param, with_article(expected)),
+            param: Some(param),
+            expected: Some(expected),
+        }
+    }
 
-    if let Err(e) = request_state.network_tx.send(command) {
-        exit_with_error(&request_state, e, ErrorKind::SendNetworkCommandConnect)
-    } else {
-        Ok(Response::with(status::Ok))
+    fn invalid_choice(param: &'static str, expected: &'static str) -> Self {
+        ApiError {
+            code: ErrorCode::InvalidChoice,
+            error: format!("Parameter '{}' must be {}", param, expected),
+            param: Some(param),
+            expected: Some(expected),
+        }
+    }
+}
+
+/// Reply to an accepted `/connect` request
+#[derive(Serialize)]
+struct ConnectResult {
+    ssid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid_raw: Option<String>,
+    /// The security type the connection profile is written for, absent when
+    /// a saved profile is activated
+    #[serde(skip_serializing_if = "Option::is_none")]
+    security: Option<&'static str>,
+    /// Seconds the activation may take, after clamping to the maximum
+    timeout: u64,
+    autoconnect: bool,
+    priority: i32,
+    metered: &'static str,
+    /// Resolvers replacing the DHCP provided ones, empty when there are none
+    dns: Vec<IpAddr>,
+    search_domains: Vec<String>,
+    /// UUID of the profile, if the network stack has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    uuid: Option<String>,
+    /// Whether a saved profile is activated, or updated with
+    /// `--duplicate-policy keep`, instead of creating one
+    reused: bool,
+}
+
+/// Reply to an accepted bulk `/connect` request, the networks in the order
+/// they are attempted in. `/status` reports how each of them fared and the
+/// one joined, as does the `connected` event with its UUID.
+#[derive(Serialize)]
+struct BulkConnectResult {
+    networks: Vec<ConnectResult>,
+}
+
+/// Body of the rejection of a bulk `/connect` request, none of its networks
+/// having been attempted
+#[derive(Serialize)]
+struct BulkConnectRejection {
+    #[serde(flatten)]
+    code: ErrorCode,
+    error: String,
+    invalid: Vec<InvalidNetwork>,
+}
+
+/// An invalid entry of a bulk `/connect` request, by its index in `networks`
+#[derive(Serialize)]
+struct InvalidNetwork {
+    index: usize,
+    #[serde(flatten)]
+    error: ApiError,
+}
+
+struct ConnectRequest {
+    /// Only absent when a saved profile is selected by UUID
+    ssid: Option<Ssid>,
+    credentials: Credentials,
+    timeout: Option<u64>,
+    profile: ProfileOptions,
+}
+
+impl ConnectRequest {
+    fn from_params(params: &Map) -> ::std::result::Result<Self, ApiError> {
+        let uuid = optional_string_param(params, "uuid")?;
+
+        let credentials = Credentials {
+            identity: optional_string_param(params, "identity")?,
+            passphrase: optional_string_param(params, "passphrase")?,
+            security: security_param(params, "security")?,
+        };
+
+        if uuid.is_some() && !credentials.is_empty() {
+            return Err(ApiError {
+                code: ErrorCode::InvalidParameter,
+                error: "Parameter 'uuid' selects a saved profile and takes no credentials"
+                    .to_string(),
+                param: Some("uuid"),
+                expected: None,
+            });
+        }
+
+        let ssid =
+            if uuid.is_some() && !params.contains_key("ssid") && !params.contains_key("ssid_raw") {
+                None
+            } else {
+                Some(ssid_param(params)?)
+            };
+
+        Ok(ConnectRequest {
+            ssid,
+            credentials,
+            timeout: timeout_param(params, "timeout")?,
+            profile: ProfileOptions {
+                autoconnect: bool_param(params, "autoconnect")?,
+                priority: priority_param(params, "priority")?,
+                metered: metered_param(params, "metered")?,
+                dns: address_list_param(params, "dns")?,
+                search_domains: domain_list_param(params, "search_domains")?,
+                uuid,
+            },
+        })
+    }
+
+    /// An entry of a bulk request, which always creates a profile
+    fn from_bulk_params(params: &Map) -> ::std::result::Result<Self, ApiError> {
+        if params.contains_key("uuid") {
+            return Err(ApiError {
+                code: ErrorCode::InvalidParameter,
+                error: "Parameter 'uuid' selects a saved profile, which bulk requests cannot"
+                    .to_string(),
+                param: Some("uuid"),
+                expected: None,
+            });
+        }
+
+        Ok(ConnectRequest {
+            ssid: Some(ssid_param(params)?),
+            ..ConnectRequest::from_params(params)?
+        })
+    }
+
+    /// The saved profile activated instead of creating one, matching the same
+    /// way the network thread does
+    fn find_saved(&self, saved_networks: Vec<SavedNetwork>) -> Option<SavedNetwork> {
+        saved_networks
+            .into_iter()
+            .filter(|saved| {
+                self.ssid
+                    .as_ref()
+                    .map_or(true, |ssid| saved.raw_ssid() == *ssid)
+                    && self
+                        .profile
+                        .uuid
+                        .as_ref()
+                        .map_or(true, |uuid| saved.uuid.as_ref() == Some(uuid))
+            })
+            .max_by_key(|saved| saved.last_used)
+    }
+
+    /// Checks the credentials against the explicitly requested security type
+    /// or, without one, against what the latest scan knows about the network.
+    /// Returns the security type the connection is attempted with.
+    fn validate(
+        &self,
+        ssid: &Ssid,
+        networks: &[Network],
+    ) -> ::std::result::Result<ConnectSecurity, ApiError> {
+        if let Some(security) = self.credentials.security {
+            validate_credentials(&self.credentials, security)?;
+            return Ok(security);
+        }
+
+        let scanned = networks
+            .iter()
+            .find(|network| network.raw_ssid() == *ssid)
+            .and_then(|network| Security::from_name(&network.security));
+
+        if let Some(scanned) = scanned {
+            if scanned != Security::None && self.credentials.passphrase.is_none() {
+                return Err(ApiError {
+                    code: ErrorCode::PassphraseRequired,
+                    error: format!("Network '{}' requires a passphrase", ssid),
+                    param: Some("passphrase"),
+                    expected: Some("string"),
+                });
+            }
+
+            if scanned == Security::Wpa && !is_valid_wpa_passphrase(self.credentials.passphrase()) {
+                return Err(invalid_wpa_passphrase());
+            }
+
+            return Ok(ConnectSecurity::from(scanned));
+        }
+
+        // Unknown networks are left to the network thread, the reported type
+        // is inferred from the credentials alone
+        let security = if self.credentials.identity.is_some() {
+            ConnectSecurity::WpaEap
+        } else if self.credentials.passphrase.is_some() {
+            ConnectSecurity::WpaPsk
+        } else {
+            ConnectSecurity::Open
+        };
+
+        Ok(security)
+    }
+}
+
+/// Checks the credentials against an explicitly requested security type
+fn validate_credentials(
+    credentials: &Credentials,
+    security: ConnectSecurity,
+) -> ::std::result::Result<(), ApiError> {
+    let takes_passphrase = security != ConnectSecurity::Open;
+    let takes_identity = security == ConnectSecurity::WpaEap;
+
+    if takes_passphrase && credentials.passphrase.is_none() {
+        return Err(ApiError {
+            code: ErrorCode::PassphraseRequired,
+            error: format!("Security '{}' requires a passphrase", security.as_str()),
+            param: Some("passphrase"),
+            expected: Some("string"),
+        });
+    }
+
+    if takes_identity && credentials.identity.is_none() {
+        return Err(ApiError {
+            code: ErrorCode::IdentityRequired,
+            error: format!("Security '{}' requires an identity", security.as_str()),
+            param: Some("identity"),
+            expected: Some("string"),
+        });
+    }
+
+    if !takes_passphrase && credentials.passphrase.is_some() {
+        return Err(unexpected_credential(security, "passphrase"));
+    }
+
+    if !takes_identity && credentials.identity.is_some() {
+        return Err(unexpected_credential(security, "identity"));
+    }
+
+    if security == ConnectSecurity::WpaPsk && !is_valid_wpa_passphrase(credentials.passphrase()) {
+        return Err(invalid_wpa_passphrase());
+    }
+
+    Ok(())
+}
+
+/// A 64 character passphrase is only accepted as the hex encoded PSK
+fn invalid_wpa_passphrase() -> ApiError {
+    ApiError {
+        code: ErrorCode::InvalidPassphrase,
+        error: "A WPA passphrase must be 8 to 63 printable characters or a PSK of 64 hexadecimal \
+                digits"
+            .to_string(),
+        param: Some("passphrase"),
+        expected: Some("string"),
+    }
+}
+
+fn unexpected_credential(security: ConnectSecurity, param: &'static str) -> ApiError {
+    ApiError {
+        code: ErrorCode::InvalidParameter,
+        error: format!("Security '{}' does not take a {}", security.as_str(), param),
+        param: Some(param),
+        expected: None,
+    }
+}
+
+fn string_param(params: &Map, param: &'static str) -> ::std::result::Result<String, ApiError> {
+    match params.get(param) {
+        Some(value) => {
+            String::from_value(value).ok_or_else(|| ApiError::wrong_type(param, "string"))
+        }
+        None => Err(ApiError::missing(param, "string")),
+    }
+}
+
+/// Missing parameters and empty strings, as sent by older UIs, are both absent
+fn optional_string_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<String>, ApiError> {
+    match params.get(param) {
+        Some(value) => match String::from_value(value) {
+            Some(ref value) if value.is_empty() => Ok(None),
+            Some(value) => Ok(Some(value)),
+            None => Err(ApiError::wrong_type(param, "string")),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Either a JSON boolean or a `true` or `false` form field string
+fn bool_param(params: &Map, param: &'static str) -> ::std::result::Result<Option<bool>, ApiError> {
+    match params.get(param) {
+        Some(value) => bool::from_value(value)
+            .map(Some)
+            .ok_or_else(|| ApiError::wrong_type(param, "boolean")),
+        None => Ok(None),
+    }
+}
+
+fn metered_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<Metered>, ApiError> {
+    let expected = "one of yes, no or auto";
+
+    match optional_string_param(params, param)? {
+        Some(name) => match Metered::from_name(&name) {
+            Some(metered) => Ok(Some(metered)),
+            None => Err(ApiError::invalid_choice(param, expected)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Comma separated IPv4 and IPv6 addresses, absent when empty
+fn address_list_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Vec<IpAddr>, ApiError> {
+    let expected = "comma separated list of IP addresses";
+
+    match optional_string_param(params, param)? {
+        Some(list) => list
+            .split(',')
+            .map(|address| {
+                address.trim().parse::<IpAddr>().map_err(|_| ApiError {
+                    code: ErrorCode::InvalidParameter,
+                    error: format!("Parameter '{}' has an invalid address '{}'", param, address),
+                    param: Some(param),
+                    expected: Some(expected),
+                })
+            })
+            .collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Comma separated domain names, absent when empty
+fn domain_list_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Vec<String>, ApiError> {
+    let expected = "comma separated list of domain names";
+
+    match optional_string_param(params, param)? {
+        Some(list) => list
+            .split(',')
+            .map(|domain| {
+                let domain = domain.trim();
+
+                if is_valid_domain_name(domain) {
+                    Ok(domain.to_string())
+                } else {
+                    Err(ApiError {
+                        code: ErrorCode::InvalidParameter,
+                        error: format!("Parameter '{}' has an invalid domain '{}'", param, domain),
+                        param: Some(param),
+                        expected: Some(expected),
+                    })
+                }
+            })
+            .collect(),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// `a string` or `an integer`, for the messages naming the expected type
+fn with_article(noun: &str) -> String {
+    let vowel = noun
+        .chars()
+        .next()
+        .map_or(false, |first| "aeiou".contains(first));
+
+    format!("{} {}", if vowel { "an" } else { "a" }, noun)
+}
+
+/// Of `MIN_PRIORITY` to `MAX_PRIORITY`
+const PRIORITY_RANGE: &str = "integer from -999 to 999";
+
+fn priority_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<i32>, ApiError> {
+    match params.get(param) {
+        Some(value) => match i64::from_value(value) {
+            Some(priority)
+                if priority >= i64::from(MIN_PRIORITY) && priority <= i64::from(MAX_PRIORITY) =>
+            {
+                Ok(Some(priority as i32))
+            }
+            Some(_) => Err(ApiError::out_of_range(param, PRIORITY_RANGE)),
+            None => Err(ApiError::wrong_type(param, "integer")),
+        },
+        None => Ok(None),
+    }
+}
+
+/// A positive number of seconds, either a JSON number or a form field string
+fn timeout_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<u64>, ApiError> {
+    match params.get(param) {
+        Some(value) => match u64::from_value(value) {
+            Some(0) | None => Err(ApiError::wrong_type(param, "positive integer")),
+            Some(seconds) => Ok(Some(seconds)),
+        },
+        None => Ok(None),
+    }
+}
+
+fn log_level_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<LogLevel>, ApiError> {
+    let expected = "one of error, warn, info, debug or trace";
+
+    match optional_string_param(params, param)? {
+        Some(name) => match name.parse::<LogLevel>() {
+            Ok(level) => Ok(Some(level)),
+            Err(_) => Err(ApiError::invalid_choice(param, expected)),
+        },
+        None => Ok(None),
+    }
+}
+
+/// SSIDs that are not valid UTF-8 are sent base64 encoded as `ssid_raw`,
+/// which takes precedence over `ssid` as clients may echo both from a listing
+fn ssid_param(params: &Map) -> ::std::result::Result<Ssid, ApiError> {
+    let (ssid, param) = match optional_string_param(params, "ssid_raw")? {
+        Some(ssid_raw) => match Ssid::from_raw(&ssid_raw) {
+            Some(ssid) => (ssid, "ssid_raw"),
+            None => return Err(ApiError::wrong_type("ssid_raw", "base64 string")),
+        },
+        None => (Ssid::from(&string_param(params, "ssid")? as &str), "ssid"),
+    };
+
+    if !ssid.is_valid_length() {
+        return Err(ApiError {
+            code: ErrorCode::InvalidSsid,
+            error: format!("An SSID must be 1 to {} bytes long", MAX_SSID_LENGTH),
+            param: Some(param),
+            expected: Some("string"),
+        });
+    }
+
+    Ok(ssid)
+}
+
+fn security_param(
+    params: &Map,
+    param: &'static str,
+) -> ::std::result::Result<Option<ConnectSecurity>, ApiError> {
+    let expected = "one of open, wep, wpa-psk, wpa-eap or sae";
+
+    match optional_string_param(params, param)? {
+        Some(name) => match ConnectSecurity::from_name(&name) {
+            Some(security) => Ok(Some(security)),
+            None => Err(ApiError::invalid_choice(param, expected)),
+        },
+        None => Ok(None),
+    }
+}
+
+#[derive(Debug)]
+struct StringError(String);
+
+impl fmt::Display for StringError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl StdError for StringError {
+    fn description(&self) -> &str {
+        &self.0
+    }
+}
+
+macro_rules! get_request_ref {
+    ($req:ident, $ty:ty, $err:expr) => {
+        match $req.get_ref::<$ty>() {
+            Ok(val) => val,
+            Err(err) => {
+                error!($err);
+                return Err(IronError::new(err, status::InternalServerError));
+            }
+        }
+    };
+}
+
+macro_rules! get_request_state {
+    ($req:ident) => {
+        get_request_ref!(
+            $req,
+            Write<RequestSharedState>,
+            "Getting reference to request shared state failed"
+        )
+        .as_ref()
+        .lock()
+        .unwrap_or_else(recover_poisoned)
+    };
+}
+
+/// The lock is only held for cloning the channels
+macro_rules! get_network_link {
+    ($req:ident) => {
+        get_request_ref!(
+            $req,
+            Write<NetworkLink>,
+            "Getting reference to the network thread channels failed"
+        )
+        .as_ref()
+        .lock()
+        .unwrap_or_else(recover_poisoned)
+        .clone()
+    };
+}
+
+fn exit_with_error<E>(exit_tx: &ExitSender, e: E, e_kind: ErrorKind) -> IronResult<Response>
+where
+    E: ::std::error::Error + Send + 'static,
+{
+    let description = e_kind.description().into();
+    let err = Err::<Response, E>(e).chain_err(|| e_kind);
+    exit(exit_tx, err.unwrap_err());
+    Err(IronError::new(
+        StringError(description),
+        status::InternalServerError,
+    ))
+}
+
+struct RequestLogger {
+    trusted_proxies: Vec<IpAddr>,
+    /// Fed the answered requests, by the logger running last only
+    metrics: Option<Arc<Metrics>>,
+    access_log: Option<Arc<AccessLog>>,
+}
+
+impl RequestLogger {
+    /// The client of the request, which is the last `X-Forwarded-For` entry
+    /// for requests arriving from a trusted proxy
+    fn client(&self, req: &Request) -> String {
+        if self.trusted_proxies.contains(&client_ip(req)) {
+            let forwarded = req
+                .headers
+                .get_raw("X-Forwarded-For")
+                .and_then(|values| values.last())
+                .and_then(|value| {
+                    String::from_utf8_lossy(value)
+                        .rsplit(',')
+                        .next()
+                        .map(|client| client.trim().to_string())
+                })
+                .and_then(|client| client.parse::<IpAddr>().ok());
+
+            if let Some(client) = forwarded {
+                return client.to_string();
+            }
+        }
+
+        SocketAddr::new(client_ip(req), req.remote_addr.port()).to_string()
+    }
+
+    /// Feeds the answered request to the metrics and the access log, with
+    /// the values of its `RES` line. Icon requests are left out, like they
+    /// are left out of the `info` log.
+    fn record(&self, req: &Request, status: u16, size: Option<u64>) {
+        if is_icon_request(req) || (self.metrics.is_none() && self.access_log.is_none()) {
+            return;
+        }
+
+        let client = self.client(req);
+        let method = req.method.to_string();
+        let path = format!("/{}", req.url.path().join("/"));
+        let version = req.version.to_string();
+        let user_agent = user_agent(req);
+
+        let entry = AccessEntry {
+            client: &client,
+            method: &method,
+            path: &path,
+            version: &version,
+            status,
+            size,
+            duration_ms: request_duration_ms(req),
+            user_agent: &user_agent,
+        };
+
+        if let Some(ref metrics) = self.metrics {
+            metrics.record(&entry);
+        }
+
+        if let Some(ref access_log) = self.access_log {
+            access_log.write(&entry);
+        }
+    }
+}
+
+/// The address of the client, with IPv4 clients of a dual-stack listener
+/// unmapped from `::ffff:a.b.c.d`
+fn client_ip(req: &Request) -> IpAddr {
+    match req.remote_addr.ip() {
+        IpAddr::V6(address) => {
+            let segments = address.segments();
+
+            if segments[..6] == [0, 0, 0, 0, 0, 0xffff] {
+                IpAddr::V4(Ipv4Addr::new(
+                    (segments[6] >> 8) as u8,
+                    segments[6] as u8,
+                    (segments[7] >> 8) as u8,
+                    segments[7] as u8,
+                ))
+            } else {
+                IpAddr::V6(address)
+            }
+        }
+        address => address,
+    }
+}
+
+/// Longest `User-Agent` logged, longer ones are cut
+const MAX_LOGGED_USER_AGENT_LENGTH: usize = 128;
+
+/// Formats the `User-Agent` of the request for logging, `-` without one
+fn user_agent(req: &Request) -> String {
+    match req.headers.get::<headers::UserAgent>() {
+        Some(user_agent) => {
+            let user_agent = user_agent
+                .chars()
+                .take(MAX_LOGGED_USER_AGENT_LENGTH)
+                .map(|c| if c == '"' || c.is_control() { '_' } else { c })
+                .collect::<String>();
+
+            format!("\"{}\"", user_agent)
+        }
+        None => "-".to_string(),
+    }
+}
+
+struct RequestStart;
+
+impl typemap::Key for RequestStart {
+    type Value = Instant;
+}
+
+/// Milliseconds elapsed since `RequestLogger::before` saw the request
+fn request_duration_ms(req: &Request) -> u128 {
+    req.extensions
+        .get::<RequestStart>()
+        .map_or(0, |start| start.elapsed().as_millis())
+}
+
+/// The response content length, if it is known yet
+fn content_length(res: &Response) -> Option<u64> {
+    res.headers
+        .get::<headers::ContentLength>()
+        .map(|length| length.0)
+}
+
+/// Formats the response content length for logging, `-` when not known yet
+fn response_size(res: &Response) -> String {
+    content_length(res).map_or_else(|| "-".to_string(), |length| length.to_string())
+}
+
+impl BeforeMiddleware for RequestLogger {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let request_id = &req as *const _ as usize;
+
+        req.extensions.insert::<RequestStart>(Instant::now());
+
+        log!(
+            request_log_level(req),
+            "REQ ({}): {} {} client={} user_agent={}",
+            request_id,
+            req.method,
+            req.url,
+            self.client(req),
+            user_agent(req)
+        );
+        Ok(())
+    }
+}
+
+impl AfterMiddleware for RequestLogger {
+    fn after(&self, req: &mut Request, res: Response) -> IronResult<Response> {
+        let request_id = &req as *const _ as usize;
+        let mut opt_code = res.status.map(|status| status.to_u16());
+        let return_code = opt_code.get_or_insert(0);
+        log!(
+            request_log_level(req),
+            "RES ({}): {} {} ({}) duration_ms={} size={} client={} user_agent={}",
+            request_id,
+            req.method,
+            req.url,
+            return_code,
+            request_duration_ms(req),
+            response_size(&res),
+            self.client(req),
+            user_agent(req)
+        );
+
+        self.record(req, *return_code, content_length(&res));
+
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        error!(
+            "Error encountered: {:?} duration_ms={}",
+            err,
+            request_duration_ms(req)
+        );
+
+        self.record(
+            req,
+            err.response.status.map_or(0, |status| status.to_u16()),
+            content_length(&err.response),
+        );
+
+        Err(err)
+    }
+}
+
+/// Browsers fetch the root icons alongside every page, which would drown the
+/// requests telling what clients do
+fn request_log_level(req: &Request) -> LogLevel {
+    if is_icon_request(req) {
+        LogLevel::Debug
+    } else {
+        LogLevel::Info
+    }
+}
+
+/// The body of an error response, kept for `LocalizedErrors`
+struct ErrorBody;
+
+impl typemap::Key for ErrorBody {
+    type Value = serde_json::Value;
+}
+
+/// Replaces the English `message` of error responses with the one of their
+/// `code` in the translation of the UI the client gets from `/locale`, if
+/// it has one under `errors`. Wraps every other handler, so that the
+/// rejections of the middlewares are translated as well.
+struct LocalizedErrors {
+    locales: Arc<Locales>,
+}
+
+impl AroundMiddleware for LocalizedErrors {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(LocalizedErrorsHandler {
+            handler,
+            locales: self.locales,
+        })
+    }
+}
+
+struct LocalizedErrorsHandler {
+    handler: Box<Handler>,
+    locales: Arc<Locales>,
+}
+
+impl LocalizedErrorsHandler {
+    fn localize(&self, req: &Request, mut response: Response) -> Response {
+        let mut body = match response.extensions.remove::<ErrorBody>() {
+            Some(body) => body,
+            None => return response,
+        };
+
+        let language = request_language(req, &self.locales);
+        let (_, strings) = self.locales.strings(&language);
+
+        if let Some(messages) = strings.get("errors").and_then(|errors| errors.as_object()) {
+            translate_messages(&mut body, messages);
+            response.set_mut(body.to_string());
+        }
+
+        response
+    }
+}
+
+impl Handler for LocalizedErrorsHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        match self.handler.handle(req) {
+            Ok(response) => Ok(self.localize(req, response)),
+            Err(mut err) => {
+                err.response = self.localize(req, err.response);
+                Err(err)
+            }
+        }
+    }
+}
+
+/// Translates the messages of the error and of the errors nested in it,
+/// like the invalid entries of a bulk `/connect` request
+fn translate_messages(
+    body: &mut serde_json::Value,
+    messages: &serde_json::Map<String, serde_json::Value>,
+) {
+    match *body {
+        serde_json::Value::Object(ref mut object) => {
+            let message = object
+                .get("code")
+                .and_then(|code| code.as_str())
+                .and_then(|code| messages.get(code))
+                .and_then(|message| message.as_str())
+                .map(String::from);
+
+            if let Some(message) = message {
+                object.insert("message".to_string(), serde_json::Value::String(message));
+            }
+
+            for value in object.values_mut() {
+                translate_messages(value, messages);
+            }
+        }
+        serde_json::Value::Array(ref mut values) => {
+            for value in values {
+                translate_messages(value, messages);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// In single client mode, the first client loading the UI claims the session
+/// and requests changing anything from other clients are refused with `423`
+struct SessionLock;
+
+impl BeforeMiddleware for SessionLock {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let client = client_ip(req);
+        let claims = req.method == Method::Get && req.url.path() == [""];
+        let changes = match req.method {
+            Method::Get | Method::Head | Method::Options => false,
+            _ => true,
+        };
+
+        let mut request_state = get_request_state!(req);
+
+        if !request_state.single_client {
+            return Ok(());
+        }
+
+        match request_state.session_owner() {
+            Some(owner) if owner == client => {
+                if let Some(ref mut session) = request_state.session {
+                    session.last_seen = Instant::now();
+                }
+            }
+            Some(owner) => {
+                if changes {
+                    info!(
+                        "Refusing request of {}, the session belongs to {}",
+                        client, owner
+                    );
+
+                    let error = ApiError::new(
+                        ErrorCode::SessionLocked,
+                        "Another client holds the provisioning session",
+                    );
+                    return Err(IronError {
+                        error: Box::new(StringError(error.error.clone())),
+                        response: error_response(status::Locked, &error),
+                    });
+                }
+            }
+            None => {
+                if claims {
+                    info!("Client {} claimed the provisioning session", client);
+
+                    request_state.session = Some(ClientSession {
+                        owner: client,
+                        last_seen: Instant::now(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Cookie the UI reads the CSRF token from
+const CSRF_COOKIE: &str = "csrf_token";
+
+/// Header, or form field, requests changing anything repeat the CSRF token
+/// in
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Clients with a token at once, past which the longest issued is dropped
+const MAX_CSRF_CLIENTS: usize = 256;
+
+/// The CSRF tokens issued so far, one per client address, so that a client
+/// cannot submit requests with the token of another one
+#[derive(Clone, Default)]
+struct CsrfTokens(Arc<Mutex<IssuedTokens>>);
+
+#[derive(Default)]
+struct IssuedTokens {
+    /// Each with the number of tokens issued before it
+    tokens: HashMap<IpAddr, (String, u64)>,
+    issued: u64,
+}
+
+impl CsrfTokens {
+    fn get(&self, client: IpAddr) -> Option<String> {
+        let issued = self.0.lock().unwrap_or_else(recover_poisoned);
+        issued
+            .tokens
+            .get(&client)
+            .map(|&(ref token, _)| token.clone())
+    }
+
+    /// The token of the client, issuing one if it has none yet
+    fn issue(&self, client: IpAddr) -> io::Result<String> {
+        let mut issued = self.0.lock().unwrap_or_else(recover_poisoned);
+
+        if let Some(&(ref token, _)) = issued.tokens.get(&client) {
+            return Ok(token.clone());
+        }
+
+        if issued.tokens.len() >= MAX_CSRF_CLIENTS {
+            let oldest = issued
+                .tokens
+                .iter()
+                .min_by_key(|&(_, &(_, order))| order)
+                .map(|(&client, _)| client);
+
+            if let Some(oldest) = oldest {
+                issued.tokens.remove(&oldest);
+            }
+        }
+
+        let token = random_token()?;
+        let order = issued.issued;
+        issued.tokens.insert(client, (token.clone(), order));
+        issued.issued += 1;
+
+        Ok(token)
+    }
+}
+
+/// Guards against pages on the client device submitting forms to the portal
+/// the moment it joins the network. Every client is issued a token of its
+/// own as a cookie, which only pages of the portal itself can read and
+/// repeat in a header or form field, and which is only accepted from that
+/// client. Bearer token holders are no browsers and skip the check.
+struct Csrf {
+    tokens: CsrfTokens,
+    api_token: Option<String>,
+}
+
+impl Csrf {
+    fn submitted_token(&self, req: &mut Request) -> Option<String> {
+        if let Some(values) = req.headers.get_raw(CSRF_HEADER) {
+            return values
+                .first()
+                .map(|value| String::from_utf8_lossy(value).trim().to_string());
+        }
+
+        let form = match req.headers.get::<headers::ContentType>() {
+            Some(&headers::ContentType(Mime(
+                TopLevel::Application,
+                SubLevel::WwwFormUrlEncoded,
+                _,
+            ))) => true,
+            _ => false,
+        };
+
+        if !form {
+            return None;
+        }
+
+        req.get_ref::<Params>()
+            .ok()
+            .and_then(|params| params.find(&[CSRF_COOKIE]))
+            .and_then(String::from_value)
+    }
+
+    fn has_cookie(&self, req: &Request, token: &str) -> bool {
+        req.headers
+            .get::<headers::Cookie>()
+            .map_or(false, |cookies| {
+                cookies
+                    .iter()
+                    .any(|cookie| cookie_value(cookie, CSRF_COOKIE) == Some(token))
+            })
+    }
+}
+
+impl BeforeMiddleware for Csrf {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        match req.method {
+            Method::Get | Method::Head | Method::Options => return Ok(()),
+            _ => {}
+        }
+
+        if self.api_token.is_some() && bearer_token(req) == self.api_token {
+            return Ok(());
+        }
+
+        let client = client_ip(req);
+        let submitted = self.submitted_token(req);
+
+        let matches = match (submitted, self.tokens.get(client)) {
+            (Some(submitted), Some(token)) => tokens_match(&submitted, &token),
+            _ => false,
+        };
+
+        if matches {
+            return Ok(());
+        }
+
+        warn!(
+            "Rejecting {} /{} of {}: missing or wrong CSRF token",
+            req.method,
+            req.url.path().join("/"),
+            client
+        );
+
+        let error = ApiError::new(ErrorCode::CsrfToken, "Missing or wrong CSRF token");
+        Err(IronError {
+            error: Box::new(StringError(error.error.clone())),
+            response: error_response(status::Forbidden, &error),
+        })
+    }
+}
+
+impl AfterMiddleware for Csrf {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        let client = client_ip(req);
+
+        let token = match self.tokens.issue(client) {
+            Ok(token) => token,
+            Err(e) => {
+                warn!("Issuing a CSRF token to {} failed: {}", client, e);
+                return Ok(res);
+            }
+        };
+
+        if self.has_cookie(req, &token) {
+            return Ok(res);
+        }
+
+        let cookie = format!("{}={}; Path=/; SameSite=Strict", CSRF_COOKIE, token);
+
+        match res.headers.get_mut::<headers::SetCookie>() {
+            // Keeps the cookies the handler set
+            Some(&mut headers::SetCookie(ref mut cookies)) => cookies.push(cookie),
+            None => res.headers.set(headers::SetCookie(vec![cookie])),
+        }
+
+        Ok(res)
+    }
+}
+
+/// The value of a `name=value` cookie, if it is the named one
+fn cookie_value<'a>(cookie: &'a str, name: &str) -> Option<&'a str> {
+    let mut parts = cookie.splitn(2, '=');
+
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) if key.trim() == name => Some(value.trim()),
+        _ => None,
+    }
+}
+
+/// Compares in constant time, so the token cannot be guessed byte by byte
+fn tokens_match(submitted: &str, token: &str) -> bool {
+    submitted.len() == token.len()
+        && submitted
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// A token of 128 random bits in hex
+fn random_token() -> io::Result<String> {
+    let mut bytes = [0; 16];
+    fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+
+    Ok(bytes.iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The `Host` headers addressing the portal, any other one is captive
+/// traffic or a DNS rebinding attempt
+#[derive(Clone)]
+struct PortalHosts {
+    hosts: Vec<String>,
+}
+
+impl PortalHosts {
+    fn new(server_config: &ServerConfig) -> Self {
+        let mut hosts = vec![
+            server_config.gateway.to_string(),
+            "localhost".to_string(),
+            Ipv4Addr::LOCALHOST.to_string(),
+        ];
+
+        if !server_config.listening_address.is_unspecified() {
+            hosts.push(server_config.listening_address.to_string());
+        }
+
+        if let Some(gateway6) = server_config.gateway6 {
+            hosts.push(gateway6.to_string());
+            hosts.push(Ipv6Addr::LOCALHOST.to_string());
+        }
+
+        hosts.extend(server_config.allowed_hosts.iter().cloned());
+
+        PortalHosts { hosts }
+    }
+
+    /// Clients may spell the host in upper case or give it with a port, and
+    /// IPv6 addresses in brackets, with a zone or in any of their forms
+    fn contains(&self, host: &str) -> bool {
+        let host = host_without_port(host).trim_end_matches('.');
+
+        let host = if host.starts_with('[') {
+            let address = host.trim_start_matches('[').trim_end_matches(']');
+            let address = address.split('%').next().unwrap_or(address);
+
+            match address.parse::<Ipv6Addr>() {
+                Ok(address) => address.to_string(),
+                Err(_) => return false,
+            }
+        } else {
+            host.to_string()
+        };
+
+        self.hosts
+            .iter()
+            .any(|allowed| allowed.eq_ignore_ascii_case(&host))
+    }
+}
+
+/// The mDNS name as clients resolve it and the configured extra names
+fn portal_host_names(config: &Config) -> Vec<String> {
+    let mut hosts = config.allowed_hosts.clone();
+
+    if !config.mdns_name.is_empty() {
+        let mut mdns_name = config.mdns_name.clone();
+
+        if !mdns_name.ends_with(".local") {
+            mdns_name.push_str(".local");
+        }
+
+        hosts.push(mdns_name);
+    }
+
+    hosts
+}
+
+/// Keeps DNS rebinding pages from reaching the API: requests naming another
+/// host are turned into the captive redirect, or refused if they would act
+struct HostCheck {
+    hosts: PortalHosts,
+}
+
+impl BeforeMiddleware for HostCheck {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let host = match req.headers.get::<headers::Host>() {
+            Some(host) => host.hostname.clone(),
+            None => return Ok(()),
+        };
+
+        if self.hosts.contains(&host) || is_captive_probe(req) {
+            return Ok(());
+        }
+
+        // Left to `RedirectMiddleware`
+        if req.method == Method::Get || req.method == Method::Head {
+            return Err(IronError::new(
+                StringError(format!("Request for foreign host {}", host)),
+                status::NotFound,
+            ));
+        }
+
+        info!("Refusing {} request for foreign host {}", req.method, host);
+
+        let error = ApiError::new(
+            ErrorCode::UnknownHost,
+            format!("The portal does not serve host '{}'", host),
+        );
+        Err(IronError {
+            error: Box::new(StringError(error.error.clone())),
+            response: error_response(status::MisdirectedRequest, &error),
+        })
+    }
+}
+
+/// Refuses request bodies over the limit of their route from their
+/// `Content-Length`, before anything of them is buffered
+struct BodyLimit {
+    max_body_size: u64,
+    max_upload_size: u64,
+}
+
+impl BodyLimit {
+    fn limit(&self, req: &Request) -> u64 {
+        if req.url.path() == ["networks", "import"] {
+            self.max_upload_size
+        } else {
+            self.max_body_size
+        }
+    }
+}
+
+impl BeforeMiddleware for BodyLimit {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let limit = self.limit(req);
+
+        let (status, error) = match req.headers.get::<headers::ContentLength>() {
+            Some(&headers::ContentLength(length)) if length > limit => (
+                status::PayloadTooLarge,
+                ApiError::new(
+                    ErrorCode::BodyTooLarge,
+                    format!("The request body exceeds the limit of {} bytes", limit),
+                ),
+            ),
+            Some(_) => return Ok(()),
+            // Chunked bodies would have to be read to learn their size
+            None if req.headers.has::<headers::TransferEncoding>() => (
+                status::LengthRequired,
+                ApiError::new(
+                    ErrorCode::LengthRequired,
+                    "Request bodies require a Content-Length",
+                ),
+            ),
+            None => return Ok(()),
+        };
+
+        warn!(
+            "Rejecting {} request for {}: {}",
+            req.method, req.url, error.error
+        );
+
+        Err(IronError {
+            error: Box::new(StringError(error.error.clone())),
+            response: error_response(status, &error),
+        })
+    }
+}
+
+/// Answers requests past the number handled at once with 503 rather than
+/// queueing them, with UI files and API requests counted apart
+struct InFlightLimit {
+    max_requests: usize,
+    max_asset_requests: usize,
+}
+
+impl AroundMiddleware for InFlightLimit {
+    fn around(self, handler: Box<Handler>) -> Box<Handler> {
+        Box::new(InFlightHandler {
+            handler,
+            api: Slots::new(self.max_requests),
+            assets: Slots::new(self.max_asset_requests),
+        })
+    }
+}
+
+struct InFlightHandler {
+    handler: Box<Handler>,
+    api: Slots,
+    assets: Slots,
+}
+
+impl Handler for InFlightHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let slots = if is_asset_request(req) {
+            &self.assets
+        } else {
+            &self.api
+        };
+
+        let _slot = match slots.acquire() {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    "Refusing {} request for {}, {} requests are in flight",
+                    req.method, req.url, slots.limit
+                );
+
+                let error = ApiError::new(ErrorCode::Busy, "The portal is busy, try again shortly");
+                let mut response = error_response(status::ServiceUnavailable, &error);
+                response.headers.set_raw("Retry-After", vec![b"1".to_vec()]);
+                return Ok(response);
+            }
+        };
+
+        self.handler.handle(req)
+    }
+}
+
+/// Counts the requests in flight against a limit, `0` for none
+struct Slots {
+    limit: usize,
+    in_flight: AtomicUsize,
+}
+
+impl Slots {
+    fn new(limit: usize) -> Self {
+        Slots {
+            limit,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+
+    fn acquire(&self) -> Option<Slot> {
+        let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst);
+
+        if self.limit > 0 && in_flight >= self.limit {
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            return None;
+        }
+
+        Some(Slot(&self.in_flight))
+    }
+}
+
+/// Frees its slot once the request is handled
+struct Slot<'a>(&'a AtomicUsize);
+
+impl<'a> Drop for Slot<'a> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether the request is for a file of the UI rather than the API
+fn is_asset_request(req: &Request) -> bool {
+    if req.method != Method::Get && req.method != Method::Head {
+        return false;
+    }
+
+    let path = req.url.path();
+
+    match path.first() {
+        Some(&"") | Some(&"static") | Some(&"css") | Some(&"img") | Some(&"js") => true,
+        _ => path.last().map_or(false, |segment| segment.contains('.')),
+    }
+}
+
+struct RedirectMiddleware {
+    captive_mode: CaptiveMode,
+    hosts: PortalHosts,
+}
+
+impl AfterMiddleware for RedirectMiddleware {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        let client = client_ip(req);
+        let portal = get_request_state!(req).portal_url(client);
+
+        // Redirecting anything else would hide the real error from API
+        // clients that address the portal by a name
+        if req.method != Method::Get && req.method != Method::Head {
+            return Err(err);
+        }
+
+        let redirect = match req.headers.get::<headers::Host>() {
+            Some(host) => !self.hosts.contains(&host.hostname),
+            None => {
+                debug!(
+                    "[no-host] HTTP/1.0 style {} request for /{}",
+                    req.method,
+                    req.url.path().join("/")
+                );
+
+                // Old clients without a Host header are meant for the portal,
+                // so only what it has no route for is sent to its front page
+                err.response.status == Some(status::NotFound)
+            }
+        };
+
+        if !redirect {
+            return Err(err);
+        }
+
+        let portal = match portal {
+            Some(portal) => portal,
+            None => {
+                error!(
+                    "No portal URL to redirect the request to {} to",
+                    req.url.host()
+                );
+                return Err(err);
+            }
+        };
+
+        info!(
+            "Redirecting Request to {} to gateway: {}",
+            req.url.host(),
+            portal.as_ref().host_str().unwrap_or("")
+        );
+
+        Ok(portal_redirect(self.captive_mode, portal, &req.url))
+    }
+}
+
+/// Points the client at the portal UI the way the captive mode asks for,
+/// passing along the URL it was after
+fn portal_redirect(captive_mode: CaptiveMode, mut portal: Url, url: &Url) -> Response {
+    let original = original_url(url, portal.as_ref().host_str().unwrap_or(""));
+
+    if let Some(original) = original {
+        portal
+            .as_mut()
+            .query_pairs_mut()
+            .append_pair("original_url", &original);
+    }
+
+    match captive_mode {
+        CaptiveMode::Redirect => Response::with((status::Found, Redirect(portal))),
+        CaptiveMode::NetworkAuthenticationRequired => {
+            network_authentication_required(&portal.to_string())
+        }
+    }
+}
+
+/// The front page of the portal at the gateway, with the port unless it is
+/// the default one
+fn portal_url(gateway: IpAddr, port: u16) -> Option<Url> {
+    let host = match gateway {
+        IpAddr::V4(gateway) => gateway.to_string(),
+        IpAddr::V6(gateway) => format!("[{}]", gateway),
+    };
+
+    let url = if port == 80 {
+        format!("http://{}/", host)
+    } else {
+        format!("http://{}:{}/", host, port)
+    };
+
+    match Url::parse(&url) {
+        Ok(url) => Some(url),
+        Err(e) => {
+            error!("Requests cannot be redirected to {}: {}", url, e);
+            None
+        }
+    }
+}
+
+/// The host name of a `Host` header
+fn host_without_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.find(']').map_or(host, |end| &host[..=end])
+    } else {
+        match host.rfind(':') {
+            // A single colon separates the port, more belong to a bare IPv6
+            // address
+            Some(colon) if host.find(':') == Some(colon) => &host[..colon],
+            _ => host,
+        }
+    }
+}
+
+/// Longest `original_url` handed to the portal UI, past which the query
+/// string is dropped, then the whole URL
+const MAX_ORIGINAL_URL_LENGTH: usize = 2048;
+
+/// The URL the client was after before being sent to the portal, for the UI
+/// to offer going back there once online. None for the portal itself, which
+/// would only send the client around in circles. IPv6 gateways are
+/// compared in brackets, the way URLs carry them.
+fn original_url(url: &Url, gateway: &str) -> Option<String> {
+    let mut original = url.as_ref().clone();
+
+    match original.host_str() {
+        Some(host) if !host.eq_ignore_ascii_case(gateway) => {}
+        _ => return None,
+    }
+
+    original.set_fragment(None);
+
+    if original.as_str().len() > MAX_ORIGINAL_URL_LENGTH {
+        original.set_query(None);
+    }
+
+    if original.as_str().len() > MAX_ORIGINAL_URL_LENGTH {
+        return None;
+    }
+
+    Some(original.into_string())
+}
+
+/// Answers page loads of paths the portal has nothing for with the
+/// `404.html` of the UI, or a page of its own linking to the UI. Requests
+/// for other hosts are redirected before reaching it.
+struct NotFoundPage {
+    ui_directory: PathBuf,
+}
+
+impl AfterMiddleware for NotFoundPage {
+    fn catch(&self, req: &mut Request, err: IronError) -> IronResult<Response> {
+        let page_load = req.method == Method::Get || req.method == Method::Head;
+
+        if !page_load || err.response.status != Some(status::NotFound) {
+            return Err(err);
+        }
+
+        let page = fs::read_to_string(self.ui_directory.join("404.html")).unwrap_or_else(|_| {
+            "<!DOCTYPE html>\n<html><head><title>Not Found</title></head>\
+             <body><p>This page does not exist. <a href=\"/\">Join a network</a> \
+             instead.</p></body></html>\n"
+                .to_string()
+        });
+
+        let mut response = Response::with((status::NotFound, page));
+        response.headers.set(headers::ContentType(Mime(
+            TopLevel::Text,
+            SubLevel::Html,
+            vec![(Attr::Charset, Value::Utf8)],
+        )));
+
+        Ok(response)
+    }
+}
+
+/// Tells why the built-in page is served instead of the UI, once at start
+/// rather than on every page load
+fn warn_on_missing_index(index: &Path) {
+    let directory = index.parent().unwrap_or(index);
+
+    let reason = if !directory.is_dir() {
+        format!("the UI directory {} does not exist", directory.display())
+    } else if !index.is_file() {
+        format!("{} does not exist", index.display())
+    } else {
+        return;
+    };
+
+    warn!(
+        "Serving the built-in provisioning page from /, as {}. Check --ui-directory",
+        reason
+    );
+}
+
+/// The built-in page, never cached so that the UI shows up once installed
+fn fallback_page() -> Response {
+    let mut response = no_store(Response::with((status::Ok, FALLBACK_PAGE)));
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Text,
+        SubLevel::Html,
+        vec![(Attr::Charset, Value::Utf8)],
+    )));
+
+    response
+}
+
+/// RFC 6585 asks for a page pointing the user at the login, which is the
+/// portal UI here
+fn network_authentication_required(portal: &str) -> Response {
+    let portal = portal.replace('&', "&amp;").replace('"', "&quot;");
+
+    let page = format!(
+        "<!DOCTYPE html>\n<html><head><title>Network Authentication Required</title>\
+         <meta http-equiv=\"refresh\" content=\"0; url={0}\"></head>\
+         <body><p>Join this network from <a href=\"{0}\">{0}</a>.</p></body></html>\n",
+        portal
+    );
+
+    let mut response = no_store(Response::with((
+        status::NetworkAuthenticationRequired,
+        page,
+    )));
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Text,
+        SubLevel::Html,
+        vec![(Attr::Charset, Value::Utf8)],
+    )));
+
+    response
+}
+
+/// The routes of the portal, with those of the API listed so that the tests
+/// check the OpenAPI document against the routes actually served
+struct PortalRoutes {
+    router: Router,
+    api_routes: Vec<(Method, &'static str)>,
+}
+
+impl PortalRoutes {
+    /// Adds a route of the API, which the OpenAPI document describes
+    fn api<H: Handler>(&mut self, method: Method, path: &'static str, handler: H, name: &str) {
+        self.router.route(method.clone(), path, handler, name);
+        self.api_routes.push((method, path));
+    }
+}
+
+/// Routes the UI, the API, the captive probes and the root icons
+fn portal_routes(
+    server_config: &ServerConfig,
+    locales: &Arc<Locales>,
+    metrics: Option<&Arc<Metrics>>,
+) -> Result<PortalRoutes> {
+    let ui_directory = &server_config.ui_directory;
+    let immutable_assets = &server_config.immutable_assets;
+    let mime_types = &server_config.mime_types;
+
+    let mut routes = PortalRoutes {
+        router: Router::new(),
+        api_routes: Vec::new(),
+    };
+
+    let index = ui_directory.join("index.html");
+    warn_on_missing_index(&index);
+    {
+        let files = StaticFiles::new(ui_directory).mime_types(mime_types);
+        let index = index.clone();
+        routes.router.get(
+            "/",
+            move |req: &mut Request| {
+                if index.is_file() {
+                    files.handle(req)
+                } else {
+                    Ok(fallback_page())
+                }
+            },
+            "index",
+        );
+    }
+    routes.api(Method::Get, "/networks", networks, "networks");
+    routes.api(Method::Get, "/api/v1/networks", networks_v1, "networks_v1");
+    routes.api(
+        Method::Get,
+        "/networks/saved",
+        saved_networks,
+        "saved_networks",
+    );
+    routes.api(Method::Post, "/connect", connect, "connect");
+    routes.api(
+        Method::Post,
+        "/connect/cancel",
+        cancel_connect,
+        "cancel_connect",
+    );
+    routes.api(Method::Get, "/status", connection_status, "status");
+    routes.api(Method::Get, "/signal", signal, "signal");
+    routes.api(Method::Get, "/interfaces", interfaces, "interfaces");
+    routes.api(Method::Get, "/clients", clients, "clients");
+    routes.api(Method::Get, "/logs", logs, "logs");
+    routes.api(Method::Get, "/qr", qr, "qr");
+    routes.api(Method::Get, "/qr.svg", qr_svg, "qr_svg");
+    routes.api(Method::Post, "/reset", reset, "reset");
+    routes.api(Method::Post, "/hostname", hostname, "hostname");
+    routes.api(
+        Method::Post,
+        "/session/release",
+        release_session,
+        "release_session",
+    );
+    routes.api(
+        Method::Get,
+        "/networks/saved/:uuid/export",
+        export_profile,
+        "export_profile",
+    );
+    routes.api(
+        Method::Post,
+        "/networks/import",
+        import_profile,
+        "import_profile",
+    );
+    routes.api(Method::Get, "/version", version, "version");
+
+    if let Some(metrics) = metrics {
+        let metrics = metrics.clone();
+        routes.api(
+            Method::Get,
+            "/metrics",
+            move |req: &mut Request| metrics_reply(req, &metrics),
+            "metrics",
+        );
+    }
+
+    if server_config.ui_tree {
+        let files = StaticFiles::new(ui_directory)
+            .immutable(immutable_assets)
+            .mime_types(mime_types);
+        routes.router.get(
+            "/*",
+            move |req: &mut Request| {
+                // The UI is loaded from `/`, its index page is not a file
+                if req.url.path() == ["index.html"] {
+                    return Err(IronError::new(
+                        StringError("Not found".to_string()),
+                        status::NotFound,
+                    ));
+                }
+
+                files.handle(req)
+            },
+            "ui_tree",
+        );
+    }
+
+    let captive_mode = server_config.captive_mode;
+
+    for &(path, name) in ANDROID_PROBE_PATHS.iter() {
+        routes.router.get(
+            path,
+            move |req: &mut Request| android_probe(req, captive_mode),
+            name,
+        );
+    }
+
+    for &(path, name, mime_type) in ICON_PATHS.iter() {
+        let file = ui_directory.join(&path[1..]);
+        routes.router.get(
+            path,
+            move |_: &mut Request| Ok(icon(&file, mime_type)),
+            name,
+        );
+    }
+
+    for &(path, name) in APPLE_PROBE_PATHS.iter() {
+        let index = index.clone();
+        routes.router.get(
+            path,
+            move |req: &mut Request| apple_probe(req, &index),
+            name,
+        );
+    }
+
+    let ui_config = UiConfig {
+        connect_strategy: server_config.connect_strategy,
+        ssid_allow: server_config.ssid_filter.allow.clone(),
+        ssid_deny: server_config.ssid_filter.deny.clone(),
+        language: String::new(),
+    };
+    {
+        let locales = locales.clone();
+        routes.api(
+            Method::Get,
+            "/config.json",
+            move |req: &mut Request| {
+                let language = request_language(req, &locales);
+                let ui_config = UiConfig {
+                    language: locales.strings(&language).0,
+                    ..ui_config.clone()
+                };
+
+                match serde_json::to_string(&ui_config) {
+                    Ok(json) => Ok(json_response(json)),
+                    Err(e) => Err(IronError::new(e, status::InternalServerError)),
+                }
+            },
+            "config",
+        );
+    }
+    {
+        let locales = locales.clone();
+        routes.api(
+            Method::Get,
+            "/locale",
+            move |req: &mut Request| locale(req, &locales),
+            "locale",
+        );
+    }
+
+    let openapi_json = openapi_document()?;
+    routes.api(
+        Method::Get,
+        "/api/openapi.json",
+        move |_: &mut Request| Ok(json_response(openapi_json.clone())),
+        "openapi",
+    );
+
+    Ok(routes)
+}
+
+/// Starts serving the portal UI and API from a background thread. Commands
+/// are forwarded to the network thread through `network_tx`, each with a
+/// response channel of its own, while failures of the network thread found
+/// while handling requests are reported through `exit_tx`.
+pub fn start_server(
+    server_config: &ServerConfig,
+    network_tx: NetworkCommandSender,
+    exit_tx: ExitSender,
+) -> Result<ServerHandle> {
+    let (listener, listening_port) = bind_server(server_config)?;
+
+    let ui_directory = &server_config.ui_directory;
+    let (event_tx, event_rx) = event_channel();
+    let request_state = RequestSharedState::new(server_config, event_rx, listening_port);
+    let network_link = NetworkLink {
+        network_tx,
+        exit_tx,
+    };
+
+    let metrics = if server_config.metrics {
+        Some(Arc::new(Metrics::default()))
+    } else {
+        None
+    };
+
+    let access_log = match server_config.access_log {
+        Some(ref path) => {
+            let access_log = AccessLog::open(path)
+                .chain_err(|| ErrorKind::OpenAccessLog(path.display().to_string()))?;
+            info!("Logging the requests to {}", path.display());
+            Some(Arc::new(access_log))
+        }
+        None => None,
+    };
+
+    let locales = Arc::new(Locales::new(ui_directory));
+
+    let router = portal_routes(server_config, &locales, metrics.as_ref())?.router;
+
+    let immutable_assets = &server_config.immutable_assets;
+    let mime_types = &server_config.mime_types;
+
+    let mut assets = Mount::new();
+    assets.mount("/", router);
+    assets.mount(
+        "/static",
+        StaticFiles::new(ui_directory.join("static"))
+            .immutable(immutable_assets)
+            .mime_types(mime_types),
+    );
+    assets.mount(
+        "/css",
+        StaticFiles::new(ui_directory.join("css"))
+            .immutable(immutable_assets)
+            .mime_types(mime_types),
+    );
+    assets.mount(
+        "/img",
+        StaticFiles::new(ui_directory.join("img")).mime_types(mime_types),
+    );
+    assets.mount(
+        "/js",
+        StaticFiles::new(ui_directory.join("js"))
+            .immutable(immutable_assets)
+            .mime_types(mime_types),
+    );
+
+    for &(ref prefix, ref directory) in &server_config.static_mounts {
+        info!("Serving {} from {}", prefix, directory.display());
+        assets.mount(
+            prefix,
+            StaticFiles::new(directory)
+                .immutable(immutable_assets)
+                .mime_types(mime_types),
+        );
+    }
+
+    let cors_middleware = CorsMiddleware::with_allow_any();
+
+    let portal_hosts = PortalHosts::new(server_config);
+
+    let mut chain = Chain::new(assets);
+    chain.link_before(RequestLogger {
+        trusted_proxies: server_config.trusted_proxies.clone(),
+        metrics: None,
+        access_log: None,
+    });
+    chain.link_after(RequestLogger {
+        trusted_proxies: server_config.trusted_proxies.clone(),
+        metrics: None,
+        access_log: None,
+    });
+    chain.link(Write::<RequestSharedState>::both(request_state));
+    chain.link(Write::<NetworkLink>::both(network_link));
+    chain.link_before(HostCheck {
+        hosts: portal_hosts.clone(),
+    });
+    chain.link_before(BodyLimit {
+        max_body_size: server_config.max_body_size,
+        max_upload_size: server_config.max_upload_size,
+    });
+    chain.link_before(SessionLock);
+
+    if server_config.csrf {
+        let csrf_tokens = CsrfTokens::default();
+        let csrf = || Csrf {
+            tokens: csrf_tokens.clone(),
+            api_token: server_config.api_token.clone(),
+        };
+        chain.link_before(csrf());
+        chain.link_after(csrf());
+    } else {
+        info!("CSRF protection disabled");
+    }
+    chain
+        .link_after(RedirectMiddleware {
+            captive_mode: server_config.captive_mode,
+            hosts: portal_hosts,
+        })
+        .link_after(NotFoundPage {
+            ui_directory: ui_directory.clone(),
+        })
+        .link_after(RequestLogger {
+            trusted_proxies: server_config.trusted_proxies.clone(),
+            metrics,
+            access_log: access_log.clone(),
+        });
+    chain.link_around(cors_middleware);
+    chain.link_around(InFlightLimit {
+        max_requests: server_config.max_requests,
+        max_asset_requests: server_config.max_asset_requests,
+    });
+    chain.link_around(LocalizedErrors { locales });
+
+    let shutdown = Arc::new(ShutdownState::default());
+    let shutdown_handler = shutdown.clone();
+
+    let handler = move |req: &mut Request| {
+        if shutdown_handler.is_stopped() {
+            // The portal is going away, so phones are released from it
+            if is_android_probe(req) {
+                return Ok(android_probe_released());
+            }
+
+            Ok(Response::with(status::ServiceUnavailable))
+        } else {
+            chain.handle(req)
+        }
+    };
+
+    let mut iron = Iron::new(handler);
+
+    if server_config.http_threads > 0 {
+        iron.threads = server_config.http_threads;
+    }
+
+    let seconds = |seconds| {
+        if seconds > 0 {
+            Some(Duration::from_secs(seconds))
+        } else {
+            None
+        }
+    };
+
+    iron.timeouts = Timeouts {
+        keep_alive: seconds(server_config.http_keepalive),
+        read: seconds(server_config.http_read_timeout),
+        ..Timeouts::default()
+    };
+
+    info!(
+        "HTTP server using {} threads and a backlog of {} connections",
+        iron.threads, server_config.http_backlog
+    );
+
+    let listener = TimeoutListener(HttpListener::from(listener));
+
+    match iron.listen(listener, Protocol::http()) {
+        Ok(listening) => {
+            info!("HTTP server listening on {}", listening.socket);
+            Ok(ServerHandle {
+                listening,
+                shutdown,
+                event_tx,
+                access_log,
+            })
+        }
+        Err(e) => bail!(ErrorKind::StartHTTPServer(
+            listening_socket(server_config, listening_port).to_string(),
+            e.to_string()
+        )),
+    }
+}
+
+/// Binds the listening port, or the fallback port if another program holds
+/// it, returning the listener along with the port bound
+fn bind_server(server_config: &ServerConfig) -> Result<(TcpListener, u16)> {
+    let interface = server_config
+        .listening_interface
+        .as_ref()
+        .map(String::as_str);
+
+    let mut port = server_config.listening_port;
+
+    loop {
+        let address = listening_socket(server_config, port);
+
+        info!("Starting HTTP server on {}", address);
+
+        match bind_listener(address, interface, server_config.http_backlog) {
+            Ok(listener) => {
+                // Port 0 binds an ephemeral one, which the portal URL names
+                let port = listener.local_addr().map_or(port, |bound| bound.port());
+                return Ok((listener, port));
+            }
+            Err(Errno::EADDRINUSE) => {
+                let holder = port_holder(port).unwrap_or_else(|| "another program".to_string());
+                warn!("Port {} is already in use by {}", port, holder);
+
+                match server_config.fallback_port {
+                    Some(fallback_port) if fallback_port != port => {
+                        info!("Falling back to port {}", fallback_port);
+                        port = fallback_port;
+                    }
+                    _ => bail!(ErrorKind::PortInUse(address.to_string(), holder)),
+                }
+            }
+            Err(e) => bail!(ErrorKind::StartHTTPServer(
+                address.to_string(),
+                e.to_string()
+            )),
+        }
+    }
+}
+
+/// The address the server binds to, which for a portal with an IPv6
+/// address is the dual-stack `[::]`, kept to the portal network by pinning
+/// the listener to its interface
+fn listening_socket(server_config: &ServerConfig, port: u16) -> SocketAddr {
+    match (server_config.gateway6, &server_config.listening_interface) {
+        (Some(_), &Some(_)) => SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+        _ => SocketAddr::V4(SocketAddrV4::new(server_config.listening_address, port)),
+    }
+}
+
+/// The process listening on the TCP port, as `name (PID pid)`, found
+/// through the socket inode of `/proc/net/tcp` among the open files of the
+/// processes. None without permission to look at them.
+fn port_holder(port: u16) -> Option<String> {
+    let inodes = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .iter()
+        .filter_map(|table| fs::read_to_string(table).ok())
+        .flat_map(|table| {
+            table
+                .lines()
+                .skip(1)
+                .filter_map(|line| listening_socket_inode(line, port))
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    if inodes.is_empty() {
+        return None;
+    }
+
+    for entry in fs::read_dir("/proc").ok()?.filter_map(|entry| entry.ok()) {
+        let pid = entry.file_name().to_string_lossy().into_owned();
+
+        if !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+
+        let fds = match fs::read_dir(entry.path().join("fd")) {
+            Ok(fds) => fds,
+            Err(_) => continue,
+        };
+
+        let holds = fds.filter_map(|fd| fd.ok()).any(|fd| {
+            fs::read_link(fd.path()).ok().map_or(false, |target| {
+                inodes
+                    .iter()
+                    .any(|inode| target.to_string_lossy() == format!("socket:[{}]", inode))
+            })
+        });
+
+        if holds {
+            let name = fs::read_to_string(entry.path().join("comm"))
+                .map(|name| name.trim().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+
+            return Some(format!("{} (PID {})", name, pid));
+        }
+    }
+
+    None
+}
+
+/// The inode of a `/proc/net/tcp` entry listening on the port, the state
+/// `0A` being `LISTEN`
+fn listening_socket_inode(line: &str, port: u16) -> Option<String> {
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+
+    let local_port = fields.get(1)?.rsplit(':').next()?;
+
+    if u16::from_str_radix(local_port, 16).ok()? != port || *fields.get(3)? != "0A" {
+        return None;
+    }
+
+    fields.get(9).map(|inode| inode.to_string())
+}
+
+/// How long binding is retried for while the interface of the portal has
+/// not got the gateway address yet
+const BIND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Binds the listener of the server, pinned to the interface if given so
+/// that it keeps serving the portal network only. The access point may come
+/// up before its address is assigned, which is waited for.
+fn bind_listener(
+    address: SocketAddr,
+    interface: Option<&str>,
+    backlog: u32,
+) -> nix::Result<TcpListener> {
+    let deadline = Instant::now() + BIND_TIMEOUT;
+    let mut delay = Duration::from_millis(100);
+
+    loop {
+        match bind_listener_once(address, interface, backlog) {
+            Err(Errno::EADDRNOTAVAIL) if Instant::now() + delay < deadline => {
+                debug!("{} is not assigned yet, retrying the bind", address.ip());
+                thread::sleep(delay);
+                delay = cmp::min(delay * 2, Duration::from_secs(2));
+            }
+            result => return result,
+        }
+    }
+}
+
+fn bind_listener_once(
+    address: SocketAddr,
+    interface: Option<&str>,
+    backlog: u32,
+) -> nix::Result<TcpListener> {
+    let family = match address {
+        SocketAddr::V4(_) => AddressFamily::Inet,
+        SocketAddr::V6(_) => AddressFamily::Inet6,
+    };
+
+    let fd = socket(family, SockType::Stream, SockFlag::SOCK_CLOEXEC, None)?;
+
+    // Closes the socket on errors
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+
+    setsockopt(fd, sockopt::ReuseAddr, &true)?;
+
+    if let Some(interface) = interface {
+        // Requires CAP_NET_RAW, without which the address alone has to do
+        if let Err(e) = setsockopt(fd, sockopt::BindToDevice, &OsString::from(interface)) {
+            warn!("Pinning the HTTP server to {} failed: {}", interface, e);
+        }
+    }
+
+    match address {
+        SocketAddr::V4(address) => bind(fd, &SockaddrIn::from(address))?,
+        SocketAddr::V6(address) => {
+            // IPv4 clients arrive on the same socket as mapped addresses
+            setsockopt(fd, sockopt::Ipv6V6Only, &false)?;
+            bind(fd, &SockaddrIn6::from(address))?
+        }
+    }
+
+    listen(fd, backlog as usize)?;
+
+    Ok(listener)
+}
+
+/// Accepts the connections of the HTTP server, noting the ones hyper drops
+/// for exceeding the read or keep-alive timeout
+#[derive(Clone)]
+struct TimeoutListener(HttpListener);
+
+impl NetworkListener for TimeoutListener {
+    type Stream = TimeoutStream;
+
+    fn accept(&mut self) -> hyper::Result<TimeoutStream> {
+        let mut stream = self.0.accept()?;
+        let peer = stream.peer_addr().ok();
+
+        Ok(TimeoutStream {
+            stream,
+            peer,
+            timed_out: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        self.0.local_addr()
+    }
+
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.0.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.0.set_write_timeout(timeout)
+    }
+}
+
+/// A connection that logs once when a read of it times out, after which
+/// hyper closes it and frees its thread
+#[derive(Clone)]
+struct TimeoutStream {
+    stream: HttpStream,
+    peer: Option<SocketAddr>,
+    timed_out: Arc<AtomicBool>,
+}
+
+impl Read for TimeoutStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.stream.read(buf);
+
+        if let Err(ref e) = result {
+            let timeout = match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => true,
+                _ => false,
+            };
+
+            if timeout && !self.timed_out.swap(true, Ordering::SeqCst) {
+                match self.peer {
+                    Some(peer) => debug!("Connection of {} timed out, closing it", peer),
+                    None => debug!("Connection timed out, closing it"),
+                }
+            }
+        }
+
+        result
+    }
+}
+
+impl io::Write for TimeoutStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        io::Write::write(&mut self.stream, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(&mut self.stream)
+    }
+}
+
+impl NetworkStream for TimeoutStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        self.stream.peer_addr()
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.set_write_timeout(timeout)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.stream.close(how)
+    }
+}
+
+/// Phones showing the portal poll this every few seconds, so a recent reply
+/// is served again unless `refresh` is asked for
+fn networks(req: &mut Request) -> IronResult<Response> {
+    networks_reply(req, NetworksFormat::Legacy)
+}
+
+fn networks_v1(req: &mut Request) -> IronResult<Response> {
+    networks_reply(req, NetworksFormat::V1)
+}
+
+fn networks_reply(req: &mut Request, format: NetworksFormat) -> IronResult<Response> {
+    let (refresh, wait) = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `networks` request params failed: {}", e);
+                let error =
+                    ApiError::new(ErrorCode::MalformedRequest, "Malformed request parameters");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        match (bool_param(params, "refresh"), bool_param(params, "wait")) {
+            (Ok(refresh), Ok(wait)) => (refresh.unwrap_or(false), wait.unwrap_or(false)),
+            (Err(error), _) | (_, Err(error)) => {
+                warn!("Rejecting `networks` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    info!("User connected to the captive portal");
+
+    let network_link = get_network_link!(req);
+
+    // The first page load would otherwise list the networks of a scan that
+    // found none yet
+    let wait = wait && !refresh && !get_request_state!(req).has_fresh_networks();
+
+    // Requests that waited for the scan of another one are served its results
+    let refresh_turn = if refresh || wait {
+        let (refresh_gate, scan_timeout) = {
+            let request_state = get_request_state!(req);
+            (
+                request_state.refresh_gate.clone(),
+                request_state.scan_timeout,
+            )
+        };
+
+        if wait {
+            debug!("Waiting for a scan to finish");
+        }
+
+        // A wedged scan cannot hold the requests behind it for longer
+        RefreshGate::enter(&refresh_gate, Duration::from_secs(scan_timeout))
+    } else {
+        None
+    };
+
+    if refresh_turn.is_none() {
+        if let Some(response) = get_request_state!(req).cached_networks(format) {
+            debug!("Serving the cached network list");
+            return Ok(response);
+        }
+    }
+
+    let response = if refresh_turn.is_some() {
+        network_link.request(
+            NetworkCommand::Rescan,
+            ErrorKind::SendNetworkCommandActivate,
+            ErrorKind::RecvAccessPointSSIDs,
+        )
+    } else {
+        network_link.request(
+            NetworkCommand::Activate,
+            ErrorKind::SendNetworkCommandActivate,
+            ErrorKind::RecvAccessPointSSIDs,
+        )
+    };
+
+    let (networks, scanned_at, weak_hidden, active_ssid) = match response {
+        Ok(NetworkCommandResponse::Networks {
+            networks,
+            scanned_at,
+            weak_hidden,
+            active_ssid,
+        }) => (networks, scanned_at, weak_hidden, active_ssid),
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    let envelope = NetworksEnvelope {
+        version: NETWORKS_ENVELOPE_VERSION,
+        scanned_at,
+        weak_hidden,
+        active_ssid: active_ssid.as_ref().map(Ssid::to_string),
+        active_ssid_raw: active_ssid.as_ref().and_then(Ssid::to_raw),
+        networks: &networks,
+    };
+
+    let serialized = serde_json::to_string(&networks)
+        .and_then(|json| serde_json::to_string(&envelope).map(|v1_json| (json, v1_json)));
+
+    let (access_points_json, v1_json) = match serialized {
+        Ok(serialized) => serialized,
+        Err(e) => {
+            return exit_with_error(
+                &network_link.exit_tx,
+                e,
+                ErrorKind::SerializeAccessPointSSIDs,
+            )
+        }
+    };
+
+    let json = match format {
+        NetworksFormat::Legacy => access_points_json.clone(),
+        NetworksFormat::V1 => v1_json.clone(),
+    };
+
+    let mut request_state = get_request_state!(req);
+
+    request_state.networks = networks;
+    request_state.networks_cache = Some(NetworksCache {
+        json: access_points_json,
+        v1_json,
+        cached_at: Instant::now(),
+    });
+
+    Ok(json_response(json))
+}
+
+fn saved_networks(req: &mut Request) -> IronResult<Response> {
+    let network_link = get_network_link!(req);
+
+    let saved_networks = match request_saved_networks(&network_link) {
+        Ok(saved_networks) => saved_networks,
+        Err(response) => return response,
+    };
+
+    match serde_json::to_string(&saved_networks) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// The most recently used saved profile, which `--duplicate-policy keep` updates
+fn kept_profile<'a>(ssid: &Ssid, saved_networks: &'a [SavedNetwork]) -> Option<&'a SavedNetwork> {
+    saved_networks
+        .iter()
+        .filter(|saved| saved.raw_ssid() == *ssid)
+        .max_by_key(|saved| saved.last_used)
+}
+
+/// Asks the network thread for the saved profiles, failing with the response
+/// to reply with
+fn request_saved_networks(
+    network_link: &NetworkLink,
+) -> ::std::result::Result<Vec<SavedNetwork>, IronResult<Response>> {
+    let response = network_link.request(
+        NetworkCommand::ListSavedNetworks,
+        ErrorKind::SendNetworkCommandListSavedNetworks,
+        ErrorKind::RecvSavedNetworks,
+    )?;
+
+    match response {
+        NetworkCommandResponse::SavedNetworks(Ok(saved_networks)) => Ok(saved_networks),
+        NetworkCommandResponse::SavedNetworks(Err(reason)) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Listing saved networks failed: {}", reason),
+            );
+            Err(Ok(error_response(status::InternalServerError, &error)))
+        }
+        _ => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            Err(Ok(error_response(status::InternalServerError, &error)))
+        }
+    }
+}
+
+fn connect(req: &mut Request) -> IronResult<Response> {
+    let connect_request = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `connect` request params failed: {}", e);
+                let error = ApiError::new(ErrorCode::MalformedRequest, "Malformed request body");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        if let Some(networks) = params.get("networks").cloned() {
+            return connect_bulk(req, &networks);
+        }
+
+        match ConnectRequest::from_params(params) {
+            Ok(connect_request) => connect_request,
+            Err(error) => {
+                warn!("Rejecting `connect` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    match connect_request.ssid {
+        Some(ref ssid) => info!("Incoming `connect` to access point `{}` request", ssid),
+        None => info!(
+            "Incoming `connect` to saved profile `{}` request",
+            connect_request
+                .profile
+                .uuid
+                .as_ref()
+                .map_or("", String::as_str)
+        ),
+    }
+
+    let network_link = get_network_link!(req);
+
+    let duplicate_policy = {
+        let mut request_state = get_request_state!(req);
+
+        if let Some(response) = connect_in_progress(&mut request_state) {
+            return Ok(response);
+        }
+
+        request_state.duplicate_policy
+    };
+
+    let saved_networks =
+        if connect_request.credentials.is_empty() || duplicate_policy != DuplicatePolicy::Replace {
+            match request_saved_networks(&network_link) {
+                Ok(saved_networks) => saved_networks,
+                Err(response) => return response,
+            }
+        } else {
+            Vec::new()
+        };
+
+    let saved = if connect_request.credentials.is_empty() {
+        connect_request.find_saved(saved_networks.clone())
+    } else {
+        None
+    };
+
+    let mut request_state = get_request_state!(req);
+
+    // Another attempt may have been dispatched while the profiles were listed
+    if let Some(response) = connect_in_progress(&mut request_state) {
+        return Ok(response);
+    }
+
+    let (ssid, saved) = match (saved, connect_request.ssid.clone()) {
+        (Some(saved), _) => (saved.raw_ssid(), Some(saved)),
+        (None, Some(ref ssid)) if connect_request.profile.uuid.is_none() => (ssid.clone(), None),
+        (None, _) => {
+            let error = ApiError {
+                code: ErrorCode::UnknownProfile,
+                error: "No saved profile matches the requested UUID".to_string(),
+                param: Some("uuid"),
+                expected: None,
+            };
+            warn!("Rejecting `connect` request: {}", error.error);
+            return Ok(error_response(status::UnprocessableEntity, &error));
+        }
+    };
+
+    if let Err((status, error)) = check_offered(&request_state, &ssid) {
+        warn!("Rejecting `connect` request: {}", error.error);
+        return Ok(error_response(status, &error));
+    }
+
+    let kept = if saved.is_none() && duplicate_policy == DuplicatePolicy::Keep {
+        kept_profile(&ssid, &saved_networks)
+    } else {
+        None
+    };
+
+    // Only a fresh profile would duplicate the saved ones
+    if saved.is_none() && duplicate_policy == DuplicatePolicy::Error {
+        if let Some(response) = duplicate_profiles(&ssid, &saved_networks) {
+            return Ok(response);
+        }
+    }
+
+    // A saved profile is activated with the security it was created with
+    let security = if saved.is_some() {
+        None
+    } else {
+        match connect_request.validate(&ssid, &request_state.networks) {
+            Ok(security) => Some(security),
+            Err(error) => {
+                warn!("Rejecting `connect` request: {}", error.error);
+                return Ok(error_response(status::UnprocessableEntity, &error));
+            }
+        }
+    };
+
+    let timeout = request_state.effective_connect_timeout(connect_request.timeout);
+
+    let mut profile = connect_request.profile;
+    let autoconnect = profile
+        .autoconnect
+        .unwrap_or(request_state.default_autoconnect);
+    profile.autoconnect = Some(autoconnect);
+
+    match saved {
+        Some(ref saved) => {
+            profile.priority = Some(saved.priority);
+            profile.search_domains = saved.search_domains.clone();
+            profile.uuid = saved.uuid.clone();
+        }
+        // Updated with the credentials, keeping its UUID
+        None if kept.is_some() => profile.uuid = kept.and_then(|kept| kept.uuid.clone()),
+        // Generated here so that it is known before the profile is created
+        None if request_state.profile_uuids => match new_uuid() {
+            Ok(uuid) => profile.uuid = Some(uuid),
+            Err(e) => warn!("Generating a connection profile UUID failed: {}", e),
+        },
+        None => {}
+    }
+
+    let result = ConnectResult {
+        ssid: ssid.to_string(),
+        ssid_raw: ssid.to_raw(),
+        security: security.as_ref().map(ConnectSecurity::as_str),
+        timeout,
+        autoconnect,
+        priority: profile.priority.unwrap_or(0),
+        metered: profile.metered.unwrap_or(Metered::Auto).as_str(),
+        dns: profile.dns.clone(),
+        search_domains: profile.search_domains.clone(),
+        uuid: profile.uuid.clone(),
+        reused: saved.is_some() || kept.is_some(),
+    };
+
+    let command = NetworkCommand::Connect {
+        ssid,
+        credentials: connect_request.credentials,
+        timeout: Some(timeout),
+        profile,
+    };
+
+    if let Err(response) = network_link.send(command, ErrorKind::SendNetworkCommandConnect) {
+        return response;
+    }
+
+    request_state.connect_attempt = Some(ConnectAttempt::new(result.ssid.clone(), timeout));
+    request_state.last_failure = None;
+    request_state.bulk = None;
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// Creates the profiles of all the `networks` of the body, then activates
+/// them in order until one of them succeeds. Nothing is attempted if any of
+/// them is invalid, the reply listing each invalid one.
+fn connect_bulk(req: &mut Request, networks: &params::Value) -> IronResult<Response> {
+    let entries = match *networks {
+        params::Value::Array(ref entries) if !entries.is_empty() => entries,
+        _ => {
+            let error = ApiError::wrong_type("networks", "non-empty array of objects");
+            warn!("Rejecting bulk `connect` request: {}", error.error);
+            return Ok(error_response(status::BadRequest, &error));
+        }
+    };
+
+    let mut requests = Vec::new();
+    let mut invalid = Vec::new();
+
+    for (index, entry) in entries.iter().enumerate() {
+        let parsed = match *entry {
+            params::Value::Map(ref params) => ConnectRequest::from_bulk_params(params),
+            _ => Err(ApiError::new(ErrorCode::MalformedRequest, "Not an object")),
+        };
+
+        match parsed {
+            Ok(request) => requests.push(request),
+            Err(error) => invalid.push(InvalidNetwork { index, error }),
+        }
+    }
+
+    if !invalid.is_empty() {
+        return Ok(bulk_rejection(status::BadRequest, invalid));
+    }
+
+    info!(
+        "Incoming bulk `connect` request for {} networks",
+        requests.len()
+    );
+
+    let network_link = get_network_link!(req);
+
+    let duplicate_policy = get_request_state!(req).duplicate_policy;
+
+    // With `replace` the network thread deletes them, once one network joined
+    let saved_networks = if duplicate_policy != DuplicatePolicy::Replace {
+        match request_saved_networks(&network_link) {
+            Ok(saved_networks) => saved_networks,
+            Err(response) => return response,
+        }
+    } else {
+        Vec::new()
+    };
+
+    let mut request_state = get_request_state!(req);
+
+    if let Some(response) = connect_in_progress(&mut request_state) {
+        return Ok(response);
+    }
+
+    let mut networks: Vec<BulkNetwork> = Vec::new();
+    let mut results = Vec::new();
+
+    for (index, request) in requests.into_iter().enumerate() {
+        let ssid = match request.ssid.clone() {
+            Some(ssid) => ssid,
+            None => {
+                let error = ApiError::missing("ssid", "string");
+                invalid.push(InvalidNetwork { index, error });
+                continue;
+            }
+        };
+
+        // A later profile of the same network would replace the earlier one
+        if networks.iter().any(|network| network.ssid == ssid) {
+            let error = ApiError {
+                code: ErrorCode::DuplicateNetwork,
+                error: format!("Network '{}' is listed more than once", ssid),
+                param: Some("ssid"),
+                expected: None,
+            };
+            invalid.push(InvalidNetwork { index, error });
+            continue;
+        }
+
+        if let Err((_, error)) = check_offered(&request_state, &ssid) {
+            invalid.push(InvalidNetwork { index, error });
+            continue;
+        }
+
+        let kept = if duplicate_policy == DuplicatePolicy::Keep {
+            kept_profile(&ssid, &saved_networks)
+        } else {
+            None
+        };
+
+        if duplicate_policy == DuplicatePolicy::Error
+            && saved_networks.iter().any(|saved| saved.raw_ssid() == ssid)
+        {
+            let error = ApiError {
+                code: ErrorCode::SavedProfileExists,
+                error: format!("Network '{}' has a saved profile already", ssid),
+                param: Some("ssid"),
+                expected: None,
+            };
+            invalid.push(InvalidNetwork { index, error });
+            continue;
+        }
+
+        let security = match request.validate(&ssid, &request_state.networks) {
+            Ok(security) => security,
+            Err(error) => {
+                invalid.push(InvalidNetwork { index, error });
+                continue;
+            }
+        };
+
+        let timeout = request_state.effective_connect_timeout(request.timeout);
+
+        let mut credentials = request.credentials;
+        credentials.security = Some(security);
+
+        let mut profile = request.profile;
+        let autoconnect = profile
+            .autoconnect
+            .unwrap_or(request_state.default_autoconnect);
+        profile.autoconnect = Some(autoconnect);
+
+        // Known before the profiles are created, for the reply to list them
+        if let Some(kept) = kept {
+            profile.uuid = kept.uuid.clone();
+        } else if request_state.profile_uuids {
+            match new_uuid() {
+                Ok(uuid) => profile.uuid = Some(uuid),
+                Err(e) => warn!("Generating a connection profile UUID failed: {}", e),
+            }
+        }
+
+        results.push(ConnectResult {
+            ssid: ssid.to_string(),
+            ssid_raw: ssid.to_raw(),
+            security: Some(security.as_str()),
+            timeout,
+            autoconnect,
+            priority: profile.priority.unwrap_or(0),
+            metered: profile.metered.unwrap_or(Metered::Auto).as_str(),
+            dns: profile.dns.clone(),
+            search_domains: profile.search_domains.clone(),
+            uuid: profile.uuid.clone(),
+            reused: kept.is_some(),
+        });
+
+        networks.push(BulkNetwork {
+            ssid,
+            credentials,
+            timeout: Some(timeout),
+            profile,
+        });
+    }
+
+    if !invalid.is_empty() {
+        return Ok(bulk_rejection(status::UnprocessableEntity, invalid));
+    }
+
+    let first_ssid = results[0].ssid.clone();
+    let total_timeout = results.iter().map(|result| result.timeout).sum();
+
+    let command = NetworkCommand::ConnectBulk(networks);
+
+    if let Err(response) = network_link.send(command, ErrorKind::SendNetworkCommandConnect) {
+        return response;
+    }
+
+    let count = results.len() as u64;
+
+    request_state.connect_attempt =
+        Some(ConnectAttempt::for_bulk(first_ssid, total_timeout, count));
+    request_state.last_failure = None;
+    request_state.bulk = Some(BulkProgress::new(&results));
+
+    let result = BulkConnectResult { networks: results };
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn bulk_rejection(status: status::Status, invalid: Vec<InvalidNetwork>) -> Response {
+    let rejection = BulkConnectRejection {
+        code: ErrorCode::InvalidNetworks,
+        error: format!("{} of the networks are invalid", invalid.len()),
+        invalid,
+    };
+    warn!("Rejecting bulk `connect` request: {}", rejection.error);
+
+    error_response(status, &rejection)
+}
+
+/// Rejects the network of the portal itself, and those the SSID filters
+/// hide, as manually entered SSIDs are held to the filters of the listed
+/// networks
+fn check_offered(
+    request_state: &RequestSharedState,
+    ssid: &Ssid,
+) -> ::std::result::Result<(), (status::Status, ApiError)> {
+    if *ssid == request_state.portal_ssid {
+        let error = ApiError {
+            code: ErrorCode::PortalNetwork,
+            error: format!("'{}' is the network of this portal itself", ssid),
+            param: Some("ssid"),
+            expected: None,
+        };
+        return Err((status::UnprocessableEntity, error));
+    }
+
+    if !request_state.ssid_filter.permits(&ssid.to_string()) {
+        let error = ApiError {
+            code: ErrorCode::NetworkNotOffered,
+            error: "The network is not offered by this portal".to_string(),
+            param: Some("ssid"),
+            expected: None,
+        };
+        return Err((status::Forbidden, error));
+    }
+
+    Ok(())
+}
+
+/// Refuses a network with saved profiles, listing them
+fn duplicate_profiles(ssid: &Ssid, saved_networks: &[SavedNetwork]) -> Option<Response> {
+    let duplicates = saved_networks
+        .iter()
+        .filter(|saved| saved.raw_ssid() == *ssid)
+        .collect::<Vec<_>>();
+
+    if duplicates.is_empty() {
+        return None;
+    }
+
+    let conflict = DuplicateProfiles {
+        code: ErrorCode::SavedProfileExists,
+        error: format!(
+            "Network '{}' has {} saved profiles already",
+            ssid,
+            duplicates.len()
+        ),
+        ssid: ssid.to_string(),
+        uuids: duplicates
+            .iter()
+            .filter_map(|saved| saved.uuid.clone())
+            .collect(),
+    };
+    warn!("Rejecting `connect` request: {}", conflict.error);
+
+    Some(error_response(status::Conflict, &conflict))
+}
+
+fn connect_in_progress(request_state: &mut RequestSharedState) -> Option<Response> {
+    let attempt = request_state.connect_attempt_in_progress()?;
+
+    let conflict = ConnectConflict {
+        code: ErrorCode::ConnectInProgress,
+        error: format!("Already connecting to '{}'", attempt.ssid),
+        ssid: attempt.ssid.clone(),
+    };
+    warn!("Rejecting `connect` request: {}", conflict.error);
+
+    Some(error_response(status::Conflict, &conflict))
+}
+
+fn connection_status(req: &mut Request) -> IronResult<Response> {
+    let network_link = get_network_link!(req);
+
+    let (ssid, last_failure, bulk) = {
+        let mut request_state = get_request_state!(req);
+
+        let ssid = request_state
+            .connect_attempt_in_progress()
+            .map(|attempt| attempt.ssid.clone());
+
+        (
+            ssid,
+            request_state.last_failure.clone(),
+            request_state.bulk.clone(),
+        )
+    };
+
+    // The network thread does not take commands while it is connecting
+    let result = if ssid.is_some() {
+        StatusResult {
+            state: "connecting",
+            ssid,
+            metered: None,
+            last_failure: None,
+            bulk,
+        }
+    } else {
+        let response = network_link.request(
+            NetworkCommand::Status,
+            ErrorKind::SendNetworkCommandStatus,
+            ErrorKind::RecvStatus,
+        );
+
+        let status = match response {
+            Ok(NetworkCommandResponse::Status(Ok(status))) => status,
+            Ok(NetworkCommandResponse::Status(Err(reason))) => {
+                let error = ApiError::new(
+                    ErrorCode::NetworkStack,
+                    format!("Getting the status failed: {}", reason),
+                );
+                return Ok(error_response(status::InternalServerError, &error));
+            }
+            Ok(_) => {
+                let error = ApiError::new(
+                    ErrorCode::NetworkThread,
+                    "Unexpected reply of the network thread",
+                );
+                return Ok(error_response(status::InternalServerError, &error));
+            }
+            Err(response) => return response,
+        };
+
+        StatusResult {
+            state: "portal",
+            ssid: None,
+            metered: Some(status.metered.as_str()),
+            last_failure,
+            bulk,
+        }
+    };
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// Reads the link properties only, so it is cheap enough to be polled while
+/// the installer moves the device around
+fn signal(req: &mut Request) -> IronResult<Response> {
+    let network_link = get_network_link!(req);
+
+    if let Some(response) = connect_conflict(&mut get_request_state!(req), "signal") {
+        return Ok(response);
+    }
+
+    let response = network_link.request(
+        NetworkCommand::Signal,
+        ErrorKind::SendNetworkCommandSignal,
+        ErrorKind::RecvSignal,
+    );
+
+    let signal = match response {
+        Ok(NetworkCommandResponse::Signal(Ok(Some(signal)))) => signal,
+        Ok(NetworkCommandResponse::Signal(Ok(None))) => {
+            let error = ApiError::new(ErrorCode::NotConnected, "No wireless connection is active");
+            return Ok(error_response(status::Conflict, &error));
+        }
+        Ok(NetworkCommandResponse::Signal(Err(reason))) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Getting the signal strength failed: {}", reason),
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    match serde_json::to_string(&signal) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn interfaces(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `interfaces` request");
+
+    let network_link = get_network_link!(req);
+
+    if let Some(response) = connect_conflict(&mut get_request_state!(req), "interfaces") {
+        return Ok(response);
+    }
+
+    let response = network_link.request(
+        NetworkCommand::ListDevices,
+        ErrorKind::SendNetworkCommandListDevices,
+        ErrorKind::RecvDevices,
+    );
+
+    let devices = match response {
+        Ok(NetworkCommandResponse::Devices(Ok(devices))) => devices,
+        Ok(NetworkCommandResponse::Devices(Err(reason))) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Listing the interfaces failed: {}", reason),
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    match serde_json::to_string(&devices) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn hostname(req: &mut Request) -> IronResult<Response> {
+    let (hostname, transient) = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `hostname` request params failed: {}", e);
+                let error = ApiError::new(ErrorCode::MalformedRequest, "Malformed request body");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        let parsed = string_param(params, "hostname").and_then(|hostname| {
+            if is_valid_hostname(&hostname) {
+                Ok(hostname)
+            } else {
+                Err(ApiError::wrong_type(
+                    "hostname",
+                    "RFC 1123 host name of up to 64 characters",
+                ))
+            }
+        });
+
+        match (parsed, bool_param(params, "transient")) {
+            (Ok(hostname), Ok(transient)) => (hostname, transient.unwrap_or(false)),
+            (Err(error), _) | (_, Err(error)) => {
+                warn!("Rejecting `hostname` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    info!("Incoming `hostname` request for '{}'", hostname);
+
+    let bearer = bearer_token(req);
+
+    // Renaming the device is refused without an API token, like a reset
+    if let Err(response) = authorize(&get_request_state!(req), bearer, "hostname") {
+        return Ok(response);
+    }
+
+    if let Err(e) = set_hostname(&hostname, transient) {
+        error!("Setting the host name failed: {}", e);
+        let error = ApiError::new(
+            ErrorCode::HostnameFailed,
+            format!("Setting the host name failed: {}", e),
+        );
+        return Ok(error_response(status::InternalServerError, &error));
+    }
+
+    match serde_json::to_string(&HostnameResult {
+        hostname,
+        transient,
+    }) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn reset(req: &mut Request) -> IronResult<Response> {
+    let dry_run = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `reset` request params failed: {}", e);
+                let error = ApiError::new(ErrorCode::MalformedRequest, "Malformed request body");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        match bool_param(params, "dry_run") {
+            Ok(dry_run) => dry_run.unwrap_or(false),
+            Err(error) => {
+                warn!("Rejecting `reset` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    info!("Incoming `reset` request");
+
+    let bearer = bearer_token(req);
+
+    let network_link = get_network_link!(req);
+
+    {
+        let mut request_state = get_request_state!(req);
+
+        // Anyone on the portal network could wipe the profiles otherwise
+        if let Err(response) = authorize(&request_state, bearer, "reset") {
+            return Ok(response);
+        }
+
+        if let Some(response) = connect_conflict(&mut request_state, "reset") {
+            return Ok(response);
+        }
+    }
+
+    let response = network_link.request(
+        |response_tx| NetworkCommand::ForgetAll {
+            dry_run,
+            response_tx,
+        },
+        ErrorKind::SendNetworkCommandForgetAll,
+        ErrorKind::RecvForgetAll,
+    );
+
+    let networks = match response {
+        Ok(NetworkCommandResponse::Forgotten(Ok(networks))) => networks,
+        Ok(NetworkCommandResponse::Forgotten(Err(reason))) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Deleting saved networks failed: {}", reason),
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    if !dry_run {
+        get_request_state!(req).networks_cache = None;
+    }
+
+    let result = ResetResult {
+        dry_run,
+        count: networks.len(),
+        networks,
+    };
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn export_profile(req: &mut Request) -> IronResult<Response> {
+    let uuid = req
+        .extensions
+        .get::<Router>()
+        .and_then(|params| params.find("uuid"))
+        .unwrap_or("")
+        .to_string();
+
+    let include_secrets = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `export` request params failed: {}", e);
+                let error = ApiError::new(ErrorCode::MalformedRequest, "Malformed request");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        match bool_param(params, "include_secrets") {
+            Ok(include_secrets) => include_secrets.unwrap_or(false),
+            Err(error) => {
+                warn!("Rejecting `export` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    info!("Incoming `export` of saved profile `{}` request", uuid);
+
+    let bearer = bearer_token(req);
+
+    let network_link = get_network_link!(req);
+
+    {
+        let mut request_state = get_request_state!(req);
+
+        if include_secrets {
+            if let Err(response) = authorize(&request_state, bearer, "export") {
+                return Ok(response);
+            }
+        }
+
+        if let Some(response) = connect_conflict(&mut request_state, "export") {
+            return Ok(response);
+        }
+    }
+
+    let response = network_link.request(
+        |response_tx| NetworkCommand::ExportProfile {
+            uuid: uuid.clone(),
+            include_secrets,
+            response_tx,
+        },
+        ErrorKind::SendNetworkCommandExportProfile,
+        ErrorKind::RecvProfileExport,
+    );
+
+    let document = match response {
+        Ok(NetworkCommandResponse::ProfileExport(Ok(Some(document)))) => document,
+        Ok(NetworkCommandResponse::ProfileExport(Ok(None))) => {
+            let error = ApiError::new(
+                ErrorCode::UnknownProfile,
+                format!("No saved profile with UUID '{}'", uuid),
+            );
+            return Ok(error_response(status::NotFound, &error));
+        }
+        Ok(NetworkCommandResponse::ProfileExport(Err(reason))) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Exporting the profile failed: {}", reason),
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    match serde_json::to_string(&document) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn import_profile(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `import` request");
+
+    let mut body = String::new();
+    if let Err(e) = req.body.read_to_string(&mut body) {
+        warn!("Reading `import` request body failed: {}", e);
+        let error = ApiError::new(ErrorCode::MalformedRequest, "Malformed request body");
+        return Ok(error_response(status::BadRequest, &error));
+    }
+
+    // Unknown fields are refused while parsing
+    let document = match serde_json::from_str::<ProfileDocument>(&body) {
+        Ok(document) => document,
+        Err(e) => {
+            let error = ApiError::new(
+                ErrorCode::InvalidProfile,
+                format!("Invalid profile document: {}", e),
+            );
+            warn!("Rejecting `import` request: {}", error.error);
+            return Ok(error_response(status::BadRequest, &error));
+        }
+    };
+
+    if let Err(error) = validate_document(&document) {
+        warn!("Rejecting `import` request: {}", error.error);
+        return Ok(error_response(status::UnprocessableEntity, &error));
+    }
+
+    let network_link = get_network_link!(req);
+
+    if let Some(response) = connect_conflict(&mut get_request_state!(req), "import") {
+        return Ok(response);
+    }
+
+    let ssid = document.raw_ssid();
+
+    let response = network_link.request(
+        |response_tx| NetworkCommand::ImportProfile {
+            document,
+            response_tx,
+        },
+        ErrorKind::SendNetworkCommandImportProfile,
+        ErrorKind::RecvProfileImport,
+    );
+
+    let uuid = match response {
+        Ok(NetworkCommandResponse::ProfileImport(Ok(uuid))) => uuid,
+        Ok(NetworkCommandResponse::ProfileImport(Err(reason))) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkStack,
+                format!("Importing the profile failed: {}", reason),
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Ok(_) => {
+            let error = ApiError::new(
+                ErrorCode::NetworkThread,
+                "Unexpected reply of the network thread",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+        Err(response) => return response,
+    };
+
+    get_request_state!(req).networks_cache = None;
+
+    let result = ImportResult {
+        ssid: ssid.to_string(),
+        ssid_raw: ssid.to_raw(),
+        uuid,
+    };
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// Checks the settings of an imported profile the way `/connect` checks its
+/// parameters
+fn validate_document(document: &ProfileDocument) -> ::std::result::Result<(), ApiError> {
+    let settings = &document.settings;
+
+    if let Some(ref ssid_raw) = document.ssid_raw {
+        if Ssid::from_raw(ssid_raw).is_none() {
+            return Err(ApiError::wrong_type("ssid_raw", "base64 string"));
+        }
+    }
+
+    if !document.raw_ssid().is_valid_length() {
+        return Err(ApiError {
+            code: ErrorCode::InvalidSsid,
+            error: format!("An SSID must be 1 to {} bytes long", MAX_SSID_LENGTH),
+            param: Some("ssid"),
+            expected: Some("string"),
+        });
+    }
+
+    let security = match ConnectSecurity::from_name(&settings.security) {
+        Some(security) => security,
+        None => {
+            return Err(ApiError::invalid_choice(
+                "security",
+                "one of open, wep, wpa-psk, wpa-eap or sae",
+            ))
+        }
+    };
+
+    let credentials = Credentials {
+        identity: settings.identity.clone(),
+        passphrase: settings.passphrase.clone(),
+        security: Some(security),
+    };
+
+    validate_credentials(&credentials, security)?;
+
+    if settings.priority < MIN_PRIORITY || settings.priority > MAX_PRIORITY {
+        return Err(ApiError::out_of_range("priority", PRIORITY_RANGE));
+    }
+
+    if let Some(ref metered) = settings.metered {
+        if Metered::from_name(metered).is_none() {
+            return Err(ApiError::invalid_choice(
+                "metered",
+                "one of yes, no or auto",
+            ));
+        }
+    }
+
+    if let Some(domain) = settings
+        .search_domains
+        .iter()
+        .find(|domain| !is_valid_domain_name(domain))
+    {
+        return Err(ApiError {
+            code: ErrorCode::InvalidParameter,
+            error: format!(
+                "Parameter 'search_domains' has an invalid domain '{}'",
+                domain
+            ),
+            param: Some("search_domains"),
+            expected: Some("list of domain names"),
+        });
+    }
+
+    Ok(())
+}
+
+/// Token of an `Authorization: Bearer` header
+fn bearer_token(req: &Request) -> Option<String> {
+    req.headers
+        .get::<headers::Authorization<headers::Bearer>>()
+        .map(|authorization| authorization.token.clone())
+}
+
+/// Privileged requests carry the configured API token as their bearer token
+fn authorize(
+    request_state: &RequestSharedState,
+    bearer: Option<String>,
+    request: &str,
+) -> ::std::result::Result<(), Response> {
+    let error = match request_state.api_token {
+        Some(ref token) if bearer.as_ref() == Some(token) => return Ok(()),
+        Some(_) => (
+            status::Unauthorized,
+            ApiError::new(ErrorCode::Unauthorized, "Missing or wrong API token"),
+        ),
+        None => (
+            status::Forbidden,
+            ApiError::new(
+                ErrorCode::ApiTokenNotConfigured,
+                "Requires an API token to be configured",
+            ),
+        ),
+    };
+
+    warn!("Rejecting `{}` request: {}", request, error.1.error);
+    Err(error_response(error.0, &error.1))
+}
+
+/// The network thread does not take commands while it is connecting
+fn connect_conflict(request_state: &mut RequestSharedState, request: &str) -> Option<Response> {
+    let attempt = request_state.connect_attempt_in_progress()?;
+
+    let conflict = ConnectConflict {
+        code: ErrorCode::ConnectInProgress,
+        error: format!("Connecting to '{}'", attempt.ssid),
+        ssid: attempt.ssid.clone(),
+    };
+    warn!("Rejecting `{}` request: {}", request, conflict.error);
+
+    Some(error_response(status::Conflict, &conflict))
+}
+
+/// Answered from the DHCP log, so it works while the network thread is busy
+fn clients(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `clients` request");
+
+    let clients = get_request_state!(req).clients.list();
+
+    match serde_json::to_string(&clients) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// The strings of the UI in the language the client prefers, as a JSON
+/// object, with the language they are in as `Content-Language`
+fn locale(req: &mut Request, locales: &Locales) -> IronResult<Response> {
+    let language = request_language(req, locales);
+    let (language, strings) = locales.strings(&language);
+
+    debug!("Serving the '{}' strings", language);
+
+    match serde_json::to_string(&strings) {
+        Ok(json) => {
+            let mut response = json_response(json);
+            response
+                .headers
+                .set_raw("Content-Language", vec![language.into_bytes()]);
+            Ok(response)
+        }
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// The language of the translation matching the `lang` query parameter of
+/// the request best, or else its `Accept-Language`
+fn request_language(req: &Request, locales: &Locales) -> String {
+    let lang = req
+        .url
+        .as_ref()
+        .query_pairs()
+        .find(|&(ref name, _)| name == "lang")
+        .map(|(_, value)| value.into_owned());
+
+    let accept_language = req.headers.get_raw("Accept-Language").map(|values| {
+        values
+            .iter()
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+            .collect::<Vec<_>>()
+            .join(",")
+    });
+
+    locales.select(
+        lang.as_ref().map(String::as_str),
+        accept_language.as_ref().map(String::as_str),
+    )
+}
+
+/// Requires the API token when one is configured, as the lines may name the
+/// networks nearby
+fn logs(req: &mut Request) -> IronResult<Response> {
+    let (lines, level) = {
+        let params = match req.get_ref::<Params>() {
+            Ok(params) => params,
+            Err(e) => {
+                warn!("Parsing `logs` request params failed: {}", e);
+                let error =
+                    ApiError::new(ErrorCode::MalformedRequest, "Malformed request parameters");
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        };
+
+        let lines = match params.get("lines").map(u64::from_value) {
+            Some(Some(lines)) if lines > 0 => Some(lines as usize),
+            Some(_) => {
+                let error = ApiError::wrong_type("lines", "positive integer");
+                warn!("Rejecting `logs` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+            None => None,
+        };
+
+        match log_level_param(params, "level") {
+            Ok(level) => (lines, level),
+            Err(error) => {
+                warn!("Rejecting `logs` request: {}", error.error);
+                return Ok(error_response(status::BadRequest, &error));
+            }
+        }
+    };
+
+    info!("Incoming `logs` request");
+
+    let bearer = bearer_token(req);
+
+    let request_state = get_request_state!(req);
+
+    if request_state.api_token.is_some() {
+        if let Err(response) = authorize(&request_state, bearer, "logs") {
+            return Ok(response);
+        }
+    }
+
+    let log_lines = match request_state.log_buffer {
+        Some(ref buffer) => buffer.recent(
+            lines.unwrap_or(usize::max_value()),
+            level.unwrap_or(LogLevel::Trace),
+        ),
+        None => {
+            let error = ApiError::new(ErrorCode::LogBufferDisabled, "The log buffer is disabled");
+            return Ok(error_response(status::NotFound, &error));
+        }
+    };
+
+    match serde_json::to_string(&log_lines) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// The request counters in the Prometheus text format
+fn metrics_reply(req: &mut Request, metrics: &Metrics) -> IronResult<Response> {
+    let bearer = bearer_token(req);
+
+    let request_state = get_request_state!(req);
+
+    if request_state.api_token.is_some() {
+        if let Err(response) = authorize(&request_state, bearer, "metrics") {
+            return Ok(response);
+        }
+    }
+
+    let mut response = Response::with((status::Ok, metrics.render()));
+    response.headers.set_raw(
+        "Content-Type",
+        vec![b"text/plain; version=0.0.4; charset=utf-8".to_vec()],
+    );
+    Ok(response)
+}
+
+fn cancel_connect(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `connect/cancel` request");
+
+    let network_link = get_network_link!(req);
+
+    let ssid = get_request_state!(req)
+        .connect_attempt_in_progress()
+        .map(|attempt| attempt.ssid.clone());
+
+    if ssid.is_some() {
+        if let Err(response) =
+            network_link.send(NetworkCommand::Cancel, ErrorKind::SendNetworkCommandCancel)
+        {
+            return response;
+        }
+    }
+
+    let result = CancelResult {
+        cancelled: ssid.is_some(),
+        ssid,
+    };
+
+    match serde_json::to_string(&result) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// Only reached by the session owner, or by anyone when no session is held
+fn release_session(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `session/release` request");
+
+    let released = get_request_state!(req).session.take().is_some();
+
+    match serde_json::to_string(&ReleaseResult { released }) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+fn qr(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `qr` request");
+
+    let payload = get_request_state!(req).portal_qr.clone();
+
+    let mut response = no_store(Response::with((status::Ok, payload)));
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Text,
+        SubLevel::Plain,
+        vec![(Attr::Charset, Value::Utf8)],
+    )));
+
+    Ok(response)
+}
+
+fn qr_svg(req: &mut Request) -> IronResult<Response> {
+    info!("Incoming `qr.svg` request");
+
+    let payload = get_request_state!(req).portal_qr.clone();
+
+    let code = match QrCode::new(payload.as_bytes()) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Encoding the portal QR code failed: {}", e);
+            let error = ApiError::new(
+                ErrorCode::QrTooLarge,
+                "The portal network does not fit in a QR code",
+            );
+            return Ok(error_response(status::InternalServerError, &error));
+        }
+    };
+
+    let image = code.render::<svg::Color>().min_dimensions(256, 256).build();
+
+    let mut response = no_store(Response::with((status::Ok, image)));
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Image,
+        SubLevel::Ext("svg+xml".to_string()),
+        vec![],
+    )));
+
+    Ok(response)
+}
+
+/// The `WIFI:` URI understood by the camera apps of phones, with `T:nopass`
+/// and no password for an open portal
+fn wifi_qr_payload(ssid: &str, passphrase: Option<&str>) -> String {
+    match passphrase {
+        Some(passphrase) => format!(
+            "WIFI:T:WPA;S:{};P:{};;",
+            escape_qr_field(ssid),
+            escape_qr_field(passphrase)
+        ),
+        None => format!("WIFI:T:nopass;S:{};;", escape_qr_field(ssid)),
+    }
+}
+
+/// Backslash escapes the separators of the `WIFI:` URI
+fn escape_qr_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        if "\\;,:\"".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+fn version(_: &mut Request) -> IronResult<Response> {
+    let version_info = VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        api_version: API_VERSION,
+    };
+
+    match serde_json::to_string(&version_info) {
+        Ok(json) => Ok(json_response(json)),
+        Err(e) => Err(IronError::new(e, status::InternalServerError)),
+    }
+}
+
+/// The OpenAPI description of the routes above, with its version kept in
+/// sync with the one reported by `/version`
+fn openapi_document() -> Result<String> {
+    let mut document: serde_json::Value = serde_json::from_str(OPENAPI_DOCUMENT)
+        .chain_err(|| "the OpenAPI document is not valid JSON")?;
+
+    document["info"]["version"] = serde_json::Value::String(API_VERSION.to_string());
+
+    Ok(document.to_string())
+}
+
+fn json_response(json: String) -> Response {
+    json_response_with_status(status::Ok, json)
+}
+
+fn error_response<E: Serialize>(status: status::Status, error: &E) -> Response {
+    let body = serde_json::to_value(error)
+        .unwrap_or_else(|_| serde_json::Value::Object(serde_json::Map::new()));
+
+    let mut response = json_response_with_status(status, body.to_string());
+    response.extensions.insert::<ErrorBody>(body);
+    response
+}
+
+fn json_response_with_status(status: status::Status, json: String) -> Response {
+    let mut response = no_store(Response::with((status, json)));
+
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Application,
+        SubLevel::Json,
+        vec![(Attr::Charset, Value::Utf8)],
+    )));
+
+    response
+}
+
+/// The icons browsers request from the root whatever the page declares, with
+/// their content type
+const ICON_PATHS: &[(&str, &str, &str)] = &[
+    ("/favicon.ico", "favicon", "image/x-icon"),
+    ("/apple-touch-icon.png", "apple_touch_icon", "image/png"),
+    (
+        "/apple-touch-icon-precomposed.png",
+        "apple_touch_icon_precomposed",
+        "image/png",
+    ),
+];
+
+fn is_icon_request(req: &Request) -> bool {
+    let path = format!("/{}", req.url.path().join("/"));
+
+    ICON_PATHS.iter().any(|&(icon, _, _)| icon == path)
+}
+
+/// The icon of the UI directory, or the built-in one without it
+fn icon(file: &Path, mime_type: &str) -> Response {
+    let (icon, mime_type) = match fs::read(file) {
+        Ok(icon) => (icon, mime_type),
+        Err(_) => (DEFAULT_ICON.to_vec(), "image/png"),
+    };
+
+    let mut response = Response::with((status::Ok, icon));
+    response
+        .headers
+        .set_raw("Content-Type", vec![mime_type.as_bytes().to_vec()]);
+    response.headers.set_raw(
+        "Cache-Control",
+        vec![ICON_CACHE_CONTROL.as_bytes().to_vec()],
+    );
+
+    response
+}
+
+/// The pages Apple devices load to learn whether they are online, which
+/// open the Captive Network Assistant sheet unless answered with
+/// `APPLE_PROBE_SUCCESS`
+const APPLE_PROBE_PATHS: &[(&str, &str)] = &[
+    ("/hotspot-detect.html", "apple_probe"),
+    ("/library/test/success.html", "apple_probe_success"),
+];
+
+const APPLE_PROBE_SUCCESS: &str =
+    "<HTML><HEAD><TITLE>Success</TITLE></HEAD><BODY>Success</BODY></HTML>";
+
+/// Probes are sent to the host names of the vendors, but are answered by
+/// the portal rather than redirected
+fn is_captive_probe(req: &Request) -> bool {
+    if req.method != Method::Get && req.method != Method::Head {
+        return false;
+    }
+
+    let path = format!("/{}", req.url.path().join("/"));
+
+    APPLE_PROBE_PATHS
+        .iter()
+        .chain(ANDROID_PROBE_PATHS.iter())
+        .any(|&(probe, _)| probe.eq_ignore_ascii_case(&path))
+}
+
+/// The pages Android devices expect an empty `204` from once online, and
+/// keep the sign in notification up for until then
+const ANDROID_PROBE_PATHS: &[(&str, &str)] = &[
+    ("/generate_204", "android_probe"),
+    ("/gen_204", "android_probe_short"),
+];
+
+fn is_android_probe(req: &Request) -> bool {
+    let path = format!("/{}", req.url.path().join("/"));
+
+    ANDROID_PROBE_PATHS
+        .iter()
+        .any(|&(probe, _)| probe.eq_ignore_ascii_case(&path))
+}
+
+/// Redirects to the portal until a network is joined, after which the
+/// `204` releases the phone from the captive browser
+fn android_probe(req: &mut Request, captive_mode: CaptiveMode) -> IronResult<Response> {
+    let client = client_ip(req);
+    let (connected, portal) = {
+        let mut request_state = get_request_state!(req);
+        (
+            request_state.is_connected(),
+            request_state.portal_url(client),
+        )
+    };
+
+    if connected {
+        debug!("Answering Android captive probe with 204");
+        return Ok(android_probe_released());
+    }
+
+    let portal = match portal {
+        Some(portal) => portal,
+        None => return Ok(Response::with(status::ServiceUnavailable)),
+    };
+
+    let mut response = portal_redirect(captive_mode, portal, &req.url);
+    response.headers.set(headers::CacheControl(vec![
+        headers::CacheDirective::NoCache,
+    ]));
+
+    Ok(response)
+}
+
+/// Some Android builds cache the probe replies, which would keep them
+/// captive or released for longer than they are
+fn android_probe_released() -> Response {
+    let mut response = Response::with(status::NoContent);
+    response.headers.set(headers::CacheControl(vec![
+        headers::CacheDirective::NoCache,
+    ]));
+    response
+}
+
+/// The assistant sheet shows the portal page itself, as it dismisses itself
+/// on some redirects, until a network is joined. The success page then lets
+/// it close on its own.
+fn apple_probe(req: &mut Request, index: &Path) -> IronResult<Response> {
+    let connected = get_request_state!(req).is_connected();
+
+    let page = if connected {
+        APPLE_PROBE_SUCCESS.to_string()
+    } else {
+        match fs::read_to_string(index) {
+            Ok(page) => page,
+            Err(e) => {
+                warn!(
+                    "Reading {} failed, answering with the built-in page: {}",
+                    index.display(),
+                    e
+                );
+                FALLBACK_PAGE.to_string()
+            }
+        }
+    };
+
+    debug!(
+        "Answering Apple captive probe with the {} page",
+        if connected { "success" } else { "portal" }
+    );
+
+    let mut response = no_store(Response::with((status::Ok, page)));
+    response.headers.set(headers::ContentType(Mime(
+        TopLevel::Text,
+        SubLevel::Html,
+        vec![(Attr::Charset, Value::Utf8)],
+    )));
+    // The assistant expects a connection per probe
+    response.headers.set(headers::Connection::close());
+
+    Ok(response)
+}
+
+/// API responses reflect the current state of the device, so neither
+/// browsers nor intermediate caches may reuse them
+fn no_store(mut response: Response) -> Response {
+    response.headers.set(headers::CacheControl(vec![
+        headers::CacheDirective::NoStore,
+    ]));
+    response.headers.set(headers::Pragma::NoCache);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::HashSet;
+    use std::env;
+    use std::io::Write as IoWrite;
+    use std::net::TcpStream;
+    use std::process;
+    use std::sync::mpsc::{channel, Receiver};
+
+    use jsonschema::{Draft, JSONSchema};
+
+    use config::PortalBuilder;
+    use exit::exit_channel;
+    use network::command_channel;
+    use poison;
+
+    /// The raw response to a raw request, read until the server closes the
+    /// connection
+    fn exchange(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(10)))
+            .unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+
+        response
+    }
+
+    fn status_of(response: &str) -> u16 {
+        response
+            .split(' ')
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .unwrap_or(0)
+    }
+
+    fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+        response
+            .split("\r\n\r\n")
+            .next()
+            .unwrap_or("")
+            .lines()
+            .skip(1)
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(header), Some(value)) if header.eq_ignore_ascii_case(name) => {
+                        Some(value.trim())
+                    }
+                    _ => None,
+                }
+            })
+            .next()
+    }
+
+    /// Status code and body of the response to a raw request
+    fn send(address: SocketAddr, request: &str) -> (u16, String) {
+        let response = exchange(address, request);
+        let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("");
+
+        (status_of(&response), body.to_string())
+    }
+
+    fn post_json(address: SocketAddr, path: &str, json: &str) -> (u16, String) {
+        send(
+            address,
+            &format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                 Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path,
+                json.len(),
+                json
+            ),
+        )
+    }
+
+    /// A GET request naming `host`, as captive clients send them
+    fn get_from(address: SocketAddr, host: &str, path: &str) -> String {
+        exchange(
+            address,
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path, host
+            ),
+        )
+    }
+
+    /// The server as the portal starts it, with a scripted network thread
+    /// answering the commands of its requests
+    struct TestPortal {
+        server: ServerHandle,
+        /// The SSIDs of the `Connect` commands the network thread received
+        connects: Receiver<String>,
+        /// Sent as the network thread starts to scan
+        scans: Receiver<()>,
+    }
+
+    impl TestPortal {
+        fn start(config: &Config) -> Self {
+            TestPortal::scanning_for(config, Duration::from_secs(0))
+        }
+
+        /// A portal whose network thread takes `scan_time` to answer each
+        /// scan
+        fn scanning_for(config: &Config, scan_time: Duration) -> Self {
+            let server_config = ServerConfig {
+                listening_address: Ipv4Addr::LOCALHOST,
+                listening_port: 0,
+                ..ServerConfig::from_config(config)
+            };
+            let (network_tx, network_rx) = command_channel();
+            let (exit_tx, _exit_rx) = exit_channel();
+            let (connect_tx, connects) = channel();
+            let (scan_tx, scans) = channel();
+
+            thread::spawn(move || {
+                while let Ok(command) = network_rx.recv() {
+                    match command {
+                        NetworkCommand::Activate(response_tx)
+                        | NetworkCommand::Rescan(response_tx) => {
+                            let _ = scan_tx.send(());
+                            thread::sleep(scan_time);
+
+                            response_tx.reply(NetworkCommandResponse::Networks {
+                                networks: vec![
+                                    scanned("Home", Security::Wpa),
+                                    scanned("Cafe", Security::None),
+                                    scanned("Office", Security::Enterprise),
+                                ],
+                                scanned_at: 1_700_000_000,
+                                weak_hidden: 1,
+                                active_ssid: None,
+                            })
+                        }
+                        NetworkCommand::ListSavedNetworks(response_tx) => {
+                            response_tx.reply(NetworkCommandResponse::SavedNetworks(Ok(Vec::new())))
+                        }
+                        NetworkCommand::ForgetAll { response_tx, .. } => {
+                            response_tx.reply(NetworkCommandResponse::Forgotten(Ok(Vec::new())))
+                        }
+                        NetworkCommand::Connect { ssid, .. } => {
+                            let _ = connect_tx.send(ssid.to_string());
+                        }
+                        _ => {}
+                    }
+                }
+            });
+
+            TestPortal {
+                server: start_server(&server_config, network_tx, exit_tx).unwrap(),
+                connects,
+                scans,
+            }
+        }
+
+        fn address(&self) -> SocketAddr {
+            self.server.address()
+        }
+
+        /// Where clients are sent to, before the `original_url` query
+        fn portal_url(&self) -> String {
+            format!("http://192.168.42.1:{}/", self.address().port())
+        }
+    }
+
+    impl Drop for TestPortal {
+        fn drop(&mut self) {
+            self.server.stop();
+        }
+    }
+
+    fn scanned(ssid: &str, security: Security) -> Network {
+        Network {
+            last_seen: 1_700_000_000,
+            ..Network::new(&Ssid::from(ssid), security)
+        }
+    }
+
+    fn json_body(body: &str) -> serde_json::Value {
+        serde_json::from_str(body).unwrap()
+    }
+
+    fn get(address: SocketAddr, path: &str) -> (u16, String) {
+        send(
+            address,
+            &format!(
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                path
+            ),
+        )
+    }
+
+    /// The count of `GET` requests answered with 200 in the metrics text
+    fn answered_gets(metrics: &str) -> u64 {
+        metrics
+            .lines()
+            .find(|line| {
+                line.starts_with("wifi_connect_http_requests_total{method=\"GET\",status=\"200\"}")
+            })
+            .and_then(|line| line.rsplit(' ').next())
+            .and_then(|count| count.parse().ok())
+            .unwrap_or(0)
+    }
+
+    #[test]
+    fn icon_requests_are_not_counted() {
+        let portal = TestPortal::start(&PortalBuilder::new().metrics(true).build());
+
+        let (_, before) = get(portal.address(), "/metrics");
+
+        for &(path, _, _) in ICON_PATHS.iter() {
+            let (status, _) = get(portal.address(), path);
+            assert_eq!(status, 200, "{}", path);
+        }
+
+        // Only the first `/metrics` request is counted in between
+        let (_, after) = get(portal.address(), "/metrics");
+        assert_eq!(answered_gets(&after), answered_gets(&before) + 1);
+    }
+
+    /// Runs `panic_locked`, which panics holding a lock of `shared`, on a
+    /// thread of its own
+    fn poison<T: Send + Sync + 'static>(shared: Arc<T>, panic_locked: fn(&T)) {
+        let _ = thread::spawn(move || panic_locked(&shared)).join();
+    }
+
+    #[test]
+    fn poisoned_shared_state_serves_later_requests() {
+        let server_config = ServerConfig::from_config(&PortalBuilder::new().build());
+        let (_event_tx, event_rx) = event_channel();
+        let request_state = RequestSharedState::new(&server_config, event_rx, 80);
+
+        let mut chain = Chain::new(|req: &mut Request| {
+            if req.url.path() == ["panic"] {
+                let _request_state = get_request_state!(req);
+                panic!("handler panicked holding the shared state");
+            }
+
+            clients(req)
+        });
+        chain.link(Write::<RequestSharedState>::both(request_state));
+
+        let mut listening = Iron::new(chain).http("127.0.0.1:0").unwrap();
+        let recoveries = poison::recoveries();
+
+        get(listening.socket, "/panic");
+
+        let (status, body) = get(listening.socket, "/clients");
+        assert_eq!(status, 200);
+        assert_eq!(body, "[]");
+
+        let exported = Metrics::default()
+            .render()
+            .lines()
+            .find(|line| line.starts_with("wifi_connect_lock_poison_recoveries_total "))
+            .and_then(|line| line.split(' ').nth(1))
+            .and_then(|count| count.parse::<usize>().ok())
+            .unwrap();
+        assert!(exported > recoveries);
+
+        let _ = listening.close();
+    }
+
+    #[test]
+    fn poisoned_refresh_gate_hands_out_turns() {
+        let gate = Arc::new(RefreshGate::default());
+
+        poison(gate.clone(), |gate| {
+            let _state = gate.state.lock();
+            panic!("panicked holding the refresh state");
+        });
+        assert!(gate.state.is_poisoned());
+
+        let turn = RefreshGate::enter(&gate, Duration::from_secs(1));
+        assert!(turn.is_some());
+        assert!(RefreshGate::enter(&gate, Duration::from_millis(10)).is_none());
+
+        drop(turn);
+        assert!(RefreshGate::enter(&gate, Duration::from_secs(1)).is_some());
+    }
+
+    #[test]
+    fn poisoned_shutdown_state_stops() {
+        let shutdown = Arc::new(ShutdownState::default());
+
+        poison(shutdown.clone(), |shutdown| {
+            let _stopped = shutdown.stopped.lock();
+            panic!("panicked holding the shutdown flag");
+        });
+        assert!(shutdown.stopped.is_poisoned());
+        assert!(!shutdown.is_stopped());
+
+        shutdown.stop();
+        assert!(shutdown.is_stopped());
+        shutdown.wait();
+    }
+
+    #[test]
+    fn csrf_tokens_are_issued_per_client() {
+        let tokens = CsrfTokens::default();
+        let first = IpAddr::V4(Ipv4Addr::new(192, 168, 42, 10));
+        let second = IpAddr::V4(Ipv4Addr::new(192, 168, 42, 11));
+
+        assert_eq!(tokens.get(first), None);
+
+        let token = tokens.issue(first).unwrap();
+        assert_eq!(token.len(), 32);
+        assert_eq!(tokens.issue(first).unwrap(), token);
+        assert_eq!(tokens.get(first), Some(token.clone()));
+
+        assert_ne!(tokens.issue(second).unwrap(), token);
+    }
+
+    #[test]
+    fn csrf_tokens_drop_the_longest_issued() {
+        let tokens = CsrfTokens::default();
+        let client = |index: usize| IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + index as u32));
+
+        for index in 0..=MAX_CSRF_CLIENTS {
+            tokens.issue(client(index)).unwrap();
+        }
+
+        assert_eq!(tokens.get(client(0)), None);
+        assert!(tokens.get(client(MAX_CSRF_CLIENTS)).is_some());
+    }
+
+    #[test]
+    fn csrf_cookies_are_set_alongside_those_of_the_handler() {
+        let csrf = Csrf {
+            tokens: CsrfTokens::default(),
+            api_token: None,
+        };
+        let mut chain = Chain::new(|_: &mut Request| {
+            let mut response = Response::with(status::Ok);
+            response
+                .headers
+                .set(headers::SetCookie(vec!["session=1; Path=/".to_string()]));
+            Ok(response)
+        });
+        chain.link_after(csrf);
+
+        let mut listening = Iron::new(chain).http("127.0.0.1:0").unwrap();
+
+        let response = get_from(listening.socket, "localhost", "/");
+        let cookies = response
+            .lines()
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| {
+                let mut parts = line.splitn(2, ':');
+                match (parts.next(), parts.next()) {
+                    (Some(header), Some(value)) if header.eq_ignore_ascii_case("Set-Cookie") => {
+                        Some(value.trim())
+                    }
+                    _ => None,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(cookies.len(), 2, "{:?}", cookies);
+        assert_eq!(cookies[0], "session=1; Path=/");
+        assert!(cookies[1].starts_with("csrf_token="));
+
+        let _ = listening.close();
+    }
+
+    /// Fields of a path item naming an operation
+    const OPENAPI_METHODS: [&str; 8] = [
+        "get", "put", "post", "delete", "options", "head", "patch", "trace",
+    ];
+
+    /// Where `document` breaks the OpenAPI 3.0 schema, as published at
+    /// https://spec.openapis.org/oas/3.0/schema/2021-09-28
+    fn openapi_violations(document: &serde_json::Value) -> Vec<String> {
+        let schema =
+            serde_json::from_str(include_str!("../tests/openapi-3.0-schema.json")).unwrap();
+        let schema = JSONSchema::options()
+            .with_draft(Draft::Draft4)
+            .compile(&schema)
+            .unwrap();
+
+        let violations = match schema.validate(document) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors
+                .map(|error| format!("{}: {}", error.instance_path, error))
+                .collect(),
+        };
+        violations
+    }
+
+    /// The `$ref`s that point at nothing, which the schema cannot tell
+    fn unresolved_refs(
+        document: &serde_json::Value,
+        value: &serde_json::Value,
+        unresolved: &mut Vec<String>,
+    ) {
+        match *value {
+            serde_json::Value::Object(ref object) => {
+                if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_str())
+                {
+                    let resolved = reference
+                        .strip_prefix('#')
+                        .and_then(|pointer| document.pointer(pointer));
+
+                    if resolved.is_none() {
+                        unresolved.push(reference.to_string());
+                    }
+                }
+
+                for value in object.values() {
+                    unresolved_refs(document, value, unresolved);
+                }
+            }
+            serde_json::Value::Array(ref values) => {
+                for value in values {
+                    unresolved_refs(document, value, unresolved);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn served_openapi_document() -> serde_json::Value {
+        serde_json::from_str(&openapi_document().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn openapi_document_follows_the_schema() {
+        let document = served_openapi_document();
+
+        assert_eq!(openapi_violations(&document), Vec::<String>::new());
+
+        let mut unresolved = Vec::new();
+        unresolved_refs(&document, &document, &mut unresolved);
+        assert_eq!(unresolved, Vec::<String>::new());
+    }
+
+    #[test]
+    fn openapi_document_tracks_the_api_version() {
+        assert_eq!(served_openapi_document()["info"]["version"], API_VERSION);
+    }
+
+    #[test]
+    fn openapi_document_describes_every_route() {
+        let document = served_openapi_document();
+        let server_config = ServerConfig::from_config(&PortalBuilder::new().build());
+        let locales = Arc::new(Locales::new(&server_config.ui_directory));
+        let metrics = Arc::new(Metrics::default());
+        let routes = portal_routes(&server_config, &locales, Some(&metrics)).unwrap();
+        let parameter = Regex::new(r":(\w+)").unwrap();
+
+        for &(ref method, path) in &routes.api_routes {
+            let path = parameter.replace_all(path, "{$1}");
+            let method = method.as_ref().to_lowercase();
+
+            assert!(
+                document["paths"][&*path][&method].is_object(),
+                "{} {} is not described",
+                method,
+                path
+            );
+        }
+
+        // Nor are routes that are not served described
+        let operations = document["paths"]
+            .as_object()
+            .unwrap()
+            .values()
+            .flat_map(|item| item.as_object().unwrap().keys())
+            .filter(|field| OPENAPI_METHODS.contains(&field.as_str()))
+            .count();
+        assert_eq!(operations, routes.api_routes.len());
+    }
+
+    #[test]
+    fn openapi_schema_rejects_broken_documents() {
+        let breakages: [fn(&mut serde_json::Value); 4] = [
+            |document| document["openapi"] = "2.0".into(),
+            |document| {
+                document["info"].as_object_mut().unwrap().remove("version");
+            },
+            |document| {
+                let responses = &mut document["paths"]["/version"]["get"]["responses"];
+                responses["600"] = responses["200"].clone();
+            },
+            |document| document["paths"]["/networks"]["get"]["parameters"][0]["in"] = "body".into(),
+        ];
+
+        for (index, breakage) in breakages.iter().enumerate() {
+            let mut document = served_openapi_document();
+            breakage(&mut document);
+
+            assert!(!openapi_violations(&document).is_empty(), "{}", index);
+        }
+    }
+
+    #[test]
+    fn networks_are_listed_as_the_network_thread_orders_them() {
+        let portal = TestPortal::start(&PortalBuilder::new().build());
+
+        let (status, body) = get(portal.address(), "/networks");
+        assert_eq!(status, 200);
+        assert_eq!(
+            json_body(&body),
+            json_body(
+                r#"[
+                    { "ssid": "Home", "security": "wpa", "known": false, "last_seen": 1700000000 },
+                    { "ssid": "Cafe", "security": "none", "known": false, "last_seen": 1700000000 },
+                    {
+                        "ssid": "Office",
+                        "security": "enterprise",
+                        "known": false,
+                        "last_seen": 1700000000
+                    }
+                ]"#
+            )
+        );
+
+        let (status, body) = get(portal.address(), "/api/v1/networks");
+        assert_eq!(status, 200);
+        let envelope = json_body(&body);
+        assert_eq!(envelope["version"].as_u64(), Some(1));
+        assert_eq!(envelope["scanned_at"].as_u64(), Some(1_700_000_000));
+        assert_eq!(envelope["weak_hidden"].as_u64(), Some(1));
+        assert!(envelope.get("active_ssid").is_none());
+        let ssids = envelope["networks"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|network| network["ssid"].as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ssids, ["Home", "Cafe", "Office"]);
+    }
+
+    #[test]
+    fn slow_scans_hold_up_no_static_files() {
+        let ui_directory = env::temp_dir().join(format!("wifi-connect-scan-{}", process::id()));
+        fs::create_dir_all(ui_directory.join("css")).unwrap();
+        fs::write(ui_directory.join("css").join("portal.css"), "body {}").unwrap();
+
+        let scan_time = Duration::from_secs(3);
+        let portal = TestPortal::scanning_for(
+            &PortalBuilder::new().ui_directory(&ui_directory).build(),
+            scan_time,
+        );
+        let address = portal.address();
+
+        let scan = thread::spawn(move || get(address, "/networks"));
+        portal.scans.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // The network thread is busy with the scan from here on
+        let started = Instant::now();
+        let (status, body) = get(address, "/css/portal.css");
+        assert_eq!(status, 200);
+        assert_eq!(body, "body {}");
+        assert!(started.elapsed() < scan_time / 3);
+
+        assert_eq!(scan.join().unwrap().0, 200);
+
+        let _ = fs::remove_dir_all(&ui_directory);
+    }
+
+    #[test]
+    fn connect_requests_are_validated() {
+        let portal = TestPortal::start(&PortalBuilder::new().csrf(false).build());
+        let address = portal.address();
+
+        // Lets the server learn the security of the scanned networks
+        get(address, "/networks");
+
+        let rejections = [
+            ("{}", 400, "missing-parameter", "ssid"),
+            (r#"{ "ssid": 5 }"#, 400, "wrong-type", "ssid"),
+            (r#"{ "ssid": "" }"#, 400, "invalid-ssid", "ssid"),
+            (
+                r#"{ "ssid": "Cafe", "priority": 5000 }"#,
+                400,
+                "out-of-range",
+                "priority",
+            ),
+            (
+                r#"{ "ssid": "Cafe", "metered": "sometimes" }"#,
+                400,
+                "invalid-choice",
+                "metered",
+            ),
+            (
+                r#"{ "ssid": "Home" }"#,
+                422,
+                "passphrase-required",
+                "passphrase",
+            ),
+            (
+                r#"{ "ssid": "Home", "passphrase": "short" }"#,
+                422,
+                "invalid-passphrase",
+                "passphrase",
+            ),
+            (
+                r#"{ "ssid": "WiFi Connect" }"#,
+                422,
+                "portal-network",
+                "ssid",
+            ),
+        ];
+
+        for &(request, expected_status, code, param) in rejections.iter() {
+            let (status, body) = post_json(address, "/connect", request);
+            let error = json_body(&body);
+
+            assert_eq!(status, expected_status, "{}", request);
+            assert_eq!(error["code"].as_str(), Some(code), "{}", request);
+            assert_eq!(error["param"].as_str(), Some(param), "{}", request);
+            assert!(error["message"].is_string(), "{}", request);
+        }
+        assert!(portal.connects.try_recv().is_err());
+
+        let (status, body) = post_json(
+            address,
+            "/connect",
+            r#"{ "ssid": "Home", "passphrase": "correct horse" }"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(json_body(&body)["ssid"].as_str(), Some("Home"));
+        assert_eq!(
+            portal.connects.recv_timeout(Duration::from_secs(10)),
+            Ok("Home".to_string())
+        );
+
+        let (status, body) = post_json(address, "/connect", r#"{ "ssid": "Cafe" }"#);
+        assert_eq!(status, 409);
+        assert_eq!(
+            json_body(&body)["code"].as_str(),
+            Some("connect-in-progress")
+        );
+    }
+
+    #[test]
+    fn foreign_hosts_are_redirected_to_the_gateway() {
+        let portal = TestPortal::start(&PortalBuilder::new().build());
+
+        let response = get_from(portal.address(), "example.com", "/");
+        assert_eq!(status_of(&response), 302);
+        assert!(header(&response, "Location")
+            .unwrap()
+            .starts_with(&portal.portal_url()));
+
+        // The portal itself is served under its own addresses
+        for host in ["localhost", "127.0.0.1", "192.168.42.1"].iter() {
+            assert_eq!(
+                status_of(&get_from(portal.address(), host, "/version")),
+                200
+            );
+        }
+    }
+
+    #[test]
+    fn captive_probes_are_answered_until_a_network_is_joined() {
+        let portal = TestPortal::start(&PortalBuilder::new().build());
+        let android = || {
+            get_from(
+                portal.address(),
+                "connectivitycheck.gstatic.com",
+                "/generate_204",
+            )
+        };
+        let apple = || {
+            get_from(
+                portal.address(),
+                "captive.apple.com",
+                "/hotspot-detect.html",
+            )
+        };
+
+        let response = android();
+        assert_eq!(status_of(&response), 302);
+        assert!(header(&response, "Location")
+            .unwrap()
+            .starts_with(&portal.portal_url()));
+
+        let response = apple();
+        assert_eq!(status_of(&response), 200);
+        assert!(!response.contains(APPLE_PROBE_SUCCESS));
+
+        portal
+            .server
+            .event_tx()
+            .send(NetworkEvent::Connected {
+                ssid: "Home".to_string(),
+                ip: None,
+                dns: Vec::new(),
+                uuid: None,
+                interface: "wlan0".to_string(),
+            })
+            .unwrap();
+
+        assert_eq!(status_of(&android()), 204);
+
+        let response = apple();
+        assert_eq!(status_of(&response), 200);
+        assert!(response.contains(APPLE_PROBE_SUCCESS));
+    }
+
+    #[test]
+    fn apple_probes_follow_the_connection_state() {
+        let portal = TestPortal::start(&PortalBuilder::new().build());
+        let event_tx = portal.server.event_tx();
+
+        // Whether each probe page is answered with the success page, checking
+        // the headers the assistant sheet relies on
+        let answered_success = || {
+            let answers = APPLE_PROBE_PATHS
+                .iter()
+                .map(|&(path, _)| {
+                    let response = get_from(portal.address(), "captive.apple.com", path);
+                    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or("");
+
+                    assert_eq!(status_of(&response), 200, "{}", path);
+                    assert_eq!(header(&response, "Connection"), Some("close"), "{}", path);
+                    assert_eq!(
+                        header(&response, "Cache-Control"),
+                        Some("no-store"),
+                        "{}",
+                        path
+                    );
+                    assert!(header(&response, "Location").is_none(), "{}", path);
+
+                    if body == APPLE_PROBE_SUCCESS {
+                        true
+                    } else {
+                        assert!(body.to_lowercase().contains("<html"), "{}", path);
+                        false
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            assert!(answers.iter().all(|&answer| answer == answers[0]));
+            answers[0]
+        };
+
+        assert!(!answered_success());
+
+        event_tx
+            .send(NetworkEvent::ConnectAttempt {
+                ssid: "Home".to_string(),
+                timeout: 60,
+            })
+            .unwrap();
+        assert!(!answered_success());
+
+        event_tx
+            .send(NetworkEvent::ConnectFailed {
+                ssid: "Home".to_string(),
+                reason: "wrong passphrase".to_string(),
+            })
+            .unwrap();
+        assert!(!answered_success());
+
+        event_tx
+            .send(NetworkEvent::Connected {
+                ssid: "Home".to_string(),
+                ip: None,
+                dns: Vec::new(),
+                uuid: None,
+                interface: "wlan0".to_string(),
+            })
+            .unwrap();
+        assert!(answered_success());
+
+        // The portal comes back up once the joined network is lost
+        event_tx.send(NetworkEvent::PortalStarted).unwrap();
+        assert!(!answered_success());
+    }
+
+    #[test]
+    fn api_responses_are_json_and_not_cached() {
+        let portal = TestPortal::start(&PortalBuilder::new().build());
+
+        // A successful reply, one served from the cache and an error
+        for path in [
+            "/networks",
+            "/networks",
+            "/version",
+            "/networks?refresh=maybe",
+        ]
+        .iter()
+        {
+            let response = get_from(portal.address(), "localhost", path);
+
+            assert_eq!(
+                header(&response, "Content-Type"),
+                Some("application/json; charset=utf-8"),
+                "{}",
+                path
+            );
+            assert_eq!(
+                header(&response, "Cache-Control"),
+                Some("no-store"),
+                "{}",
+                path
+            );
+            assert_eq!(header(&response, "Pragma"), Some("no-cache"), "{}", path);
+        }
+    }
+
+    fn connect_params(params: &[(&str, params::Value)]) -> Map {
+        let mut map = Map::new();
+
+        for &(name, ref value) in params {
+            map.assign(name, value.clone()).unwrap();
+        }
+
+        map
+    }
+
+    fn connect_rejection(params: &[(&str, params::Value)]) -> ApiError {
+        match ConnectRequest::from_params(&connect_params(params)) {
+            Ok(_) => panic!("accepted the parameters"),
+            Err(error) => error,
+        }
+    }
+
+    fn text(value: &str) -> params::Value {
+        params::Value::String(value.to_string())
+    }
+
+    #[test]
+    fn connect_parameters_are_parsed() {
+        let request = ConnectRequest::from_params(&connect_params(&[
+            ("ssid", text("Home")),
+            ("passphrase", text("correct horse")),
+            ("timeout", params::Value::U64(30)),
+            ("autoconnect", text("false")),
+            ("priority", params::Value::I64(-5)),
+            ("metered", text("yes")),
+        ]))
+        .ok()
+        .unwrap();
+
+        assert_eq!(request.ssid, Some(Ssid::from("Home")));
+        assert_eq!(
+            request.credentials.passphrase,
+            Some("correct horse".to_string())
+        );
+        assert_eq!(request.timeout, Some(30));
+        assert_eq!(request.profile.autoconnect, Some(false));
+        assert_eq!(request.profile.priority, Some(-5));
+        assert_eq!(request.profile.metered, Some(Metered::Yes));
+    }
+
+    #[test]
+    fn missing_connect_parameters_are_named() {
+        let error = connect_rejection(&[("passphrase", text("correct horse"))]);
+        assert_eq!(error.code, ErrorCode::MissingParameter);
+        assert_eq!(error.param, Some("ssid"));
+        assert_eq!(error.expected, Some("string"));
+
+        // Empty form fields are as good as missing
+        let error = connect_rejection(&[("ssid_raw", text("")), ("ssid", text(""))]);
+        assert_eq!(error.code, ErrorCode::InvalidSsid);
+        assert_eq!(error.param, Some("ssid"));
+    }
+
+    #[test]
+    fn connect_parameters_of_the_wrong_type_are_named() {
+        let cases = [
+            ("ssid", params::Value::U64(5), "string"),
+            ("ssid_raw", text("not base64!"), "base64 string"),
+            ("passphrase", params::Value::Boolean(true), "string"),
+            ("identity", params::Value::U64(1), "string"),
+            ("autoconnect", text("maybe"), "boolean"),
+            ("priority", params::Value::Boolean(true), "integer"),
+            ("timeout", params::Value::I64(-5), "positive integer"),
+            ("timeout", params::Value::U64(0), "positive integer"),
+        ];
+
+        for &(param, ref value, expected) in cases.iter() {
+            let mut params = vec![("ssid", text("Home")), (param, value.clone())];
+            if param == "ssid" {
+                params.remove(0);
+            }
+
+            let error = connect_rejection(&params);
+            assert_eq!(error.code, ErrorCode::WrongType, "{}", param);
+            assert_eq!(error.param, Some(param));
+            assert_eq!(error.expected, Some(expected), "{}", param);
+        }
+    }
+
+    #[test]
+    fn connect_parameters_out_of_range_are_named() {
+        for &priority in [i64::from(MAX_PRIORITY) + 1, i64::from(MIN_PRIORITY) - 1].iter() {
+            let error = connect_rejection(&[
+                ("ssid", text("Home")),
+                ("priority", params::Value::I64(priority)),
+            ]);
+            assert_eq!(error.code, ErrorCode::OutOfRange);
+            assert_eq!(error.param, Some("priority"));
+            assert_eq!(error.expected, Some(PRIORITY_RANGE));
+        }
+
+        let error = connect_rejection(&[("ssid", text(&"x".repeat(MAX_SSID_LENGTH + 1)))]);
+        assert_eq!(error.code, ErrorCode::InvalidSsid);
+        assert_eq!(error.param, Some("ssid"));
+
+        let error = connect_rejection(&[("ssid", text("Home")), ("security", text("wpa3"))]);
+        assert_eq!(error.code, ErrorCode::InvalidChoice);
+        assert_eq!(error.param, Some("security"));
+    }
+
+    #[test]
+    fn connect_credentials_are_validated_against_the_scan() {
+        let networks = [
+            scanned("Home", Security::Wpa),
+            scanned("Cafe", Security::None),
+        ];
+        let validate = |params: &[(&str, params::Value)]| {
+            let request = ConnectRequest::from_params(&connect_params(params))
+                .ok()
+                .unwrap();
+            let ssid = request.ssid.clone().unwrap();
+            request.validate(&ssid, &networks)
+        };
+
+        let error = validate(&[("ssid", text("Home"))]).unwrap_err();
+        assert_eq!(error.code, ErrorCode::PassphraseRequired);
+        assert_eq!(error.param, Some("passphrase"));
+
+        let error = validate(&[("ssid", text("Home")), ("passphrase", text("short"))]).unwrap_err();
+        assert_eq!(error.code, ErrorCode::InvalidPassphrase);
+
+        assert_eq!(
+            validate(&[
+                ("ssid", text("Home")),
+                ("passphrase", text("correct horse"))
+            ])
+            .unwrap(),
+            ConnectSecurity::WpaPsk
+        );
+        assert_eq!(
+            validate(&[("ssid", text("Cafe"))]).unwrap(),
+            ConnectSecurity::Open
+        );
+    }
+
+    #[test]
+    fn portal_hosts_match_with_and_without_ports() {
+        let config = PortalBuilder::new()
+            .gateway6("fd00::1".parse().unwrap())
+            .allowed_host("setup.example")
+            .build();
+        let hosts = PortalHosts::new(&ServerConfig::from_config(&config));
+
+        let portal = [
+            "192.168.42.1",
+            "192.168.42.1:8080",
+            "localhost",
+            "LOCALHOST:80",
+            "127.0.0.1:80",
+            "setup.example",
+            "Setup.Example.:8080",
+            "[fd00::1]",
+            "[fd00::1]:8080",
+            "[FD00:0:0::1]:80",
+            "[fd00::1%wlan0]:80",
+            "[::1]",
+        ];
+        for host in portal.iter() {
+            assert!(hosts.contains(host), "{}", host);
+        }
+
+        let foreign = [
+            "example.com",
+            "example.com:80",
+            "192.168.42.10",
+            "192.168.42.10:80",
+            "192.168.42.1.example.com",
+            "setup.example.com:8080",
+            "[fd00::2]:8080",
+            "[not-an-address]",
+        ];
+        for host in foreign.iter() {
+            assert!(!hosts.contains(host), "{}", host);
+        }
+    }
+
+    #[test]
+    fn only_safe_methods_are_redirected() {
+        let portal = TestPortal::start(&PortalBuilder::new().csrf(false).build());
+        let address = portal.address();
+
+        let response = exchange(
+            address,
+            "HEAD / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n",
+        );
+        assert_eq!(status_of(&response), 302);
+
+        // A port does not make the portal a foreign host
+        let response = get_from(address, "192.168.42.1:8080", "/version");
+        assert_eq!(status_of(&response), 200);
+
+        let (status, _) = send(
+            address,
+            "POST /connect HTTP/1.1\r\nHost: example.com\r\nContent-Type: application/json\r\n\
+             Content-Length: 2\r\nConnection: close\r\n\r\n{}",
+        );
+        assert_ne!(status, 302);
+    }
+
+    #[test]
+    fn foreign_hosts_reach_no_handler() {
+        let portal = TestPortal::start(
+            &PortalBuilder::new()
+                .csrf(false)
+                .allowed_host("setup.example")
+                .build(),
+        );
+        let address = portal.address();
+        let post = |host: &str| {
+            let json = r#"{ "ssid": "Cafe" }"#;
+            send(
+                address,
+                &format!(
+                    "POST /connect HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    host,
+                    json.len(),
+                    json
+                ),
+            )
+        };
+
+        // Refused before the handler, rather than redirected
+        let (status, body) = post("rebound.example.com");
+        assert_eq!(status, 421);
+        assert_eq!(json_body(&body)["code"].as_str(), Some("unknown-host"));
+        assert!(portal.connects.try_recv().is_err());
+
+        // Reads of the API under a foreign host are sent to the portal
+        // instead of being answered
+        let response = get_from(address, "rebound.example.com", "/networks");
+        assert_eq!(status_of(&response), 302);
+        assert!(header(&response, "Location")
+            .unwrap()
+            .starts_with(&portal.portal_url()));
+
+        // Probes name the hosts of the vendors, and are answered all the same
+        let response = get_from(address, "captive.apple.com", "/hotspot-detect.html");
+        assert_eq!(status_of(&response), 200);
+
+        // The allowed names are the portal
+        assert_eq!(
+            status_of(&get_from(address, "setup.example", "/version")),
+            200
+        );
+        let (status, _) = post("setup.example");
+        assert_eq!(status, 200);
+        assert_eq!(
+            portal.connects.recv_timeout(Duration::from_secs(10)),
+            Ok("Cafe".to_string())
+        );
+    }
+
+    #[test]
+    fn oversized_and_chunked_bodies_are_refused() {
+        let portal = TestPortal::start(
+            &PortalBuilder::new()
+                .csrf(false)
+                .max_body_size(1024)
+                .max_upload_size(4096)
+                .build(),
+        );
+        // Refused from the headers alone, so no body is sent after them
+        let post = |path: &str, body_headers: &str| {
+            send(
+                portal.address(),
+                &format!(
+                    "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                     {}\r\nConnection: close\r\n\r\n",
+                    path, body_headers
+                ),
+            )
+        };
+
+        let (status, body) = post("/connect", "Content-Length: 1025");
+        assert_eq!(status, 413);
+        assert_eq!(json_body(&body)["code"].as_str(), Some("body-too-large"));
+
+        let (status, body) = post("/networks/import", "Content-Length: 4097");
+        assert_eq!(status, 413);
+        assert_eq!(json_body(&body)["code"].as_str(), Some("body-too-large"));
+
+        let (status, body) = post("/connect", "Transfer-Encoding: chunked");
+        assert_eq!(status, 411);
+        assert_eq!(json_body(&body)["code"].as_str(), Some("length-required"));
+
+        // Uploads may exceed the limit of the other routes
+        let document = format!(r#"{{ "padding": "{}" }}"#, "x".repeat(2048));
+        let (status, body) = send(
+            portal.address(),
+            &format!(
+                "POST /networks/import HTTP/1.1\r\nHost: localhost\r\n\
+                 Content-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                document.len(),
+                document
+            ),
+        );
+        // Read in full, and refused for what it is
+        assert_eq!(status, 400);
+        assert_eq!(json_body(&body)["code"].as_str(), Some("invalid-profile"));
+
+        let (status, _) = post_json(portal.address(), "/connect", "{}");
+        assert_eq!(status, 400);
+    }
+
+    /// A JSON request carrying `authorization` as its `Authorization` header
+    fn post_authorized(
+        address: SocketAddr,
+        path: &str,
+        authorization: &str,
+        json: &str,
+    ) -> (u16, String) {
+        send(
+            address,
+            &format!(
+                "POST {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+                 Authorization: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                path,
+                authorization,
+                json.len(),
+                json
+            ),
+        )
+    }
+
+    #[test]
+    fn resets_and_renames_require_the_api_token() {
+        let portal = TestPortal::start(&PortalBuilder::new().csrf(false).build());
+
+        let (status, body) = post_json(portal.address(), "/reset", "{}");
+        assert_eq!(status, 403);
+        assert_eq!(
+            json_body(&body)["code"].as_str(),
+            Some("api-token-not-configured")
+        );
+
+        let (status, body) =
+            post_json(portal.address(), "/hostname", r#"{ "hostname": "device" }"#);
+        assert_eq!(status, 403);
+        assert_eq!(
+            json_body(&body)["code"].as_str(),
+            Some("api-token-not-configured")
+        );
+
+        let portal =
+            TestPortal::start(&PortalBuilder::new().csrf(false).api_token("secret").build());
+
+        for path in ["/reset", "/hostname"].iter() {
+            let (status, body) = post_authorized(
+                portal.address(),
+                path,
+                "Bearer guessed",
+                r#"{ "hostname": "device" }"#,
+            );
+            assert_eq!(status, 401, "{}", path);
+            assert_eq!(
+                json_body(&body)["code"].as_str(),
+                Some("unauthorized"),
+                "{}",
+                path
+            );
+        }
+
+        let (status, body) = post_authorized(
+            portal.address(),
+            "/reset",
+            "Bearer secret",
+            r#"{ "dry_run": true }"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(json_body(&body)["count"].as_u64(), Some(0));
+    }
+
+    /// Every error code, the match naming the one after each so that a new
+    /// code cannot be added without being listed
+    fn error_codes() -> Vec<ErrorCode> {
+        let mut codes = Vec::new();
+        let mut next = Some(ErrorCode::MissingParameter);
+
+        while let Some(code) = next {
+            codes.push(code);
+            next = match code {
+                ErrorCode::MissingParameter => Some(ErrorCode::WrongType),
+                ErrorCode::WrongType => Some(ErrorCode::OutOfRange),
+                ErrorCode::OutOfRange => Some(ErrorCode::InvalidChoice),
+                ErrorCode::InvalidChoice => Some(ErrorCode::InvalidParameter),
+                ErrorCode::InvalidParameter => Some(ErrorCode::MalformedRequest),
+                ErrorCode::MalformedRequest => Some(ErrorCode::PassphraseRequired),
+                ErrorCode::PassphraseRequired => Some(ErrorCode::IdentityRequired),
+                ErrorCode::IdentityRequired => Some(ErrorCode::InvalidPassphrase),
+                ErrorCode::InvalidPassphrase => Some(ErrorCode::InvalidSsid),
+                ErrorCode::InvalidSsid => Some(ErrorCode::UnknownProfile),
+                ErrorCode::UnknownProfile => Some(ErrorCode::DuplicateNetwork),
+                ErrorCode::DuplicateNetwork => Some(ErrorCode::SavedProfileExists),
+                ErrorCode::SavedProfileExists => Some(ErrorCode::InvalidNetworks),
+                ErrorCode::InvalidNetworks => Some(ErrorCode::PortalNetwork),
+                ErrorCode::PortalNetwork => Some(ErrorCode::NetworkNotOffered),
+                ErrorCode::NetworkNotOffered => Some(ErrorCode::ConnectInProgress),
+                ErrorCode::ConnectInProgress => Some(ErrorCode::NotConnected),
+                ErrorCode::NotConnected => Some(ErrorCode::SessionLocked),
+                ErrorCode::SessionLocked => Some(ErrorCode::CsrfToken),
+                ErrorCode::CsrfToken => Some(ErrorCode::Unauthorized),
+                ErrorCode::Unauthorized => Some(ErrorCode::ApiTokenNotConfigured),
+                ErrorCode::ApiTokenNotConfigured => Some(ErrorCode::UnknownHost),
+                ErrorCode::UnknownHost => Some(ErrorCode::BodyTooLarge),
+                ErrorCode::BodyTooLarge => Some(ErrorCode::LengthRequired),
+                ErrorCode::LengthRequired => Some(ErrorCode::Busy),
+                ErrorCode::Busy => Some(ErrorCode::NetworkThread),
+                ErrorCode::NetworkThread => Some(ErrorCode::NetworkStack),
+                ErrorCode::NetworkStack => Some(ErrorCode::HostnameFailed),
+                ErrorCode::HostnameFailed => Some(ErrorCode::InvalidProfile),
+                ErrorCode::InvalidProfile => Some(ErrorCode::LogBufferDisabled),
+                ErrorCode::LogBufferDisabled => Some(ErrorCode::QrTooLarge),
+                ErrorCode::QrTooLarge => None,
+            };
+        }
+
+        codes
+    }
+
+    #[test]
+    fn every_error_code_has_an_english_message() {
+        let mut names = HashSet::new();
+
+        for code in error_codes() {
+            assert!(names.insert(code.as_str()), "{} is taken", code.as_str());
+
+            let message = code.message();
+            assert!(message.ends_with('.'), "{:?}: {}", code, message);
+            assert!(message.len() > 1, "{:?}", code);
+
+            let body = serde_json::to_value(ApiError::new(code, "")).unwrap();
+            assert_eq!(body["code"].as_str(), Some(code.as_str()));
+            assert_eq!(body["message"].as_str(), Some(message));
+        }
+
+        // The limits the validation enforces
+        assert_eq!(
+            ErrorCode::InvalidPassphrase.message(),
+            "A WiFi password has 8 to 63 characters, or 64 hexadecimal digits."
+        );
+        assert_eq!(
+            ErrorCode::InvalidSsid.message(),
+            "A network name has 1 to 32 bytes."
+        );
     }
 }
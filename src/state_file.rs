@@ -0,0 +1,147 @@
+use std::fs;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use serde_json;
+
+use config::Config;
+use errors::*;
+use network::{event_channel, unix_time, EventReceiver, EventSender, NetworkEvent};
+
+const FLUSH_TIMEOUT_SECS: u64 = 5;
+
+/// Phase of the provisioning flow as written to `--state-file`
+#[derive(Serialize)]
+struct State {
+    phase: &'static str,
+    ssid: Option<String>,
+    ip: Option<Ipv4Addr>,
+    /// Why the latest connect attempt failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    /// Seconds since the epoch
+    updated_at: u64,
+    /// Of the process writing the file, for telling a crashed one
+    pid: u32,
+    /// Set once the process is exiting, the phase staying the last one
+    terminal: bool,
+}
+
+impl State {
+    fn new(phase: &'static str, ssid: Option<&str>) -> Self {
+        State {
+            phase,
+            ssid: ssid.map(String::from),
+            ip: None,
+            reason: None,
+            updated_at: unix_time(),
+            pid: process::id(),
+            terminal: false,
+        }
+    }
+}
+
+/// Rewrites `--state-file` on every phase change from a dedicated thread,
+/// so that other processes on the device can follow the flow without
+/// reaching the portal over HTTP
+pub struct StateFile {
+    event_tx: EventSender,
+    done_rx: Receiver<()>,
+}
+
+impl StateFile {
+    pub fn spawn(config: &Config) -> Option<Self> {
+        let path = config.state_file.clone()?;
+
+        if let Some(parent) = path.parent() {
+            if let Err(e) = fs::create_dir_all(parent) {
+                warn!("Creating {} failed: {}", parent.display(), e);
+            }
+        }
+
+        let (event_tx, event_rx) = event_channel();
+        let (done_tx, done_rx) = channel();
+
+        thread::spawn(move || {
+            write_states(&path, &event_rx);
+            let _ = done_tx.send(());
+        });
+
+        Some(StateFile { event_tx, done_rx })
+    }
+
+    pub fn event_tx(&self) -> EventSender {
+        self.event_tx.clone()
+    }
+
+    /// Waits for the terminal state to be written before exiting
+    pub fn flush(self) {
+        let StateFile { event_tx, done_rx } = self;
+
+        drop(event_tx);
+
+        if done_rx
+            .recv_timeout(Duration::from_secs(FLUSH_TIMEOUT_SECS))
+            .is_err()
+        {
+            warn!("Giving up on writing the state file");
+        }
+    }
+}
+
+fn write_states(path: &Path, event_rx: &EventReceiver) {
+    let mut state = State::new("portal", None);
+
+    while let Ok(event) = event_rx.recv() {
+        state = match event {
+            NetworkEvent::PortalStarted | NetworkEvent::ConnectCancelled { .. } => {
+                State::new("portal", None)
+            }
+            // Not phase changes
+            NetworkEvent::ClientConnected { .. }
+            | NetworkEvent::BulkConnectFinished { .. }
+            | NetworkEvent::NetworksRefreshed => continue,
+            NetworkEvent::ConnectAttempt { ref ssid, .. } => State::new("connecting", Some(ssid)),
+            NetworkEvent::Connected { ref ssid, ip, .. } => State {
+                ip,
+                ..State::new("connected", Some(ssid))
+            },
+            NetworkEvent::ConnectFailed {
+                ref ssid,
+                ref reason,
+            } => State {
+                reason: Some(reason.clone()),
+                ..State::new("failed", Some(ssid))
+            },
+            NetworkEvent::Exiting => State {
+                updated_at: unix_time(),
+                terminal: true,
+                ..state
+            },
+        };
+
+        if let Err(e) = write_state(path, &state) {
+            warn!("Writing the state file {} failed: {}", path.display(), e);
+        }
+    }
+}
+
+/// Writes a temporary file next to the state file and renames it over the
+/// latter, so that readers never see partial JSON
+fn write_state(path: &Path, state: &State) -> Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let temporary = PathBuf::from(temporary);
+
+    let mut json = serde_json::to_vec(state).chain_err(|| "serializing the state failed")?;
+    json.push(b'\n');
+
+    fs::write(&temporary, &json)?;
+    fs::rename(&temporary, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,624 @@
+use std::cmp;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use iron::method::Method;
+use iron::prelude::*;
+use iron::{status, Handler};
+use regex::Regex;
+use staticfile::Static;
+
+/// Content codings of the pre-compressed variants, most compact first, with
+/// the extension of their files
+const ENCODINGS: [(&str, &str); 2] = [("br", "br"), ("gzip", "gz")];
+
+/// `Cache-Control` of files named after their content, which a phone may
+/// keep for good
+const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// `Cache-Control` of every other file, revalidated with its `ETag`
+const REVALIDATE: &str = "no-cache";
+
+/// Content types of the file extensions a web UI is made of, which strict
+/// captive browsers refuse to apply when served as `application/octet-stream`
+const MIME_TYPES: &[(&str, &str)] = &[
+    ("html", "text/html"),
+    ("htm", "text/html"),
+    ("css", "text/css"),
+    ("js", "text/javascript"),
+    ("mjs", "text/javascript"),
+    ("json", "application/json"),
+    ("map", "application/json"),
+    ("webmanifest", "application/manifest+json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain"),
+    ("csv", "text/csv"),
+    ("md", "text/markdown"),
+    ("svg", "image/svg+xml"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("ico", "image/x-icon"),
+    ("bmp", "image/bmp"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+    ("eot", "application/vnd.ms-fontobject"),
+    ("wasm", "application/wasm"),
+    ("pdf", "application/pdf"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+];
+
+/// Content type of the files with an extension missing from `MIME_TYPES`
+const DEFAULT_MIME_TYPE: &str = "application/octet-stream";
+
+/// Shortest run of hex digits taken for a content hash, like the `3f9c2a1b`
+/// of `app.3f9c2a1b.js`
+const MIN_HASH_LENGTH: usize = 8;
+
+/// A `Range` of the request the file can be served for
+enum ByteRange {
+    /// The first and last byte, both included
+    Satisfiable(u64, u64),
+    /// Starting past the end of the file
+    Unsatisfiable,
+}
+
+/// Serves the files of a directory like `Static`, preferring the `.br` or
+/// `.gz` variant a UI build emits next to a file when the client accepts
+/// it, which spares compressing on the device. Files are revalidated with
+/// their `ETag`, unless named after their content, and a single byte range
+/// of them is served to clients asking for one.
+pub struct StaticFiles {
+    root: PathBuf,
+    files: Static,
+    /// Regular expressions of the file names with a content hash, the
+    /// built-in detection if empty, none for directories without
+    /// fingerprinted files
+    immutable: Option<Vec<Regex>>,
+    /// Content types of extensions taking precedence over `MIME_TYPES`
+    mime_types: Vec<(String, String)>,
+}
+
+impl StaticFiles {
+    pub fn new<P: AsRef<Path>>(root: P) -> Self {
+        StaticFiles {
+            root: root.as_ref().to_path_buf(),
+            files: Static::new(root),
+            immutable: None,
+            mime_types: Vec::new(),
+        }
+    }
+
+    /// Serves the files with the extensions as the content types, over the
+    /// built-in ones
+    pub fn mime_types(mut self, mime_types: &[(String, String)]) -> Self {
+        self.mime_types = mime_types.to_vec();
+        self
+    }
+
+    /// The content type of the file by its extension, the text ones in
+    /// UTF-8
+    fn content_type(&self, path: &Path) -> String {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+
+        let mime_type = self
+            .mime_types
+            .iter()
+            .find(|&&(ref known, _)| *known == extension)
+            .map(|&(_, ref mime_type)| mime_type.as_str())
+            .or_else(|| {
+                MIME_TYPES
+                    .iter()
+                    .find(|&&(known, _)| known == extension)
+                    .map(|&(_, mime_type)| mime_type)
+            })
+            .unwrap_or(DEFAULT_MIME_TYPE);
+
+        if is_text(mime_type) && !mime_type.contains(';') {
+            format!("{}; charset=utf-8", mime_type)
+        } else {
+            mime_type.to_string()
+        }
+    }
+
+    /// Lets clients cache the files with a content hash in their name for
+    /// good, found by the regular expressions matching anywhere in the name,
+    /// or by a run of hex digits between dots or dashes without any
+    pub fn immutable(mut self, patterns: &[Regex]) -> Self {
+        self.immutable = Some(patterns.to_vec());
+        self
+    }
+
+    fn cache_control(&self, path: &Path) -> &'static str {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy(),
+            None => return REVALIDATE,
+        };
+
+        let hashed = match self.immutable {
+            Some(ref patterns) if patterns.is_empty() => has_content_hash(&name),
+            Some(ref patterns) => patterns.iter().any(|pattern| pattern.is_match(&name)),
+            None => false,
+        };
+
+        if hashed && name != "index.html" {
+            IMMUTABLE
+        } else {
+            REVALIDATE
+        }
+    }
+
+    /// The file of the request path, none if it is not a file of the
+    /// directory, directories standing for their `index.html`
+    fn resolve(&self, req: &Request) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+
+        for segment in req.url.path() {
+            let segment = percent_decode(segment)?;
+
+            if segment == ".." || segment.contains('/') {
+                return None;
+            }
+
+            path.push(segment);
+        }
+
+        if path.is_dir() && req.url.path().last() == Some(&"") {
+            path.push("index.html");
+        }
+
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// The content coding and file of the pre-compressed variant the
+    /// client accepts, if there is one
+    fn variant(&self, req: &Request, path: &Path) -> Option<(&'static str, PathBuf)> {
+        // Ranges address the bytes of the plain file
+        if req.headers.get_raw("Range").is_some() {
+            return None;
+        }
+
+        let accepted = accepted_encodings(req);
+
+        ENCODINGS
+            .iter()
+            .filter(|&&(encoding, _)| accepted.iter().any(|accepted| accepted == encoding))
+            .map(|&(encoding, extension)| (encoding, variant_path(path, extension)))
+            .find(|&(_, ref variant)| variant.is_file())
+    }
+}
+
+impl Handler for StaticFiles {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        let path = match self.resolve(req) {
+            Some(path) => path,
+            None => return self.files.handle(req),
+        };
+
+        let variant = self.variant(req, &path);
+        let served = variant
+            .as_ref()
+            .map_or(path.clone(), |&(_, ref variant)| variant.clone());
+
+        let etag = entity_tag(&served);
+        let cache_control = self.cache_control(&path);
+
+        let mut response = match etag {
+            Some(ref etag) if if_none_match(req, etag) => Response::with(status::NotModified),
+            _ => match variant {
+                Some((encoding, ref variant)) => match File::open(variant) {
+                    Ok(file) => {
+                        debug!("Serving {}", variant.display());
+
+                        let mut response = Response::with((status::Ok, file));
+                        response
+                            .headers
+                            .set_raw("Content-Encoding", vec![encoding.as_bytes().to_vec()]);
+                        response
+                    }
+                    Err(_) => self.files.handle(req)?,
+                },
+                None => match partial_content(req, &path, etag.as_ref().map(String::as_str)) {
+                    Some(response) => response,
+                    None => self.files.handle(req)?,
+                },
+            },
+        };
+
+        // The type of the plain file, for variants and ranges as well
+        if response.status == Some(status::Ok) || response.status == Some(status::PartialContent) {
+            response
+                .headers
+                .set_raw("Content-Type", vec![self.content_type(&path).into_bytes()]);
+        }
+
+        // Caches have to tell apart the responses of files with variants
+        if has_variant(&path) {
+            response
+                .headers
+                .set_raw("Vary", vec![b"Accept-Encoding".to_vec()]);
+        }
+
+        if let Some(etag) = etag {
+            response.headers.set_raw("ETag", vec![etag.into_bytes()]);
+        }
+
+        response
+            .headers
+            .set_raw("Cache-Control", vec![cache_control.as_bytes().to_vec()]);
+        response
+            .headers
+            .set_raw("Accept-Ranges", vec![b"bytes".to_vec()]);
+
+        // The headers of the GET, its Content-Length included, which iron
+        // would reset for a response without a body
+        if req.method == Method::Head {
+            response.body = Some(Box::new(Vec::<u8>::new()));
+        }
+
+        Ok(response)
+    }
+}
+
+/// The `206` of the byte range the request asks for, or the `416` of one
+/// past the end of the file. Requests for several ranges, ranges of another
+/// unit or of another version of the file are answered with all of it,
+/// which RFC 7233 allows.
+fn partial_content(req: &Request, path: &Path, etag: Option<&str>) -> Option<Response> {
+    let values = req.headers.get_raw("Range")?;
+
+    if values.len() != 1 {
+        return None;
+    }
+
+    // A range of another version would not fit the rest of the client's copy
+    if let Some(validators) = req.headers.get_raw("If-Range") {
+        let matches = etag.map_or(false, |etag| {
+            validators
+                .iter()
+                .any(|validator| String::from_utf8_lossy(validator).trim() == etag)
+        });
+
+        if !matches {
+            return None;
+        }
+    }
+
+    let length = fs::metadata(path).ok()?.len();
+
+    match byte_range(&String::from_utf8_lossy(&values[0]), length)? {
+        ByteRange::Satisfiable(first, last) => {
+            let mut file = File::open(path).ok()?;
+            file.seek(SeekFrom::Start(first)).ok()?;
+
+            let mut body = Vec::new();
+            file.take(last - first + 1).read_to_end(&mut body).ok()?;
+
+            debug!("Serving bytes {}-{} of {}", first, last, path.display());
+
+            let mut response = Response::with((status::PartialContent, body));
+            response.headers.set_raw(
+                "Content-Range",
+                vec![format!("bytes {}-{}/{}", first, last, length).into_bytes()],
+            );
+
+            Some(response)
+        }
+        ByteRange::Unsatisfiable => {
+            let mut response = Response::with(status::RangeNotSatisfiable);
+            response.headers.set_raw(
+                "Content-Range",
+                vec![format!("bytes */{}", length).into_bytes()],
+            );
+
+            Some(response)
+        }
+    }
+}
+
+/// The range of a `bytes=first-last`, `bytes=first-` or `bytes=-suffix`
+/// header for a file of `length` bytes, none if it is not a single one of
+/// those
+fn byte_range(value: &str, length: u64) -> Option<ByteRange> {
+    let value = value.trim();
+
+    match value.get(..6) {
+        Some(unit) if unit.eq_ignore_ascii_case("bytes=") => {}
+        _ => return None,
+    }
+
+    let spec = value[6..].trim();
+
+    if spec.contains(',') {
+        return None;
+    }
+
+    let mut bounds = spec.splitn(2, '-').map(str::trim);
+    let (first, last) = (bounds.next()?, bounds.next()?);
+
+    let (first, last) = match (first.is_empty(), last.is_empty()) {
+        (true, true) => return None,
+        // The last `suffix` bytes
+        (true, false) => {
+            let suffix = last.parse::<u64>().ok()?;
+
+            if suffix == 0 || length == 0 {
+                return Some(ByteRange::Unsatisfiable);
+            }
+
+            (length.saturating_sub(suffix), length - 1)
+        }
+        (false, true) => (first.parse::<u64>().ok()?, length.saturating_sub(1)),
+        (false, false) => {
+            let (first, last) = (first.parse::<u64>().ok()?, last.parse::<u64>().ok()?);
+
+            if last < first {
+                return None;
+            }
+
+            (first, cmp::min(last, length.saturating_sub(1)))
+        }
+    };
+
+    if first >= length {
+        Some(ByteRange::Unsatisfiable)
+    } else {
+        Some(ByteRange::Satisfiable(first, last))
+    }
+}
+
+/// A strong `ETag` from the size and modification time of the file
+fn entity_tag(path: &Path) -> Option<String> {
+    let metadata = fs::metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+
+    Some(format!(
+        "\"{:x}-{:x}.{:x}\"",
+        metadata.len(),
+        modified.as_secs(),
+        modified.subsec_nanos()
+    ))
+}
+
+/// Whether an `If-None-Match` of the request lists the tag, weak
+/// comparison being what RFC 7232 asks for
+fn if_none_match(req: &Request, etag: &str) -> bool {
+    let values = match req.headers.get_raw("If-None-Match") {
+        Some(values) => values,
+        None => return false,
+    };
+
+    values.iter().any(|value| {
+        String::from_utf8_lossy(value).split(',').any(|tag| {
+            let tag = tag.trim();
+            tag == "*" || tag.trim_start_matches("W/") == etag
+        })
+    })
+}
+
+/// Whether a part of the file name between dots or dashes is a run of hex
+/// digits, with at least one digit so that words are not taken for one
+fn has_content_hash(name: &str) -> bool {
+    let stem = match name.rfind('.') {
+        Some(extension) => &name[..extension],
+        None => return false,
+    };
+
+    stem.split(|c| c == '.' || c == '-' || c == '_')
+        .skip(1)
+        .any(|part| {
+            part.len() >= MIN_HASH_LENGTH
+                && part.chars().all(|c| c.is_ascii_hexdigit())
+                && part.chars().any(|c| c.is_ascii_digit())
+        })
+}
+
+/// Whether the content type is text, which browsers decode as UTF-8 only
+/// when told so
+fn is_text(mime_type: &str) -> bool {
+    let mime_type = mime_type.to_lowercase();
+
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || mime_type == "application/json"
+        || mime_type == "application/xml"
+        || mime_type == "application/javascript"
+}
+
+fn has_variant(path: &Path) -> bool {
+    ENCODINGS
+        .iter()
+        .any(|&(_, extension)| variant_path(path, extension).is_file())
+}
+
+/// `app.js.br` for `app.js`
+fn variant_path(path: &Path, extension: &str) -> PathBuf {
+    let mut variant = path.to_path_buf().into_os_string();
+    variant.push(".");
+    variant.push(extension);
+    PathBuf::from(variant)
+}
+
+/// The content codings of `Accept-Encoding`, without the ones refused with
+/// `q=0`
+fn accepted_encodings(req: &Request) -> Vec<String> {
+    let values = match req.headers.get_raw("Accept-Encoding") {
+        Some(values) => values,
+        None => return Vec::new(),
+    };
+
+    values
+        .iter()
+        .flat_map(|value| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .filter_map(|item| {
+                    let mut parts = item.split(';').map(str::trim);
+                    let coding = parts.next()?.to_lowercase();
+
+                    let refused = parts.any(|param| {
+                        let param = param.replace(' ', "");
+                        param.starts_with("q=") && param[2..].parse::<f32>().ok() == Some(0.0)
+                    });
+
+                    if coding.is_empty() || refused {
+                        None
+                    } else {
+                        Some(coding)
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Decodes the `%XX` escapes of a path segment, none if they do not make
+/// UTF-8
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = ::std::str::from_utf8(&bytes[i + 1..i + 3]).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The content type every extension of `MIME_TYPES` is served as
+    const CONTENT_TYPES: &[(&str, &str)] = &[
+        ("html", "text/html; charset=utf-8"),
+        ("htm", "text/html; charset=utf-8"),
+        ("css", "text/css; charset=utf-8"),
+        ("js", "text/javascript; charset=utf-8"),
+        ("mjs", "text/javascript; charset=utf-8"),
+        ("json", "application/json; charset=utf-8"),
+        ("map", "application/json; charset=utf-8"),
+        ("webmanifest", "application/manifest+json; charset=utf-8"),
+        ("xml", "application/xml; charset=utf-8"),
+        ("txt", "text/plain; charset=utf-8"),
+        ("csv", "text/csv; charset=utf-8"),
+        ("md", "text/markdown; charset=utf-8"),
+        ("svg", "image/svg+xml; charset=utf-8"),
+        ("png", "image/png"),
+        ("jpg", "image/jpeg"),
+        ("jpeg", "image/jpeg"),
+        ("gif", "image/gif"),
+        ("webp", "image/webp"),
+        ("avif", "image/avif"),
+        ("ico", "image/x-icon"),
+        ("bmp", "image/bmp"),
+        ("woff", "font/woff"),
+        ("woff2", "font/woff2"),
+        ("ttf", "font/ttf"),
+        ("otf", "font/otf"),
+        ("eot", "application/vnd.ms-fontobject"),
+        ("wasm", "application/wasm"),
+        ("pdf", "application/pdf"),
+        ("mp3", "audio/mpeg"),
+        ("ogg", "audio/ogg"),
+        ("wav", "audio/wav"),
+        ("mp4", "video/mp4"),
+        ("webm", "video/webm"),
+    ];
+
+    fn content_type(files: &StaticFiles, name: &str) -> String {
+        files.content_type(Path::new(name))
+    }
+
+    #[test]
+    fn every_extension_has_its_content_type() {
+        let files = StaticFiles::new("ui");
+
+        for &(extension, expected) in CONTENT_TYPES.iter() {
+            assert_eq!(
+                content_type(&files, &format!("asset.{}", extension)),
+                expected,
+                "{}",
+                extension
+            );
+        }
+
+        // An extension added to the table has to be added here as well
+        for &(extension, _) in MIME_TYPES.iter() {
+            assert!(
+                CONTENT_TYPES.iter().any(|&(known, _)| known == extension),
+                "{}",
+                extension
+            );
+        }
+    }
+
+    #[test]
+    fn extensions_match_in_any_case() {
+        let files = StaticFiles::new("ui");
+
+        assert_eq!(content_type(&files, "FONT.WOFF2"), "font/woff2");
+        assert_eq!(
+            content_type(&files, "Index.Html"),
+            "text/html; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn unknown_extensions_are_served_as_bytes() {
+        let files = StaticFiles::new("ui");
+
+        assert_eq!(content_type(&files, "firmware.bin"), DEFAULT_MIME_TYPE);
+        assert_eq!(content_type(&files, "LICENSE"), DEFAULT_MIME_TYPE);
+    }
+
+    #[test]
+    fn configured_content_types_take_precedence() {
+        let files = StaticFiles::new("ui").mime_types(&[
+            ("glb".to_string(), "model/gltf-binary".to_string()),
+            ("js".to_string(), "application/javascript".to_string()),
+            (
+                "txt".to_string(),
+                "text/plain; charset=iso-8859-1".to_string(),
+            ),
+        ]);
+
+        assert_eq!(content_type(&files, "scene.glb"), "model/gltf-binary");
+        assert_eq!(
+            content_type(&files, "app.js"),
+            "application/javascript; charset=utf-8"
+        );
+        assert_eq!(
+            content_type(&files, "notes.txt"),
+            "text/plain; charset=iso-8859-1"
+        );
+        assert_eq!(content_type(&files, "font.woff2"), "font/woff2");
+    }
+}
@@ -0,0 +1,84 @@
+use std::io::{self, Write};
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+
+use wifi_connect::NetworkEvent;
+
+use json_events;
+
+/// The network the device joined, recorded from the `connected` event for
+/// the summary printed once the flow succeeded
+#[derive(Clone, Default)]
+pub struct Summary(Arc<Mutex<Option<Connection>>>);
+
+struct Connection {
+    ssid: String,
+    uuid: Option<String>,
+    ip: Option<Ipv4Addr>,
+    interface: String,
+}
+
+impl Summary {
+    /// Event callback recording the network joined
+    pub fn recorder(&self) -> impl Fn(&NetworkEvent) + Send + Sync + 'static {
+        let summary = self.clone();
+
+        move |event| {
+            if let NetworkEvent::Connected {
+                ref ssid,
+                ip,
+                ref uuid,
+                ref interface,
+                ..
+            } = *event
+            {
+                if let Ok(mut connection) = summary.0.lock() {
+                    *connection = Some(Connection {
+                        ssid: ssid.clone(),
+                        uuid: uuid.clone(),
+                        ip,
+                        interface: interface.clone(),
+                    });
+                }
+            }
+        }
+    }
+
+    /// A single line on stdout, or an object in `--json-events` mode. The
+    /// `connected` event is only sent after the connectivity check, so the
+    /// network is usable by then.
+    pub fn print(&self) {
+        let connection = match self.0.lock() {
+            Ok(connection) => connection,
+            Err(_) => return,
+        };
+
+        let connection = match *connection {
+            Some(ref connection) => connection,
+            None => return,
+        };
+
+        if json_events::is_enabled() {
+            json_events::write_summary(json!({
+                "ssid": connection.ssid,
+                "uuid": connection.uuid,
+                "ip": connection.ip,
+                "iface": connection.interface,
+            }));
+            return;
+        }
+
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+
+        let _ = writeln!(
+            stdout,
+            "connected ssid={} uuid={} ip={} iface={}",
+            connection.ssid,
+            connection.uuid.as_ref().map_or("", String::as_str),
+            connection.ip.map_or_else(String::new, |ip| ip.to_string()),
+            connection.interface
+        )
+        .and_then(|()| stdout.flush());
+    }
+}
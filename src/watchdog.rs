@@ -0,0 +1,189 @@
+use std::env;
+use std::fmt;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use config::{Config, WorkerFailure};
+use errors::*;
+use exit::{exit, ExitSender};
+use network::supervise_network_commands;
+
+/// How often the network thread beats while it waits for commands
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the watchdog looks at the network thread
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Allowance on top of the longest connect attempt and scan, for waiting
+/// on connectivity, binding the listener and raising the portal again
+const STALL_MARGIN_SECS: u64 = 120;
+
+/// Restarts so far, carried over to the program executed again
+const RESTARTS_ENV: &str = "WIFI_CONNECT_WORKER_RESTARTS";
+
+/// A network thread failing again and again is not restarted any more
+const MAX_RESTARTS: u32 = 5;
+
+/// Spares the device a tight restart loop when the thread fails on startup
+const RESTART_DELAY: Duration = Duration::from_secs(5);
+
+/// Updated by the network thread whenever it is not blocked, so that the
+/// watchdog can tell a stuck thread from an idle one
+#[derive(Clone)]
+pub struct Heartbeat {
+    started: Instant,
+    /// Milliseconds since `started` of the latest beat
+    last: Arc<AtomicUsize>,
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        Heartbeat {
+            started: Instant::now(),
+            last: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn beat(&self) {
+        let elapsed = duration_millis(self.started.elapsed());
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    fn since_last(&self) -> Duration {
+        let last = Duration::from_millis(self.last.load(Ordering::Relaxed) as u64);
+        self.started.elapsed().checked_sub(last).unwrap_or_default()
+    }
+}
+
+/// How the network thread failed
+enum Failure {
+    Panicked,
+    /// Silent for that long
+    Stuck(Duration),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Failure::Panicked => write!(f, "panicked"),
+            Failure::Stuck(silent) => write!(f, "is stuck for {}s", silent.as_secs()),
+        }
+    }
+}
+
+/// Runs the network thread and watches it, reporting a panic or a thread
+/// that stopped beating through `exit_tx`, or executing the program again
+/// with `--on-worker-failure restart`
+pub fn spawn_network_thread(config: Config, exit_tx: ExitSender) {
+    thread::spawn(move || {
+        let failure = match watch_network_thread(&config, &exit_tx) {
+            Some(failure) => failure,
+            None => return,
+        };
+
+        error!("The network thread {}", failure);
+
+        if config.on_worker_failure == WorkerFailure::Restart {
+            if let Err(e) = restart(&config, &failure) {
+                warn!("Not restarting: {}", e);
+            }
+        }
+
+        exit(
+            &exit_tx,
+            ErrorKind::NetworkWorkerFailed(failure.to_string()).into(),
+        );
+    });
+}
+
+/// Waits for the network thread to end, none if it reported the outcome of
+/// the flow, the failure otherwise
+fn watch_network_thread(config: &Config, exit_tx: &ExitSender) -> Option<Failure> {
+    let heartbeat = Heartbeat::new();
+    let (done_tx, done_rx) = channel();
+
+    {
+        let config = config.clone();
+        let exit_tx = exit_tx.clone();
+        let heartbeat = heartbeat.clone();
+
+        thread::spawn(move || {
+            supervise_network_commands(&config, &exit_tx, &heartbeat);
+
+            // Never sent if the thread panicked, which drops the sender
+            let _ = done_tx.send(());
+        });
+    }
+
+    let stall_timeout = stall_timeout(config);
+
+    loop {
+        match done_rx.recv_timeout(CHECK_INTERVAL) {
+            Ok(()) => return None,
+            Err(RecvTimeoutError::Disconnected) => return Some(Failure::Panicked),
+            Err(RecvTimeoutError::Timeout) => {
+                let silent = heartbeat.since_last();
+
+                if silent > stall_timeout {
+                    return Some(Failure::Stuck(silent));
+                }
+            }
+        }
+    }
+}
+
+/// Connect attempts and scans block the network thread, so it is only taken
+/// for stuck once it has been silent for longer than the longest of them
+fn stall_timeout(config: &Config) -> Duration {
+    Duration::from_secs(config.max_connect_timeout + config.scan_timeout + STALL_MARGIN_SECS)
+}
+
+/// Executes the program again, which sets up the portal from scratch. Only
+/// a thread that panicked has torn down the portal and dnsmasq, and only a
+/// process still running as root can raise them again.
+fn restart(config: &Config, failure: &Failure) -> Result<()> {
+    if let Failure::Stuck(_) = *failure {
+        bail!("the stuck thread still holds the portal");
+    }
+
+    if config.run_as.is_some() {
+        bail!("root privileges were dropped");
+    }
+
+    let restarts = env::var(RESTARTS_ENV)
+        .ok()
+        .and_then(|restarts| restarts.parse().ok())
+        .unwrap_or(0u32);
+
+    if restarts >= MAX_RESTARTS {
+        bail!("restarted {} times already", restarts);
+    }
+
+    warn!(
+        "Restarting in {}s ({} of {})",
+        RESTART_DELAY.as_secs(),
+        restarts + 1,
+        MAX_RESTARTS
+    );
+
+    thread::sleep(RESTART_DELAY);
+
+    // Only returns if executing failed
+    let e = Command::new(env::current_exe()?)
+        .args(env::args_os().skip(1))
+        .env(RESTARTS_ENV, (restarts + 1).to_string())
+        .exec();
+
+    Err(e.into())
+}
+
+fn duration_millis(duration: Duration) -> usize {
+    (duration.as_secs() as usize)
+        .saturating_mul(1000)
+        .saturating_add(duration.subsec_millis() as usize)
+}
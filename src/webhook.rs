@@ -0,0 +1,126 @@
+use std::cmp;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::Duration;
+
+use hyper::header::{Authorization, Bearer, ContentType};
+use hyper::Client;
+use serde_json;
+
+use config::Config;
+use network::{event_channel, EventReceiver, EventSender, NetworkEvent};
+
+const DELIVERY_ATTEMPTS: u32 = 6;
+const MAX_BACKOFF_SECS: u64 = 16;
+const REQUEST_TIMEOUT_SECS: u64 = 10;
+const FLUSH_TIMEOUT_SECS: u64 = 60;
+
+/// Delivers provisioning events to `--webhook-url` from a dedicated thread.
+/// Failed deliveries are retried with backoff, they never block or fail the
+/// provisioning flow itself.
+pub struct Webhook {
+    event_tx: EventSender,
+    done_rx: Receiver<()>,
+}
+
+impl Webhook {
+    pub fn spawn(config: &Config) -> Option<Self> {
+        let url = config.webhook_url.clone()?;
+        let token = config.webhook_token.clone();
+
+        if !url.starts_with("http://") {
+            warn!("Only plain HTTP webhook URLs are supported: {}", url);
+            return None;
+        }
+
+        let (event_tx, event_rx) = event_channel();
+        let (done_tx, done_rx) = channel();
+
+        thread::spawn(move || {
+            deliver_events(&url, &token, &event_rx);
+            let _ = done_tx.send(());
+        });
+
+        Some(Webhook { event_tx, done_rx })
+    }
+
+    pub fn event_tx(&self) -> EventSender {
+        self.event_tx.clone()
+    }
+
+    /// Waits for queued events to be delivered before exiting. The connected
+    /// event can only go out once the new uplink is up, so this gives it a
+    /// bounded amount of time.
+    pub fn flush(self) {
+        let Webhook { event_tx, done_rx } = self;
+
+        drop(event_tx);
+
+        if done_rx
+            .recv_timeout(Duration::from_secs(FLUSH_TIMEOUT_SECS))
+            .is_err()
+        {
+            warn!("Giving up on pending webhook deliveries");
+        }
+    }
+}
+
+fn deliver_events(url: &str, token: &Option<String>, event_rx: &EventReceiver) {
+    let mut client = Client::new();
+    client.set_read_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+    client.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)));
+
+    while let Ok(event) = event_rx.recv() {
+        match event {
+            NetworkEvent::NetworksRefreshed | NetworkEvent::Exiting => continue,
+            _ => {}
+        }
+
+        let body = match serde_json::to_string(&event) {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Serializing webhook event failed: {}", e);
+                continue;
+            }
+        };
+
+        deliver_event(&client, url, token, event.name(), &body);
+    }
+}
+
+fn deliver_event(client: &Client, url: &str, token: &Option<String>, name: &str, body: &str) {
+    for attempt in 1..=DELIVERY_ATTEMPTS {
+        let mut request = client.post(url).header(ContentType::json()).body(body);
+
+        if let Some(ref token) = *token {
+            request = request.header(Authorization(Bearer {
+                token: token.clone(),
+            }));
+        }
+
+        match request.send() {
+            Ok(ref response) if response.status.is_success() => {
+                debug!("Webhook event '{}' delivered", name);
+                return;
+            }
+            Ok(response) => warn!(
+                "Webhook event '{}' rejected (attempt {}): {}",
+                name, attempt, response.status
+            ),
+            Err(e) => warn!(
+                "Webhook event '{}' delivery failed (attempt {}): {}",
+                name, attempt, e
+            ),
+        }
+
+        if attempt < DELIVERY_ATTEMPTS {
+            let backoff = cmp::min(1 << (attempt - 1), MAX_BACKOFF_SECS);
+            thread::sleep(Duration::from_secs(backoff));
+        }
+    }
+
+    error!(
+        "Dropping webhook event '{}' after {} attempts",
+        name, DELIVERY_ATTEMPTS
+    );
+}
@@ -0,0 +1,166 @@
+//! Runs the provisioning flow of the mock backend with `--json-events`, as
+//! orchestration tools do, and checks the events written to stdout
+#![cfg(feature = "mock-backend")]
+
+extern crate serde_json;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::Value;
+
+/// Time each step of the flow may take
+const STEP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The mock backend refuses any passphrase of its secured networks but this
+const MOCK_PASSPHRASE: &str = "mock-passphrase";
+
+/// Kills the portal if the test fails halfway through the flow
+struct Portal(Child);
+
+impl Drop for Portal {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+/// A port nothing listens on, the portal refusing to listen on port 0
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// The lines written to stdout, read on a thread of their own so that a
+/// wedged flow fails the test instead of hanging it
+fn read_events(stdout: ChildStdout) -> Receiver<Value> {
+    let (event_tx, event_rx) = channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let line = line.unwrap();
+            let event = serde_json::from_str(&line)
+                .unwrap_or_else(|e| panic!("'{}' is not a JSON object: {}", line, e));
+
+            if event_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    event_rx
+}
+
+/// The next event, which has to carry the schema version
+fn next_event(events: &Receiver<Value>) -> Value {
+    let event = events
+        .recv_timeout(STEP_TIMEOUT)
+        .expect("the portal wrote no further event");
+
+    assert_eq!(event["version"], 1, "{}", event);
+    event
+}
+
+/// Status code of the reply to the connect request, 0 if none was read
+fn connect(port: u16, ssid: &str, passphrase: &str) -> u16 {
+    let body = format!(
+        r#"{{ "ssid": "{}", "passphrase": "{}" }}"#,
+        ssid, passphrase
+    );
+    let deadline = Instant::now() + STEP_TIMEOUT;
+
+    let mut stream = loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+            Err(e) => panic!("the portal does not listen on port {}: {}", port, e),
+        }
+    };
+    stream.set_read_timeout(Some(STEP_TIMEOUT)).unwrap();
+
+    write!(
+        stream,
+        "POST /connect HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .unwrap();
+
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+
+    response
+        .split(' ')
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0)
+}
+
+#[test]
+fn events_follow_the_provisioning_flow() {
+    let port = free_port();
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_wifi-connect"))
+        .args(&[
+            "--backend",
+            "mock",
+            "--json-events",
+            "--portal-gateway",
+            "127.0.0.1",
+            "--portal-listening-port",
+            &port.to_string(),
+            "--no-csrf",
+            "--allow-root",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let events = read_events(child.stdout.take().unwrap());
+    let mut portal = Portal(child);
+
+    assert_eq!(next_event(&events)["event"], "portal-started");
+
+    assert_eq!(connect(port, "Mock WPA", "wrong-passphrase"), 200);
+
+    let event = next_event(&events);
+    assert_eq!(event["event"], "credentials-received");
+    assert_eq!(event["ssid"], "Mock WPA");
+    // The credentials themselves are never written
+    assert!(event.get("passphrase").is_none());
+
+    let event = next_event(&events);
+    assert_eq!(event["event"], "failed");
+    assert_eq!(event["ssid"], "Mock WPA");
+    assert!(event["reason"].is_string());
+
+    // The portal may be gone before the reply to this one is read
+    connect(port, "Mock WPA", MOCK_PASSPHRASE);
+
+    let event = next_event(&events);
+    assert_eq!(event["event"], "credentials-received");
+    assert_eq!(event["ssid"], "Mock WPA");
+
+    let event = next_event(&events);
+    assert_eq!(event["event"], "connected");
+    assert_eq!(event["ssid"], "Mock WPA");
+
+    assert_eq!(next_event(&events)["event"], "summary");
+
+    let event = next_event(&events);
+    assert_eq!(event["event"], "exiting");
+    assert_eq!(event["code"], 0);
+
+    // Nothing follows the exit
+    assert!(events.recv_timeout(STEP_TIMEOUT).is_err());
+    assert!(portal.0.wait().unwrap().success());
+}
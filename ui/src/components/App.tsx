@@ -29,16 +29,29 @@ const StyledNavbar = styled(Navbar)`background-color: #0B1227;`;
 
 export interface NetworkInfo {
 	ssid?: string;
+	ssid_raw?: string;
 	identity?: string;
 	passphrase?: string;
 }
 
 export interface Network {
 	ssid: string;
+	ssid_raw?: string;
 	security: string;
 }
 
+// Repeated in requests changing anything, which the portal refuses otherwise
+const getCsrfToken = () =>
+	document.cookie.match(/(?:^|;\s*)csrf_token=([^;]*)/)?.[1] ?? '';
+
+// The page the device was trying to open before being sent to the portal
+const getOriginalUrl = () => {
+	const url = new URLSearchParams(window.location.search).get('original_url');
+	return url && /^https?:\/\//i.test(url) ? url : undefined;
+};
+
 const App = () => {
+	const [originalUrl] = React.useState(getOriginalUrl);
 	const [attemptedConnect, setAttemptedConnect] = React.useState(false);
 	const [isFetchingNetworks, setIsFetchingNetworks] = React.useState(true);
 	const [error, setError] = React.useState('');
@@ -68,11 +81,17 @@ const App = () => {
 		setAttemptedConnect(true);
 		setError('');
 
+		// Networks with SSIDs that are not valid UTF-8 are only joinable by
+		// their raw bytes
+		const network = availableNetworks.find((n) => n.ssid === data.ssid);
+		const body = network?.ssid_raw ? { ...data, ssid_raw: network.ssid_raw } : data;
+
 		fetch('/connect', {
 			method: 'POST',
-			body: JSON.stringify(data),
+			body: JSON.stringify(body),
 			headers: {
 				'Content-Type': 'application/json',
+				'X-CSRF-Token': getCsrfToken(),
 			},
 		})
 			.then((resp) => {
@@ -97,6 +116,7 @@ const App = () => {
 						isFetchingNetworks || availableNetworks.length > 0
 					}
 					error={error}
+					originalUrl={originalUrl}
 				/>
 				<NetworkInfoForm
 					availableNetworks={availableNetworks}
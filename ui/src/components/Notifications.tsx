@@ -5,10 +5,12 @@ export const Notifications = ({
 	hasAvailableNetworks,
 	attemptedConnect,
 	error,
+	originalUrl,
 }: {
 	hasAvailableNetworks: boolean;
 	attemptedConnect: boolean;
 	error: string;
+	originalUrl?: string;
 }) => {
 	return (
 		<>
@@ -20,6 +22,12 @@ export const Notifications = ({
 						Access Point will be back up in a few minutes, and reloading this
 						page will allow you to try again.
 					</Txt.span>
+					{!!originalUrl && (
+						<Txt.span>
+							&nbsp;Once online, <a href={originalUrl}>continue to where you were
+							going</a>.
+						</Txt.span>
+					)}
 				</Alert>
 			)}
 			{!hasAvailableNetworks && (